@@ -3,5 +3,7 @@ extern crate ructe;
 use ructe::{Result, Ructe};
 
 fn main() -> Result<()> {
-    Ructe::from_env()?.compile_templates("templates")
+    Ructe::from_env()?
+        .add_env_constant("PKG_NAME", "CARGO_PKG_NAME")
+        .compile_templates("templates")
 }
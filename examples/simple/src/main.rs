@@ -27,6 +27,43 @@ fn test_hello() {
     );
 }
 
+#[test]
+fn test_limited_output() {
+    let mut buf = Vec::new();
+    let err = hello_html(&mut LimitedWriter::new(&mut buf, 10)).unwrap_err();
+    assert_eq!(err.to_string(), "template output too large");
+    assert_eq!(buf, b"<h1>Hello ");
+}
+
+#[test]
+fn test_context_page() {
+    let ctx = PageContext {
+        title: "Shopping",
+        items: &["milk", "bread"],
+    };
+    assert_eq!(
+        r2s(|o| context_page_html(o, &ctx)),
+        "<h1>Shopping</h1>\n<ul><li>milk</li><li>bread</li></ul>\n"
+    );
+}
+
+#[test]
+fn test_macro_fragments() {
+    assert_eq!(
+        r2s(|o| macro_fragments_html(o, "home")),
+        "<header><button data-page=\"home\">Share</button></header>\n\
+         <footer><button data-page=\"home\">Tell a friend</button></footer>\n"
+    );
+}
+
+#[test]
+fn test_hello_static() {
+    assert_eq!(
+        templates::HELLO_HTML_STATIC,
+        r2s(|o| hello_html(o)).as_bytes(),
+    );
+}
+
 #[test]
 fn test_hello_args() {
     assert_eq!(
@@ -43,6 +80,46 @@ fn test_hello_encodes_args() {
     );
 }
 
+#[test]
+fn test_hello_args_to_string() {
+    assert_eq!(
+        hello_args_html_to_string("encoded < & >").unwrap(),
+        "<h1>Hello encoded &lt; &amp; &gt;!</h1>\n"
+    );
+}
+
+#[test]
+fn test_hello_args_to_bytes() {
+    let bytes = hello_args_html_to_bytes("World").unwrap();
+    assert_eq!(bytes, b"<h1>Hello World!</h1>\n");
+    assert!(bytes.capacity() >= "<h1>Hello !</h1>\n".len());
+}
+
+#[test]
+fn test_static_size() {
+    assert_eq!(HELLO_ARGS_HTML_STATIC_SIZE, "<h1>Hello !</h1>\n".len());
+    assert_eq!(
+        templates::page::BASE_HTML_STATIC_SIZE,
+        r2s(|o| templates::page::base_html(o, "", |_| Ok(()), |_| Ok(())))
+            .len()
+    );
+}
+
+#[test]
+fn test_content_args_to_string() {
+    use templates::page::base_html_to_string;
+    assert_eq!(
+        base_html_to_string(
+            "Title",
+            |o| o.write_all(b"<p>Body</p>"),
+            |_| Ok(())
+        )
+        .unwrap(),
+        "<!doctype html>\n<html>\n  <head><title>Title</title></head>\n  \
+         <body>\n    <h1>Title</h1>\n    <p>Body</p>\n  </body>\n</html>\n"
+    );
+}
+
 #[test]
 fn test_hello_args_two() {
     assert_eq!(
@@ -79,6 +156,166 @@ fn test_if_let_destructure() {
     )
 }
 
+#[test]
+fn test_if_else_if() {
+    assert_eq!(
+        [(Some(1), Ok(2)), (None, Err("failed")), (None, Ok(2))]
+            .iter()
+            .map(|&(a, b)| r2s(|o| if_else_if(o, a, b)))
+            .collect::<Vec<_>>(),
+        ["<p>a is 1</p>\n", "<p>failed</p>\n", "<p>nothing</p>\n"],
+    );
+}
+
+#[test]
+fn test_match_number() {
+    assert_eq!(
+        [0, 1, 7, 11]
+            .iter()
+            .map(|n| r2s(|o| match_number_html(o, *n)))
+            .collect::<Vec<_>>(),
+        [
+            "<p> few </p>\n",
+            "<p> few </p>\n",
+            "<p> some (7) </p>\n",
+            "<p> many </p>\n",
+        ],
+    );
+}
+
+#[test]
+fn test_while_pop() {
+    let stack = std::cell::RefCell::new(vec![1, 2, 3]);
+    assert_eq!(
+        r2s(|o| while_pop(o, &stack)),
+        "<ul><li>3</li><li>2</li><li>1</li></ul>\n",
+    );
+}
+
+#[test]
+fn test_let_binding() {
+    assert_eq!(
+        r2s(|o| let_binding(o, &[2, 3, 5])),
+        "\n\n<p>2 to 5, 10 in total, 10.</p>\n",
+    );
+}
+
+#[test]
+fn test_try_operator() {
+    assert_eq!(r2s(|o| try_operator(o, "Cargo.toml")), "<p>true</p>\n",);
+}
+
+#[test]
+fn test_try_operator_error() {
+    let mut buf = Vec::new();
+    let err = try_operator(&mut buf, "no/such/file").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(buf, b"<p>");
+}
+
+#[test]
+fn test_closure_join() {
+    assert_eq!(
+        r2s(|o| closure_join(o, &["a", "b&c"])),
+        "<p>A, B&amp;C</p>\n",
+    );
+}
+
+#[test]
+fn test_generic_list() {
+    assert_eq!(
+        r2s(|o| generic_list(o, &[1.5, 2.0])),
+        "<ul><li>1.5</li><li>2</li></ul>\n",
+    );
+}
+
+#[test]
+fn test_generic_filter() {
+    assert_eq!(
+        r2s(|o| generic_filter(o, &[1, 2, 3, 4], |n| n % 2 == 0)),
+        "<ul><li>2</li><li>4</li></ul>\n",
+    );
+}
+
+#[test]
+fn test_lifetime_args() {
+    let content = String::from("Some <content>");
+    assert_eq!(
+        r2s(|o| lifetime_args(o, "Title".into(), &content, &mut 0)),
+        "<h1>Title</h1>\n<p>Some &lt;content&gt;</p>\n",
+    );
+}
+
+#[test]
+fn test_dyn_iterator() {
+    assert_eq!(
+        r2s(|o| dyn_iterator(o, &mut "a b".split(' '), || "; ".into())),
+        "<p>a; b; </p>\n",
+    );
+}
+
+#[test]
+fn test_raw_html() {
+    assert_eq!(
+        r2s(|o| raw_html(o, "A & B", "<p>Trusted <em>markup</em></p>")),
+        "<h1>A &amp; B</h1>\n<div><p>Trusted <em>markup</em></p></div>\n",
+    );
+}
+
+#[test]
+fn test_trim_whitespace() {
+    assert_eq!(
+        r2s(|o| trim_whitespace(o, &["a", "b"], None)),
+        "<ul><li>a</li><li>b</li></ul>\n",
+    );
+}
+
+#[test]
+fn test_extends_layout() {
+    assert_eq!(
+        r2s(|o| extends_layout(o, "Items", &["a", "b"])),
+        "<!doctype html>\n\
+         <html><head><title>Items</title></head>\n\
+         <body><ul><li>a</li><li>b</li></ul></body></html>\n",
+    );
+}
+
+#[test]
+fn test_layout_alone() {
+    assert_eq!(
+        r2s(|o| base_layout(o, "Empty")),
+        "<!doctype html>\n\
+         <html><head><title>Empty</title></head>\n\
+         <body></body></html>\n",
+    );
+}
+
+#[test]
+fn test_include_partial() {
+    assert_eq!(
+        r2s(|o| include_partial(o, "Users", &["A", "B"])),
+        "<h1>Users</h1>\n\
+         <nav>Logged in as A</nav>\n\
+         <nav>Logged in as B</nav>\n",
+    );
+}
+
+#[test]
+fn test_include_from_template_dir() {
+    assert_eq!(
+        r2s(|o| templates::page::welcome_html(o, "World")),
+        "<main><h1>Hello World!</h1>\n</main>\n",
+    );
+}
+
+#[test]
+fn test_filter_in_subdirectory() {
+    assert_eq!(
+        r2s(|o| templates::page::shout_name_html(o, "World")),
+        "<b>WORLD</b>\n",
+    );
+}
+
 #[test]
 fn test_list() {
     assert_eq!(
@@ -92,6 +329,25 @@ fn test_list_empty() {
     assert_eq!(r2s(|o| list_html(o, &[])), "\n<p>No items</p>\n\n");
 }
 
+#[test]
+fn test_list_writes() {
+    struct CountWrites(usize);
+    impl Write for CountWrites {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += 1;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    // The text between two items is written in one call, so this is
+    // two writes less than writing the text around each item.
+    let mut out = CountWrites(0);
+    list_html(&mut out, &["a", "b", "c"]).unwrap();
+    assert_eq!(out.0, 10);
+}
+
 #[test]
 fn test_list_destructure() {
     assert_eq!(
@@ -110,6 +366,33 @@ fn test_list_destructure_2() {
     );
 }
 
+#[test]
+fn test_for_patterns() {
+    let ages = [("Rasmus", 44), ("Mike", 36)].iter().cloned().collect();
+    assert_eq!(
+        r2s(|o| for_patterns_html(o, &ages)),
+        "<dl><dt>Mike</dt><dd>36</dd><dt>Rasmus</dt><dd>44</dd></dl>\n\
+         <p>(1, 2)(3, -4)</p>\n"
+    );
+}
+
+#[test]
+fn test_const_values() {
+    assert_eq!(
+        r2s(|o| const_values_html(o, &["a", "b", "c"])),
+        "\n<p class=\"item\">a</p><p class=\"item\">b</p>"
+    );
+}
+
+#[test]
+fn test_site_footer() {
+    assert_eq!(
+        r2s(|o| site_footer_html(o)),
+        "<footer>&copy; 2020 Ructe example, made with simple</footer>\n"
+    );
+    assert_eq!(templates::SITE_NAME, "Ructe example");
+}
+
 #[test]
 fn test_uselist() {
     assert_eq!(
@@ -133,6 +416,72 @@ fn test_hello_utf8() {
     );
 }
 
+#[test]
+fn test_named_args() {
+    let args = HelloUtf8HtmlArgs::new()
+        .full("δ < ε")
+        .raw("δ &lt; ε")
+        .one("δ")
+        .other("ε");
+    assert_eq!(
+        r2s(|o| hello_utf8_html_with_args(o, args)),
+        r2s(|o| hello_utf8_html(o, "δ", "ε", "δ < ε", "δ &lt; ε")),
+    );
+    let mut buf = Vec::new();
+    let err = hello_utf8_html_with_args(
+        &mut buf,
+        HelloUtf8HtmlArgs::new().one("δ"),
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Missing argument other of hello_utf8_html");
+}
+
+#[test]
+fn test_cfg_template() {
+    // This template only exists in test builds.
+    assert_eq!(
+        r2s(|o| test_build_html(o, "one")),
+        "<p>Test build one</p>\n"
+    );
+}
+
+#[test]
+fn test_validated_args() {
+    let errors = ValidatedListHtmlArgs::new()
+        .title("")
+        .max_items(12)
+        .validate()
+        .unwrap_err();
+    assert_eq!(
+        errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+        [
+            "title: must not be empty",
+            "max_items: must be between 1 and 10",
+        ],
+    );
+    let args = ValidatedListHtmlArgs::new()
+        .title("Items")
+        .validator("max_items", |args| match args.max_items {
+            Some(n) if n % 2 == 1 => Err("must be even".into()),
+            _ => Ok(()),
+        });
+    assert_eq!(
+        args.validate().unwrap_err(),
+        [ValidationError {
+            argument: "max_items",
+            message: "is not set".into(),
+        }],
+    );
+    let args = args.max_items(3);
+    assert_eq!(args.validate().unwrap_err()[0].to_string(), "max_items: must be even");
+    let args = ValidatedListHtmlArgs::new().title("Items").max_items(4);
+    assert_eq!(args.validate(), Ok(()));
+    assert_eq!(
+        r2s(|o| validated_list_html_with_args(o, args)),
+        "<h1>Items</h1>\n<p>Showing 4 items.</p>\n"
+    );
+}
+
 #[test]
 fn test_comments() {
     assert_eq!(
@@ -142,9 +491,50 @@ fn test_comments() {
     );
 }
 
+#[test]
+fn test_nested_comments() {
+    assert_eq!(r2s(|o| nested_comments(o, true)), "<p>shown</p> \n\n");
+}
+
+#[test]
+fn test_recursive_template() {
+    use models::Tree;
+    let leaf = |name| Tree {
+        name,
+        children: vec![],
+    };
+    let tree = Tree {
+        name: "a",
+        children: vec![leaf("b"), leaf("c")],
+    };
+    assert_eq!(
+        r2s(|o| tree_html(o, &tree)),
+        "<li>a<ul><li>b</li>\n<li>c</li>\n</ul></li>\n",
+    );
+}
+
+#[test]
+fn test_recursion_depth() {
+    use models::Tree;
+    let mut tree = Tree {
+        name: "x",
+        children: vec![],
+    };
+    for _ in 0..40 {
+        tree = Tree {
+            name: "x",
+            children: vec![tree],
+        };
+    }
+    let out = r2s(|o| tree_html(o, &tree));
+    assert!(out.contains("<ul><!-- max depth exceeded --></ul>"));
+    assert_eq!(out.matches("<li>").count(), 33);
+}
+
 mod models {
     use std::fmt;
-    use templates::Html;
+    use std::io::{self, Write};
+    use templates::{Html, ToHtml};
 
     pub struct User<'a> {
         pub name: &'a str,
@@ -160,6 +550,255 @@ mod models {
             f.write_str(self.name)
         }
     }
+
+    /// A node of a tree, rendered by a recursive template.
+    pub struct Tree {
+        pub name: &'static str,
+        pub children: Vec<Tree>,
+    }
+
+    /// A filter adding an exclamation mark to a value.
+    pub fn shout(value: impl fmt::Display) -> String {
+        format!("{}!", value)
+    }
+
+    /// A text that is shortened to a number of characters when
+    /// written to a template.
+    pub struct Truncated<'a>(pub &'a str, pub usize);
+
+    impl<'a> ToHtml for Truncated<'a> {
+        fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
+            match self.0.char_indices().nth(self.1) {
+                Some((i, _)) => format!("{}…", &self.0[..i]).to_html(out),
+                None => self.0.to_html(out),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_filters() {
+    assert_eq!(
+        r2s(|o| filters_html(o, "Hi", "  Wow! Fish and chips  ")),
+        "<h1>HI!</h1>\n<p>Wow. Fish an…</p>\n<p>23 chars | Site</p>\n"
+    );
+}
+
+/// A tiny stand-in for a real message catalog.
+mod i18n {
+    pub fn gettext(msgid: &str) -> String {
+        match msgid {
+            "Hello & welcome!" => "Hej & välkommen!".into(),
+            _ => msgid.into(),
+        }
+    }
+    pub fn ngettext(msgid: &str, plural: &str, n: u32) -> String {
+        match (msgid, n) {
+            ("One new message", 1) => "Ett nytt meddelande".into(),
+            ("One new message", _) => "Flera nya meddelanden".into(),
+            (_, 1) => msgid.into(),
+            _ => plural.into(),
+        }
+    }
+    /// Only handles a `one` and an `other` plural form, for `count`.
+    pub fn icu_msg(pattern: &str, args: &[(&str, u64)]) -> String {
+        let count = args.iter().find(|(name, _)| *name == "count");
+        let form = if count.map(|&(_, n)| n) == Some(1) {
+            "one{"
+        } else {
+            "other{"
+        };
+        let start = pattern.find(form).unwrap() + form.len();
+        let end = start + pattern[start..].find('}').unwrap();
+        let rest = &pattern[pattern.rfind('}').unwrap() + 1..];
+        let n = count.map(|(_, n)| n.to_string()).unwrap_or_default();
+        pattern[start..end].replace('#', &n) + rest
+    }
+}
+
+#[test]
+fn test_translated() {
+    assert_eq!(
+        r2s(|o| translated_html(o, 1)),
+        "<p>Hej &amp; välkommen!</p>\n<p>Ett nytt meddelande</p>\n\
+         <p>One unread message</p>\n"
+    );
+    assert_eq!(
+        r2s(|o| translated_html(o, 2)),
+        "<p>Hej &amp; välkommen!</p>\n<p>Flera nya meddelanden</p>\n\
+         <p>Unread messages</p>\n"
+    );
+}
+
+#[test]
+fn test_icu_message() {
+    assert_eq!(r2s(|o| icu_message_html(o, 1)), "<p>1 file &amp; more</p>\n");
+    assert_eq!(
+        r2s(|o| icu_message_html(o, 7)),
+        "<p>7 files &amp; more</p>\n"
+    );
+}
+
+#[test]
+fn test_locale_values() {
+    assert_eq!(
+        r2s(|o| locale_values_html(o, 1234.5, (2024, 1, 5), "de-DE")),
+        "<p>1.234,5 (5. Januar 2024)</p>\n"
+    );
+    assert_eq!(
+        r2s(|o| locale_values_html(o, 1234.5, (2024, 1, 5), "en-US")),
+        "<p>1,234.5 (Jan 5, 2024)</p>\n"
+    );
+}
+
+#[test]
+fn test_csp_nonce() {
+    assert_eq!(
+        r2s(|o| csp_page_html(o, "r4nd\"m")),
+        "<script nonce=\"r4nd&quot;m\"\n  src=\"/app.js\"></script>\n\
+         <style nonce=\"r4nd&quot;m\">p { color: red; }</style>\n"
+    );
+}
+
+#[test]
+fn test_form_fields() {
+    assert_eq!(
+        r2s(|o| form_fields_html(o, "a@b", Some("Taken"), "pro")),
+        "<form method=\"post\"><div class=\"field has-error\">\
+         <label for=\"field-email\">Email</label>\
+         <input type=\"text\" id=\"field-email\" name=\"email\" \
+         aria-invalid=\"true\" aria-describedby=\"field-email-error\" \
+         value=\"a@b\"><p class=\"field-error\" id=\"field-email-error\">\
+         Taken</p></div><div class=\"field\">\
+         <label for=\"field-password\">Password</label>\
+         <input type=\"password\" id=\"field-password\" name=\"password\" \
+         value=\"\"></div><div class=\"field\">\
+         <label for=\"field-plan\">Plan</label>\
+         <select id=\"field-plan\" name=\"plan\">\
+         <option value=\"free\">Free</option>\
+         <option value=\"pro\" selected>Pro</option></select></div></form>\n"
+    );
+}
+
+#[test]
+fn test_csrf_form() {
+    assert_eq!(
+        r2s(|o| csrf_form_html(o, "t<k>")),
+        "<head><meta name=\"csrf-token\" content=\"t&lt;k&gt;\"></head>\n\
+         <form method=\"post\">\
+         <input type=\"hidden\" name=\"csrf\" value=\"t&lt;k&gt;\"></form>\n"
+    );
+}
+
+#[test]
+fn test_url_encoded() {
+    assert_eq!(
+        r2s(|o| search_link(o, "fish & chips")),
+        "<a href=\"/search?q=fish%20%26%20chips\">fish &amp; chips</a>\n"
+    );
+}
+
+#[test]
+fn test_js_string() {
+    assert_eq!(
+        r2s(|o| page_state(o, "</script><script>alert('x')")),
+        "<script>var user = \"<\\/script><script>alert(\\'x\\')\";</script>\n"
+    );
+}
+
+#[test]
+fn test_json_string_in_attribute() {
+    assert_eq!(
+        r2s(|o| data_attr(o, "\"><script>")),
+        "<div data-user=\"\\&quot;&gt;&lt;script&gt;\"></div>\n"
+    );
+}
+
+#[test]
+fn test_assert_template_eq() {
+    assert_template_eq!(
+        hello(),
+        "<h1>Hello World!</h1>\n\
+         <p>Note: Brackets and @ signs needs to be escaped: { ... }</p>\n"
+    );
+    assert_template_eq!(
+        templates::hello_args("World"),
+        "<h1>Hello World!</h1>\n",
+    );
+}
+
+#[test]
+fn test_assert_template_contains() {
+    assert_template_contains!(
+        page::page_html("sample"),
+        "<p>This is page content for sample</p>"
+    );
+}
+
+#[test]
+#[should_panic(expected = "template output differs")]
+fn test_assert_template_eq_fails() {
+    assert_template_eq!(hello_args("World"), "<h1>Hello Nobody!</h1>\n");
+}
+
+#[test]
+fn test_plain_text() {
+    assert_eq!(
+        r2s(|o| greeting_txt(o, "Tom & Jerry", 3)),
+        "Hello Tom & Jerry!\nYou have 3 <new> messages.\n"
+    );
+}
+
+#[test]
+fn test_json_template() {
+    assert_eq!(
+        r2s(|o| user_json(o, "Tom \"Cat\"", 3)),
+        "{\"name\": \"Tom \\\"Cat\\\"\", \"messages\": 3}\n"
+    );
+}
+
+#[test]
+fn test_svg_template() {
+    assert_eq!(
+        r2s(|o| badge_svg(o, "it's <ok>")),
+        "<svg xmlns=\"http://www.w3.org/2000/svg\">\
+         <text>it&apos;s &lt;ok&gt;</text></svg>\n"
+    );
+    assert_eq!(BADGE_SVG_CONTENT_TYPE, "image/svg+xml");
+    assert_eq!(HELLO_HTML_CONTENT_TYPE, "text/html; charset=utf-8");
+    assert_eq!(FEED_XML_CONTENT_TYPE, "application/atom+xml");
+}
+
+#[test]
+fn test_http_status() {
+    assert_eq!(
+        r2s(|o| not_found_html(o, "/x")),
+        "<h1>Not found</h1>\n<p>There is nothing at /x.</p>\n"
+    );
+    assert_eq!(NOT_FOUND_HTML_HTTP_STATUS, 404);
+    assert_eq!(HELLO_HTML_HTTP_STATUS, 200);
+}
+
+#[test]
+fn test_cache_control() {
+    assert_eq!(NOT_FOUND_HTML_CACHE_CONTROL, Some("max-age=60"));
+    assert_eq!(HELLO_HTML_CACHE_CONTROL, None);
+}
+
+#[test]
+fn test_xml_escaping() {
+    assert_eq!(
+        r2s(|o| feed_xml(o, "Tom's <feed>")),
+        "<feed><title>Tom&apos;s &lt;feed&gt;</title></feed>\n"
+    );
+}
+
+#[test]
+fn test_custom_to_html() {
+    assert_eq!(
+        r2s(|o| custom_to_html(o, "Fish & chips & more")),
+        "<p>Fish &amp; chips…</p>\n"
+    );
 }
 
 #[test]
@@ -54,6 +54,13 @@ fn test_get_static_unknown() {
     assert_eq!(StaticFile::get("foo-bar.css").map(|s| s.name), None)
 }
 
+#[test]
+fn test_static_file_path() {
+    use templates::statics::static_file_path;
+    assert_eq!(static_file_path("style.css"), Some("style-o2rFo1lI.css"));
+    assert_eq!(static_file_path("style-o2rFo1lI.css"), None);
+}
+
 #[test]
 fn test_all_statics_known() {
     use templates::statics::STATICS;
@@ -31,6 +31,8 @@
 //! Rust types that implements [`Display`] automatically implements
 //! [`ToHtml`] in such a way that contents are safely escaped for
 //! html.
+//! Other types can implement [`ToHtml`] (which is in your generated
+//! `templates` module) to decide how they should be written.
 //!
 //! ```html
 //! @use any::rust::Type;
@@ -61,6 +63,42 @@
 //! precedes expressions and special blocks; verbtim `@` signs must be
 //! escaped as `@@`.
 //!
+//! Comments that are not copied to the output are written as
+//! `@* ... *@` (which may be nested, to comment out code that
+//! contains comments) or as `@// ...` to the end of the line.
+//! Comments are allowed anywhere, including before the argument list.
+//!
+//! ```html
+//! @* This is not sent to the client. *@
+//! <p>Visible</p> @// and this is not sent either
+//! ```
+//!
+//! The generated function of each template gets a doc comment,
+//! naming the template file and its arguments.
+//! A `@* ... *@` comment directly after the argument list is added to
+//! that doc comment, so it is shown by `cargo doc`.
+//!
+//! ```html
+//! @(user: &User)
+//! @* The profile page of a user. *@
+//! <h1>@user.name</h1>
+//! ```
+//!
+//! Whitespace around control structures is copied to the output as
+//! any other text.
+//! To avoid that, a dash may be added to mark whitespace for removal:
+//! `@-` (as in `@-if` or `@-for`) removes all whitespace before the
+//! expression, and a `-@` directly after an expression or block
+//! removes all whitespace after it.
+//!
+//! ```html
+//! <ul>
+//!   @-for item in items {<li>@item</li>}-@
+//! </ul>
+//! ```
+//!
+//! This renders as `<ul><li>...</li></ul>`.
+//!
 //! [`ToHtml`]: ../templates/trait.ToHtml.html
 //! [`Display`]: https://doc.rust-lang.org/std/fmt/trait.Display.html
 #![allow(non_snake_case)]
@@ -115,6 +153,13 @@ pub mod a_Value_expressions {
     //! <p>Argument: @call(a + 3, |t| t.something()).</p>
     //! ```
     //!
+    //! Closures are allowed as arguments, and so are turbofish type
+    //! arguments, with balanced angle brackets:
+    //!
+    //! ```text
+    //! <p>@items.iter().map(|x| x.name).collect::<Vec<_>>().join(", ")</p>
+    //! ```
+    //!
     //! An expression ends when parenthesis and brackets are matched
     //! and it is followed by something not allowed in an expression.
     //! This includes whitespace and e.g. the `<` and `@` characters.
@@ -140,6 +185,222 @@ pub mod a_Value_expressions {
     //! <p>name.len()</p>
     //! <p>1</p>
     //! ```
+    //!
+    //! # Raw output
+    //!
+    //! Expression values are html-escaped when written.
+    //! Inside a `@raw { ... }` block, the values of expressions are
+    //! written as they are instead, so the block can be used to output
+    //! html from a trusted source, such as a markdown renderer.
+    //! The content of the block is ordinary template code.
+    //!
+    //! ```text
+    //! <h1>@title</h1>
+    //! @raw {<div>@rendered_markdown</div>}
+    //! ```
+    //!
+    //! # Url encoding
+    //!
+    //! A value that is part of an url, such as a query parameter or a
+    //! path segment, should be percent-encoded.
+    //! The [`UrlEncoded`] wrapper does that, and the [`url_encode`]
+    //! function percent-encodes a string slice for use in expressions.
+    //!
+    //! ```text
+    //! @use super::UrlEncoded;
+    //!
+    //! <a href="/search?q=@UrlEncoded(query)">@query</a>
+    //! ```
+    //!
+    //! [`UrlEncoded`]: ../../templates/struct.UrlEncoded.html
+    //! [`url_encode`]: ../../templates/fn.url_encode.html
+    //!
+    //! # Numbers and dates for a locale
+    //!
+    //! The [`LocaleNumber`] and [`LocaleDate`] wrappers write a number
+    //! or a date as is customary in a locale, which is given explicitly
+    //! rather than read from some global state.
+    //!
+    //! ```text
+    //! @use super::{LocaleDate, LocaleNumber};
+    //!
+    //! @(price: f64, date: (i32, u32, u32), locale: &str)
+    //! <p>@LocaleNumber::new(price, locale) (@LocaleDate::new(date, locale))</p>
+    //! ```
+    //!
+    //! With `locale` as `"de-DE"`, this may be written as
+    //! `<p>1.234,5 (5. Januar 2024)</p>`.
+    //!
+    //! [`LocaleNumber`]: ../../templates/struct.LocaleNumber.html
+    //! [`LocaleDate`]: ../../templates/struct.LocaleDate.html
+    //!
+    //! # Javascript strings
+    //!
+    //! Values written in an inline `<script>` element should not be html
+    //! escaped, but escaped as a javascript string literal, so that they
+    //! can't break out of the string or the script element.
+    //! The [`JsString`] wrapper does that.
+    //!
+    //! ```text
+    //! @use super::JsString;
+    //!
+    //! <script>var user = "@JsString(name)";</script>
+    //! ```
+    //!
+    //! Similarly, the [`JsonString`] wrapper escapes a value for use
+    //! inside a json string.
+    //! In a json template (`.rs.json`), all values are escaped that way.
+    //! In an html template, the json string is also html escaped, so
+    //! it can be used in an attribute, like
+    //! `<div data-user="@JsonString(name)">`.
+    //!
+    //! [`JsString`]: ../../templates/struct.JsString.html
+    //! [`JsonString`]: ../../templates/struct.JsonString.html
+    //!
+    //! Since a template function returns an `io::Result<()>`, the `?`
+    //! operator can be used after a function call in an expression, to
+    //! return an error from the template.
+    //! The `?` is only part of the expression when it directly follows
+    //! the closing parenthesis of a call.
+    //!
+    //! ```text
+    //! <p>Size: @std::fs::metadata(path)?.len() bytes.</p>
+    //! <p>Is it @name?</p>@* The ? is plain text here *@
+    //! ```
+    //!
+    //! # Filters
+    //!
+    //! A value can be passed through a chain of filters, each written
+    //! as ` | ` followed by the name of a function and any extra
+    //! arguments in parentheses.
+    //! The first filter is called with a reference to the value, and
+    //! each following filter with the result of the previous one.
+    //!
+    //! ```text
+    //! <p title="@title | lower">@summary | trim | truncate(50)</p>
+    //! ```
+    //!
+    //! The standard filters are `upper`, `lower`, `trim`,
+    //! `truncate(n)`, `replace(from, to)` and `default(fallback)`,
+    //! defined in the [`filters`] module.
+    //! A function imported with `@use` can also be used as a filter,
+    //! and is used instead of a standard filter with the same name.
+    //! The content of the `<p>` above is the same as
+    //! `@truncate(trim(&summary), 50)`.
+    //! Other names are not filters, and the `|` must have a space on
+    //! both sides, so e.g. `@title | home` or `@title|upper` is a
+    //! value followed by text.
+    //!
+    //! [`filters`]: ../../templates/filters/index.html
+    //!
+    //! # Translated strings
+    //!
+    //! A string literal can be translated with `@t("...")`, which
+    //! writes the result of `gettext("...")`, escaped as any other
+    //! value.
+    //! For plural forms, `@tn("singular", "plural", count)` (or the
+    //! longer `@ngettext("singular", "plural", count)`) writes the
+    //! result of `ngettext("singular", "plural", count)`.
+    //! Selecting the right form for the count, including for languages
+    //! with more than two plural forms, is up to the `ngettext`
+    //! function.
+    //! The functions need to be in scope in the template, e.g. by
+    //! `@use gettextrs::{gettext, ngettext};`, or they can be set for
+    //! all templates with [`set_gettext_function`] and
+    //! [`set_ngettext_function`] in the build script.
+    //!
+    //! ```text
+    //! @use gettextrs::{gettext, ngettext};
+    //!
+    //! @(n: u32)
+    //! <h1>@t("Welcome!")</h1>
+    //! <p>@tn("You have one new message", "You have new messages", n)</p>
+    //! ```
+    //!
+    //! [`set_gettext_function`]: ../../struct.Ructe.html#method.set_gettext_function
+    //! [`set_ngettext_function`]: ../../struct.Ructe.html#method.set_ngettext_function
+    //!
+    //! # ICU messages
+    //!
+    //! Languages with more complex plural rules, and messages with
+    //! several values, may be better handled by ICU MessageFormat
+    //! patterns.
+    //! `@icu_msg("pattern", name: value, ...)` writes the result of
+    //! calling `icu_msg` with the pattern and a slice of the names and
+    //! values of the arguments, where each value is converted with
+    //! `.into()`, so the function decides what type the values get.
+    //! As for `@t`, the function needs to be in scope in the template,
+    //! or it can be set for all templates with [`set_icu_function`].
+    //!
+    //! ```text
+    //! @use crate::i18n::icu_msg;
+    //!
+    //! @(items: &[Item], user: &str)
+    //! <p>@icu_msg("{user} has {count, plural, one{# item} other{# items}}",
+    //!             user: user, count: items.len())</p>
+    //! ```
+    //!
+    //! The function can use e.g. the [fluent] crate, with a
+    //! `FluentValue` as the type of the values:
+    //!
+    //! ```text
+    //! pub fn icu_msg(pattern: &str, args: &[(&str, FluentValue)]) -> String {
+    //!     let mut fluent_args = FluentArgs::new();
+    //!     for (name, value) in args {
+    //!         fluent_args.set(*name, value.clone());
+    //!     }
+    //!     // Find the translation of `pattern` in a bundle for the
+    //!     // current language, and format it with `fluent_args`.
+    //! }
+    //! ```
+    //!
+    //! [`set_icu_function`]: ../../struct.Ructe.html#method.set_icu_function
+    //! [fluent]: https://crates.rs/crates/fluent
+    //!
+    //! # Local bindings
+    //!
+    //! A value that is used more than once can be computed once and
+    //! bound to a local name with `@let`.
+    //! The binding is terminated by a semicolon and is in scope for
+    //! the rest of the enclosing block.
+    //! The left hand side may be a simple pattern, such as a tuple,
+    //! and may have a type, which makes rustc report a value of the
+    //! wrong type at the binding rather than where it is used.
+    //!
+    //! ```text
+    //! @let total: u32 = items.iter().map(|i| i.price).sum();
+    //! @let (first, last) = (items.first(), items.last());
+    //! <p>Total: @total</p>
+    //! ```
+    //!
+    //! # Constants
+    //!
+    //! A value known when compiling, such as a class name or a limit,
+    //! can be declared once with `@const`, with a name, a type and a
+    //! value, just as a rust `const`.
+    //! A constant declared before the argument list is declared in the
+    //! module of the template, and one declared in the body is local
+    //! to the template function.
+    //!
+    //! ```text
+    //! @const MAX_ITEMS: usize = 10;
+    //! @(items: &[&str])
+    //! @const CLASS: &str = "item";
+    //! @for item in items.iter().take(MAX_ITEMS) {<p class="@CLASS">@item</p>}
+    //! ```
+    //!
+    //! Constants for all templates can be declared with `@const` in
+    //! a file named `_globals.rs.html` in the template directory, or
+    //! with [`Ructe::add_constant`] in the build script.
+    //! They are declared in the generated module, so they can also be
+    //! used from rust code, as `templates::SITE_NAME`.
+    //!
+    //! ```text
+    //! @const SITE_NAME: &str = "My Site";
+    //! @const COPYRIGHT_YEAR: u16 = 2020;
+    //! ```
+    //!
+    //! [`Ructe::add_constant`]: ../../struct.Ructe.html#method.add_constant
 }
 
 pub mod b_Loops {
@@ -170,6 +431,15 @@ pub mod b_Loops {
     //! }
     //! ```
     //!
+    //! Array patterns work the same way, as do nested patterns:
+    //!
+    //! ```text
+    //! @for &[x, y] in points {<p>(@x, @y)</p>}
+    //! @for (i, (key, value)) in map.iter().enumerate() {
+    //!     <p>@i. @key: @value</p>
+    //! }
+    //! ```
+    //!
     //! It is also possible to loop over a literal array (which may be
     //! an array of tuples), as long as you do it by reference:
     //!
@@ -178,6 +448,18 @@ pub mod b_Loops {
     //!     <p>@name is @age years old.</p>
     //! }
     //! ```
+    //!
+    //! # While loops
+    //!
+    //! A `@while` loop repeats its body as long as a condition holds.
+    //! The condition is written just as the condition of an `@if`,
+    //! so `@while let` is supported as well:
+    //!
+    //! ```text
+    //! <ul>@while let Some(item) = queue.borrow_mut().pop() {
+    //!   <li>@item</li>
+    //! }</ul>
+    //! ```
 }
 
 pub mod c_Conditionals {
@@ -205,6 +487,19 @@ pub mod c_Conditionals {
     //! }
     //! ```
     //!
+    //! The `else` keyword may also be written as `@else`, and an else
+    //! part may itself be another condition.
+    //!
+    //! ```text
+    //! @if let Some(foo) = foo {
+    //!   <p>Foo is @foo.</p>
+    //! } @else if let Err(e) = bar {
+    //!   <p>Bar failed: @e</p>
+    //! } @else {
+    //!   <p>There is no foo.</p>
+    //! }
+    //! ```
+    //!
     //! The condition or let expression should allow anything that would be
     //! allowed in the same place in plain rust.
     //! As with loops, the things in the curly brackets are ructe template
@@ -228,6 +523,17 @@ pub mod c_Conditionals {
     //! The let expression and patterns should allow anything that would be
     //! allowed in the same place in plain rust.
     //! As above, the things in the curly brackets are ructe template code.
+    //!
+    //! A pattern can have alternatives separated by `|`, and an arm can
+    //! have a guard, just as in rust.
+    //!
+    //! ```text
+    //! @match n {
+    //!   0 | 1 => { <p>Just a few.</p> }
+    //!   n if n < 10 => { <p>There are @n of them.</p> }
+    //!   _ => { <p>Too many to count.</p> }
+    //! }
+    //! ```
 }
 
 pub mod d_Calling_other_templates {
@@ -301,4 +607,398 @@ pub mod d_Calling_other_templates {
     //!     <p>page content ...</p>
     //! })
     //! ```
+    //!
+    //! The same syntax can call any function that takes the output
+    //! as its first argument, such as the [`csrf_field`] and
+    //! [`csrf_meta`] helpers, which write a hidden form field and a
+    //! meta element with a CSRF token:
+    //!
+    //! ```text
+    //! @use super::csrf_field;
+    //!
+    //! @(token: &str)
+    //! <form method="post">@:csrf_field(token) ...</form>
+    //! ```
+    //!
+    //! Form fields can be written in the same way by the
+    //! [`text_field`], [`input_field`], [`textarea_field`] and
+    //! [`select_field`] helpers, that write a control with a label,
+    //! and with an error message if there is one:
+    //!
+    //! ```text
+    //! @use super::{select_field, text_field};
+    //!
+    //! @(email: &str, error: Option<&str>, plan: &str)
+    //! <form method="post">
+    //!   @:text_field("email", "Email", email, error)
+    //!   @:select_field("plan", "Plan", &[("free", "Free"), ("pro", "Pro")], plan, None)
+    //! </form>
+    //! ```
+    //!
+    //! [`csrf_field`]: ../../templates/fn.csrf_field.html
+    //! [`csrf_meta`]: ../../templates/fn.csrf_meta.html
+    //! [`text_field`]: ../../templates/fn.text_field.html
+    //! [`input_field`]: ../../templates/fn.input_field.html
+    //! [`textarea_field`]: ../../templates/fn.textarea_field.html
+    //! [`select_field`]: ../../templates/fn.select_field.html
+    //!
+    //! # Recursive templates
+    //!
+    //! A template can call itself, without a `use`, e.g. to render a
+    //! tree:
+    //!
+    //! ```text
+    //! @(tree: &Tree)
+    //! <li>@tree.name<ul>@for child in &tree.children {@:tree_html(child)}</ul></li>
+    //! ```
+    //!
+    //! The nested calls are counted, and a call nested deeper than
+    //! the limit set by [`max_template_depth`] (32 by default) writes
+    //! `<!-- max depth exceeded -->` instead, or nothing in a template
+    //! that is not html or xml.
+    //! This works for templates without type parameters or `Content`
+    //! arguments.
+    //!
+    //! [`max_template_depth`]: ../../struct.Ructe.html#method.max_template_depth
+    //!
+    //! # Including a template
+    //!
+    //! A template can also be included with `@include "path"`.
+    //! The path is relative to the including template, and the `.rs`
+    //! part of the file name may be left out.
+    //! The arguments of the included template are passed by name, so
+    //! each of them must be available where the template is included,
+    //! as an argument of the including template or e.g. as a loop
+    //! variable.
+    //!
+    //! ```text
+    //! @(title: &str, users: &[&str])
+    //!
+    //! <h1>@title</h1>
+    //! @for user in users {@include "page/user_nav.html"}
+    //! ```
+    //!
+    //! Here, `page/user_nav.rs.html` should be a template with a
+    //! `user` argument, such as `@(user: &str)`.
+    //!
+    //! The arguments can also be given in braces after the path,
+    //! either as just a name or as `name: value`.
+    //! All arguments of the included template must then be given.
+    //! With braces, the path may be written without quotes, and is
+    //! then relative to the template directory rather than to the
+    //! including template, so it does not change if the including
+    //! template is moved.
+    //! The `.rs.html` of an html template can be left out:
+    //!
+    //! ```text
+    //! @(user: &User)
+    //! @include admin/dashboard { user, title: "Admin" }
+    //! ```
+    //!
+    //! # Extending a layout
+    //!
+    //! As an alternative to calling a page template with the content
+    //! as a block argument, a template can extend a layout template.
+    //! The layout marks the places where content goes with
+    //! `@yield name`, or with `@block name { ... }` if there is a
+    //! default content:
+    //!
+    //! ```text
+    //! @(title: &str)
+    //!
+    //! <html>
+    //!   <head><title>@block page_title {@title}</title></head>
+    //!   <body>@yield content</body>
+    //! </html>
+    //! ```
+    //!
+    //! A template that starts with `@extends "path"` gets the content
+    //! of the layout, with each `@block` in the template filling the
+    //! block or yield point of the same name in the layout:
+    //!
+    //! ```text
+    //! @extends "base_layout.html"
+    //! @(title: &str, items: &[&str])
+    //!
+    //! @block content {<ul>@for item in items {<li>@item</li>}</ul>}
+    //! ```
+    //!
+    //! The path is relative to the extending template, and the `.rs`
+    //! part of the file name may be left out.
+    //! The generated function takes the arguments of the extending
+    //! template, followed by any arguments of the layout that it does
+    //! not declare itself.
+    //! A layout can itself extend another layout.
+    //! The layout is still compiled as a template of its own, where
+    //! each yield point is empty.
+    //!
+    //! # Macros
+    //!
+    //! A fragment that is repeated within one template can be defined
+    //! as a macro with `@macro name(args) { ... }`, and used after
+    //! that in the same template as `@name(...)`:
+    //!
+    //! ```text
+    //! @(page: &str)
+    //!
+    //! @macro share(label: &str) {<button>@label @page</button>}
+    //! <header>@share("Share")</header>
+    //! <footer>@share("Tell a friend")</footer>
+    //! ```
+    //!
+    //! The macro becomes a closure in the generated function, so its
+    //! body can use the arguments of the template as well as its own.
+    //! A macro can use macros defined before it, but not itself.
+}
+
+pub mod e_Template_arguments {
+    //! The arguments of a template are declared with `@(...)`, just as
+    //! the arguments of a rust function.
+    //!
+    //! # Imports
+    //!
+    //! Before the argument declaration, a template may have any number
+    //! of `@use` statements.
+    //! Each is emitted as a `use` statement in the module of the
+    //! generated template function, so the imported names can be used
+    //! both in argument types and in the template body.
+    //!
+    //! ```text
+    //! @use std::collections::BTreeMap;
+    //! @use chrono::{DateTime, Utc};
+    //!
+    //! @(when: DateTime<Utc>, map: &BTreeMap<u8, u8>)
+    //! ```
+    //!
+    //! # Generic templates
+    //!
+    //! A template may take generic type parameters, declared in angle
+    //! brackets between the `@` and the argument list.
+    //! A `where` clause may follow the argument list, on the same line.
+    //! Both are passed verbatim to the generated function.
+    //!
+    //! ```text
+    //! @<T: Display, F: Fn(&T) -> bool>(items: &[T], keep: F)
+    //!
+    //! <ul>@for item in items.iter().filter(|i| keep(i)) {
+    //!   <li>@item</li>
+    //! }</ul>
+    //! ```
+    //!
+    //! If there is a `where` clause but no generic parameter list, each
+    //! simple name bounded in the where clause is declared as a
+    //! parameter of the template.
+    //!
+    //! ```text
+    //! @(items: &[T]) where T: std::fmt::Display
+    //! ```
+    //!
+    //! The generated function also has a type parameter `W` for the
+    //! output, so that name should not be used in a template.
+    //!
+    //! # Lifetimes
+    //!
+    //! Lifetime parameters may be declared in the same list as type
+    //! parameters (lifetimes first, as in rust) and used in the
+    //! argument types.
+    //! Mutable references are also allowed.
+    //!
+    //! ```text
+    //! @<'a, T>(content: &'a str, items: &'a [Foo<'a, T>], count: &mut u32)
+    //! ```
+    //!
+    //! # Context structs
+    //!
+    //! Instead of many separate arguments, a template can declare a
+    //! struct of fields with `@context Name { field: Type, ... }` just
+    //! before the argument list.
+    //! The struct is generated as `templates::Name`, with a public
+    //! field for each declared field, and the template function gets
+    //! it as a first argument called `ctx`:
+    //!
+    //! ```text
+    //! @context PageContext { title: &str, items: &[&str] }
+    //! @()
+    //!
+    //! <h1>@ctx.title</h1>
+    //! <ul>@for item in ctx.items {<li>@item</li>}</ul>
+    //! ```
+    //!
+    //! Each reference in a field type gets the lifetime of the struct,
+    //! so the above struct is `PageContext<'a>`, with a
+    //! `title: &'a str` field.
+    //! A template that extends a layout with a context struct uses the
+    //! struct of the layout, if it does not declare one itself.
+    //!
+    //! # Trait arguments
+    //!
+    //! Arguments may be `impl` trait or `dyn` trait types, including
+    //! associated type bindings, the `Fn(..) -> T` sugar and extra
+    //! bounds.
+    //!
+    //! ```text
+    //! @(items: &mut dyn Iterator<Item = &str>, sep: impl Fn() -> String + Copy)
+    //! ```
+    //!
+    //! # Email stylesheets
+    //!
+    //! An html template for email may declare a stylesheet with
+    //! `@email_css("path")` first in the template (after any
+    //! `@extends`), with the path relative to the template file.
+    //! When ructe is built with the `email` feature, each html template
+    //! also gets a `{name}_email` function, that writes the output with
+    //! the styles of any `<style>` elements, and of that stylesheet,
+    //! inlined as `style` attributes.
+    //!
+    //! ```text
+    //! @email_css("email.css")
+    //! @(user: &str)
+    //! <p class="greeting">Welcome, @user!</p>
+    //! ```
+    //!
+    //! # Content type and status
+    //!
+    //! The `{NAME}_CONTENT_TYPE` constant of a template is based on the
+    //! file suffix, e.g. `text/html; charset=utf-8` for `.rs.html`.
+    //! Another content type can be declared with `@mime("...")` (or
+    //! the equivalent `@content_type("...")`) directly after the
+    //! argument list.
+    //! Similarly, the `{NAME}_HTTP_STATUS` constant is 200, unless
+    //! another status is declared with `@status(...)`, e.g. for an error
+    //! page.
+    //! A page that can be cached may declare a `Cache-Control` header
+    //! with `@cache_control("max-age=3600, public")`, which is the
+    //! value of the `{NAME}_CACHE_CONTROL` constant (an
+    //! `Option<&str>`, that is `None` if no header is declared).
+    //! The directives may be given in any order.
+    //! The framework integrations, such as `render_response_as` for
+    //! axum, take the status and content type of the response as
+    //! arguments, to be used with the constants, and the `_cached`
+    //! variants, such as `render_response_cached`, also take the cache
+    //! control header.
+    //!
+    //! ```text
+    //! @(entries: &[Entry])
+    //! @mime("application/atom+xml")
+    //! <feed>...</feed>
+    //! ```
+    //!
+    //! ```text
+    //! @(path: &str)
+    //! @status(404)
+    //! <h1>Not found</h1>
+    //! ```
+    //!
+    //! ```text
+    //! @(products: &[Product])
+    //! @cache_control("max-age=3600, public")
+    //! <ul>@for p in products {<li>@p.name</li>}</ul>
+    //! ```
+    //!
+    //! # Validated arguments
+    //!
+    //! The `{Name}Args` builder of a template (for calling it with
+    //! named arguments, see `{name}_with_args`) has a `validate`
+    //! method, that returns all problems with the arguments as a
+    //! `Vec<ValidationError>`.
+    //! An argument that is not set is a problem, and rules for the
+    //! values can be declared with `@validate(arg: rule)` after the
+    //! argument list, where the rule is one of the standard
+    //! validators `not_empty`, `positive` or `in_range(min, max)`
+    //! (found in the `validators` module), or any other function in
+    //! scope that takes a reference to the value and returns a
+    //! `Result<(), String>`.
+    //! Other checks can be added to the builder as closures, with
+    //! `.validator("arg", |args| ...)`.
+    //!
+    //! ```text
+    //! @(url: &str, max_items: usize)
+    //! @validate(url: not_empty)
+    //! @validate(max_items: in_range(1, 100))
+    //! <a href="@url">More</a>
+    //! ```
+    //!
+    //! # Content security policy nonces
+    //!
+    //! With a strict content security policy, each `<script>` and
+    //! `<style>` element needs a `nonce` attribute.
+    //! Rather than writing it on each element, a template may declare
+    //! `@csp_nonce(expr)` first in the template (after any `@extends`
+    //! and `@email_css`).
+    //! A ` nonce="@expr"` attribute is then added to each `<script` and
+    //! `<style` tag in the template text, also when the attributes of
+    //! the tag are on following lines.
+    //!
+    //! ```text
+    //! @csp_nonce(nonce)
+    //! @(nonce: &str)
+    //! <script src="/app.js"></script>
+    //! ```
+    //!
+    //! # Conditional templates
+    //!
+    //! A template that declares `@cfg(predicate)` (after any
+    //! `@csp_nonce`, before the `@use` statements) is only compiled
+    //! when the predicate is true, as for a `#[cfg(predicate)]`
+    //! attribute in rust.
+    //! This can be used for templates that belong to an optional
+    //! feature of the crate.
+    //!
+    //! ```text
+    //! @cfg(feature = "admin")
+    //! @use super::AdminStats;
+    //! @(stats: &AdminStats)
+    //! <p>@stats.users users</p>
+    //! ```
+}
+
+pub mod f_Generated_items {
+    //! Besides the `template_html` function of a template file
+    //! `template.rs.html`, some other items are generated for it.
+    //!
+    //! # Rendering to a string
+    //!
+    //! The `template_html_to_string` and `template_html_to_bytes`
+    //! functions take the same arguments as `template_html`, except
+    //! the output, and render the template to an `io::Result<String>`
+    //! or an `io::Result<Vec<u8>>`.
+    //!
+    //! # Named arguments
+    //!
+    //! The `template_html_with_args` function takes the arguments by
+    //! name, set on a `TemplateHtmlArgs` builder, for templates without
+    //! type parameters, `Content` arguments or argument types that may
+    //! have hidden lifetimes.
+    //! The builder has a `validate` method, that checks the rules
+    //! declared with `@validate(arg: rule)` in the template (see
+    //! Validated arguments in [the template arguments
+    //! chapter](../e_Template_arguments/index.html)).
+    //!
+    //! ```text
+    //! let args = PageHtmlArgs::new().title("Home").n(3);
+    //! templates::page_html_with_args(&mut out, args)?;
+    //! ```
+    //!
+    //! # Constants
+    //!
+    //! `TEMPLATE_HTML_STATIC_SIZE` is the size of the static parts of
+    //! the template, that may be useful for allocating a buffer for
+    //! its output.
+    //!
+    //! `TEMPLATE_HTML_CONTENT_TYPE` is the content type of the output,
+    //! e.g. `text/html; charset=utf-8` for html templates and
+    //! `image/svg+xml` for svg templates, unless the template declares
+    //! another content type with `@mime("...")`.
+    //! Similarly, `TEMPLATE_HTML_HTTP_STATUS` is the http status for a
+    //! response with the output, which is 200 unless the template
+    //! declares another with `@status(404)`, and
+    //! `TEMPLATE_HTML_CACHE_CONTROL` is the value of a `Cache-Control`
+    //! header declared with `@cache_control("...")`, if any.
+    //!
+    //! A template without any expressions (except string and integer
+    //! literals), such as a copyright footer, always writes the same
+    //! output, so it is rendered when compiling and its function just
+    //! writes a `TEMPLATE_HTML_STATIC` constant, which is also
+    //! available to other code as a `&[u8]` (or a `&str`, when
+    //! writing to a `fmt::Write`).
 }
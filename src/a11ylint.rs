@@ -0,0 +1,197 @@
+use htmllint::{
+    attributes, for_each_block, is_empty_element, scan, Token, DYNAMIC,
+};
+use templateexpression::TemplateExpression;
+
+/// Input types that do not need a label.
+const UNLABELED_INPUTS: &[&str] =
+    &["button", "hidden", "image", "reset", "submit"];
+
+/// Elements that can get focus without a `tabindex`.
+const FOCUSABLE: &[&str] = &[
+    "a", "button", "details", "input", "select", "summary", "textarea",
+];
+
+/// Check the static html in `body` for basic accessibility problems.
+///
+/// The problems found are images without alternative text, form
+/// controls without a label, elements with click handlers that cannot
+/// get keyboard focus, and positive `tabindex` values.
+/// As for the html lint, only static markup is checked, so an element
+/// with attributes written by an expression is not checked.
+pub fn lint(body: &[TemplateExpression]) -> Vec<String> {
+    let mut label_ids = Vec::new();
+    let mut dynamic_labels = false;
+    for_each_block(body, &mut |html| {
+        scan(html, &mut |token| {
+            if let Token::Open { ref name, attrs } = token {
+                if name == "label" {
+                    match attr(attrs, "for") {
+                        Some(id) if id.contains(DYNAMIC) => {
+                            dynamic_labels = true
+                        }
+                        Some(id) => label_ids.push(id),
+                        None => (),
+                    }
+                }
+            }
+        })
+    });
+    let mut problems = Vec::new();
+    for_each_block(body, &mut |html| {
+        let mut open_labels = 0usize;
+        scan(html, &mut |token| match token {
+            Token::Open { ref name, attrs }
+                if name == "label" && !is_empty_element(name, attrs) =>
+            {
+                open_labels += 1;
+            }
+            Token::Close { ref name } if name == "label" => {
+                open_labels = open_labels.saturating_sub(1);
+            }
+            Token::Open { name, attrs } => {
+                let labeled = |attrs: &[(String, String)]| {
+                    open_labels > 0
+                        || dynamic_labels
+                        || attrs.iter().any(|(name, value)| match &name[..] {
+                            "aria-label" | "aria-labelledby" | "title" => {
+                                true
+                            }
+                            "id" => {
+                                value.contains(DYNAMIC)
+                                    || label_ids.contains(value)
+                            }
+                            _ => false,
+                        })
+                };
+                if let Some(problem) = check_tag(&name, attrs, labeled) {
+                    let problem = format!(
+                        "{}, at <{}{}>",
+                        problem,
+                        name,
+                        attrs.replace(DYNAMIC, "@.."),
+                    );
+                    if !problems.contains(&problem) {
+                        problems.push(problem);
+                    }
+                }
+            }
+            _ => (),
+        })
+    });
+    problems
+}
+
+/// Check the element `name` with `attrs`.
+///
+/// The `labeled` function tells if a form control with some
+/// attributes has a label.
+fn check_tag<F>(name: &str, attrs: &str, labeled: F) -> Option<&'static str>
+where
+    F: Fn(&[(String, String)]) -> bool,
+{
+    let attrs = attributes(attrs)?;
+    let get = |key: &str| {
+        attrs
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    };
+    let is_control = match name {
+        "input" => !UNLABELED_INPUTS.contains(
+            &get("type").unwrap_or("text").to_ascii_lowercase().as_str(),
+        ),
+        "select" | "textarea" => true,
+        _ => false,
+    };
+    if name == "img" && get("alt").is_none() {
+        Some("Image without alt text")
+    } else if is_control && !labeled(&attrs) {
+        Some("Form control without a label")
+    } else if get("tabindex").and_then(|t| t.parse::<i32>().ok()) > Some(0) {
+        Some("Positive tabindex changes the focus order")
+    } else if get("onclick").is_some()
+        && get("tabindex").is_none()
+        && !FOCUSABLE.contains(&name)
+    {
+        Some("Element with onclick cannot get keyboard focus")
+    } else {
+        None
+    }
+}
+
+/// The value of the attribute `key` in `attrs`, if it is there.
+fn attr(attrs: &str, key: &str) -> Option<String> {
+    attributes(attrs)?
+        .into_iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::lint;
+    use templateexpression::TemplateExpression;
+
+    fn lint_text(html: &str) -> Vec<String> {
+        lint(&[TemplateExpression::text(html)])
+    }
+
+    #[test]
+    fn accessible_form() {
+        assert_eq!(
+            lint_text(
+                "<form><label for=\"q\">Search</label><input id=\"q\">\
+                 <label>Name <input name=\"name\"></label>\
+                 <input type=\"hidden\" name=\"t\"><textarea title=\"Text\">\
+                 </textarea><button onclick=\"go()\">Go</button>\
+                 <img src=\"a.png\" alt=\"\"></form>"
+            ),
+            Vec::<String>::new(),
+        );
+    }
+
+    #[test]
+    fn unlabeled_controls() {
+        assert_eq!(
+            lint_text("<input name=\"q\"><select id=\"s\"></select>"),
+            [
+                "Form control without a label, at <input name=\"q\">",
+                "Form control without a label, at <select id=\"s\">",
+            ],
+        );
+    }
+
+    #[test]
+    fn images_and_focus() {
+        assert_eq!(
+            lint_text(
+                "<img src=\"a.png\"><div onclick=\"open()\">Open</div>\
+                 <span tabindex=\"2\">x</span>\
+                 <div onclick=\"open()\" tabindex=\"0\" role=\"button\">ok</div>"
+            ),
+            [
+                "Image without alt text, at <img src=\"a.png\">",
+                "Element with onclick cannot get keyboard focus, \
+                 at <div onclick=\"open()\">",
+                "Positive tabindex changes the focus order, \
+                 at <span tabindex=\"2\">",
+            ],
+        );
+    }
+
+    #[test]
+    fn labels_in_other_blocks() {
+        let body = [
+            TemplateExpression::text("<input id=\"q\">"),
+            TemplateExpression::IfBlock {
+                expr: "show".into(),
+                body: vec![TemplateExpression::text(
+                    "<label for=\"q\">Query</label>",
+                )],
+                else_body: None,
+            },
+        ];
+        assert_eq!(lint(&body), Vec::<String>::new());
+    }
+}
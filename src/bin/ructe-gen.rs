@@ -0,0 +1,60 @@
+//! Render a directory of ructe templates as a static site.
+//!
+//! Usage: `ructe-gen [--templates DIR] [--out DIR] [--data FILE]
+//! [--ructe DIR]`
+//!
+//! See [`ructe::generate_site`] for how the templates are rendered.
+extern crate ructe;
+
+use std::env;
+use std::path::PathBuf;
+use std::process::exit;
+
+const USAGE: &str = "Usage: ructe-gen [--templates DIR] [--out DIR] \
+                     [--data FILE] [--ructe DIR]\n\
+                     \n\
+                     Render each template in DIR (default \"templates\") \
+                     to a file\nin the out DIR (default \"dist\"), \
+                     with arguments from the json FILE.\n\
+                     The templates are built with the published ructe, \
+                     or the ructe\nsource in the --ructe DIR.";
+
+fn main() {
+    let mut templates = PathBuf::from("templates");
+    let mut out = PathBuf::from("dist");
+    let mut data = None;
+    let mut ructe = None;
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let target = match arg.to_str() {
+            Some("--templates") => &mut templates,
+            Some("--out") => &mut out,
+            Some("--data") => data.get_or_insert_with(PathBuf::new),
+            Some("--ructe") => ructe.get_or_insert_with(PathBuf::new),
+            Some("-h") | Some("--help") => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => fail(&format!("Unexpected argument {:?}", arg)),
+        };
+        match args.next() {
+            Some(value) => *target = PathBuf::from(value),
+            None => fail(&format!("Missing value for {:?}", arg)),
+        }
+    }
+    let result = ructe::generate_site(
+        &templates,
+        &out,
+        data.as_deref(),
+        ructe.as_deref(),
+    );
+    if let Err(e) = result {
+        eprintln!("ructe-gen: {}", e);
+        exit(1);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("ructe-gen: {}\n{}", message, USAGE);
+    exit(2);
+}
@@ -0,0 +1,61 @@
+//! Refactor ructe templates and the code using them.
+//!
+//! Usage: `ructe-refactor rename-arg TEMPLATE OLD NEW [--src DIR]`
+//!
+//! See [`ructe::refactor::rename_argument`] for what is changed.
+extern crate ructe;
+
+use std::env;
+use std::path::PathBuf;
+use std::process::exit;
+
+const USAGE: &str = "Usage: ructe-refactor rename-arg TEMPLATE OLD NEW \
+                     [--src DIR]\n\
+                     \n\
+                     Rename the argument OLD of the TEMPLATE file to NEW, \
+                     in the template\nand in the named argument setters \
+                     in the rust files in DIR\n(default \"src\").";
+
+fn main() {
+    let mut src = PathBuf::from("src");
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--src" => match args.next() {
+                Some(dir) => src = PathBuf::from(dir),
+                None => fail("Missing value for \"--src\""),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let (template, old, new) = match &positional[..] {
+        [command, template, old, new] if command == "rename-arg" => {
+            (PathBuf::from(template), old, new)
+        }
+        _ => fail("Expected a command and its arguments"),
+    };
+    match ructe::refactor::rename_argument(&template, old, new, &src) {
+        Ok(changes) => {
+            for file in changes.changed {
+                println!("Changed {}", file.display());
+            }
+            for line in changes.to_check {
+                println!("Check {}", line);
+            }
+        }
+        Err(e) => {
+            eprintln!("ructe-refactor: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("ructe-refactor: {}\n{}", message, USAGE);
+    exit(2);
+}
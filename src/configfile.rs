@@ -0,0 +1,98 @@
+/// A value in a `.ructe.toml` file.
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Bool(bool),
+    Int(usize),
+}
+
+/// A `key = value` setting, with the line it is on, starting at 1.
+pub type Setting = (usize, String, Value);
+
+/// Parse the settings of a `.ructe.toml` file.
+///
+/// This is not a full toml parser, but handles the top-level keys
+/// with string, boolean and integer values that ructe uses.
+/// On failure, the line and a description of the problem is
+/// returned.
+pub fn parse(text: &str) -> Result<Vec<Setting>, (usize, String)> {
+    let mut settings = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+            None => return Err((line_no, "Expected key = value".into())),
+        };
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err((line_no, format!("Bad key {:?}", key)));
+        }
+        let value = parse_value(value).ok_or_else(|| {
+            (line_no, format!("Bad value for {}: {}", key, value))
+        })?;
+        settings.push((line_no, key.to_string(), value));
+    }
+    Ok(settings)
+}
+
+/// Parse a value, followed by an optional comment.
+fn parse_value(value: &str) -> Option<Value> {
+    if let Some(rest) = value.strip_prefix('"') {
+        let end = rest.find('"')?;
+        let tail = rest[end + 1..].trim();
+        if (tail.is_empty() || tail.starts_with('#'))
+            && !rest[..end].contains('\\')
+        {
+            return Some(Value::Str(rest[..end].to_string()));
+        }
+        return None;
+    }
+    let value = value.split('#').next().unwrap_or("").trim();
+    match value {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        value => value.replace('_', "").parse().ok().map(Value::Int),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Value};
+
+    #[test]
+    fn settings() {
+        assert_eq!(
+            parse(
+                "# ructe settings\n\
+                 template-dir = \"views\" # the templates\n\
+                 \n\
+                 html-lint = true\n\
+                 inline-threshold = 1_000\n"
+            ),
+            Ok(vec![
+                (2, "template-dir".into(), Value::Str("views".into())),
+                (4, "html-lint".into(), Value::Bool(true)),
+                (5, "inline-threshold".into(), Value::Int(1000)),
+            ]),
+        );
+    }
+
+    #[test]
+    fn bad_settings() {
+        assert_eq!(
+            parse("lenient = yes\n"),
+            Err((1, "Bad value for lenient: yes".into())),
+        );
+        assert_eq!(
+            parse("\n[lint]\n"),
+            Err((2, "Expected key = value".into())),
+        );
+    }
+}
@@ -1,14 +1,16 @@
 use nom::branch::alt;
 use nom::bytes::complete::{escaped, is_a, is_not, tag};
-use nom::character::complete::{alpha1, char, digit1, none_of, one_of};
-use nom::combinator::{map, map_res, not, opt, recognize, value};
+use nom::character::complete::{
+    alpha1, char, digit1, none_of, one_of, space1,
+};
+use nom::combinator::{map, map_res, not, opt, recognize, value, verify};
 use nom::error::context; //, VerboseError};
 use nom::multi::{fold_many0, many0, separated_list};
 use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 use parseresult::PResult;
 use std::str::{from_utf8, Utf8Error};
 
-pub fn expression(input: &[u8]) -> PResult<&str> {
+pub fn expression(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(context(
             "Expected rust expression",
@@ -25,7 +27,8 @@ pub fn expression(input: &[u8]) -> PResult<&str> {
                     alt((
                         preceded(context("separator", tag(".")), expression),
                         preceded(tag("::"), expression),
-                        expr_in_parens,
+                        preceded(tag("::"), expr_in_angles),
+                        try_call,
                         expr_in_braces,
                         expr_in_brackets,
                         preceded(tag("!"), expr_in_parens),
@@ -40,35 +43,149 @@ pub fn expression(input: &[u8]) -> PResult<&str> {
     )(input)
 }
 
+/// A call (or other parenthesized part), optionally followed by the
+/// `?` operator.
+///
+/// The `?` is only accepted directly after a closing parenthesis,
+/// so a question mark after a plain name (as in "Is it @name?") is
+/// still template text.
+fn try_call(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(terminated(expr_in_parens, opt(tag("?")))),
+        input_to_str,
+    )(input)
+}
+
+/// The standard filters, that are defined in the `filters`
+/// module of the generated templates module.
+const STANDARD_FILTERS: &[&str] =
+    &["default", "lower", "replace", "trim", "truncate", "upper"];
+
+/// A possible filter after an expression, as parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    /// The name of the filter function.
+    pub name: String,
+    /// The arguments of the filter, after the value, if any.
+    pub args: Option<String>,
+    /// The source of the filter, including the ` | ` before it.
+    pub source: String,
+}
+
+impl Filter {
+    /// True if this is one of the standard filters or one of the
+    /// names `used` by the template.
+    /// A glob import does not make any name a filter.
+    ///
+    /// Other names after a `|` are just template text, as in
+    /// "@title | home".
+    pub fn is_known(&self, used: &[String]) -> bool {
+        STANDARD_FILTERS.contains(&self.name.as_str())
+            || used.contains(&self.name)
+    }
+}
+
+/// A chain of possible filters after an expression, as in
+/// `@value | upper | truncate(50)`.
+///
+/// Each filter is a ` | ` followed by a lowercase name and optional
+/// arguments in parentheses.
+/// A name starting with an uppercase letter is not a filter, so a
+/// `|` in text like "@title | My site" is still text.
+/// Which of the other names are filters is only known when the names
+/// used by the template are known, see `Filter::is_known`.
+pub fn filters(input: &[u8]) -> PResult<'_, Vec<Filter>> {
+    many0(filter)(input)
+}
+
+fn filter(input: &[u8]) -> PResult<'_, Filter> {
+    let (rest, (name, args)) = preceded(
+        tuple((space1, char('|'), space1)),
+        pair(
+            verify(rust_name, |name: &str| {
+                name.starts_with(|c: char| c == '_' || c.is_ascii_lowercase())
+            }),
+            opt(delimited(char('('), expr_inside_parens, char(')'))),
+        ),
+    )(input)?;
+    let source = &input[..input.len() - rest.len()];
+    let filter = Filter {
+        name: name.to_string(),
+        args: args.map(String::from),
+        source: String::from_utf8_lossy(source).into_owned(),
+    };
+    Ok((rest, filter))
+}
+
+/// The code for applying `filters` to the value of `expr`.
+///
+/// The first filter gets a reference to the value, and each following
+/// filter gets the result of the previous one, followed by any
+/// arguments of the filter.
+/// A filter that is one of the names `used` by the template is that
+/// function, so it can replace a standard filter.
+/// The other standard filters are found in the `filters` module.
+/// Without filters, this is just `expr`.
+pub fn apply_filters(
+    expr: &str,
+    filters: &[Filter],
+    used: &[String],
+) -> String {
+    if filters.is_empty() {
+        return expr.to_string();
+    }
+    let mut result = format!("&{}", expr);
+    for filter in filters {
+        let name = &filter.name;
+        let name = if STANDARD_FILTERS.contains(&name.as_str())
+            && !used.contains(name)
+        {
+            format!("super::filters::{}", name)
+        } else {
+            name.to_string()
+        };
+        result = match filter.args.as_ref().map(|args| args.trim()) {
+            Some(args) if !args.is_empty() => {
+                format!("{}({}, {})", name, result, args)
+            }
+            _ => format!("{}({})", name, result),
+        };
+    }
+    result
+}
+
 pub fn input_to_str(s: &[u8]) -> Result<&str, Utf8Error> {
-    from_utf8(&s)
+    from_utf8(s)
 }
 
-pub fn comma_expressions(input: &[u8]) -> PResult<String> {
+pub fn comma_expressions(input: &[u8]) -> PResult<'_, String> {
     map(
         separated_list(preceded(tag(","), many0(tag(" "))), expression),
         |list: Vec<_>| list.join(", "),
     )(input)
 }
 
-pub fn rust_name(input: &[u8]) -> PResult<&str> {
+pub fn rust_name(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(pair(
             alt((tag("_"), alpha1)),
-            opt(is_a("_0123456789abcdefghijklmnopqrstuvwxyz")),
+            opt(is_a(
+                "_0123456789abcdefghijklmnopqrstuvwxyz\
+                 ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+            )),
         )),
         input_to_str,
     )(input)
 }
 
-fn expr_in_parens(input: &[u8]) -> PResult<&str> {
+pub fn expr_in_parens(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(delimited(tag("("), expr_inside_parens, tag(")"))),
         input_to_str,
     )(input)
 }
 
-fn expr_in_brackets(input: &[u8]) -> PResult<&str> {
+pub fn expr_in_brackets(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(delimited(
             tag("["),
@@ -87,7 +204,24 @@ fn expr_in_brackets(input: &[u8]) -> PResult<&str> {
     )(input)
 }
 
-pub fn expr_in_braces(input: &[u8]) -> PResult<&str> {
+/// The generic arguments of a turbofish, as in `collect::<Vec<_>>()`.
+fn expr_in_angles(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(delimited(
+            tag("<"),
+            many0(alt((
+                value((), is_not("<>{}[]()\"")),
+                value((), expr_in_angles),
+                value((), expr_in_parens),
+                value((), expr_in_brackets),
+            ))),
+            tag(">"),
+        )),
+        input_to_str,
+    )(input)
+}
+
+pub fn expr_in_braces(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(delimited(
             tag("{"),
@@ -105,7 +239,7 @@ pub fn expr_in_braces(input: &[u8]) -> PResult<&str> {
     )(input)
 }
 
-pub fn expr_inside_parens(input: &[u8]) -> PResult<&str> {
+pub fn expr_inside_parens(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(many0(alt((
             value((), is_not("{}[]()\"/")),
@@ -120,7 +254,7 @@ pub fn expr_inside_parens(input: &[u8]) -> PResult<&str> {
     )(input)
 }
 
-pub fn quoted_string(input: &[u8]) -> PResult<&str> {
+pub fn quoted_string(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(delimited(
             char('"'),
@@ -131,7 +265,7 @@ pub fn quoted_string(input: &[u8]) -> PResult<&str> {
     )(input)
 }
 
-pub fn rust_comment(input: &[u8]) -> PResult<&[u8]> {
+pub fn rust_comment(input: &[u8]) -> PResult<'_, &[u8]> {
     delimited(
         tag("/*"),
         recognize(many0(alt((
@@ -144,7 +278,7 @@ pub fn rust_comment(input: &[u8]) -> PResult<&[u8]> {
 
 #[cfg(test)]
 mod test {
-    use expression::expression;
+    use expression::{apply_filters, expression, filters};
 
     #[test]
     fn expression_1() {
@@ -159,6 +293,10 @@ mod test {
         check_expr("a_b_c");
     }
     #[test]
+    fn expression_upper_case() {
+        check_expr("MAX_ITEMS");
+    }
+    #[test]
     fn expression_4() {
         check_expr("foo.bar");
     }
@@ -235,6 +373,84 @@ mod test {
         check_expr("(2 + 3*4 - 5/2)");
     }
 
+    #[test]
+    fn expression_try() {
+        check_expr("file.read_to_string(&mut buf)?");
+    }
+    #[test]
+    fn expression_try_chained() {
+        check_expr("db.query()?.rows()?.len()");
+    }
+    #[test]
+    fn expression_question_mark_after_name() {
+        assert_eq!(expression(b"name?"), Ok((&b"?"[..], "name")));
+    }
+
+    #[test]
+    fn expression_closure_arg() {
+        check_expr("items.iter().map(|x| x.name).count()");
+    }
+    #[test]
+    fn expression_closure_with_braces() {
+        check_expr(
+            "items.iter().filter(|x| { x.len() > 2 || x.is_empty() })",
+        );
+    }
+    #[test]
+    fn expression_closure_move() {
+        check_expr("run(move |a, b| a | b)");
+    }
+    #[test]
+    fn expression_turbofish() {
+        check_expr("items.iter().sum::<u32>()");
+    }
+    #[test]
+    fn expression_turbofish_nested() {
+        check_expr(
+            "items.iter().map(|x| x.name).collect::<Vec<_>>().join(\", \")",
+        );
+    }
+    #[test]
+    fn expression_turbofish_tuple() {
+        check_expr("parse::<(u8, [u8; 2])>(s)");
+    }
+
+    #[test]
+    fn filter_chain() {
+        let (rest, chain) =
+            filters(b" | upper | truncate(50) | my_filter() | More").unwrap();
+        assert_eq!(rest, b" | More");
+        assert_eq!(chain[1].source, " | truncate(50)");
+        assert_eq!(
+            apply_filters("item.name", &chain, &[]),
+            "my_filter(super::filters::truncate(\
+             super::filters::upper(&item.name), 50))",
+        );
+    }
+
+    #[test]
+    fn used_filter_replaces_standard() {
+        let (_, chain) = filters(b" | trim | upper").unwrap();
+        assert_eq!(
+            apply_filters("x", &chain, &["upper".to_string()]),
+            "upper(super::filters::trim(&x))",
+        );
+    }
+
+    #[test]
+    fn known_filters() {
+        let (_, chain) = filters(b" | upper | shout | word").unwrap();
+        let used = ["shout".to_string()];
+        let known = chain.iter().map(|f| f.is_known(&used));
+        assert_eq!(known.collect::<Vec<_>>(), [true, true, false]);
+        assert!(!chain[2].is_known(&["*".to_string()]));
+    }
+
+    #[test]
+    fn no_filters() {
+        assert_eq!(filters(b"|upper"), Ok((&b"|upper"[..], vec![])));
+    }
+
     fn check_expr(expr: &str) {
         assert_eq!(expression(expr.as_bytes()), Ok((&b""[..], expr)));
     }
@@ -0,0 +1,380 @@
+use templateexpression::{TemplateArgument, TemplateExpression};
+
+/// Stands for the output of an expression in the static text of a
+/// template.
+pub const DYNAMIC: char = '\u{0}';
+
+/// Elements that have no content and no closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "keygen",
+    "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// Elements whose closing tag may be omitted.
+const OPTIONAL_CLOSE: &[&str] = &[
+    "body", "caption", "colgroup", "dd", "dt", "head", "html", "li",
+    "optgroup", "option", "p", "rp", "rt", "tbody", "td", "tfoot", "th",
+    "thead", "tr",
+];
+
+/// Elements that are obsolete in html 5.
+const DEPRECATED: &[&str] = &[
+    "acronym", "applet", "basefont", "big", "blink", "center", "dir", "font",
+    "frame", "frameset", "isindex", "marquee", "nobr", "noframes", "strike",
+    "tt",
+];
+
+/// Check the static html in `body` for common mistakes.
+///
+/// Each block of the template is checked by itself, with the output of
+/// expressions, calls and nested blocks unknown.
+/// A tag is only reported as unclosed if it is not closed in the same
+/// block, and tags with unknown attributes are not checked for
+/// required attributes.
+pub fn lint(body: &[TemplateExpression]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for_each_block(body, &mut |html| {
+        for problem in lint_html(html) {
+            if !problems.contains(&problem) {
+                problems.push(problem);
+            }
+        }
+    });
+    problems
+}
+
+/// Call `f` with the static html of each block in `body`, starting
+/// with the top level.
+///
+/// In the html of a block, a `DYNAMIC` mark stands for each
+/// expression or nested block.
+pub fn for_each_block<F>(body: &[TemplateExpression], f: &mut F)
+where
+    F: FnMut(&str),
+{
+    let mut html = String::new();
+    let mut nested = Vec::new();
+    flatten(body, &mut html, &mut |body| nested.push(body));
+    f(&html);
+    for body in nested {
+        for_each_block(body, f);
+    }
+}
+
+/// Write the static text of `body` to `html`, with a `DYNAMIC` mark
+/// for each other part, and call `nested` for each nested block.
+///
+/// Raw blocks and named blocks are written in place, so their static
+/// text is part of `html`.
+fn flatten<'a, F>(
+    body: &'a [TemplateExpression],
+    html: &mut String,
+    nested: &mut F,
+) where
+    F: FnMut(&'a [TemplateExpression]),
+{
+    for expr in body {
+        match *expr {
+            TemplateExpression::Text { ref text } => html.push_str(text),
+            TemplateExpression::Comment
+            | TemplateExpression::Let { .. }
+            | TemplateExpression::Const { .. }
+            | TemplateExpression::Yield { .. } => (),
+            TemplateExpression::RawBlock { ref body }
+            | TemplateExpression::Block { ref body, .. } => {
+                flatten(body, html, nested)
+            }
+            TemplateExpression::Macro { ref body, .. } => nested(body),
+            TemplateExpression::ForLoop { ref body, .. }
+            | TemplateExpression::WhileLoop { ref body, .. } => {
+                nested(body);
+                html.push(DYNAMIC);
+            }
+            TemplateExpression::IfBlock {
+                ref body,
+                ref else_body,
+                ..
+            } => {
+                nested(body);
+                if let Some(ref else_body) = *else_body {
+                    nested(else_body);
+                }
+                html.push(DYNAMIC);
+            }
+            TemplateExpression::MatchBlock { ref arms, .. } => {
+                for (_, body) in arms {
+                    nested(body);
+                }
+                html.push(DYNAMIC);
+            }
+            TemplateExpression::CallTemplate { ref args, .. } => {
+                for arg in args {
+                    if let TemplateArgument::Body(ref body) = *arg {
+                        nested(body);
+                    }
+                }
+                html.push(DYNAMIC);
+            }
+            TemplateExpression::Expression { .. }
+            | TemplateExpression::Filtered { .. }
+            | TemplateExpression::MacroCall { .. }
+            | TemplateExpression::Include(_) => html.push(DYNAMIC),
+        }
+    }
+}
+
+/// A part of the static html of a template.
+pub enum Token<'a> {
+    /// An opening tag, with the lowercase name and the text of the
+    /// attributes.
+    Open { name: String, attrs: &'a str },
+    /// A closing tag, with the lowercase name.
+    Close { name: String },
+    /// An `&` that does not start a character reference.
+    RawAmp,
+}
+
+/// Call `f` for each tag and raw `&` in `html`.
+///
+/// Comments, doctype declarations and the content of `<script>` and
+/// `<style>` elements are skipped.
+pub fn scan<'a, F>(html: &'a str, f: &mut F)
+where
+    F: FnMut(Token<'a>),
+{
+    let mut rest = html;
+    while let Some(pos) = rest.find(['<', '&']) {
+        let tail = &rest[pos..];
+        if let Some(after) = tail.strip_prefix('&') {
+            if !is_entity(after) {
+                f(Token::RawAmp);
+            }
+            rest = after;
+        } else if let Some(comment) = tail.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+        } else if tail.starts_with("<!") || tail.starts_with("<?") {
+            rest = tail.find('>').map_or("", |end| &tail[end + 1..]);
+        } else if let Some(close) = tail.strip_prefix("</") {
+            f(Token::Close {
+                name: tag_name(close),
+            });
+            rest = close.find('>').map_or("", |end| &close[end + 1..]);
+        } else if tail[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            let name = tag_name(&tail[1..]);
+            let (attrs, after) = split_tag(&tail[1 + name.len()..]);
+            rest = after;
+            if name == "script" || name == "style" {
+                let end = format!("</{}", name);
+                let lower = rest.to_ascii_lowercase();
+                rest = lower.find(&end).map_or("", |i| &rest[i..]);
+            }
+            f(Token::Open { name, attrs });
+        } else {
+            rest = &tail[1..];
+        }
+    }
+}
+
+/// Check a fragment of html, where `DYNAMIC` marks unknown content.
+fn lint_html(html: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut open: Vec<String> = Vec::new();
+    scan(html, &mut |token| match token {
+        Token::RawAmp => problems
+            .push("Raw '&' in html, should probably be \"&amp;\"".into()),
+        Token::Close { name } => {
+            if let Some(i) = open.iter().rposition(|o| *o == name) {
+                for unclosed in open.drain(i..).skip(1) {
+                    unclosed_tag(&unclosed, &mut problems);
+                }
+            }
+        }
+        Token::Open { name, attrs } => {
+            check_tag(&name, attrs, &mut problems);
+            if !is_empty_element(&name, attrs) {
+                open.push(name);
+            }
+        }
+    });
+    for unclosed in open {
+        unclosed_tag(&unclosed, &mut problems);
+    }
+    problems
+}
+
+/// True if the element has no closing tag.
+pub fn is_empty_element(name: &str, attrs: &str) -> bool {
+    attrs.trim_end().ends_with('/')
+        || VOID_ELEMENTS.contains(&name)
+        || name == "script"
+        || name == "style"
+}
+
+fn unclosed_tag(name: &str, problems: &mut Vec<String>) {
+    if !OPTIONAL_CLOSE.contains(&name) {
+        problems.push(format!("Unclosed <{}> tag", name));
+    }
+}
+
+/// Check the attributes of an opening tag.
+fn check_tag(name: &str, attrs: &str, problems: &mut Vec<String>) {
+    if DEPRECATED.contains(&name) {
+        problems.push(format!("The <{}> element is deprecated", name));
+    }
+    let required = match name {
+        "a" => "href",
+        "img" => "alt",
+        _ => return,
+    };
+    if let Some(attrs) = attributes(attrs) {
+        if !attrs.iter().any(|(n, _)| n == required) {
+            problems.push(format!("<{}> tag without {}", name, required));
+        }
+    }
+}
+
+/// The lowercase tag name at the start of `tag`.
+fn tag_name(tag: &str) -> String {
+    tag.chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Split the rest of a tag into the attributes and the text after the
+/// tag.
+fn split_tag(tag: &str) -> (&str, &str) {
+    let mut quote = None;
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return (&tag[..i], &tag[i + 1..]),
+            _ => (),
+        }
+    }
+    (tag, "")
+}
+
+/// The lowercase names and the values of the attributes, or `None` if
+/// some of them may be written by an expression.
+///
+/// A value may contain `DYNAMIC` marks.
+pub fn attributes(attrs: &str) -> Option<Vec<(String, String)>> {
+    let mut result = Vec::new();
+    let mut rest = attrs.trim_start();
+    while !rest.is_empty() {
+        let name_len = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let (name, after) = rest.split_at(name_len);
+        if name.contains(DYNAMIC) {
+            return None;
+        }
+        let after = after.trim_start();
+        let (value, after) = match after.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                match value.chars().next() {
+                    Some(q) if q == '"' || q == '\'' => {
+                        let end =
+                            value[1..].find(q).map_or(value.len(), |e| e + 1);
+                        (&value[1..end], value.get(end + 1..).unwrap_or(""))
+                    }
+                    _ => {
+                        let end = value
+                            .find(char::is_whitespace)
+                            .unwrap_or(value.len());
+                        if value[..end].contains(DYNAMIC) {
+                            return None;
+                        }
+                        value.split_at(end)
+                    }
+                }
+            }
+            None => ("", after),
+        };
+        if !name.is_empty() && name != "/" {
+            result.push((name.to_ascii_lowercase(), value.to_string()));
+        }
+        rest = after.trim_start();
+    }
+    Some(result)
+}
+
+/// True if `text` (after an `&`) starts with a character reference.
+fn is_entity(text: &str) -> bool {
+    let name = text
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '#')
+        .count();
+    name > 0 && text[name..].starts_with(';')
+}
+
+#[cfg(test)]
+mod test {
+    use super::lint;
+    use templateexpression::TemplateExpression;
+
+    fn lint_text(html: &str) -> Vec<String> {
+        lint(&[TemplateExpression::text(html)])
+    }
+
+    #[test]
+    fn clean_html() {
+        assert_eq!(
+            lint_text(
+                "<!DOCTYPE html>\n<ul><li>One &amp; two<li>&#8212;</ul>\
+                 <br><img src=\"a.png\" alt=\"\"/><!-- <b> -->\
+                 <script>if (a && b < c) {}</script>"
+            ),
+            Vec::<String>::new(),
+        );
+    }
+
+    #[test]
+    fn unclosed_tags() {
+        assert_eq!(
+            lint_text("<div><span>text</div><section>"),
+            ["Unclosed <span> tag", "Unclosed <section> tag"],
+        );
+    }
+
+    #[test]
+    fn required_attributes() {
+        assert_eq!(
+            lint_text("<a name=\"x\">x</a><img src='a.png' title=\"alt\">"),
+            ["<a> tag without href", "<img> tag without alt"],
+        );
+    }
+
+    #[test]
+    fn raw_ampersand_and_deprecated() {
+        assert_eq!(
+            lint_text("<center>Fish & chips</center>"),
+            [
+                "The <center> element is deprecated",
+                "Raw '&' in html, should probably be \"&amp;\"",
+            ],
+        );
+    }
+
+    #[test]
+    fn dynamic_parts() {
+        let body = [
+            TemplateExpression::text("<a href=\""),
+            TemplateExpression::Expression { expr: "url".into() },
+            TemplateExpression::text("\">x</a><img "),
+            TemplateExpression::Expression {
+                expr: "attrs".into(),
+            },
+            TemplateExpression::text(">"),
+            TemplateExpression::IfBlock {
+                expr: "open".into(),
+                body: vec![TemplateExpression::text("<b>")],
+                else_body: None,
+            },
+        ];
+        assert_eq!(lint(&body), ["Unclosed <b> tag"]);
+    }
+}
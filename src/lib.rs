@@ -5,12 +5,12 @@
 //! This has the benefits that:
 //!
 //! 1. Many syntactical and logical errors in templates are caught
-//! compile-time, rather than in a running server.
+//!    compile-time, rather than in a running server.
 //! 2. No extra latency on the first request, since the templates are
-//! fully compiled before starting the program.
+//!    fully compiled before starting the program.
 //! 3. The template files does not have to be distributed / installed.
-//! Templates (and static assets) are included in the compiled
-//! program, which can be a single binary.
+//!    Templates (and static assets) are included in the compiled
+//!    program, which can be a single binary.
 //!
 //! The template syntax, which is inspired by [Twirl], the Scala-based
 //! template engine in [Play framework], is documented in
@@ -71,11 +71,30 @@
 //! `Ok(())` unless writing to the destination fails.
 //!
 //! ```
+//! # mod templates {
+//! #     use std::io::{Result, Write};
+//! #     pub fn hello_html(out: &mut impl Write, name: &str) -> Result<()> {
+//! #         writeln!(out, "<h1>Hello {}!</h1>", name)
+//! #     }
+//! # }
+//! let mut buf = Vec::new();
+//! templates::hello_html(&mut buf, "World").unwrap();
+//! assert_eq!(buf, b"<h1>Hello World!</h1>\n");
+//! ```
+//!
+//! The generated module also defines the macros `assert_template_eq!`
+//! and `assert_template_contains!`, which provide the buffer and show
+//! the full output if the assertion fails.
+//! They are available in code following the `include!` of the
+//! generated module.
+//!
+//! ```ignore
 //! #[test]
 //! fn test_hello() {
-//!     let mut buf = Vec::new();
-//!     templates::hello_html(&mut buf, "World").unwrap();
-//!     assert_eq!(buf, b"<h1>Hello World!</h1>\n");
+//!     assert_template_eq!(
+//!         templates::hello_html("World"),
+//!         "<h1>Hello World!</h1>\n",
+//!     );
 //! }
 //! ```
 //!
@@ -85,15 +104,88 @@
 //!
 //! * `sass` -- Compile sass and include the compiled css as static assets.
 //! * `mime03` -- Static files know their mime types, compatible with
-//! version 0.3.x of the [mime] crate.
+//!   version 0.3.x of the [mime] crate.
 //! * `mime02` -- Static files know their mime types, compatible with
-//! version 0.2.x of the [mime] crate.
+//!   version 0.2.x of the [mime] crate.
 //! * `warp02` -- Provide an extension to [`Response::Builder`] to
-//! simplify template rendering in the [warp] framework, versions 0.2.x.
+//!   simplify template rendering in the [warp] framework, versions 0.2.x.
+//! * `warp` -- Provide a `warp_support::warp_reply` function, to
+//!   render a template as an `impl Reply` in the [warp] framework.
+//! * `axum` -- Provide a `TemplateResponse` type implementing
+//!   `IntoResponse`, so that a template call can be returned directly
+//!   from a handler in the [axum] framework.
+//! * `actix` -- Provide a `RenderedTemplate` type implementing
+//!   `Responder`, so that a template call can be returned directly
+//!   from a handler in the [actix-web] framework, versions 4.x.
+//! * `rocket` -- Provide a `TemplateResponder` type implementing
+//!   `Responder`, so that a template call can be returned directly
+//!   from a handler in the [rocket] framework, versions 0.5.x.
+//! * `insta` -- Provide the `render_template!` and
+//!   `render_template_to_bytes!` macros in a `testing` module, for
+//!   snapshot testing of templates with [insta].
+//! * `email` -- For html templates, also generate a `{name}_email`
+//!   function that inlines the css of `<style>` elements, and of any
+//!   stylesheet declared with `@email_css("style.css")`, into `style`
+//!   attributes, using the [css-inline] crate, since many email
+//!   clients ignore `<style>`.
+//! * `parallel` -- Parse and compile the templates in parallel, using
+//!   [rayon].
+//! * `async-tokio` -- Make it possible to also generate `async fn`
+//!   templates writing to a [tokio] `AsyncWrite`, see
+//!   [`Ructe::generate_async`].
+//! * `async-std` -- Make it possible to also generate `async fn`
+//!   templates writing to a [futures] `AsyncWrite`, as used by
+//!   async-std.
+//! * `bytes` -- For each template, also generate a
+//!   `{name}_to_bytes_buf` function that renders it to a
+//!   `bytes::Bytes`, as used for response bodies by e.g. hyper and
+//!   actix-web, without copying the output.  The crate using the
+//!   templates needs a dependency on [bytes].
+//! * `serde` -- For each template without `Content` arguments, also
+//!   generate a `{name}_to_json` function that serializes its
+//!   arguments as a json object, for an api serving the same data
+//!   as a page.  The crate using the templates needs dependencies on
+//!   [serde] (with the `derive` feature) and `serde_json`.
+//! * `stream` -- Also generate a `_stream` function for each async
+//!   template, that returns the output as a stream of [bytes]
+//!   chunks, see [`Ructe::generate_async`].  This implies
+//!   `async-tokio`.
+//! * `simd-escaping` -- Find the characters that need html escaping
+//!   with the vectorized search of the [memchr] crate, which is
+//!   faster for long texts.  The crate using the templates needs a
+//!   dependency on `memchr` as well.
+//! * `num-format` -- Take the separators used by [`LocaleNumber`]
+//!   from the [num-format] crate, rather than from a small built in
+//!   table.  The crate using the templates needs a dependency on
+//!   `num-format` as well.
+//! * `timing` -- Record how long each call of a template function
+//!   takes, in a buffer for each thread.  The times are taken with
+//!   `templates::timing::drain_timings()`.  Without the feature, no
+//!   timing code is generated.
+//! * `sitegen` -- Build the `ructe-gen` command, that renders a
+//!   directory of templates as a static site, see `generate_site`.
+//!   Install it with `cargo install ructe --features sitegen`.
+//! * `refactor` -- Build the `ructe-refactor` command, that renames
+//!   an argument of a template, see `refactor::rename_argument`.
+//!   Install it with `cargo install ructe --features refactor`.
 //!
 //! [`response::Builder`]: ../http/response/struct.Builder.html
 //! [mime]: https://crates.rs/crates/mime
 //! [warp]: https://crates.rs/crates/warp
+//! [axum]: https://crates.rs/crates/axum
+//! [actix-web]: https://crates.rs/crates/actix-web
+//! [rocket]: https://crates.rs/crates/rocket
+//! [insta]: https://crates.rs/crates/insta
+//! [css-inline]: https://crates.rs/crates/css-inline
+//! [rayon]: https://crates.rs/crates/rayon
+//! [num-format]: https://crates.rs/crates/num-format
+//! [`LocaleNumber`]: templates/struct.LocaleNumber.html
+//! [tokio]: https://crates.rs/crates/tokio
+//! [futures]: https://crates.rs/crates/futures
+//! [memchr]: https://crates.rs/crates/memchr
+//! [serde]: https://crates.rs/crates/serde
+//! [bytes]: https://crates.rs/crates/bytes
+//! [`Ructe::generate_async`]: struct.Ructe.html#method.generate_async
 //!
 //! The `mime02` and `mime03` features are mutually exclusive and
 //! requires a dependency on a matching version of `mime`.
@@ -108,36 +200,78 @@
 //! [dependencies]
 //! mime = "0.3.13"
 //! ```
+//!
+//! # Reloading templates during development
+//!
+//! Since templates are compiled to rust code, there is no way to
+//! reload a changed template in a running program; the program
+//! has to be rebuilt.
+//! Ructe tells cargo to rerun the build script whenever a template
+//! or static file changes, so a tool like [cargo-watch] can be used
+//! to rebuild and restart a development server on any change:
+//!
+//! ```sh
+//! cargo watch -x run
+//! ```
+//!
+//! [cargo-watch]: https://crates.rs/crates/cargo-watch
 #![warn(missing_docs)]
 extern crate base64;
 extern crate bytecount;
 extern crate itertools;
 extern crate md5;
+#[cfg(feature = "memchr")]
+extern crate memchr;
 #[cfg(feature = "mime")]
 extern crate mime;
 extern crate nom;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 #[cfg(feature = "sass")]
 extern crate rsass;
 
 pub mod Template_syntax;
+mod a11ylint;
+mod configfile;
 mod expression;
+mod htmllint;
 #[doc(hidden)] // public for doctest to work, but hide from docs.
 pub mod nom_delimited_list;
 mod parseresult;
+mod pathglob;
+#[cfg(feature = "refactor")]
+pub mod refactor;
+#[cfg(feature = "sitegen")]
+mod sitegen;
 mod spacelike;
 mod staticfiles;
 mod template;
 mod templateexpression;
+mod workspace;
 
-use parseresult::show_errors;
+use itertools::Itertools;
+use parseresult::{error_location, line_and_column, show_errors, PResult};
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::error::Error;
 use std::fmt::{self, Display};
-use std::fs::{create_dir_all, read_dir, File};
-use std::io::{self, Read, Write};
-use std::path::{Path, PathBuf};
-use template::template;
+use std::fs::{
+    create_dir_all, read, read_dir, read_to_string, remove_file, rename,
+    write, File, OpenOptions,
+};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::str;
+use std::time::{Duration, Instant};
+use template::{
+    args_struct_name, contains_ident, global_constants, template,
+    template_head, Template,
+};
+use templateexpression::{Escaping, Include, Target};
 
+#[cfg(feature = "sitegen")]
+pub use sitegen::generate_site;
 pub use staticfiles::StaticFiles;
 
 /// The main build-time interface of ructe.
@@ -181,16 +315,231 @@ pub use staticfiles::StaticFiles;
 /// ```
 ///
 ///
-/// When creating a `Ructe` it will create a file called
-/// `templates.rs` in your `$OUT_DIR` (which is normally created and
-/// specified by `cargo`).
+/// A `Ructe` will create a file called `templates.rs` in your
+/// `$OUT_DIR` (which is normally created and specified by `cargo`).
 /// The methods will add content, and when the `Ructe` goes of of
-/// scope, the file will be completed.
+/// scope, the file will be written.
+/// The name of the module (and file) can be changed with
+/// [`set_module_name`].
+///
+/// [`set_module_name`]: #method.set_module_name
 ///
 /// [cargo]: https://doc.rust-lang.org/cargo/
 pub struct Ructe {
-    f: File,
+    /// The body of the generated module, written on drop.
+    f: Vec<u8>,
     outdir: PathBuf,
+    module_name: String,
+    template_dirs: Vec<PathBuf>,
+    config: TemplateConfig,
+    stats: CompilationStats,
+}
+
+/// Statistics about compiling templates.
+///
+/// See [`Ructe::statistics`].
+///
+/// [`Ructe::statistics`]: struct.Ructe.html#method.statistics
+#[derive(Debug, Default)]
+pub struct CompilationStats {
+    /// The number of templates compiled.
+    pub compiled: usize,
+    /// The number of templates that failed to parse, and got a
+    /// panicking stub instead (see [`Ructe::set_lenient`]), or that
+    /// failed in a dry run.
+    ///
+    /// [`Ructe::set_lenient`]: struct.Ructe.html#method.set_lenient
+    pub failed: usize,
+    /// The number of lines of rust code generated for the templates.
+    pub generated_lines: usize,
+    /// The time spent compiling templates.
+    pub elapsed: Duration,
+    /// The errors found in a dry run (see [`Ructe::dry_run`]).
+    ///
+    /// [`Ructe::dry_run`]: struct.Ructe.html#method.dry_run
+    pub errors: Vec<RucteError>,
+}
+
+impl CompilationStats {
+    fn add(&mut self, other: CompilationStats) {
+        self.compiled += other.compiled;
+        self.failed += other.failed;
+        self.generated_lines += other.generated_lines;
+        self.elapsed += other.elapsed;
+        self.errors.extend(other.errors);
+    }
+}
+
+impl Display for CompilationStats {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            out,
+            "Compiled {} templates ({} failed) to {} lines of rust in {:.2?}",
+            self.compiled, self.failed, self.generated_lines, self.elapsed,
+        )
+    }
+}
+
+/// Settings for how template files are found and compiled.
+struct TemplateConfig {
+    /// File name suffixes that mark a file as a template.
+    suffixes: Vec<String>,
+    /// Warn when a template overrides one from an earlier directory.
+    warn_overrides: bool,
+    /// Glob patterns of files in the template directories to skip.
+    exclude_patterns: Vec<String>,
+    /// If not empty, only files matching one of these are templates.
+    include_patterns: Vec<String>,
+    /// The trait the generated template functions write to.
+    output: OutputTrait,
+    /// Also generate an `async fn` for each template, for this runtime.
+    async_runtime: Option<AsyncRuntime>,
+    /// Write a graph of which templates call which to `template_deps.dot`.
+    dependency_graph: bool,
+    /// Print statistics as a cargo warning after compiling templates.
+    report_statistics: bool,
+    /// Only check the templates, without writing any code.
+    dry_run: bool,
+    /// Warn about templates not referenced in the rust files here.
+    warn_unused: Option<PathBuf>,
+    /// The name, type and value of each constant for all templates.
+    constants: Vec<(String, String, String)>,
+    /// The name of each module re-exporting the templates of another
+    /// crate, and the path of those templates.
+    imports: Vec<(String, String)>,
+    /// The function to call for `@t(...)`, if not `gettext` in scope.
+    gettext_function: Option<String>,
+    /// The function to call for `@tn(...)`, if not `ngettext` in scope.
+    ngettext_function: Option<String>,
+    /// The function to call for `@icu_msg(...)`, if not `icu_msg` in
+    /// scope.
+    icu_function: Option<String>,
+    /// Write a panicking stub for a template that fails to parse.
+    lenient: bool,
+    /// Templates with fewer nodes than this get an `#[inline]` function.
+    inline_threshold: usize,
+    /// Other templates get an `#[inline(never)]` function.
+    inline_never: bool,
+    /// The most nested recursive calls of a template allowed.
+    max_template_depth: usize,
+    /// Run `rustfmt` on each generated template file.
+    format_generated: bool,
+    /// Write a comment around the output of each expression in debug
+    /// builds.
+    trace_expressions: bool,
+    /// Warn about common mistakes in the html of html templates.
+    html_lint: bool,
+    /// Warn about accessibility problems in the html of html templates.
+    a11y_lint: bool,
+}
+
+/// The kind of output the generated template functions write to.
+///
+/// See [`Ructe::set_output_trait`].
+///
+/// [`Ructe::set_output_trait`]: struct.Ructe.html#method.set_output_trait
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputTrait {
+    /// Write to a [`std::io::Write`], returning an `io::Result<()>`.
+    /// This is the default.
+    ///
+    /// [`std::io::Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+    Io,
+    /// Write to a [`std::fmt::Write`], such as a `String` or a
+    /// `fmt::Formatter`, returning a `fmt::Result`.
+    ///
+    /// [`std::fmt::Write`]: https://doc.rust-lang.org/std/fmt/trait.Write.html
+    Fmt,
+}
+
+impl OutputTrait {
+    /// The module of the `Write` trait and result type.
+    fn module(self) -> &'static str {
+        match self {
+            OutputTrait::Io => "io",
+            OutputTrait::Fmt => "fmt",
+        }
+    }
+}
+
+/// The async runtime to generate `async fn` template functions for.
+///
+/// Each variant is available with the ructe feature of the same name.
+/// See [`Ructe::generate_async`].
+///
+/// [`Ructe::generate_async`]: struct.Ructe.html#method.generate_async
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsyncRuntime {
+    /// Write to a `tokio::io::AsyncWrite`.
+    #[cfg(feature = "async-tokio")]
+    Tokio,
+    /// Write to a `futures::io::AsyncWrite`, as used by async-std.
+    #[cfg(feature = "async-std")]
+    AsyncStd,
+}
+
+impl AsyncRuntime {
+    /// The module of the `AsyncWrite` and `AsyncWriteExt` traits.
+    fn io_module(self) -> &'static str {
+        match self {
+            #[cfg(feature = "async-tokio")]
+            AsyncRuntime::Tokio => "tokio::io",
+            #[cfg(feature = "async-std")]
+            AsyncRuntime::AsyncStd => "futures::io",
+        }
+    }
+
+    /// The template utilities for this runtime.
+    fn template_utils(self) -> &'static [u8] {
+        match self {
+            #[cfg(feature = "async-tokio")]
+            AsyncRuntime::Tokio => include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_tokio.rs"
+            )),
+            #[cfg(feature = "async-std")]
+            AsyncRuntime::AsyncStd => include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_async_std.rs"
+            )),
+        }
+    }
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        TemplateConfig {
+            suffixes: vec![
+                ".rs.html".into(),
+                ".rs.svg".into(),
+                ".rs.xml".into(),
+                ".rs.txt".into(),
+                ".rs.json".into(),
+            ],
+            warn_overrides: false,
+            exclude_patterns: Vec::new(),
+            include_patterns: Vec::new(),
+            output: OutputTrait::Io,
+            async_runtime: None,
+            dependency_graph: false,
+            report_statistics: false,
+            dry_run: false,
+            warn_unused: None,
+            constants: Vec::new(),
+            imports: Vec::new(),
+            gettext_function: None,
+            ngettext_function: None,
+            icu_function: None,
+            lenient: false,
+            inline_threshold: 20,
+            inline_never: false,
+            max_template_depth: template::DEFAULT_MAX_DEPTH,
+            format_generated: false,
+            trace_expressions: false,
+            html_lint: false,
+            a11y_lint: false,
+        }
+    }
 }
 
 impl Ructe {
@@ -199,11 +548,25 @@ impl Ructe {
     /// A file called `templates.rs` (and a directory called
     /// `templates` containing sub-modules) will be created in the
     /// directory that cargo specifies with the `OUT_DIR` environment
-    /// variable.
+    /// variable, unless another name is given with
+    /// [`set_module_name`].
+    ///
+    /// If the crate has a `.ructe.toml` file next to its
+    /// `Cargo.toml`, the settings in it are loaded, see
+    /// [`load_config_file`].
+    ///
+    /// [`set_module_name`]: #method.set_module_name
+    /// [`load_config_file`]: #method.load_config_file
     ///
     /// [cargo]: https://doc.rust-lang.org/cargo/
     pub fn from_env() -> Result<Ructe> {
-        Ructe::new(PathBuf::from(get_env("OUT_DIR")?))
+        let mut ructe = Ructe::new(PathBuf::from(get_env("OUT_DIR")?))?;
+        let config =
+            PathBuf::from(get_env("CARGO_MANIFEST_DIR")?).join(".ructe.toml");
+        if config.is_file() {
+            ructe.load_config_file(config)?;
+        }
+        Ok(ructe)
     }
 
     /// Create  a ructe instance writing to a given directory.
@@ -212,23 +575,210 @@ impl Ructe {
     /// and is writable.
     /// A file called `templates.rs` (and a directory called
     /// `templates` containing sub-modules) will be created in
-    /// `out_dir`.
+    /// `out_dir`, unless another name is given with
+    /// [`set_module_name`].
     ///
     /// If you are using Ructe in a project that uses [cargo],
     /// you should probably use [`from_env`] instead.
     ///
     /// [cargo]: https://doc.rust-lang.org/cargo/
     /// [`from_env`]: #method.from_env
+    /// [`set_module_name`]: #method.set_module_name
     pub fn new(out_dir: PathBuf) -> Result<Ructe> {
-        let mut f = File::create(out_dir.join("templates.rs"))?;
-        f.write_all(
-            b"pub mod templates {\n\
-              use std::io::{self, Write};\n\
-              use std::fmt::Display;\n\n",
+        Ok(Ructe {
+            f: Vec::new(),
+            outdir: out_dir,
+            module_name: "templates".into(),
+            template_dirs: Vec::new(),
+            config: TemplateConfig::default(),
+            stats: CompilationStats::default(),
+        })
+    }
+
+    /// Load settings from a toml file, such as `.ructe.toml`.
+    ///
+    /// Each setting corresponds to a method of `Ructe`, and is
+    /// applied as if the method was called, so methods called after
+    /// loading the file override its settings.
+    /// The settings are:
+    ///
+    /// * `template-dir = "templates"` -- a template directory, see
+    ///   [`add_template_dir`] (this can be given more than once).
+    ///   The directories are compiled by [`compile_template_dirs`].
+    /// * `module-name = "views"` -- see [`set_module_name`].
+    /// * `suffix = ".html.rs"` -- see [`set_template_suffix`].
+    /// * `html-lint = true` -- see [`enable_html_lint`].
+    /// * `a11y-lint = true` -- see [`enable_a11y_lint`].
+    /// * `warn-overrides = true` -- see [`set_warn_overrides`].
+    /// * `exclude-pattern = "**/_*.html"` -- see [`exclude_pattern`]
+    ///   (this can be given more than once).
+    /// * `include-only = "**/*.rs.html"` -- see [`include_only`]
+    ///   (this can be given more than once).
+    /// * `lenient = true` -- see [`set_lenient`].
+    /// * `format-generated = true` -- see [`format_generated`].
+    /// * `trace-expressions = true` -- see [`trace_expressions`].
+    /// * `inline-threshold = 20` -- see [`inline_threshold`].
+    /// * `inline-never = true` -- see [`set_inline_never`].
+    /// * `max-template-depth = 32` -- see [`max_template_depth`].
+    /// * `report-statistics = true` -- see [`report_statistics`].
+    ///
+    /// Cargo features of ructe, such as `simd-escaping`, are
+    /// selected in `Cargo.toml` and can't be set here.
+    /// An unknown setting gives a cargo warning, and a line that
+    /// can't be parsed gives a [`RucteError::Parse`].
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("config-file");
+    /// # create_dir_all(p.join("views"))?;
+    /// # write(p.join("views").join("page.rs.html"), b"@()\n<p>Hello</p>\n")?;
+    /// # let (config, views) = (p.join(".ructe.toml"), p.join("views"));
+    /// write(
+    ///     &config,
+    ///     format!("template-dir = {:?}\nhtml-lint = true\n", views),
+    /// )?;
+    /// let mut ructe = Ructe::new(p.clone())?;
+    /// ructe.load_config_file(&config)?.compile_template_dirs()?;
+    /// # assert!(p.join("templates").join("template_page_html.rs").is_file());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`add_template_dir`]: #method.add_template_dir
+    /// [`compile_template_dirs`]: #method.compile_template_dirs
+    /// [`set_module_name`]: #method.set_module_name
+    /// [`set_template_suffix`]: #method.set_template_suffix
+    /// [`enable_html_lint`]: #method.enable_html_lint
+    /// [`enable_a11y_lint`]: #method.enable_a11y_lint
+    /// [`set_warn_overrides`]: #method.set_warn_overrides
+    /// [`exclude_pattern`]: #method.exclude_pattern
+    /// [`include_only`]: #method.include_only
+    /// [`set_lenient`]: #method.set_lenient
+    /// [`format_generated`]: #method.format_generated
+    /// [`trace_expressions`]: #method.trace_expressions
+    /// [`inline_threshold`]: #method.inline_threshold
+    /// [`set_inline_never`]: #method.set_inline_never
+    /// [`max_template_depth`]: #method.max_template_depth
+    /// [`report_statistics`]: #method.report_statistics
+    /// [`RucteError::Parse`]: enum.RucteError.html#variant.Parse
+    pub fn load_config_file<P>(&mut self, path: P) -> Result<&mut Self>
+    where
+        P: AsRef<Path>,
+    {
+        use configfile::Value;
+        let path = path.as_ref();
+        println!("cargo:rerun-if-changed={}", path.display());
+        let settings = configfile::parse(&read_to_string(path)?).map_err(
+            |(line, message)| RucteError::Parse {
+                path: path.into(),
+                line,
+                column: 1,
+                message,
+            },
         )?;
-        let outdir = out_dir.join("templates");
-        create_dir_all(&outdir)?;
-        Ok(Ructe { f, outdir })
+        for (line, key, value) in settings {
+            match (key.as_str(), value) {
+                ("template-dir", Value::Str(dir)) => {
+                    self.add_template_dir(dir);
+                }
+                ("module-name", Value::Str(name)) => {
+                    self.set_module_name(&name);
+                }
+                ("suffix", Value::Str(suffix)) => {
+                    self.set_template_suffix(&suffix);
+                }
+                ("html-lint", Value::Bool(lint)) => {
+                    self.enable_html_lint(lint);
+                }
+                ("a11y-lint", Value::Bool(lint)) => {
+                    self.enable_a11y_lint(lint);
+                }
+                ("warn-overrides", Value::Bool(warn)) => {
+                    self.set_warn_overrides(warn);
+                }
+                ("exclude-pattern", Value::Str(pattern)) => {
+                    self.exclude_pattern(&pattern);
+                }
+                ("include-only", Value::Str(pattern)) => {
+                    self.include_only(&pattern);
+                }
+                ("lenient", Value::Bool(lenient)) => {
+                    self.set_lenient(lenient);
+                }
+                ("format-generated", Value::Bool(format)) => {
+                    self.format_generated(format);
+                }
+                ("trace-expressions", Value::Bool(trace)) => {
+                    self.trace_expressions(trace);
+                }
+                ("inline-threshold", Value::Int(threshold)) => {
+                    self.inline_threshold(threshold);
+                }
+                ("inline-never", Value::Bool(never)) => {
+                    self.set_inline_never(never);
+                }
+                ("max-template-depth", Value::Int(depth)) => {
+                    self.max_template_depth(depth);
+                }
+                ("report-statistics", Value::Bool(report)) => {
+                    self.report_statistics(report);
+                }
+                (key, value) => println!(
+                    "cargo:warning={}:{}: Unknown setting {} = {:?}",
+                    path.display(),
+                    line,
+                    key,
+                    value,
+                ),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Use `name` as the name of the generated module, instead of
+    /// `templates`.
+    ///
+    /// The generated file will be called `name.rs` (and the directory
+    /// containing sub-modules will be called `name`), so it should be
+    /// included like this:
+    ///
+    /// ```rust,ignore
+    /// include!(concat!(env!("OUT_DIR"), "/views.rs"));
+    /// ```
+    ///
+    /// This must be called before any templates are compiled or
+    /// static files are added.
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, read_to_string, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("module-name");
+    /// # create_dir_all(p.join("in"))?;
+    /// # write(p.join("in").join("page.rs.html"), b"@()\n<p>Hello</p>\n")?;
+    /// # {
+    /// let mut ructe = Ructe::new(p.clone())?;
+    /// ructe.set_module_name("views").compile_templates(p.join("in"))?;
+    /// # }
+    /// # assert!(read_to_string(p.join("views.rs"))?.starts_with("pub mod views {"));
+    /// # assert!(p.join("views").join("template_page_html.rs").is_file());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_module_name(&mut self, name: &str) -> &mut Self {
+        self.module_name = name.into();
+        self
+    }
+
+    /// The directory for the sub-modules of the generated module.
+    fn module_dir(&self) -> Result<PathBuf> {
+        let dir = self.outdir.join(&self.module_name);
+        create_dir_all(&dir)?;
+        Ok(dir)
     }
 
     /// Create a `templates` module in `outdir` containing rust code for
@@ -238,209 +788,2320 @@ impl Ructe {
     /// directory of your crate, i.e. the directory containing your
     /// `Cargo.toml` file.
     ///
-    /// Files with suffix `.rs.html`, `.rs.svg`, or `.rs.xml` are
-    /// considered templates.
+    /// Files with suffix `.rs.html`, `.rs.svg`, `.rs.xml`, `.rs.txt`,
+    /// or `.rs.json` are considered templates.
+    /// Values written by `.rs.xml` and `.rs.svg` templates (or any
+    /// template whose function name ends with `_xml` or `_svg`) are
+    /// escaped for xml, using [`ToHtml::to_xml`], rather than for html.
+    /// Values written by `.rs.txt` templates (function names ending
+    /// with `_txt`) are plain text, written with `Display` without
+    /// any escaping, and values written by `.rs.json` templates are
+    /// escaped for use in json strings, using [`ToHtml::to_json`].
     /// A templete file called `template.rs.html`, `template.rs.svg`,
     /// etc, will result in a callable function named `template_html`,
     /// `template_svg`, etc.
+    /// Some other functions and constants are generated for each
+    /// template, such as `template_html_to_string` and
+    /// `TEMPLATE_HTML_CONTENT_TYPE`, see [Generated items].
     /// The `template_html` function will get a `template` alias for
     /// backwards compatibility, but that will be removed in a future
     /// release.
+    ///
+    /// If a template cannot be parsed, the problem is shown as cargo
+    /// warnings and a [`RucteError::Parse`] is returned, so the build
+    /// script can tell syntax errors from io errors.
+    ///
+    /// ```
+    /// # use ructe::{RucteError, Ructe};
+    /// # use std::fs::{create_dir_all, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<(), RucteError> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("parse-error");
+    /// # create_dir_all(p.join("in"))?;
+    /// # write(p.join("in").join("bad.rs.html"), b"@()\n<p>@if { oops }</p>\n")?;
+    /// # let mut ructe = Ructe::new(p.clone())?;
+    /// match ructe.compile_templates(p.join("in")) {
+    ///     Err(RucteError::Parse { line, column, .. }) => {
+    ///         assert_eq!((line, column), (2, 8));
+    ///     }
+    ///     other => panic!("Unexpected result {:?}", other),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [Generated items]: Template_syntax/f_Generated_items/index.html
+    /// [`RucteError::Parse`]: enum.RucteError.html#variant.Parse
+    /// [`ToHtml::to_xml`]: templates/trait.ToHtml.html#method.to_xml
+    /// [`ToHtml::to_json`]: templates/trait.ToHtml.html#method.to_json
     pub fn compile_templates<P>(&mut self, indir: P) -> Result<()>
     where
         P: AsRef<Path>,
     {
-        handle_entries(&mut self.f, indir.as_ref(), &self.outdir)
+        let indirs = [indir.as_ref().into()];
+        let outdir = self.module_dir()?;
+        self.config.load_globals(&indirs)?;
+        let stats =
+            self.config.handle_entries(&mut self.f, &indirs, &outdir)?;
+        self.stats.add(stats);
+        Ok(())
     }
 
-    /// Create a [`StaticFiles`] handler for this Ructe instance.
+    /// Add a directory of templates to be compiled by
+    /// [`compile_template_dirs`].
     ///
-    /// This will create a `statics` module inside the generated
-    /// `templates` module.
-    ///
-    /// # Examples
+    /// As for [`compile_templates`], a relative path should be
+    /// relative to the main directory of your crate.
     ///
-    /// This code goes into the `build.rs`:
+    /// This returns `&mut self`, so calls for configuring a `Ructe`
+    /// can be chained:
     ///
     /// ```no_run
-    /// # extern crate ructe;
-    /// # use ructe::{Ructe, RucteError};
-    /// # fn main() -> Result<(), RucteError> {
-    /// let mut ructe = Ructe::from_env()?;
-    /// ructe.statics()?.add_files("static")
-    /// # }
+    /// use ructe::{Result, Ructe};
+    ///
+    /// fn main() -> Result<()> {
+    ///     Ructe::from_env()?
+    ///         .add_template_dir("templates")
+    ///         .compile_template_dirs()
+    /// }
     /// ```
     ///
-    /// Assuming your project have a directory named `static` that
-    /// contains e.g. a file called `logo.svg` and you have included
-    /// the generated `templates.rs`, you can now use
-    /// `templates::statics::logo_png` as a [`StaticFile`] in your
-    /// project.
+    /// [`compile_template_dirs`]: #method.compile_template_dirs
+    /// [`compile_templates`]: #method.compile_templates
+    pub fn add_template_dir<P>(&mut self, indir: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.template_dirs.push(indir.as_ref().into());
+        self
+    }
+
+    /// Add the `templates` directory of each crate in the cargo
+    /// workspace at `workspace_root` to be compiled by
+    /// [`compile_template_dirs`].
     ///
-    /// [`StaticFiles`]: struct.StaticFiles.html
-    /// [`StaticFile`]: templates/struct.StaticFile.html
-    pub fn statics(&mut self) -> Result<StaticFiles> {
-        self.f.write_all(b"pub mod statics;")?;
-        Ok(StaticFiles::for_template_dir(
-            &self.outdir,
-            &PathBuf::from(get_env("CARGO_MANIFEST_DIR")?),
-        )?)
+    /// The members are read from the `[workspace]` section of the
+    /// `Cargo.toml` in `workspace_root`, where a member ending with
+    /// `/*` means each crate directory in that directory, and members
+    /// listed in `exclude` are skipped.
+    /// If the workspace root is also a package, its `templates`
+    /// directory comes first.
+    /// As for [`add_template_dir`], a template in a later directory
+    /// overrides a template with the same name in an earlier one.
+    ///
+    /// [`add_template_dir`]: #method.add_template_dir
+    /// [`compile_template_dirs`]: #method.compile_template_dirs
+    pub fn add_workspace_template_dirs<P>(
+        &mut self,
+        workspace_root: P,
+    ) -> Result<&mut Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dirs = workspace::template_dirs(workspace_root.as_ref())?;
+        self.template_dirs.extend(dirs);
+        Ok(self)
     }
-}
 
-impl Drop for Ructe {
-    fn drop(&mut self) {
-        self.f
-            .write_all(include_bytes!(concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/src/template_utils.rs"
-            )))
-            .unwrap();
-        if cfg!(feature = "warp02") {
-            self.f
-                .write_all(include_bytes!(concat!(
-                    env!("CARGO_MANIFEST_DIR"),
-                    "/src/template_utils_warp02.rs"
-                )))
-                .unwrap();
-        }
-        self.f.write_all(b"\n}\n").unwrap();
+    /// Compile the templates of all crates in the workspace of the
+    /// crate being built into one `templates` module.
+    ///
+    /// The workspace root is taken from the `CARGO_WORKSPACE_DIR`
+    /// environment variable, if cargo sets it, or else found by
+    /// looking for a `Cargo.toml` with a `[workspace]` section in the
+    /// crate directory and its parents.
+    /// See [`add_workspace_template_dirs`] for which directories are
+    /// used.
+    ///
+    /// To share the templates, call this from the build script of one
+    /// crate in the workspace, include the module in that crate, and
+    /// let the other crates depend on it.
+    ///
+    /// ```no_run
+    /// use ructe::{Result, Ructe};
+    ///
+    /// fn main() -> Result<()> {
+    ///     Ructe::from_env()?.compile_workspace_templates()
+    /// }
+    /// ```
+    ///
+    /// [`add_workspace_template_dirs`]: #method.add_workspace_template_dirs
+    pub fn compile_workspace_templates(&mut self) -> Result<()> {
+        let root = workspace::workspace_root()?;
+        self.add_workspace_template_dirs(root)?
+            .compile_template_dirs()
     }
-}
 
-fn handle_entries(
-    f: &mut impl Write,
-    indir: &Path,
-    outdir: &Path,
-) -> Result<()> {
-    println!("cargo:rerun-if-changed={}", indir.display());
-    for entry in read_dir(indir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if entry.file_type()?.is_dir() {
-            if let Some(filename) = entry.file_name().to_str() {
-                let outdir = outdir.join(filename);
-                create_dir_all(&outdir)?;
-                let mut modrs = File::create(outdir.join("mod.rs"))?;
-                modrs.write_all(
-                    b"#[allow(renamed_and_removed_lints)]\n\
-                      #[cfg_attr(feature=\"cargo-clippy\", \
-                      allow(useless_attribute))]\n\
-                      #[allow(unused)]\n\
-                      use super::{Html,ToHtml};\n",
-                )?;
-                handle_entries(&mut modrs, &path, &outdir)?;
-                writeln!(f, "pub mod {name};\n", name = filename)?;
-            }
-        } else if let Some(filename) = entry.file_name().to_str() {
-            for suffix in &[".rs.html", ".rs.svg", ".rs.xml"] {
-                if filename.ends_with(suffix) {
-                    println!("cargo:rerun-if-changed={}", path.display());
-                    let prename = &filename[..filename.len() - suffix.len()];
-                    let name =
-                        format!("{}_{}", prename, &suffix[".rs.".len()..]);
-                    if handle_template(&name, &path, outdir)? {
-                        writeln!(
-                            f,
-                            "mod template_{name};\n\
-                             pub use self::template_{name}::{name};\n",
-                            name = name,
-                        )?;
-                        // Backwards compatibility to 0.7.2 and earlier.
-                        if suffix == &".rs.html" {
-                            writeln!(
-                                f,
-                                "#[deprecated(since=\"0.7.4\", \
-                                 note=\"please use `{name}` instead\")]\n\
-                                 pub use self::{name} as {alias};\n",
-                                alias = prename,
-                                name = name,
-                            )?;
-                        }
-                    }
-                }
-            }
-        }
+    /// Use `suffix` as the only file name suffix for templates.
+    ///
+    /// By default, files ending with `.rs.html`, `.rs.svg`, `.rs.xml`,
+    /// `.rs.txt`, or `.rs.json` are considered templates.
+    /// The name of the generated function is the file name without
+    /// the suffix, followed by the parts of the suffix other than
+    /// `rs`, so with the suffix `.html.rs`, a file called
+    /// `page.html.rs` will result in a function named `page_html`.
+    ///
+    /// This affects subsequent calls to [`compile_templates`] and
+    /// [`compile_template_dirs`].
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("template-suffix");
+    /// # create_dir_all(p.join("in"))?;
+    /// # write(p.join("in").join("page.html.rs"), b"@()\n<p>Hello</p>\n")?;
+    /// # let mut ructe = Ructe::new(p.clone())?;
+    /// ructe.set_template_suffix(".html.rs").compile_templates(p.join("in"))?;
+    /// # assert!(p.join("templates").join("template_page_html.rs").is_file());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`compile_templates`]: #method.compile_templates
+    /// [`compile_template_dirs`]: #method.compile_template_dirs
+    pub fn set_template_suffix(&mut self, suffix: &str) -> &mut Self {
+        self.config.suffixes = vec![suffix.into()];
+        self
     }
-    Ok(())
-}
 
-fn handle_template(
-    name: &str,
-    path: &Path,
-    outdir: &Path,
-) -> io::Result<bool> {
-    let mut input = File::open(path)?;
-    let mut buf = Vec::new();
-    input.read_to_end(&mut buf)?;
-    match template(&buf) {
-        Ok((_, t)) => {
-            File::create(outdir.join(format!("template_{}.rs", name)))
-                .and_then(|mut f| t.write_rust(&mut f, name))?;
-            Ok(true)
-        }
-        Err(error) => {
-            println!("cargo:warning=Template parse error in {:?}:", path);
-            show_errors(&mut io::stdout(), &buf, &error, "cargo:warning=");
-            Ok(false)
-        }
+    /// Compile the templates in all directories added by
+    /// [`add_template_dir`].
+    ///
+    /// All the templates are merged into one `templates` module, and
+    /// subdirectories with the same name in different template
+    /// directories are merged into one submodule.
+    /// If the same template name exists in more than one of the
+    /// directories, the one in the last added directory is used.
+    /// This makes it possible to override some of a set of default
+    /// templates:
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, read_to_string, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("template-dirs");
+    /// # let (defaults, custom) = (p.join("defaults"), p.join("custom"));
+    /// # create_dir_all(&defaults)?;
+    /// # create_dir_all(&custom)?;
+    /// # write(defaults.join("page.rs.html"), "@()\n<p>Default page</p>\n")?;
+    /// # write(defaults.join("footer.rs.html"), "@()\n<p>Default footer</p>\n")?;
+    /// # write(custom.join("page.rs.html"), "@()\n<p>Custom page</p>\n")?;
+    /// # let mut ructe = Ructe::new(p.clone())?;
+    /// ructe
+    ///     .add_template_dir(defaults)
+    ///     .add_template_dir(custom)
+    ///     .compile_template_dirs()?;
+    /// # let out = p.join("templates");
+    /// # assert!(read_to_string(out.join("template_page_html.rs"))?.contains("Custom page"));
+    /// # assert!(read_to_string(out.join("template_footer_html.rs"))?.contains("Default footer"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`add_template_dir`]: #method.add_template_dir
+    pub fn compile_template_dirs(&mut self) -> Result<()> {
+        let outdir = self.module_dir()?;
+        self.config.load_globals(&self.template_dirs)?;
+        let stats = self.config.handle_entries(
+            &mut self.f,
+            &self.template_dirs,
+            &outdir,
+        )?;
+        self.stats.add(stats);
+        Ok(())
     }
-}
 
-/// The module containing your generated template code will also
-/// contain everything from here.
-///
-/// The name `ructe::templates` should never be used.  Instead, you
-/// should use the module templates created when compiling your
-/// templates.
-/// If you include the generated `templates.rs` in your `main.rs` (or
-/// `lib.rs` in a library crate), this module will be
-/// `crate::templates`.
-pub mod templates {
-    #[cfg(feature = "mime03")]
-    use mime::Mime;
-    use std::fmt::Display;
-    use std::io::{self, Write};
+    /// Print statistics as a cargo warning after compiling templates.
+    ///
+    /// The statistics are the number of templates compiled and
+    /// failed, the number of lines of rust code generated, and the
+    /// time it took.
+    /// This may be useful for tuning the build, or noticing when the
+    /// templates grow.
+    /// The statistics are also available from [`statistics`].
+    ///
+    /// [`statistics`]: #method.statistics
+    pub fn report_statistics(&mut self, report: bool) -> &mut Self {
+        self.config.report_statistics = report;
+        self
+    }
 
-    #[cfg(feature = "mime02")]
-    /// Documentation mock.  The real Mime type comes from the `mime` crate.
-    pub type Mime = u8; // mock
+    /// Statistics about the templates compiled so far by this
+    /// `Ructe`.
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("statistics");
+    /// # create_dir_all(p.join("in"))?;
+    /// # write(p.join("in").join("page.rs.html"), b"@(x: u8)\n<p>@x</p>\n")?;
+    /// let mut ructe = Ructe::new(p.clone())?;
+    /// ructe.compile_templates(p.join("in"))?;
+    /// assert_eq!(ructe.statistics().compiled, 1);
+    /// # assert!(ructe.statistics().generated_lines > 10);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn statistics(&self) -> &CompilationStats {
+        &self.stats
+    }
 
-    /// A static file has a name (so its url can be recognized) and the
-    /// actual file contents.
+    /// Warn when a template overrides a template with the same name
+    /// from a previously added template directory.
     ///
-    /// The content-type (mime type) of the file is available as a
-    /// static field when building ructe with the `mime03` feature or
-    /// as the return value of a method when building ructe with the
-    /// `mime02` feature (in `mime` version 0.2.x, a Mime cannot be
-    /// defined as a part of a const static value.
-    pub struct StaticFile {
-        /// The actual static file contents.
-        pub content: &'static [u8],
-        /// The file name as used in a url, including a short (48 bits
-        /// as 8 base64 characters) hash of the content, to enable
-        /// long-time caching of static resourses in the clients.
-        pub name: &'static str,
-        /// The Mime type of this static file, as defined in the mime
-        /// crate version 0.3.x.
-        #[cfg(feature = "mime03")]
-        pub mime: &'static Mime,
+    /// By default, overriding is silent.
+    /// When enabled, each overridden template is reported as a cargo
+    /// warning by [`compile_template_dirs`].
+    ///
+    /// [`compile_template_dirs`]: #method.compile_template_dirs
+    pub fn set_warn_overrides(&mut self, warn: bool) -> &mut Self {
+        self.config.warn_overrides = warn;
+        self
     }
 
-    impl StaticFile {
-        /// Get the mime type of this static file.
-        ///
-        /// Currently, this method parses a (static) string every time.
-        /// A future release of `mime` may support statically created
-        /// `Mime` structs, which will make this nicer.
-        #[allow(unused)]
-        #[cfg(feature = "mime02")]
-        pub fn mime(&self) -> Mime {
-            unimplemented!()
-        }
+    /// Skip files in the template directories that match `pattern`.
+    ///
+    /// The pattern is matched against the path of each file relative
+    /// to its template directory, with `/` between directories.
+    /// In the pattern, `**` matches any number of directories, `*`
+    /// matches any part of a file or directory name, and `?` matches
+    /// a single character.
+    /// This can be called more than once, to skip files matching any
+    /// of the patterns.
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("exclude-pattern");
+    /// # let templates = p.join("templates-src");
+    /// # create_dir_all(templates.join("page"))?;
+    /// # write(templates.join("page").join("_draft.rs.html"), b"@(\n")?;
+    /// # write(templates.join("page").join("post.rs.html"), b"@()\n<p>Post</p>\n")?;
+    /// let mut ructe = Ructe::new(p.clone())?;
+    /// ructe
+    ///     .exclude_pattern("**/_*.html")
+    ///     .compile_templates(&templates)?;
+    /// # assert!(!p.join("templates").join("page").join("template__draft_html.rs").exists());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn exclude_pattern(&mut self, pattern: &str) -> &mut Self {
+        self.config.exclude_patterns.push(pattern.into());
+        self
     }
 
-    include!("template_utils.rs");
+    /// Only use files in the template directories that match
+    /// `pattern` as templates.
+    ///
+    /// The pattern is written as for [`exclude_pattern`].
+    /// This can be called more than once, to use files matching any
+    /// of the patterns.
+    /// By default, all files with a template suffix are used.
+    ///
+    /// [`exclude_pattern`]: #method.exclude_pattern
+    pub fn include_only(&mut self, pattern: &str) -> &mut Self {
+        self.config.include_patterns.push(pattern.into());
+        self
+    }
+
+    /// Compile the crate even if some templates fail to parse.
+    ///
+    /// All templates are compiled even if some of them fail to parse,
+    /// and each error is reported as cargo warnings.
+    /// By default, compiling the templates then returns the first
+    /// error.
+    /// When lenient, a template that fails to parse instead gets a
+    /// function that panics with the parse error, so the crate still
+    /// compiles but the broken template fails when it is used.
+    /// This requires that the arguments of the template can be parsed.
+    ///
+    /// This may be useful during development, but should not be used
+    /// for release builds.
+    pub fn set_lenient(&mut self, lenient: bool) -> &mut Self {
+        self.config.lenient = lenient;
+        self
+    }
+
+    /// Only check that the templates are correct, without writing
+    /// any code for them.
+    ///
+    /// Each template is parsed and checked as when compiling it,
+    /// including its layouts and includes and the lints that are
+    /// enabled, but no code is generated.
+    /// Instead of failing on the first error, all errors are
+    /// collected in the [`errors`] of the [`statistics`].
+    /// This may be useful for checking the templates in a separate
+    /// CI step, or from a standalone tool.
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("dry-run");
+    /// # create_dir_all(p.join("in"))?;
+    /// # write(p.join("in").join("good.rs.html"), b"@()\n<p>ok</p>\n")?;
+    /// # write(p.join("in").join("bad.rs.html"), b"@(x: u8)\n@if x {\n")?;
+    /// let mut ructe = Ructe::new(p.clone())?;
+    /// ructe.dry_run(true).compile_templates(p.join("in"))?;
+    /// let stats = ructe.statistics();
+    /// assert_eq!((stats.compiled, stats.failed), (1, 1));
+    /// assert_eq!(stats.errors.len(), 1);
+    /// # assert!(!p.join("templates").join("template_good_html.rs").exists());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`errors`]: struct.CompilationStats.html#structfield.errors
+    /// [`statistics`]: #method.statistics
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// Mark the functions for small templates `#[inline]`.
+    ///
+    /// A template is small if it has fewer than `threshold` parts,
+    /// where each text, expression, loop, conditional, call etc., is a
+    /// part, including each part nested inside another.
+    /// The default threshold is 20.
+    /// A threshold of 0 means that no template function is marked
+    /// `#[inline]`.
+    pub fn inline_threshold(&mut self, threshold: usize) -> &mut Self {
+        self.config.inline_threshold = threshold;
+        self
+    }
+
+    /// Mark the functions for templates that are not small (see
+    /// [`inline_threshold`]) `#[inline(never)]`.
+    ///
+    /// By default, they are not marked, so the compiler decides.
+    ///
+    /// [`inline_threshold`]: #method.inline_threshold
+    pub fn set_inline_never(&mut self, never: bool) -> &mut Self {
+        self.config.inline_never = never;
+        self
+    }
+
+    /// Limit how deeply a template may call itself.
+    ///
+    /// A template that calls itself, e.g. to render a tree, gets a
+    /// guard that counts the nested calls.
+    /// When more than `depth` calls are nested, the innermost call
+    /// writes nothing instead of its body (just a
+    /// `<!-- max depth exceeded -->` comment in html and xml
+    /// templates), so bad data can't overflow the stack.
+    /// The default depth is 32.
+    pub fn max_template_depth(&mut self, depth: usize) -> &mut Self {
+        self.config.max_template_depth = depth;
+        self
+    }
+
+    /// Run `rustfmt` on the generated code for each template.
+    ///
+    /// The generated code is compact and not very readable by default.
+    /// Formatting it makes it easier to read when debugging a
+    /// template, at the cost of some build time.
+    /// If `rustfmt` is not installed (or fails), the file is left
+    /// unformatted and a cargo warning is shown.
+    /// The `RUSTFMT` environment variable can name another formatter
+    /// to run.
+    pub fn format_generated(&mut self, format: bool) -> &mut Self {
+        self.config.format_generated = format;
+        self
+    }
+
+    /// Mark the output of each expression in html and xml templates
+    /// with comments, to find out which expression wrote what.
+    ///
+    /// When enabled, `@user.name` writes
+    /// `<!-- begin: user.name -->` before the name and
+    /// `<!-- end: user.name -->` after it.
+    /// The comments are only written in debug builds, as the code
+    /// for them is behind `if cfg!(debug_assertions)`, so release
+    /// builds write the same output as without tracing.
+    /// Other templates, such as json or text templates, are not
+    /// traced.
+    pub fn trace_expressions(&mut self, trace: bool) -> &mut Self {
+        self.config.trace_expressions = trace;
+        self
+    }
+
+    /// Check the static html of html templates for common mistakes.
+    ///
+    /// When enabled, each problem found is reported as a cargo
+    /// warning.
+    /// The checks are for tags that are not closed (except those
+    /// where html allows the closing tag to be omitted), `<a>` tags
+    /// without `href`, `<img>` tags without `alt`, `&` characters that
+    /// do not start a character reference, and obsolete elements such
+    /// as `<center>` and `<font>`.
+    ///
+    /// Only the static text of the template is checked, so a tag
+    /// opened in a conditional and closed after it is reported as
+    /// unclosed, and a tag with attributes written by an expression is
+    /// not checked for required attributes.
+    pub fn enable_html_lint(&mut self, lint: bool) -> &mut Self {
+        self.config.html_lint = lint;
+        self
+    }
+
+    /// Check the static html of html templates for basic
+    /// accessibility problems.
+    ///
+    /// When enabled, each problem found is reported as a cargo
+    /// warning, with the tag where it was found.
+    /// The checks are for `<img>` tags without `alt`, form controls
+    /// without a label (either a `<label>` around the control or
+    /// with a `for` attribute matching its `id`, or an `aria-label`,
+    /// `aria-labelledby` or `title` attribute), elements with an
+    /// `onclick` handler that cannot get keyboard focus, and positive
+    /// `tabindex` values.
+    ///
+    /// As for [`enable_html_lint`], only the static text of the
+    /// template is checked, and false positives are possible.
+    /// Problems that need an understanding of the content, such as
+    /// information given only by color, are not found.
+    ///
+    /// [`enable_html_lint`]: #method.enable_html_lint
+    pub fn enable_a11y_lint(&mut self, lint: bool) -> &mut Self {
+        self.config.a11y_lint = lint;
+        self
+    }
+
+    /// Write a graph of which templates call which other templates.
+    ///
+    /// When enabled, compiling the templates also writes a
+    /// `template_deps.dot` file, in [Graphviz] DOT format, to the
+    /// directory of the generated `templates` module in `OUT_DIR`.
+    /// Each template is a node, and there is an edge from each
+    /// template to each template it calls or includes.
+    ///
+    /// [Graphviz]: https://graphviz.org/
+    pub fn emit_dependency_graph(&mut self, emit: bool) -> &mut Self {
+        self.config.dependency_graph = emit;
+        self
+    }
+
+    /// Warn about templates that are never used.
+    ///
+    /// When enabled, compiling the templates also scans the rust files
+    /// in `src_dir` (usually `"src"`, relative to the crate directory)
+    /// and its subdirectories.
+    /// Each template that is not called by another template, and whose
+    /// function name (or `_to_string`, `_to_bytes` or `_email`
+    /// variant) is not mentioned in any of the files, is reported as a
+    /// cargo warning.
+    ///
+    /// The scanning is simple, so a template only used through a
+    /// renaming `use` is reported anyway.
+    /// Since the warnings require scanning the sources, the build
+    /// script is rerun whenever a file in `src_dir` changes.
+    pub fn warn_unused_templates<P>(&mut self, src_dir: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.config.warn_unused = Some(src_dir.as_ref().into());
+        self
+    }
+
+    /// Set the function to call to translate `@t("...")` strings.
+    ///
+    /// The function is called with the string to translate, and should
+    /// return the translation as something that implements
+    /// `Display`, such as a `String`.
+    /// By default, a function called `gettext` is called, so it must
+    /// be imported in each template using `@t`, e.g. with
+    /// `@use gettextrs::gettext;`.
+    ///
+    /// ```no_run
+    /// use ructe::{Result, Ructe};
+    ///
+    /// fn main() -> Result<()> {
+    ///     Ructe::from_env()?
+    ///         .set_gettext_function("crate::i18n::gettext")
+    ///         .compile_templates("templates")
+    /// }
+    /// ```
+    pub fn set_gettext_function(&mut self, path: &str) -> &mut Self {
+        self.config.gettext_function = Some(path.into());
+        self
+    }
+
+    /// Set the function to call to translate `@tn("...", "...", n)`
+    /// plural strings.
+    ///
+    /// The function is called with the singular and plural strings
+    /// and the count, and should return the translation for the count.
+    /// By default, a function called `ngettext` is called, as for
+    /// [`set_gettext_function`].
+    ///
+    /// [`set_gettext_function`]: #method.set_gettext_function
+    pub fn set_ngettext_function(&mut self, path: &str) -> &mut Self {
+        self.config.ngettext_function = Some(path.into());
+        self
+    }
+
+    /// Set the function to call to format `@icu_msg("...", ...)`
+    /// ICU MessageFormat messages.
+    ///
+    /// The function is called with the pattern and a slice of the
+    /// names and values of the arguments, where each value is
+    /// converted with `.into()` to the argument type of the function.
+    /// By default, a function called `icu_msg` is called, as for
+    /// [`set_gettext_function`].
+    ///
+    /// [`set_gettext_function`]: #method.set_gettext_function
+    pub fn set_icu_function(&mut self, path: &str) -> &mut Self {
+        self.config.icu_function = Some(path.into());
+        self
+    }
+
+    /// Add a string constant for all templates.
+    ///
+    /// The constant is declared as `pub const NAME: &str = "value";`
+    /// in the generated module, so it can be used as `NAME` in any
+    /// template, and as `templates::NAME` in rust code.
+    /// Constants of any type can also be declared with `@const`
+    /// lines in a `_globals.rs.html` file in the template directory,
+    /// such as `@const MAX_ITEMS: usize = 20;`.
+    ///
+    /// ```no_run
+    /// use ructe::{Result, Ructe};
+    ///
+    /// fn main() -> Result<()> {
+    ///     Ructe::from_env()?
+    ///         .add_constant("SITE_NAME", "My Site")
+    ///         .add_env_constant("BASE_URL", "SITE_BASE_URL")
+    ///         .compile_templates("templates")
+    /// }
+    /// ```
+    ///
+    /// The constants should be added before compiling the templates.
+    pub fn add_constant(&mut self, name: &str, value: &str) -> &mut Self {
+        self.add_constant_code(name, format!("{:?}", value))
+    }
+
+    /// Add a string constant for all templates, with the value of the
+    /// environment variable `var` when compiling the templates.
+    ///
+    /// The constant is declared as
+    /// `pub const NAME: &str = env!("VAR");`, so the variable is read
+    /// when the crate using the templates is compiled.
+    /// See [`add_constant`].
+    ///
+    /// [`add_constant`]: #method.add_constant
+    pub fn add_env_constant(&mut self, name: &str, var: &str) -> &mut Self {
+        self.add_constant_code(name, format!("env!({:?})", var))
+    }
+
+    fn add_constant_code(&mut self, name: &str, value: String) -> &mut Self {
+        self.config
+            .constants
+            .push((name.into(), "&str".into(), value));
+        self
+    }
+
+    /// Make the templates of another crate available to the templates
+    /// of this crate.
+    ///
+    /// A module named as the crate (with `-` replaced by `_`) is added
+    /// to the generated module, re-exporting everything in the
+    /// `templates` module of that crate.
+    /// The crate must be a dependency and make its generated module
+    /// public, by including it in its `lib.rs` outside of any private
+    /// module.
+    /// If the module of that crate has another name, give it after
+    /// the crate name, as in `"ui-components::views"`.
+    ///
+    /// A template can then call a template of the other crate:
+    ///
+    /// ```text
+    /// @use super::ui_components::button_html;
+    ///
+    /// @()
+    /// <form>@:button_html("Save")</form>
+    /// ```
+    ///
+    /// ```
+    /// # use ructe::{Result, Ructe};
+    /// # use std::fs::{create_dir_all, read_to_string, write};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("import-templates");
+    /// # create_dir_all(p.join("in"))?;
+    /// # write(p.join("in").join("page.rs.html"), b"@()\n<p>Hello</p>\n")?;
+    /// # {
+    /// let mut ructe = Ructe::new(p.clone())?;
+    /// ructe
+    ///     .import_templates_from("ui-components")
+    ///     .compile_templates(p.join("in"))?;
+    /// # }
+    /// # assert!(read_to_string(p.join("templates.rs"))?.contains(
+    /// #     "pub mod ui_components {\npub use ::ui_components::templates::*;\n}"
+    /// # ));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import_templates_from(&mut self, krate: &str) -> &mut Self {
+        let mut parts = krate.splitn(2, "::");
+        let name = parts.next().unwrap_or_default().replace('-', "_");
+        let module = parts.next().unwrap_or("templates");
+        let path = format!("::{}::{}", name, module);
+        self.config.imports.push((name, path));
+        self
+    }
+
+    /// Set the kind of output the generated template functions write to.
+    ///
+    /// By default, templates write to a `std::io::Write`.
+    /// With [`OutputTrait::Fmt`], each template function instead takes
+    /// a `&mut W` where `W: std::fmt::Write` and returns a
+    /// `std::fmt::Result`, so a template can write directly to a
+    /// `String` or be used in a `Display` implementation.
+    ///
+    /// ```no_run
+    /// # use ructe::{OutputTrait, Ructe, RucteError};
+    /// # fn main() -> Result<(), RucteError> {
+    /// let mut ructe = Ructe::from_env()?;
+    /// ructe
+    ///     .set_output_trait(OutputTrait::Fmt)
+    ///     .compile_templates("templates")
+    /// # }
+    /// ```
+    ///
+    /// [`OutputTrait::Fmt`]: enum.OutputTrait.html#variant.Fmt
+    pub fn set_output_trait(&mut self, output: OutputTrait) -> &mut Self {
+        self.config.output = output;
+        self
+    }
+
+    /// Also generate an `async fn` for each template, writing to the
+    /// `AsyncWrite` of the given `runtime`.
+    ///
+    /// The async functions are in an `asynchronous` module inside
+    /// the generated `templates` module, with the same structure and
+    /// names as the plain functions, so for a template
+    /// `page.rs.html` there is an `async fn
+    /// templates::asynchronous::page_html`.
+    /// It is also available beside the plain function, as
+    /// `templates::page_html_async`.
+    /// It takes a `&mut W` where `W: AsyncWrite + Unpin + ?Sized`
+    /// and writes each part of the template to it with
+    /// `write_all(...).await`, so the output can be streamed to a
+    /// socket without blocking a thread.
+    /// The crate using the templates must depend on `tokio` or
+    /// `futures`, as appropriate for the `runtime`.
+    /// The async code needs rust 1.75 or later, since the
+    /// `ToHtmlAsync` trait returns an `impl Future`.
+    ///
+    /// Templates called with `@:` from an async template are the
+    /// async versions, found by the same `@use` declarations, and a
+    /// `Content` argument is an async closure, which requires rust
+    /// 1.85 or later.
+    ///
+    /// With the `stream` feature, each async template (except those
+    /// with `Content` arguments) also gets a `page_html_stream`
+    /// function, taking the same arguments and returning a
+    /// `TemplateStream`, which is a `futures_core::Stream` of
+    /// `io::Result<bytes::Bytes>` with a chunk for each part written,
+    /// to use as a streaming response body.
+    /// The template is rendered as the stream is polled, so no task
+    /// is spawned and the arguments may be borrowed.
+    /// This needs the tokio runtime, and the crate using the templates
+    /// must depend on `bytes` and `futures-core` as well.
+    ///
+    /// This requires the `async-tokio` or `async-std` feature of ructe.
+    ///
+    /// ```no_run
+    /// # use ructe::{AsyncRuntime, Ructe, RucteError};
+    /// # fn main() -> Result<(), RucteError> {
+    /// let mut ructe = Ructe::from_env()?;
+    /// ructe
+    ///     .generate_async(AsyncRuntime::Tokio)
+    ///     .compile_templates("templates")
+    /// # }
+    /// ```
+    #[cfg(any(feature = "async-tokio", feature = "async-std"))]
+    pub fn generate_async(&mut self, runtime: AsyncRuntime) -> &mut Self {
+        self.config.async_runtime = Some(runtime);
+        self
+    }
+
+    /// Create a [`StaticFiles`] handler for this Ructe instance.
+    ///
+    /// This will create a `statics` module inside the generated
+    /// `templates` module.
+    ///
+    /// # Examples
+    ///
+    /// This code goes into the `build.rs`:
+    ///
+    /// ```no_run
+    /// # extern crate ructe;
+    /// # use ructe::{Ructe, RucteError};
+    /// # fn main() -> Result<(), RucteError> {
+    /// let mut ructe = Ructe::from_env()?;
+    /// ructe.statics()?.add_files("static")
+    /// # }
+    /// ```
+    ///
+    /// Assuming your project have a directory named `static` that
+    /// contains e.g. a file called `logo.svg` and you have included
+    /// the generated `templates.rs`, you can now use
+    /// `templates::statics::logo_png` as a [`StaticFile`] in your
+    /// project.
+    ///
+    /// [`StaticFiles`]: struct.StaticFiles.html
+    /// [`StaticFile`]: templates/struct.StaticFile.html
+    pub fn statics(&mut self) -> Result<StaticFiles> {
+        self.f.write_all(b"pub mod statics;")?;
+        StaticFiles::for_template_dir(
+            &self.module_dir()?,
+            &PathBuf::from(get_env("CARGO_MANIFEST_DIR")?),
+        )
+    }
+}
+
+impl Ructe {
+    /// Write the generated module, with the template utilities.
+    fn write_module(&self) -> io::Result<()> {
+        let mut f = File::create(
+            self.outdir.join(format!("{}.rs", self.module_name)),
+        )?;
+        writeln!(
+            f,
+            "pub mod {} {{\n\
+             #![macro_use]\n\
+             use std::io::{{self, Write}};\n\
+             use std::fmt::Display;\n",
+            self.module_name,
+        )?;
+        if !self.config.constants.is_empty() {
+            f.write_all(
+                b"/// Constants for all templates.\npub mod globals {\n",
+            )?;
+            for (name, ty, value) in &self.config.constants {
+                writeln!(f, "pub const {}: {} = {};", name, ty, value)?;
+            }
+            f.write_all(b"}\npub use self::globals::*;\n\n")?;
+        }
+        for (name, path) in &self.config.imports {
+            writeln!(
+                f,
+                "/// The templates of the `{name}` crate.\n\
+                 pub mod {name} {{\n\
+                 pub use {path}::*;\n\
+                 }}\n",
+                name = name,
+                path = path,
+            )?;
+        }
+        f.write_all(&self.f)?;
+        f.write_all(include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/template_utils.rs"
+        )))?;
+        if cfg!(feature = "simd-escaping") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_memchr.rs"
+            )))?;
+        } else {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_scan.rs"
+            )))?;
+        }
+        if cfg!(feature = "num-format") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_num_format.rs"
+            )))?;
+        } else {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_locale.rs"
+            )))?;
+        }
+        if let Some(runtime) = self.config.async_runtime {
+            f.write_all(runtime.template_utils())?;
+            if cfg!(feature = "stream") {
+                f.write_all(include_bytes!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/src/template_utils_stream.rs"
+                )))?;
+            }
+        }
+        if cfg!(feature = "bytes") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_bytes.rs"
+            )))?;
+        }
+        if cfg!(feature = "warp02") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_warp02.rs"
+            )))?;
+        }
+        if cfg!(feature = "axum") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_axum.rs"
+            )))?;
+        }
+        if cfg!(feature = "actix") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_actix.rs"
+            )))?;
+        }
+        if cfg!(feature = "rocket") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_rocket.rs"
+            )))?;
+        }
+        if cfg!(feature = "warp") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_warp.rs"
+            )))?;
+        }
+        if cfg!(feature = "insta") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_testing.rs"
+            )))?;
+        }
+        if cfg!(feature = "timing") {
+            f.write_all(include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/template_utils_timing.rs"
+            )))?;
+        }
+        f.write_all(b"\n}\n")
+    }
+}
+
+impl Drop for Ructe {
+    fn drop(&mut self) {
+        self.write_module().unwrap();
+    }
+}
+
+impl TemplateConfig {
+    /// Compile the templates found in `indirs` to `outdir`, and write
+    /// the declarations of the resulting module to `f`.
+    fn handle_entries(
+        &self,
+        f: &mut impl Write,
+        indirs: &[PathBuf],
+        outdir: &Path,
+    ) -> Result<CompilationStats> {
+        let start = Instant::now();
+        let mut jobs = Vec::new();
+        let target = Target::Sync(self.output);
+        if self.dry_run {
+            let mut f = io::sink();
+            self.find_templates(
+                &mut f, indirs, 0, outdir, target, &mut jobs,
+            )?;
+            return Ok(self.check_templates(&jobs, start));
+        }
+        self.find_templates(f, indirs, 0, outdir, target, &mut jobs)?;
+        if let Some(runtime) = self.async_runtime {
+            let outdir = outdir.join("asynchronous");
+            create_dir_all(&outdir)?;
+            let mut modrs = File::create(outdir.join("mod.rs"))?;
+            modrs.write_all(b"#[allow(unused)]\nuse super::*;\n")?;
+            let target = Target::Async(runtime);
+            self.find_templates(
+                &mut modrs, indirs, 0, &outdir, target, &mut jobs,
+            )?;
+            writeln!(f, "pub mod asynchronous;\n")?;
+        }
+        let (failed, exports) =
+            handle_templates(&jobs, &self.code_options(), self.lenient)?;
+        write_exports(f, outdir, &jobs, &exports)?;
+        if self.dependency_graph {
+            write_dependency_graph(outdir, &jobs)?;
+        }
+        if let Some(ref src_dir) = self.warn_unused {
+            warn_unused_templates(outdir, &jobs, src_dir)?;
+        }
+        let mut generated_lines = 0;
+        for job in &jobs {
+            let outfile =
+                job.outdir.join(format!("template_{}.rs", job.name));
+            generated_lines += bytecount::count(&read(outfile)?, b'\n');
+        }
+        let stats = CompilationStats {
+            compiled: jobs.len() - failed,
+            failed,
+            generated_lines,
+            elapsed: start.elapsed(),
+            errors: Vec::new(),
+        };
+        if self.report_statistics {
+            println!("cargo:warning={}", stats);
+        }
+        Ok(stats)
+    }
+
+    /// Check all the `jobs` without writing any code, and collect the
+    /// errors.
+    fn check_templates(
+        &self,
+        jobs: &[TemplateJob],
+        start: Instant,
+    ) -> CompilationStats {
+        let options = self.code_options();
+        let errors = jobs
+            .iter()
+            .filter_map(|job| {
+                prepare_template(&job.name, &job.path, &job.root, &options)
+                    .err()
+            })
+            .collect::<Vec<_>>();
+        let stats = CompilationStats {
+            compiled: jobs.len() - errors.len(),
+            failed: errors.len(),
+            generated_lines: 0,
+            elapsed: start.elapsed(),
+            errors,
+        };
+        if self.report_statistics {
+            println!("cargo:warning={}", stats);
+        }
+        stats
+    }
+
+    /// The settings for the code generated for each template.
+    fn code_options(&self) -> CodeOptions {
+        let gettext = self.gettext_function.iter().map(|f| (f, "gettext"));
+        let ngettext = self.ngettext_function.iter().map(|f| (f, "ngettext"));
+        let icu = self.icu_function.iter().map(|f| (f, "icu_msg"));
+        let globals = Some("super::globals::*".to_string())
+            .filter(|_| !self.constants.is_empty());
+        CodeOptions {
+            uses: gettext
+                .chain(ngettext)
+                .chain(icu)
+                .map(|(f, name)| format!("{} as {}", f, name))
+                .chain(globals)
+                .collect(),
+            inline_threshold: self.inline_threshold,
+            inline_never: self.inline_never,
+            max_template_depth: self.max_template_depth,
+            format_generated: self.format_generated,
+            trace_expressions: self.trace_expressions,
+            html_lint: self.html_lint,
+            a11y_lint: self.a11y_lint,
+        }
+    }
+
+    /// Read the constants declared in a `_globals.rs.html` file in
+    /// any of `indirs`.
+    fn load_globals(&mut self, indirs: &[PathBuf]) -> Result<()> {
+        for indir in indirs {
+            let path = indir.join(GLOBALS_FILE);
+            if !path.is_file() {
+                continue;
+            }
+            println!("cargo:rerun-if-changed={}", path.display());
+            let buf = read(&path)?;
+            let buf = without_bom(&buf);
+            let (rest, constants) = parse_file(&path, buf, global_constants)?;
+            if !rest.is_empty() {
+                let message = "Expected @const declaration".to_string();
+                return Err(located_error(&path, buf, rest.len(), message));
+            }
+            for (name, ty, value) in constants {
+                let constant = (name.into(), ty.into(), value.into());
+                if !self.constants.contains(&constant) {
+                    self.constants.push(constant);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// True if the file at `path`, `depth` levels of subdirectories
+    /// below its template directory, is selected by the include and
+    /// exclude patterns.
+    fn is_template_path(&self, path: &Path, depth: usize) -> bool {
+        let mut parts = path
+            .iter()
+            .rev()
+            .take(depth + 1)
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>();
+        parts.reverse();
+        let path = parts.join("/");
+        let matches = |pattern: &String| pathglob::matches(pattern, &path);
+        (self.include_patterns.is_empty()
+            || self.include_patterns.iter().any(matches))
+            && !self.exclude_patterns.iter().any(matches)
+    }
+
+    /// Find the templates in `indirs`, write the declarations of the
+    /// module for them to `f`, and add them to `jobs`.
+    ///
+    /// Subdirectories with the same name in several of the `indirs`
+    /// are merged into one module.
+    /// If several of the `indirs` contain templates with the same
+    /// name, the one in the last of them is used.
+    /// The `indirs` are `depth` levels of subdirectories below the
+    /// template directories.
+    fn find_templates(
+        &self,
+        f: &mut impl Write,
+        indirs: &[PathBuf],
+        depth: usize,
+        outdir: &Path,
+        target: Target,
+        jobs: &mut Vec<TemplateJob>,
+    ) -> Result<()> {
+        let mut subdirs = BTreeMap::<String, Vec<PathBuf>>::new();
+        let mut templates = BTreeMap::<String, TemplateFile>::new();
+        for indir in indirs {
+            println!("cargo:rerun-if-changed={}", indir.display());
+            for entry in read_dir(indir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    if let Some(filename) = entry.file_name().to_str() {
+                        subdirs
+                            .entry(filename.into())
+                            .or_default()
+                            .push(path);
+                    }
+                } else if let Some(filename) = entry.file_name().to_str() {
+                    if !self.is_template_path(&path, depth)
+                        || (depth == 0 && filename == GLOBALS_FILE)
+                    {
+                        continue;
+                    }
+                    for suffix in &self.suffixes {
+                        if let Some(prename) = filename.strip_suffix(suffix) {
+                            let name = template_fn_name(prename, suffix);
+                            // Backwards compatibility to 0.7.2 and earlier.
+                            let alias = if suffix == ".rs.html" {
+                                Some(prename.to_string())
+                            } else {
+                                None
+                            };
+                            let template = TemplateFile { path, alias };
+                            if let Some(old) =
+                                templates.insert(name, template)
+                            {
+                                if self.warn_overrides {
+                                    println!(
+                                        "cargo:warning=Template {} is \
+                                         overridden by {}",
+                                        old.path.display(),
+                                        entry.path().display(),
+                                    );
+                                }
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let mut reserved = Vec::new();
+        if depth == 0 {
+            // The async templates are in a module of the sync templates.
+            if self.async_runtime.is_some() {
+                reserved.push("asynchronous");
+            }
+            if !self.constants.is_empty() {
+                reserved.push("globals");
+            }
+        }
+        let collisions = name_collisions(&templates, &subdirs, &reserved);
+        if !collisions.is_empty() {
+            for problem in &collisions {
+                println!("cargo:warning={}", problem);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                collisions.join("\n"),
+            )
+            .into());
+        }
+        for (name, template) in templates {
+            println!("cargo:rerun-if-changed={}", template.path.display());
+            let cfg = template_cfg(&template.path);
+            // The items of the template are exported when it is
+            // compiled, see `write_exports`.
+            writeln!(
+                f,
+                "{cfg}mod template_{name};\n",
+                name = name,
+                cfg = cfg,
+            )?;
+            if !target.is_async() && self.async_runtime.is_some() {
+                writeln!(
+                    f,
+                    "{cfg}pub use {module}::{name} as {name}_async;\n",
+                    name = name,
+                    cfg = cfg,
+                    module = async_module(outdir, depth),
+                )?;
+            }
+            if let Some(alias) = template.alias {
+                writeln!(
+                    f,
+                    "#[deprecated(since=\"0.7.4\", \
+                     note=\"please use `{name}` instead\")]\n\
+                     {cfg}pub use self::{name} as {alias};\n",
+                    alias = alias,
+                    name = name,
+                    cfg = cfg,
+                )?;
+            }
+            let root = template.path.ancestors().nth(depth + 1);
+            jobs.push(TemplateJob {
+                name,
+                root: root.unwrap_or_else(|| Path::new("")).into(),
+                path: template.path,
+                outdir: outdir.into(),
+                target,
+                cfg,
+            });
+        }
+        for (filename, indirs) in &subdirs {
+            let outdir = outdir.join(filename);
+            let mut modrs: Box<dyn Write> = if self.dry_run {
+                Box::new(io::sink())
+            } else {
+                create_dir_all(&outdir)?;
+                Box::new(File::create(outdir.join("mod.rs"))?)
+            };
+            modrs.write_all(
+                b"#[allow(renamed_and_removed_lints)]\n\
+                  #[cfg_attr(feature=\"cargo-clippy\", \
+                  allow(useless_attribute))]\n\
+                  #[allow(unused)]\n\
+                  use super::{Html,ToHtml};\n\
+                  #[allow(unused)]\n\
+                  use super::{validators, ValidationError};\n\
+                  #[allow(unused)]\n\
+                  use super::filters;\n",
+            )?;
+            if !self.constants.is_empty() {
+                modrs
+                    .write_all(b"#[allow(unused)]\nuse super::globals;\n")?;
+            }
+            if cfg!(feature = "timing") {
+                modrs.write_all(b"#[allow(unused)]\nuse super::timing;\n")?;
+            }
+            match target {
+                Target::Sync(_) if cfg!(feature = "bytes") => {
+                    modrs.write_all(
+                        b"#[allow(unused)]\nuse super::BytesMutWriter;\n",
+                    )?;
+                }
+                Target::Sync(_) => (),
+                Target::Async(_) => {
+                    modrs.write_all(
+                        b"#[allow(unused)]\nuse super::ToHtmlAsync;\n",
+                    )?;
+                    if cfg!(feature = "stream") {
+                        modrs.write_all(
+                            b"#[allow(unused)]\nuse super::TemplateStream;\n",
+                        )?;
+                    }
+                }
+            }
+            self.find_templates(
+                &mut modrs,
+                indirs,
+                depth + 1,
+                &outdir,
+                target,
+                jobs,
+            )?;
+            writeln!(f, "pub mod {name};\n", name = filename)?;
+        }
+        Ok(())
+    }
+}
+
+/// The path from the sync templates module in `outdir`, at `depth`
+/// in the generated module, to the corresponding async module.
+fn async_module(outdir: &Path, depth: usize) -> String {
+    let mut parts = outdir
+        .components()
+        .rev()
+        .take(depth)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    parts.push("asynchronous".into());
+    parts.push(if depth == 0 { "self" } else { "super" }.into());
+    parts.extend((1..depth).map(|_| "super".into()));
+    parts.into_iter().rev().join("::")
+}
+
+/// Find names in the generated module that would collide.
+///
+/// A subdirectory may not have the name of the module or the args
+/// struct of a template, or one of the `reserved` names.
+/// Two templates may not get the same args struct, as
+/// `foo_bar.rs.html` and `fooBar.rs.html` would, and two templates or
+/// subdirectories may not have names that differ only in case, since
+/// their generated files would collide on a case-insensitive file
+/// system.
+fn name_collisions(
+    templates: &BTreeMap<String, TemplateFile>,
+    subdirs: &BTreeMap<String, Vec<PathBuf>>,
+    reserved: &[&str],
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (dir, paths) in subdirs {
+        let dir_path = paths[0].display();
+        if reserved.contains(&dir.as_str()) {
+            problems.push(format!(
+                "The directory {} has the reserved name {}",
+                dir_path, dir,
+            ));
+        }
+        for (name, template) in templates {
+            if *dir == format!("template_{}", name)
+                || *dir == args_struct_name(name)
+            {
+                problems.push(format!(
+                    "The directory {} collides with the template {} \
+                     in the generated code",
+                    dir_path,
+                    template.path.display(),
+                ));
+            }
+        }
+    }
+    let templates = templates.iter().collect::<Vec<_>>();
+    for (i, (name, template)) in templates.iter().enumerate() {
+        for (other, other_template) in &templates[i + 1..] {
+            if name.eq_ignore_ascii_case(other)
+                || args_struct_name(name) == args_struct_name(other)
+            {
+                problems.push(format!(
+                    "The templates {} and {} get colliding names in the \
+                     generated code",
+                    template.path.display(),
+                    other_template.path.display(),
+                ));
+            }
+        }
+    }
+    let subdirs = subdirs.iter().collect::<Vec<_>>();
+    for (i, (dir, paths)) in subdirs.iter().enumerate() {
+        for (other, other_paths) in &subdirs[i + 1..] {
+            if dir.eq_ignore_ascii_case(other) {
+                problems.push(format!(
+                    "The directories {} and {} collide on a \
+                     case-insensitive file system",
+                    paths[0].display(),
+                    other_paths[0].display(),
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// The `#[cfg(...)]` attribute for the template at `path`, if it has
+/// a `@cfg(...)` directive, as a line of code.
+///
+/// A template that can't be read or parsed gets no attribute, the
+/// error is reported when it is compiled.
+fn template_cfg(path: &Path) -> String {
+    read(path)
+        .ok()
+        .and_then(|buf| {
+            let (_, (t, _)) = template_head(without_bom(&buf)).ok()?;
+            t.cfg().map(|cfg| format!("#[cfg({})]\n", cfg))
+        })
+        .unwrap_or_default()
+}
+
+/// Settings for the code generated for each template.
+#[derive(Debug, Default)]
+struct CodeOptions {
+    /// Paths to `use` in each template, to get the configured
+    /// translation functions in scope.
+    uses: Vec<String>,
+    /// Templates with fewer nodes than this get an `#[inline]` function.
+    inline_threshold: usize,
+    /// Other templates get an `#[inline(never)]` function.
+    inline_never: bool,
+    /// The most nested recursive calls of a template allowed.
+    max_template_depth: usize,
+    /// Run `rustfmt` on each generated file.
+    format_generated: bool,
+    /// Write a comment around the output of each expression in debug
+    /// builds.
+    trace_expressions: bool,
+    /// Warn about common mistakes in the html of html templates.
+    html_lint: bool,
+    /// Warn about accessibility problems in the html of html templates.
+    a11y_lint: bool,
+}
+
+impl CodeOptions {
+    /// Apply these settings to the template `t`.
+    fn apply(&self, t: &mut Template) {
+        for path in &self.uses {
+            t.add_use(path);
+        }
+        if t.node_count() < self.inline_threshold {
+            t.add_fn_attribute("#[inline]");
+        } else if self.inline_never {
+            t.add_fn_attribute("#[inline(never)]");
+        }
+        t.set_max_depth(self.max_template_depth);
+    }
+
+    /// Write the code for the template `t` to `outfile`.
+    ///
+    /// The code is written to a temporary file next to `outfile`, that
+    /// is renamed to `outfile` when done, so an interrupted build
+    /// never leaves a partly written template behind.
+    fn write_template(
+        &self,
+        t: &Template,
+        outfile: &Path,
+        name: &str,
+        target: Target,
+    ) -> Result<()> {
+        let tmpfile = outfile.with_extension("rs.tmp");
+        let result = File::create(&tmpfile)
+            .and_then(|mut f| {
+                t.write_rust(&mut f, name, target)?;
+                f.sync_all()
+            })
+            .and_then(|()| {
+                if self.format_generated {
+                    format_file(&tmpfile);
+                }
+                rename(&tmpfile, outfile)
+            });
+        if result.is_err() {
+            let _ = remove_file(&tmpfile);
+        }
+        Ok(result?)
+    }
+}
+
+/// A template to compile.
+struct TemplateJob {
+    name: String,
+    path: PathBuf,
+    /// The template directory that `path` is in.
+    root: PathBuf,
+    outdir: PathBuf,
+    target: Target,
+    /// The `#[cfg(...)]` attribute of the template, if any.
+    cfg: String,
+}
+
+/// Export the public `exports` of the code of each of the `jobs` from
+/// the module of its directory.
+///
+/// The module of the templates directly in `outdir` is written to
+/// `f`, the export for a template in a subdirectory is added to the
+/// `mod.rs` of that directory.
+fn write_exports(
+    f: &mut impl Write,
+    outdir: &Path,
+    jobs: &[TemplateJob],
+    exports: &[Vec<String>],
+) -> io::Result<()> {
+    for (job, exports) in jobs.iter().zip(exports) {
+        let export = format!(
+            "{cfg}pub use self::template_{name}::{{{items}}};\n",
+            cfg = job.cfg,
+            name = job.name,
+            items = exports.join(", "),
+        );
+        if job.outdir == outdir {
+            f.write_all(export.as_bytes())?;
+        } else {
+            OpenOptions::new()
+                .append(true)
+                .open(job.outdir.join("mod.rs"))?
+                .write_all(export.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Compile all the `jobs`, even if some of them fail.
+///
+/// If any job failed, the first error is returned, unless `lenient`
+/// is true and the errors are parse errors that could be replaced by
+/// stubs.
+/// Otherwise, the number of stubs is returned, with the exports of
+/// each template.
+#[cfg(not(feature = "parallel"))]
+fn handle_templates(
+    jobs: &[TemplateJob],
+    options: &CodeOptions,
+    lenient: bool,
+) -> Result<(usize, Vec<Vec<String>>)> {
+    let compiled = jobs
+        .iter()
+        .map(|job| handle_job(job, options, lenient))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    let (ok, exports): (Vec<bool>, _) = compiled.into_iter().unzip();
+    Ok((ok.into_iter().filter(|ok| !ok).count(), exports))
+}
+
+/// Compile all the `jobs`, even if some of them fail.
+///
+/// If any job failed, the first error is returned, unless `lenient`
+/// is true and the errors are parse errors that could be replaced by
+/// stubs.
+/// Otherwise, the number of stubs is returned, with the exports of
+/// each template.
+#[cfg(feature = "parallel")]
+fn handle_templates(
+    jobs: &[TemplateJob],
+    options: &CodeOptions,
+    lenient: bool,
+) -> Result<(usize, Vec<Vec<String>>)> {
+    use rayon::prelude::*;
+    let compiled = jobs
+        .par_iter()
+        .map(|job| handle_job(job, options, lenient))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    let (ok, exports): (Vec<bool>, _) = compiled.into_iter().unzip();
+    Ok((ok.into_iter().filter(|ok| !ok).count(), exports))
+}
+
+/// Compile the template of `job`.
+///
+/// If the template fails to parse and `lenient` is true, a stub is
+/// written instead, and false is returned.
+/// The public items of the template are returned beside it.
+fn handle_job(
+    job: &TemplateJob,
+    options: &CodeOptions,
+    lenient: bool,
+) -> Result<(bool, Vec<String>)> {
+    let result = handle_template(
+        &job.name,
+        &job.path,
+        &job.root,
+        &job.outdir,
+        job.target,
+        options,
+    );
+    match result {
+        Err(RucteError::Parse {
+            ref path,
+            line,
+            column,
+            ref message,
+        }) if lenient => {
+            let message = format!(
+                "{}:{}:{}: {}",
+                path.display(),
+                line,
+                column,
+                message
+            );
+            write_stub(job, options, message)
+                .map(|exports| (false, exports))
+                .or(result.map(|exports| (false, exports)))
+        }
+        result => result.map(|exports| (true, exports)),
+    }
+}
+
+/// Write a stub for the template of `job`, that panics with `message`.
+///
+/// The stub has the arguments declared by the template, so that calls
+/// to it still compile.
+/// The public items of the stub are returned.
+fn write_stub(
+    job: &TemplateJob,
+    options: &CodeOptions,
+    message: String,
+) -> Result<Vec<String>> {
+    let buf = read(&job.path)?;
+    let mut t = match template_head(without_bom(&buf)) {
+        Ok((_, (t, _))) => t,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Failed to parse template arguments",
+            )
+            .into())
+        }
+    };
+    t.set_panic_message(message);
+    t.set_file_name(&file_name(&job.path, &job.root));
+    options.apply(&mut t);
+    println!(
+        "cargo:warning={}: Template replaced by a panicking stub",
+        job.path.display(),
+    );
+    // The stub must be replaced when the template is fixed, even if it
+    // is fixed to be exactly as before.
+    let hashfile = job.outdir.join(format!("template_{}.hash", job.name));
+    if hashfile.is_file() {
+        remove_file(hashfile)?;
+    }
+    let outfile = job.outdir.join(format!("template_{}.rs", job.name));
+    options.write_template(&t, &outfile, &job.name, job.target)?;
+    Ok(t.exports(&job.name, job.target))
+}
+
+/// Run `rustfmt` (or the formatter named by `RUSTFMT`) on `path`.
+///
+/// Failing to format is not an error, the file is just left as it is.
+fn format_file(path: &Path) {
+    let rustfmt = env::var_os("RUSTFMT").unwrap_or_else(|| "rustfmt".into());
+    let result = Command::new(&rustfmt)
+        .args(["--edition", "2018"])
+        .arg(path)
+        .status();
+    let problem = match result {
+        Ok(ref status) if status.success() => return,
+        Ok(status) => format!("failed with {}", status),
+        Err(e) => format!("could not be run: {}", e),
+    };
+    println!(
+        "cargo:warning=Not formatting {}: {} {}",
+        path.display(),
+        rustfmt.to_string_lossy(),
+        problem,
+    );
+}
+
+/// Write `template_deps.dot` in `outdir`, with an edge from each of
+/// the (non-async) templates in `jobs` to each template it calls.
+fn write_dependency_graph(outdir: &Path, jobs: &[TemplateJob]) -> Result<()> {
+    let (nodes, edges) = template_graph(outdir, jobs)?;
+    let mut dot = String::from("digraph templates {\n");
+    for node in nodes.keys() {
+        dot.push_str(&format!("  {:?};\n", node));
+    }
+    for (from, to) in edges {
+        dot.push_str(&format!("  {:?} -> {:?};\n", from, to));
+    }
+    dot.push_str("}\n");
+    write(outdir.join("template_deps.dot"), dot)?;
+    Ok(())
+}
+
+/// The (non-async) templates in `jobs`, by their path in the
+/// templates module, and the calls between them.
+type TemplateGraph<'a> = (
+    BTreeMap<String, &'a TemplateJob>,
+    BTreeSet<(String, String)>,
+);
+
+/// Find which of the templates in `jobs` call which.
+///
+/// A call is matched to a template by its path, ignoring any leading
+/// `super::` or `self::`, so `super::base_html` is matched to a
+/// `base_html` template in any module.
+fn template_graph<'a>(
+    outdir: &Path,
+    jobs: &'a [TemplateJob],
+) -> Result<TemplateGraph<'a>> {
+    let mut nodes = BTreeMap::new();
+    for job in jobs.iter().filter(|job| !job.target.is_async()) {
+        let module = job.outdir.strip_prefix(outdir).unwrap_or(&job.outdir);
+        let node = module
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .chain(Some(job.name.clone()))
+            .join("::");
+        nodes.insert(node, job);
+    }
+    let mut edges = BTreeSet::new();
+    for (node, job) in &nodes {
+        let mut t =
+            load_template(&job.path, &job.root, &mut Vec::new(), true)?;
+        t.for_each_include(|include, _scope| {
+            include.call = include_call(&job.path, &include.file);
+            Ok::<(), RucteError>(())
+        })?;
+        for call in t.called_templates() {
+            let mut call = call.as_str();
+            while let Some(rest) = call
+                .strip_prefix("super::")
+                .or_else(|| call.strip_prefix("self::"))
+            {
+                call = rest;
+            }
+            let suffix = format!("::{}", call);
+            for target in nodes.keys() {
+                if *target == call || target.ends_with(&suffix) {
+                    edges.insert((node.clone(), target.clone()));
+                }
+            }
+        }
+    }
+    Ok((nodes, edges))
+}
+
+/// Warn about each template in `jobs` that is not called by another
+/// template and not mentioned in any rust file in `src_dir`.
+fn warn_unused_templates(
+    outdir: &Path,
+    jobs: &[TemplateJob],
+    src_dir: &Path,
+) -> Result<()> {
+    println!("cargo:rerun-if-changed={}", src_dir.display());
+    let (nodes, edges) = template_graph(outdir, jobs)?;
+    let mut code = String::new();
+    read_rust_sources(src_dir, &mut code)?;
+    for (node, job) in &nodes {
+        let called =
+            edges.iter().any(|(from, to)| to == node && from != node);
+        if !called && !is_referenced(node, &code) {
+            println!(
+                "cargo:warning=Template {:?} ({}) is compiled but never \
+                 referenced in source",
+                node,
+                job.path.display(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Append the content of each `.rs` file in `dir`, recursively, to
+/// `code`.
+fn read_rust_sources(dir: &Path, code: &mut String) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_rust_sources(&path, code)?;
+        } else if path.extension().map_or(false, |ext| ext == "rs") {
+            code.push_str(&String::from_utf8_lossy(&read(&path)?));
+            code.push('\n');
+        }
+    }
+    Ok(())
+}
+
+/// True if `code` may use the template function `node`.
+///
+/// The function, its `_to_string`, `_to_bytes`, `_to_bytes_buf`,
+/// `_with_args`, `_to_json`, `_email` or `_stream` variants, or the
+/// deprecated alias of an html template, must be mentioned by name.
+/// Calls through a `use` with another name are not detected.
+fn is_referenced(node: &str, code: &str) -> bool {
+    let name = node.rsplit("::").next().unwrap_or(node);
+    [
+        "",
+        "_to_string",
+        "_to_bytes",
+        "_to_bytes_buf",
+        "_with_args",
+        "_to_json",
+        "_email",
+        "_stream",
+    ]
+    .iter()
+    .map(|suffix| format!("{}{}", name, suffix))
+    .chain(name.strip_suffix("_html").map(String::from))
+    .any(|name| contains_ident(code, &name))
+}
+
+/// A template file found when scanning the template directories.
+struct TemplateFile {
+    path: PathBuf,
+    /// A deprecated alternative name for the template function.
+    alias: Option<String>,
+}
+
+/// The name of the function generated for a template file.
+///
+/// The name is `prename` (the file name with `suffix` removed),
+/// followed by each part of the suffix except `rs`, e.g. `page.rs.html`
+/// gives `page_html`.
+fn template_fn_name(prename: &str, suffix: &str) -> String {
+    suffix
+        .split('.')
+        .filter(|part| !part.is_empty() && *part != "rs")
+        .fold(prename.to_string(), |name, part| name + "_" + part)
+}
+
+/// Compile a template to `outdir`.
+///
+/// A checksum of the template sources is saved beside the generated
+/// code, with the public items of the code and the paths of the
+/// sources, so a template that has not changed since the last time it
+/// was compiled is not even parsed again.
+/// The public items are returned.
+fn handle_template(
+    name: &str,
+    path: &Path,
+    root: &Path,
+    outdir: &Path,
+    target: Target,
+    options: &CodeOptions,
+) -> Result<Vec<String>> {
+    let outfile = outdir.join(format!("template_{}.rs", name));
+    let hashfile = outdir.join(format!("template_{}.hash", name));
+    if outfile.is_file() {
+        if let Some(exports) = up_to_date_exports(&hashfile, target, options)
+        {
+            return Ok(exports);
+        }
+    }
+    let (t, sources) = prepare_template(name, path, root, options)?;
+    options.write_template(&t, &outfile, name, target)?;
+    let exports = t.exports(name, target);
+    let mut hash =
+        source_checksum(&sources, target, options, &enabled_features())?;
+    hash.push(b'\n');
+    hash.extend_from_slice(exports.join(" ").as_bytes());
+    for path in &sources {
+        hash.push(b'\n');
+        hash.extend_from_slice(path.to_string_lossy().as_bytes());
+    }
+    write(&hashfile, &hash)?;
+    Ok(exports)
+}
+
+/// The public items listed in the `hashfile` of a template, if the
+/// checksum in it is the checksum of the sources listed after them,
+/// as they are now.
+///
+/// Cargo is told to rerun the build if any of the sources change,
+/// as it is when the template is parsed.
+fn up_to_date_exports(
+    hashfile: &Path,
+    target: Target,
+    options: &CodeOptions,
+) -> Option<Vec<String>> {
+    let hash = read_to_string(hashfile).ok()?;
+    let mut lines = hash.lines();
+    let checksum = lines.next().map(str::as_bytes);
+    let exports = lines.next()?.split(' ').map(String::from).collect_vec();
+    let paths = lines.map(PathBuf::from).collect_vec();
+    let current =
+        source_checksum(&paths, target, options, &enabled_features()).ok();
+    if paths.is_empty() || current.as_deref() != checksum {
+        return None;
+    }
+    for path in &paths[1..] {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    Some(exports)
+}
+
+/// The paths of the source files of a template, its layouts and its
+/// includes.
+type Sources = Vec<PathBuf>;
+
+/// Load and check a template, ready to write the code for it.
+///
+/// Warnings about the template are written as cargo warnings.
+/// The template is returned with its sources.
+fn prepare_template(
+    name: &str,
+    path: &Path,
+    root: &Path,
+    options: &CodeOptions,
+) -> Result<(Template, Sources)> {
+    let mut sources = Vec::new();
+    let mut t = load_template(path, root, &mut sources, true)?;
+    t.set_file_name(&file_name(path, root));
+    options.apply(&mut t);
+    for arg in t.unused_args() {
+        println!(
+            "cargo:warning={}: Argument {} is never used",
+            path.display(),
+            arg,
+        );
+    }
+    if Escaping::for_template(name) == Escaping::Html {
+        let html = Some(t.html_problems()).filter(|_| options.html_lint);
+        let a11y = Some(t.a11y_problems()).filter(|_| options.a11y_lint);
+        for problem in html.into_iter().chain(a11y).flatten() {
+            println!("cargo:warning={}: {}", path.display(), problem);
+        }
+    }
+    if options.trace_expressions
+        && Escaping::for_template(name) != Escaping::Json
+        && Escaping::for_template(name) != Escaping::Text
+    {
+        t.trace_expressions();
+    }
+    t.for_each_include(|include, _scope| {
+        include.call = include_call(path, &include.file);
+        Ok::<(), RucteError>(())
+    })?;
+    Ok((t, sources))
+}
+
+/// The name of the template file at `path`, from the template
+/// directory `root`.
+fn file_name(path: &Path, root: &Path) -> String {
+    let path = path.strip_prefix(root).unwrap_or(path);
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .join("/")
+}
+
+/// A file in the template directory with constants for all templates.
+const GLOBALS_FILE: &str = "_globals.rs.html";
+
+/// The byte order mark that some editors write at the start of utf-8
+/// files.
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The content of a template file, without any byte order mark.
+fn without_bom(buf: &[u8]) -> &[u8] {
+    buf.strip_prefix(BOM).unwrap_or(buf)
+}
+
+/// Replace each `\r\n` and each lone `\r` in `buf` by `\n`.
+fn normalize_newlines(buf: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter().peekable();
+    while let Some(&b) = bytes.next() {
+        if b == b'\r' {
+            bytes.next_if_eq(&&b'\n');
+            result.push(b'\n');
+        } else {
+            result.push(b);
+        }
+    }
+    result
+}
+
+/// Read and parse a template, and any layout it extends.
+///
+/// A byte order mark at the start of a template is ignored, with a
+/// warning, and a template that is not valid utf-8 is an error.
+/// Windows and old Mac line endings are read as `\n`.
+/// The path of the template, and of each layout, is added to
+/// `sources`, followed by the path of any email stylesheet.
+/// If `includes` is true, the templates included by the template are
+/// also read, and their paths added.
+fn load_template(
+    path: &Path,
+    root: &Path,
+    sources: &mut Sources,
+    includes: bool,
+) -> Result<Template> {
+    if sources.iter().any(|p| p == path) {
+        println!("cargo:warning={}: Template extends itself", path.display());
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: Template extends itself", path.display()),
+        )
+        .into());
+    }
+    let mut buf = read(path)?;
+    if buf.starts_with(BOM) {
+        println!(
+            "cargo:warning={}: Ignoring the byte order mark at the start \
+             of the template",
+            path.display(),
+        );
+        buf.drain(..BOM.len());
+    }
+    if buf.contains(&b'\r') {
+        buf = normalize_newlines(&buf);
+    }
+    if let Err(e) = str::from_utf8(&buf) {
+        let rest_len = buf.len() - e.valid_up_to();
+        let message = "Template is not valid UTF-8".to_string();
+        return Err(located_error(path, &buf, rest_len, message));
+    }
+    let mut t = parse_template(path, &buf)?;
+    let mut included = Vec::new();
+    if let Some(css) = t.email_css().map(|css| template_path(path, css)) {
+        println!("cargo:rerun-if-changed={}", css.display());
+        match css.canonicalize() {
+            Ok(css) => {
+                t.set_email_css(css.to_string_lossy().into());
+                included.push(css);
+            }
+            Err(e) => {
+                println!(
+                    "cargo:warning={}: Stylesheet {} not found",
+                    path.display(),
+                    css.display(),
+                );
+                return Err(e.into());
+            }
+        }
+    }
+    if includes {
+        t.for_each_include(|include, scope| {
+            resolve_include(path, root, &buf, include, scope, &mut included)
+        })?;
+    }
+    sources.push(path.into());
+    sources.append(&mut included);
+    match t.extends().map(|layout| template_path(path, layout)) {
+        Some(layout) => {
+            println!("cargo:rerun-if-changed={}", layout.display());
+            if !layout.is_file() {
+                println!(
+                    "cargo:warning={}: Layout template {} not found",
+                    path.display(),
+                    layout.display(),
+                );
+            }
+            Ok(t.extend(load_template(&layout, root, sources, includes)?))
+        }
+        None => Ok(t),
+    }
+}
+
+/// Find the included template and the arguments to pass to it.
+///
+/// Unless the arguments are given in the include, each argument of
+/// the included template is passed by name, so it must be in `scope`
+/// where it is included.
+/// A path from the template directory is relative to `root`.
+fn resolve_include(
+    path: &Path,
+    root: &Path,
+    buf: &[u8],
+    include: &mut Include,
+    scope: &[String],
+    sources: &mut Sources,
+) -> Result<()> {
+    let file = if include.from_root {
+        template_file(root.join(&include.path))
+    } else {
+        template_path(path, &include.path)
+    };
+    println!("cargo:rerun-if-changed={}", file.display());
+    if !file.is_file() {
+        return Err(located_error(
+            path,
+            buf,
+            include.rest_len,
+            format!("Included template {} not found", file.display()),
+        ));
+    }
+    let mut included = Vec::new();
+    let args = load_template(&file, root, &mut included, false)?.arg_names();
+    if let Some(ref given) = include.given_args {
+        let error =
+            |message| located_error(path, buf, include.rest_len, message);
+        if let Some((unknown, _)) =
+            given.iter().find(|(n, _)| !args.contains(n))
+        {
+            return Err(error(format!(
+                "Included template {} has no argument {:?}",
+                include.path, unknown,
+            )));
+        }
+        let mut values = Vec::new();
+        for arg in &args {
+            match given.iter().find(|(name, _)| name == arg) {
+                Some((_, value)) => values.push(value.clone()),
+                None => {
+                    return Err(error(format!(
+                        "Included template {} needs argument {:?}, \
+                         which is not given",
+                        include.path, arg,
+                    )))
+                }
+            }
+        }
+        include.file = file;
+        include.args = values;
+        sources.append(&mut included);
+        return Ok(());
+    }
+    if let Some(missing) = args.iter().find(|arg| !scope.contains(arg)) {
+        return Err(located_error(
+            path,
+            buf,
+            include.rest_len,
+            format!(
+                "Included template {} needs argument {:?}, \
+                 which is not available here",
+                include.path, missing,
+            ),
+        ));
+    }
+    include.file = file;
+    include.args = args;
+    sources.append(&mut included);
+    Ok(())
+}
+
+/// Report an error at the position `rest_len` bytes from the end of
+/// the template source `buf`.
+fn located_error(
+    path: &Path,
+    buf: &[u8],
+    rest_len: usize,
+    message: String,
+) -> RucteError {
+    let (line, column) = line_and_column(buf, buf.len() - rest_len);
+    println!(
+        "cargo:warning={}:{}:{}: {}",
+        path.display(),
+        line,
+        column,
+        message,
+    );
+    RucteError::Parse {
+        path: path.into(),
+        line,
+        column,
+        message,
+    }
+}
+
+/// The path to the function generated for the template file `to`, as
+/// seen from the code generated for the template file `from`.
+fn include_call(from: &Path, to: &Path) -> String {
+    fn normalized_dir(path: &Path) -> Vec<String> {
+        let mut dir = Vec::new();
+        if let Some(parent) = path.parent() {
+            for part in parent.components() {
+                match part {
+                    Component::CurDir => (),
+                    Component::ParentDir => {
+                        dir.pop();
+                    }
+                    part => {
+                        dir.push(part.as_os_str().to_string_lossy().into())
+                    }
+                }
+            }
+        }
+        dir
+    }
+    let (from, to_dir) = (normalized_dir(from), normalized_dir(to));
+    let common = from.iter().zip(&to_dir).take_while(|(a, b)| a == b).count();
+    let name = to
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default()
+        .split('.')
+        .filter(|part| !part.is_empty() && *part != "rs")
+        .join("_");
+    std::iter::repeat("super".to_string())
+        .take(from.len() - common + 1)
+        .chain(to_dir[common..].iter().cloned())
+        .chain(Some(name))
+        .join("::")
+}
+
+/// The path of a template referenced (by `@extends` or `@include`)
+/// from another template, relative to that template.
+///
+/// The `.rs` part of the file name may be left out, so both
+/// `"layout.html"` and `"layout.rs.html"` refers to `layout.rs.html`.
+fn template_path(template: &Path, layout: &str) -> PathBuf {
+    template_file(
+        template
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(layout),
+    )
+}
+
+/// The template file at `path`, where the `.rs` part of the file
+/// name may be left out, or the whole `.rs.html` suffix of an html
+/// template.
+fn template_file(path: PathBuf) -> PathBuf {
+    if path.is_file() {
+        return path;
+    }
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path.with_file_name(format!(
+            "{}.rs.{}",
+            stem.to_string_lossy(),
+            ext.to_string_lossy(),
+        )),
+        (Some(stem), None) => {
+            path.with_file_name(format!("{}.rs.html", stem.to_string_lossy()))
+        }
+        _ => path,
+    }
+}
+
+fn parse_template(path: &Path, buf: &[u8]) -> Result<Template> {
+    parse_file(path, buf, template).map(|(_, t)| t)
+}
+
+/// Parse `buf`, the content of the file at `path`, with `parser`.
+///
+/// On failure, the error is written as cargo warnings.
+fn parse_file<'a, T, P>(
+    path: &Path,
+    buf: &'a [u8],
+    parser: P,
+) -> Result<(&'a [u8], T)>
+where
+    P: Fn(&'a [u8]) -> PResult<'a, T>,
+{
+    match parser(buf) {
+        Ok(result) => Ok(result),
+        Err(error) => {
+            let (line, column, message) = error_location(buf, &error);
+            // Write the message in one go, so it is not mixed up with
+            // messages about other templates compiled in parallel.
+            let mut msg = format!(
+                "cargo:warning={}:{}:{}: {}\n",
+                path.display(),
+                line,
+                column,
+                message,
+            )
+            .into_bytes();
+            show_errors(&mut msg, buf, &error, "cargo:warning=");
+            io::stdout().write_all(&msg)?;
+            Err(RucteError::Parse {
+                path: path.into(),
+                line,
+                column,
+                message,
+            })
+        }
+    }
+}
+
+/// A checksum of the source files of a template, to tell if the
+/// generated code is up to date.
+///
+/// The ructe version, the target of the generated code and the
+/// enabled `features` of ructe are included, since the generated
+/// code may differ between them.
+fn source_checksum(
+    paths: &[PathBuf],
+    target: Target,
+    options: &CodeOptions,
+    features: &[&str],
+) -> io::Result<Vec<u8>> {
+    let mut context = md5::Context::new();
+    context.consume(env!("CARGO_PKG_VERSION"));
+    context.consume(format!("{:?}", target));
+    context.consume(format!("{:?}", options));
+    context.consume(format!("{:?}", features));
+    for path in paths {
+        context.consume(read(path)?);
+    }
+    Ok(format!("{:x}", context.compute()).into_bytes())
+}
+
+/// The cargo features of ructe that are enabled in this build.
+fn enabled_features() -> Vec<&'static str> {
+    [
+        ("actix", cfg!(feature = "actix")),
+        ("async-std", cfg!(feature = "async-std")),
+        ("async-tokio", cfg!(feature = "async-tokio")),
+        ("axum", cfg!(feature = "axum")),
+        ("bytes", cfg!(feature = "bytes")),
+        ("email", cfg!(feature = "email")),
+        ("insta", cfg!(feature = "insta")),
+        ("mime02", cfg!(feature = "mime02")),
+        ("mime03", cfg!(feature = "mime03")),
+        ("num-format", cfg!(feature = "num-format")),
+        ("rocket", cfg!(feature = "rocket")),
+        ("sass", cfg!(feature = "sass")),
+        ("serde", cfg!(feature = "serde")),
+        ("simd-escaping", cfg!(feature = "simd-escaping")),
+        ("stream", cfg!(feature = "stream")),
+        ("timing", cfg!(feature = "timing")),
+        ("warp", cfg!(feature = "warp")),
+        ("warp02", cfg!(feature = "warp02")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| *name)
+    .collect()
+}
+
+/// The module containing your generated template code will also
+/// contain everything from here.
+///
+/// The name `ructe::templates` should never be used.  Instead, you
+/// should use the module templates created when compiling your
+/// templates.
+/// If you include the generated `templates.rs` in your `main.rs` (or
+/// `lib.rs` in a library crate), this module will be
+/// `crate::templates`.
+pub mod templates {
+    #[cfg(feature = "mime03")]
+    use mime::Mime;
+    use std::fmt::Display;
+    use std::io::{self, Write};
+
+    #[cfg(feature = "mime02")]
+    /// Documentation mock.  The real Mime type comes from the `mime` crate.
+    pub type Mime = u8; // mock
+
+    /// A static file has a name (so its url can be recognized) and the
+    /// actual file contents.
+    ///
+    /// The content-type (mime type) of the file is available as a
+    /// static field when building ructe with the `mime03` feature or
+    /// as the return value of a method when building ructe with the
+    /// `mime02` feature (in `mime` version 0.2.x, a Mime cannot be
+    /// defined as a part of a const static value.
+    pub struct StaticFile {
+        /// The actual static file contents.
+        pub content: &'static [u8],
+        /// The file name as used in a url, including a short (48 bits
+        /// as 8 base64 characters) hash of the content, to enable
+        /// long-time caching of static resourses in the clients.
+        pub name: &'static str,
+        /// The Mime type of this static file, as defined in the mime
+        /// crate version 0.3.x.
+        #[cfg(feature = "mime03")]
+        pub mime: &'static Mime,
+    }
+
+    impl StaticFile {
+        /// Get the mime type of this static file.
+        ///
+        /// Currently, this method parses a (static) string every time.
+        /// A future release of `mime` may support statically created
+        /// `Mime` structs, which will make this nicer.
+        #[allow(unused)]
+        #[cfg(feature = "mime02")]
+        pub fn mime(&self) -> Mime {
+            unimplemented!()
+        }
+    }
+
+    include!("template_utils.rs");
+    #[cfg(feature = "simd-escaping")]
+    include!("template_utils_memchr.rs");
+    #[cfg(not(feature = "simd-escaping"))]
+    include!("template_utils_scan.rs");
+    include!("template_utils_locale.rs");
+    #[cfg(feature = "insta")]
+    include!("template_utils_testing.rs");
+    #[cfg(feature = "timing")]
+    include!("template_utils_timing.rs");
 
     #[test]
     fn encoded() {
@@ -457,6 +3118,45 @@ pub mod templates {
         assert_eq!(b"xxxxx&gt;&amp;", &buf[..]);
     }
 
+    #[test]
+    fn locale_numbers() {
+        assert_eq!(
+            LocaleNumber::new(1234567.5, "en").to_string(),
+            "1,234,567.5"
+        );
+        assert_eq!(
+            LocaleNumber::new(-1234.25, "de-DE").to_string(),
+            "-1.234,25"
+        );
+        assert_eq!(LocaleNumber::new(999, "fr").to_string(), "999");
+        assert_eq!(
+            LocaleNumber::new(12345, "sv_SE").to_string(),
+            "12\u{a0}345"
+        );
+        assert_eq!(
+            LocaleNumber::new("1000.00", "de-CH").to_string(),
+            "1’000.00"
+        );
+        assert_eq!(LocaleNumber::new(f64::NAN, "en").to_string(), "NaN");
+    }
+
+    #[test]
+    fn locale_dates() {
+        let date = (2024, 1, 5);
+        assert_eq!(LocaleDate::new(date, "en-US").to_string(), "Jan 5, 2024");
+        assert_eq!(LocaleDate::new(date, "en-GB").to_string(), "5 Jan 2024");
+        assert_eq!(LocaleDate::new(date, "de").to_string(), "5. Januar 2024");
+        assert_eq!(
+            LocaleDate::new(date, "es").to_string(),
+            "5 de enero de 2024"
+        );
+        assert_eq!(
+            LocaleDate::new(date, "fr-CA").to_string(),
+            "5 janvier 2024"
+        );
+        assert_eq!(LocaleDate::new(date, "ja").to_string(), "2024-01-05");
+    }
+
     #[test]
     fn encoded_empty() {
         let mut buf = Vec::new();
@@ -485,12 +3185,240 @@ pub mod templates {
         assert_eq!(b"&#39;&#39;&#39;&#39;&#39;&#39;&#39;&#39;&#39;&#39;&#39;&#39;&#39;&#39;" as &[u8], &buf[..]);
     }
 
+    #[test]
+    fn encoded_long_text() {
+        let text = format!("{0}<{0}>{0}\"{0}", "abcdefgh".repeat(40));
+        let mut buf = Vec::new();
+        text.to_html(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            text.replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;"),
+        );
+    }
+
     #[test]
     fn raw_html() {
         let mut buf = Vec::new();
         Html("a<b>c</b>").to_html(&mut buf).unwrap();
         assert_eq!(b"a<b>c</b>", &buf[..]);
     }
+
+    #[cfg(feature = "insta")]
+    #[test]
+    fn render_template() {
+        fn hello(out: &mut dyn Write, who: &str) -> io::Result<()> {
+            write!(out, "Hello ")?;
+            who.to_html(out)
+        }
+        assert_eq!(render_template!(hello, "<World>"), "Hello &lt;World&gt;");
+        assert_eq!(render_template_to_bytes!(hello, "you"), b"Hello you");
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn render_timing() {
+        fn hello(out: &mut dyn Write) -> io::Result<()> {
+            let _timer = timing::Timer::start("hello_html");
+            write!(out, "Hello")
+        }
+        hello(&mut Vec::new()).unwrap();
+        hello(&mut Vec::new()).unwrap();
+        let timings = timing::drain_timings();
+        assert_eq!(
+            timings.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            ["hello_html", "hello_html"],
+        );
+        assert!(timing::drain_timings().is_empty());
+    }
+
+    #[test]
+    fn url_encoded() {
+        assert_eq!(url_encode("a-b_c.d~e"), "a-b_c.d~e");
+        assert_eq!(url_encode("a b&c=d/ö"), "a%20b%26c%3Dd%2F%C3%B6");
+
+        let mut buf = Vec::new();
+        UrlEncoded("<a & b>").to_html(&mut buf).unwrap();
+        assert_eq!(b"%3Ca%20%26%20b%3E", &buf[..]);
+    }
+
+    #[test]
+    fn standard_filters() {
+        use self::filters::*;
+        assert_eq!(upper("Ructe"), "RUCTE");
+        assert_eq!(lower(trim(" Ructe ")), "ructe");
+        assert_eq!(truncate("Ructe templates", 5), "Ructe…");
+        assert_eq!(truncate("Ructe", 5), "Ructe");
+        assert_eq!(replace("a-b-c", "-", ", "), "a, b, c");
+        assert_eq!(default("", "none"), "none");
+        assert_eq!(default(7, "none"), "7");
+    }
+
+    #[test]
+    fn standard_validators() {
+        use self::validators::*;
+        assert_eq!(not_empty(&"x"), Ok(()));
+        assert_eq!(
+            not_empty(&Vec::<u8>::new()),
+            Err("must not be empty".into())
+        );
+        assert_eq!(positive(&1.5), Ok(()));
+        assert_eq!(positive(&0), Err("must be positive".into()));
+        assert_eq!(in_range(1, 10)(&10), Ok(()));
+        assert_eq!(
+            in_range(1, 10)(&0),
+            Err("must be between 1 and 10".into())
+        );
+    }
+
+    #[test]
+    fn pooled_buffer() {
+        let first = with_pooled_buffer(|buf| {
+            buf.extend_from_slice(b"<p>");
+            let inner = with_pooled_buffer(|inner| {
+                "a&b".to_html(inner).unwrap();
+                inner.clone()
+            });
+            buf.extend_from_slice(&inner);
+            (buf.clone(), buf.as_ptr())
+        });
+        assert_eq!(first.0, b"<p>a&amp;b");
+        with_pooled_buffer(|buf| {
+            assert!(buf.is_empty());
+            assert_eq!(buf.as_ptr(), first.1);
+        });
+    }
+
+    #[test]
+    fn csrf() {
+        let mut buf = Vec::new();
+        csrf_field(&mut buf, "a\"b").unwrap();
+        csrf_meta(&mut buf, "a&b").unwrap();
+        assert_eq!(
+            "<input type=\"hidden\" name=\"csrf\" value=\"a&quot;b\">\
+             <meta name=\"csrf-token\" content=\"a&amp;b\">",
+            String::from_utf8(buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn form_fields() {
+        let mut buf = Vec::new();
+        text_field(&mut buf, "name", "Name", "A & B", None).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "<div class=\"field\"><label for=\"field-name\">Name</label>\
+             <input type=\"text\" id=\"field-name\" name=\"name\" \
+             value=\"A &amp; B\"></div>",
+        );
+
+        let mut buf = Vec::new();
+        select_field(
+            &mut buf,
+            "size",
+            "Size",
+            &[("s", "Small"), ("l", "Large")],
+            "l",
+            Some("Sold <out>"),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "<div class=\"field has-error\">\
+             <label for=\"field-size\">Size</label>\
+             <select id=\"field-size\" name=\"size\" aria-invalid=\"true\" \
+             aria-describedby=\"field-size-error\">\
+             <option value=\"s\">Small</option>\
+             <option value=\"l\" selected>Large</option></select>\
+             <p class=\"field-error\" id=\"field-size-error\">\
+             Sold &lt;out&gt;</p></div>",
+        );
+
+        let mut buf = Vec::new();
+        textarea_field(&mut buf, "bio", "Bio", "<hi>", None).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "<div class=\"field\"><label for=\"field-bio\">Bio</label>\
+             <textarea id=\"field-bio\" name=\"bio\">&lt;hi&gt;</textarea>\
+             </div>",
+        );
+    }
+
+    #[test]
+    fn limited_output() {
+        let mut buf = Vec::new();
+        {
+            let mut out = LimitedWriter::new(&mut buf, 8);
+            out.write_all(b"<p>").unwrap();
+            "Hello".to_html(&mut out).unwrap();
+            assert_eq!(out.remaining(), 0);
+            let err = out.write_all(b"</p>").unwrap_err();
+            assert_eq!(err.to_string(), "template output too large");
+        }
+        assert_eq!(buf, b"<p>Hello");
+
+        let mut out = LimitedWriter::new(Vec::new(), 4);
+        assert!(out.write_all(b"<p>Hi</p>").is_err());
+        assert_eq!(out.into_inner(), b"<p>H");
+    }
+
+    #[test]
+    fn js_string() {
+        let mut buf = Vec::new();
+        JsString("it's \"</script>\" \\ `x`\n\u{2028}")
+            .to_html(&mut buf)
+            .unwrap();
+        assert_eq!(
+            r#"it\'s \"<\/script>\" \\ \`x\`\n\u2028"#.as_bytes(),
+            &buf[..]
+        );
+    }
+
+    #[test]
+    fn json_encoded() {
+        let mut buf = Vec::new();
+        "say \"hi\"\\\n<b>".to_json(&mut buf).unwrap();
+        assert_eq!(br#"say \"hi\"\\\u000a<b>"#, &buf[..]);
+
+        let mut buf = Vec::new();
+        Html("{\"a\": 1}").to_json(&mut buf).unwrap();
+        assert_eq!(br#"{"a": 1}"#, &buf[..]);
+
+        let mut buf = Vec::new();
+        JsonString("a\"<b").to_html(&mut buf).unwrap();
+        JsonString("a\"<b").to_json(&mut buf).unwrap();
+        assert_eq!(br#"a\&quot;&lt;ba\"<b"#, &buf[..]);
+
+        let mut buf = String::new();
+        "a\"b\t".fmt_json(&mut buf).unwrap();
+        assert_eq!(buf, r#"a\"b\u0009"#);
+    }
+
+    #[test]
+    fn xml_encoded() {
+        let mut buf = Vec::new();
+        "a < 'b' & \"c\"".to_xml(&mut buf).unwrap();
+        assert_eq!(b"a &lt; &apos;b&apos; &amp; &quot;c&quot;", &buf[..]);
+
+        let mut buf = String::new();
+        "'b'".fmt_xml(&mut buf).unwrap();
+        assert_eq!(buf, "&apos;b&apos;");
+    }
+
+    #[test]
+    fn fmt_encoded() {
+        let mut buf = String::new();
+        "a < b & 'c' > \"d\"".fmt_html(&mut buf).unwrap();
+        assert_eq!(buf, "a &lt; b &amp; &#39;c&#39; &gt; &quot;d&quot;");
+    }
+
+    #[test]
+    fn fmt_raw_html() {
+        let mut buf = String::new();
+        Html("a<b>c</b>").fmt_html(&mut buf).unwrap();
+        assert_eq!(buf, "a<b>c</b>");
+    }
 }
 
 fn get_env(name: &str) -> Result<String> {
@@ -504,6 +3432,17 @@ pub enum RucteError {
     Io(io::Error),
     /// Error resolving a given environment variable.
     Env(String, env::VarError),
+    /// Error parsing a template or a `.ructe.toml` file.
+    Parse {
+        /// The file that failed to parse.
+        path: PathBuf,
+        /// The line of the error, starting at 1.
+        line: usize,
+        /// The column of the error, starting at 1.
+        column: usize,
+        /// A description of what went wrong.
+        message: String,
+    },
     /// Error bundling a sass stylesheet as css.
     #[cfg(feature = "sass")]
     Sass(rsass::Error),
@@ -516,6 +3455,19 @@ impl Display for RucteError {
         match self {
             RucteError::Io(err) => err.fmt(out),
             RucteError::Env(var, err) => write!(out, "{:?}: {}", var, err),
+            RucteError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                out,
+                "{}:{}:{}: {}",
+                path.display(),
+                line,
+                column,
+                message,
+            ),
             #[cfg(feature = "sass")]
             RucteError::Sass(err) => err.fmt(out),
         }
@@ -539,3 +3491,807 @@ impl From<rsass::Error> for RucteError {
 ///
 /// [`RucteError`]: enum.RucteError.html
 pub type Result<T> = std::result::Result<T, RucteError>;
+
+#[cfg(test)]
+mod test {
+    use super::{
+        enabled_features, handle_template, include_call, normalize_newlines,
+        source_checksum, up_to_date_exports, CodeOptions, OutputTrait, Ructe,
+        RucteError, Target,
+    };
+    use std::fs::{
+        create_dir_all, read_to_string, remove_dir, remove_file, write,
+    };
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("target")
+            .join("test-tmp")
+            .join(name);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn byte_order_mark_and_bad_utf8() {
+        let dir = test_dir("encoding");
+        let src = dir.join("page.rs.html");
+        let compile = || {
+            handle_template(
+                "page_html",
+                &src,
+                &dir,
+                &dir,
+                Target::default(),
+                &CodeOptions::default(),
+            )
+        };
+        write(&src, b"\xEF\xBB\xBF@()\n<p>Hello</p>\n").unwrap();
+        compile().unwrap();
+        let code = read_to_string(dir.join("template_page_html.rs")).unwrap();
+        assert!(code.contains("<p>Hello</p>"));
+        assert!(!code.contains('\u{feff}'));
+
+        write(&src, b"@()\n<p>Caf\xE9</p>\n").unwrap();
+        match compile() {
+            Err(RucteError::Parse {
+                line,
+                column,
+                message,
+                ..
+            }) => {
+                assert_eq!(
+                    (line, column, &message[..]),
+                    (2, 7, "Template is not valid UTF-8")
+                );
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        assert_eq!(normalize_newlines(b"a\r\nb\rc\n\r\n"), b"a\nb\nc\n\n");
+        let dir = test_dir("crlf");
+        let src = dir.join("page.rs.html");
+        write(&src, "@(n: u8)\r\n@if n > 1 {\r\n<p>@n</p>\r\n}\r\n").unwrap();
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default(),
+        )
+        .unwrap();
+        let code = read_to_string(dir.join("template_page_html.rs")).unwrap();
+        assert!(code.contains("<p>"));
+        assert!(!code.contains("\\r"));
+    }
+
+    #[test]
+    fn template_is_replaced_atomically() {
+        let dir = test_dir("atomic-write");
+        let (src, out) =
+            (dir.join("page.rs.html"), dir.join("template_page_html.rs"));
+        let tmp = dir.join("template_page_html.rs.tmp");
+        write(&src, "@()\n<p>New</p>\n").unwrap();
+        write(&out, "// old").unwrap();
+        let _ = remove_file(dir.join("template_page_html.hash"));
+        create_dir_all(&tmp).unwrap();
+        let compile = || {
+            handle_template(
+                "page_html",
+                &src,
+                &dir,
+                &dir,
+                Target::default(),
+                &CodeOptions::default(),
+            )
+        };
+        assert!(compile().is_err());
+        assert_eq!(read_to_string(&out).unwrap(), "// old");
+
+        remove_dir(&tmp).unwrap();
+        compile().unwrap();
+        assert!(read_to_string(&out).unwrap().contains("New"));
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn unchanged_template_is_not_regenerated() {
+        let dir = test_dir("unchanged-template");
+        let (src, out) =
+            (dir.join("page.rs.html"), dir.join("template_page_html.rs"));
+        write(&src, "@()\n<p>One</p>\n").unwrap();
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default(),
+        )
+        .unwrap();
+        assert!(read_to_string(&out).unwrap().contains("One"));
+
+        write(&out, "// not regenerated").unwrap();
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read_to_string(&out).unwrap(), "// not regenerated");
+
+        write(&src, "@()\n<p>Two</p>\n").unwrap();
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default(),
+        )
+        .unwrap();
+        assert!(read_to_string(&out).unwrap().contains("Two"));
+    }
+
+    #[test]
+    fn fmt_output_trait() {
+        let dir = test_dir("fmt-output");
+        let (src, out) =
+            (dir.join("page.rs.html"), dir.join("template_page_html.rs"));
+        write(&src, "@(x: u8)\n<p>@x</p>\n").unwrap();
+        let target = Target::Sync(OutputTrait::Fmt);
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            target,
+            &CodeOptions::default(),
+        )
+        .unwrap();
+        let code = read_to_string(&out).unwrap();
+        assert!(code.starts_with("use std::fmt::{self, Write};\n"));
+        assert!(code.contains(") -> fmt::Result where W: ?Sized"));
+        assert!(code.contains("x.fmt_html(&mut _ructe_out_)?;"));
+    }
+
+    #[test]
+    fn explicit_exports() {
+        use super::TemplateConfig;
+        let dir = test_dir("explicit-exports");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(indir.join("sub")).unwrap();
+        write(indir.join("page.rs.html"), "@(x: u8)\n<p>@x</p>\n").unwrap();
+        write(indir.join("sub").join("part.rs.html"), "@()\nx\n").unwrap();
+        let mut f = Vec::new();
+        TemplateConfig::default()
+            .handle_entries(&mut f, &[indir], &outdir)
+            .unwrap();
+        let f = String::from_utf8(f).unwrap();
+        assert!(f.contains(
+            "pub use self::template_page_html::{page_html, \
+             PAGE_HTML_STATIC_SIZE, PAGE_HTML_CONTENT_TYPE, \
+             PAGE_HTML_HTTP_STATUS, PAGE_HTML_CACHE_CONTROL, \
+             page_html_to_string, page_html_to_bytes, PageHtmlArgs, \
+             page_html_with_args"
+        ));
+        let modrs =
+            read_to_string(outdir.join("sub").join("mod.rs")).unwrap();
+        assert!(modrs.contains(
+            "pub use self::template_part_html::{part_html, \
+                       PART_HTML_STATIC, PART_HTML_STATIC_SIZE,"
+        ));
+        assert!(!f.contains("::*;") && !modrs.contains("::*;"));
+    }
+
+    #[cfg(feature = "async-tokio")]
+    #[test]
+    fn async_tokio_templates() {
+        use super::{AsyncRuntime, TemplateConfig};
+        let dir = test_dir("async-tokio");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(indir.join("sub")).unwrap();
+        write(indir.join("page.rs.html"), "@(x: u8)\n<p>@x</p>\n").unwrap();
+        write(indir.join("sub").join("part.rs.html"), "@()\nx\n").unwrap();
+        let config = TemplateConfig {
+            async_runtime: Some(AsyncRuntime::Tokio),
+            ..TemplateConfig::default()
+        };
+        let mut f = Vec::new();
+        config.handle_entries(&mut f, &[indir], &outdir).unwrap();
+        let f = String::from_utf8(f).unwrap();
+        assert!(f.contains("pub mod asynchronous;"));
+        assert!(f.contains(
+            "pub use self::asynchronous::page_html as page_html_async;"
+        ));
+        assert!(read_to_string(outdir.join("sub").join("mod.rs"))
+            .unwrap()
+            .contains(
+                "pub use super::asynchronous::sub::part_html \
+                 as part_html_async;"
+            ));
+        let asynchronous = outdir.join("asynchronous");
+        assert!(read_to_string(asynchronous.join("mod.rs"))
+            .unwrap()
+            .starts_with("#[allow(unused)]\nuse super::*;\n"));
+        let code = read_to_string(asynchronous.join("template_page_html.rs"))
+            .unwrap();
+        assert!(code.contains(
+            "pub async fn page_html<W>(_ructe_out_: &mut W, x: u8) \
+             -> std::io::Result<()> \
+             where W: tokio::io::AsyncWrite + Unpin + ?Sized {"
+        ));
+        assert!(code.contains("x.to_html_async(&mut *_ructe_out_).await?;"));
+        assert!(asynchronous
+            .join("sub")
+            .join("template_part_html.rs")
+            .is_file());
+        assert!(!read_to_string(outdir.join("template_page_html.rs"))
+            .unwrap()
+            .contains("async"));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_fn_in_subdirectory() {
+        use super::TemplateConfig;
+        let dir = test_dir("bytes-subdir");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(indir.join("sub")).unwrap();
+        write(indir.join("sub").join("part.rs.html"), "@()\nx\n").unwrap();
+        TemplateConfig::default()
+            .handle_entries(&mut Vec::new(), &[indir], &outdir)
+            .unwrap();
+        let sub = outdir.join("sub");
+        assert!(read_to_string(sub.join("mod.rs"))
+            .unwrap()
+            .contains("\nuse super::BytesMutWriter;\n"));
+        assert!(read_to_string(sub.join("template_part_html.rs"))
+            .unwrap()
+            .contains("let mut buf: super::BytesMutWriter ="));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn stream_templates() {
+        use super::{AsyncRuntime, TemplateConfig};
+        let dir = test_dir("stream");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(indir.join("sub")).unwrap();
+        write(
+            indir.join("page.rs.html"),
+            "@<T: ToHtml>(title: &str, items: &[T], n: impl ToHtml)\n\
+             <h1>@title</h1>@for i in items {<p>@i @n</p>}\n",
+        )
+        .unwrap();
+        write(indir.join("wrap.rs.html"), "@(body: Content)\n@:body()\n")
+            .unwrap();
+        write(indir.join("sub").join("part.rs.html"), "@()\nx\n").unwrap();
+        let config = TemplateConfig {
+            async_runtime: Some(AsyncRuntime::Tokio),
+            ..TemplateConfig::default()
+        };
+        config
+            .handle_entries(&mut Vec::new(), &[indir], &outdir)
+            .unwrap();
+        let asynchronous = outdir.join("asynchronous");
+        let code = read_to_string(asynchronous.join("template_page_html.rs"))
+            .unwrap();
+        assert!(code.contains(
+            "pub fn page_html_stream<'_ructe, T: ToHtml>(\
+             title: &'_ructe str, items: &'_ructe [T], \
+             n: impl ToHtml + '_ructe) -> super::TemplateStream<\
+             impl std::future::Future<Output = std::io::Result<()>> \
+             + '_ructe> where T: '_ructe {\n\
+             super::TemplateStream::new(move |mut _ructe_out_| async move {\n\
+             page_html(&mut _ructe_out_, title, items, n).await\n"
+        ));
+        assert!(!read_to_string(asynchronous.join("template_wrap_html.rs"))
+            .unwrap()
+            .contains("wrap_html_stream"));
+        let sub = asynchronous.join("sub");
+        assert!(read_to_string(sub.join("mod.rs"))
+            .unwrap()
+            .contains("\nuse super::TemplateStream;\n"));
+        assert!(read_to_string(sub.join("template_part_html.rs"))
+            .unwrap()
+            .contains("-> super::TemplateStream<"));
+    }
+
+    #[test]
+    fn dependency_graph() {
+        use super::TemplateConfig;
+        let dir = test_dir("dependency-graph");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(indir.join("sub")).unwrap();
+        write(
+            indir.join("page.rs.html"),
+            "@use super::sub::nav_html;\n@()\n@:nav_html()@include \"footer.rs.html\"\n",
+        )
+        .unwrap();
+        write(indir.join("footer.rs.html"), "@()\n<footer/>\n").unwrap();
+        write(indir.join("sub").join("nav.rs.html"), "@()\n<nav/>\n")
+            .unwrap();
+        let config = TemplateConfig {
+            dependency_graph: true,
+            ..TemplateConfig::default()
+        };
+        config
+            .handle_entries(&mut Vec::new(), &[indir], &outdir)
+            .unwrap();
+        assert_eq!(
+            read_to_string(outdir.join("template_deps.dot")).unwrap(),
+            "digraph templates {\n  \
+             \"footer_html\";\n  \
+             \"page_html\";\n  \
+             \"sub::nav_html\";\n  \
+             \"page_html\" -> \"footer_html\";\n  \
+             \"page_html\" -> \"sub::nav_html\";\n\
+             }\n",
+        );
+    }
+
+    #[test]
+    fn referenced_templates() {
+        use super::is_referenced;
+        let code = "use templates::{self, page_html};\n\
+                    templates::sub::nav_html_to_string()?;\n\
+                    templates::hello(&mut out)?;\n\
+                    // list_html_old(out)\n";
+        assert!(is_referenced("page_html", code));
+        assert!(is_referenced("sub::nav_html", code));
+        assert!(is_referenced("hello_html", code));
+        assert!(!is_referenced("nav_html", "nav_htmlx"));
+        assert!(!is_referenced("list_html", code));
+    }
+
+    #[test]
+    fn gettext_functions() {
+        use super::TemplateConfig;
+        let dir = test_dir("gettext-functions");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(&indir).unwrap();
+        create_dir_all(&outdir).unwrap();
+        write(
+            indir.join("page.rs.html"),
+            "@()\n<p>@t(\"Hello\") @icu_msg(\"{n} hi\", n: 2)</p>\n",
+        )
+        .unwrap();
+        let config = TemplateConfig {
+            gettext_function: Some("crate::i18n::gettext".into()),
+            icu_function: Some("crate::i18n::format_icu".into()),
+            ..TemplateConfig::default()
+        };
+        config
+            .handle_entries(&mut Vec::new(), &[indir], &outdir)
+            .unwrap();
+        let code =
+            read_to_string(outdir.join("template_page_html.rs")).unwrap();
+        assert!(code.contains(
+            "#[allow(unused_imports)]\n\
+             use crate::i18n::gettext as gettext;\n"
+        ));
+        assert!(
+            code.contains("gettext(\"Hello\").to_html(&mut _ructe_out_)?;")
+        );
+        assert!(code.contains(
+            "#[allow(unused_imports)]\n\
+             use crate::i18n::format_icu as icu_msg;\n"
+        ));
+    }
+
+    #[test]
+    fn workspace_template_dirs() {
+        let root = test_dir("workspace");
+        for dir in &["templates", "web/templates", "crates/a/templates"] {
+            create_dir_all(root.join(dir)).unwrap();
+        }
+        create_dir_all(root.join("crates/old/templates")).unwrap();
+        create_dir_all(root.join("crates/b")).unwrap();
+        for dir in &["crates/a", "crates/b", "crates/old"] {
+            write(root.join(dir).join("Cargo.toml"), "[package]\n").unwrap();
+        }
+        write(root.join("templates/page.rs.html"), "@()\nroot\n").unwrap();
+        write(root.join("web/templates/page.rs.html"), "@()\nweb\n").unwrap();
+        write(root.join("crates/a/templates/a.rs.html"), "@()\na\n").unwrap();
+        write(
+            root.join("Cargo.toml"),
+            "[package]\nname = \"site\"\n\n[workspace]\n\
+             members = [\"web\", \"crates/*\"]\nexclude = [\"crates/old\"]\n",
+        )
+        .unwrap();
+        let out = root.join("out");
+        create_dir_all(&out).unwrap();
+        let mut ructe = Ructe::new(out.clone()).unwrap();
+        let dirs = &ructe
+            .add_workspace_template_dirs(&root)
+            .unwrap()
+            .template_dirs;
+        assert_eq!(
+            dirs,
+            &[
+                root.join("templates"),
+                root.join("web/templates"),
+                root.join("crates/a/templates"),
+            ],
+        );
+        ructe.compile_template_dirs().unwrap();
+        let page =
+            read_to_string(out.join("templates/template_page_html.rs"));
+        assert!(page.unwrap().contains("web"));
+        assert!(out.join("templates/template_a_html.rs").is_file());
+    }
+
+    #[test]
+    fn format_generated_code() {
+        let dir = test_dir("format-generated");
+        let src = dir.join("page.rs.html");
+        write(&src, "@(x: u8)\n<p>@x</p>\n").unwrap();
+        let options = CodeOptions {
+            format_generated: true,
+            ..CodeOptions::default()
+        };
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &options,
+        )
+        .unwrap();
+        let code = read_to_string(dir.join("template_page_html.rs")).unwrap();
+        if Command::new("rustfmt").arg("--version").output().is_ok() {
+            assert!(code.contains("\n    Ok(())\n}\n"), "{}", code);
+        } else {
+            assert!(code.contains("\nOk(())\n}\n"), "{}", code);
+        }
+    }
+
+    #[test]
+    fn traced_expressions() {
+        let dir = test_dir("trace-expressions");
+        let options = CodeOptions {
+            trace_expressions: true,
+            ..CodeOptions::default()
+        };
+        let compile = |name: &str, file: &str, source: &str| {
+            let src = dir.join(file);
+            write(&src, source).unwrap();
+            handle_template(
+                name,
+                &src,
+                &dir,
+                &dir,
+                Target::default(),
+                &options,
+            )
+            .unwrap();
+            read_to_string(dir.join(format!("template_{}.rs", name))).unwrap()
+        };
+        let code =
+            compile("page_html", "page.rs.html", "@(x: u8)\n<p>@x</p>\n");
+        assert!(
+            code.contains(
+                "if cfg!(debug_assertions) {\n\
+             _ructe_out_.write_all(b\"<!-- begin: x -->\")?;\n\
+             }\n\
+             x.to_html(&mut _ructe_out_)?;\n\
+             if cfg!(debug_assertions) {\n\
+             _ructe_out_.write_all(b\"<!-- end: x -->\")?;\n\
+             }\n"
+            ),
+            "{}",
+            code
+        );
+        let code = compile("data_json", "data.rs.json", "@(x: u8)\n[@x]\n");
+        assert!(!code.contains("debug_assertions"), "{}", code);
+    }
+
+    #[test]
+    fn inline_small_templates() {
+        let dir = test_dir("inline-small");
+        let src = dir.join("page.rs.html");
+        let out = dir.join("template_page_html.rs");
+        write(&src, "@(x: u8)\n<p>@x</p>\n").unwrap();
+        let mut options = CodeOptions {
+            inline_threshold: 20,
+            ..CodeOptions::default()
+        };
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &options,
+        )
+        .unwrap();
+        assert!(read_to_string(&out)
+            .unwrap()
+            .contains("\n#[inline]\npub fn page_html<W>("));
+
+        options.inline_threshold = 3;
+        options.inline_never = true;
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &options,
+        )
+        .unwrap();
+        assert!(read_to_string(&out)
+            .unwrap()
+            .contains("\n#[inline(never)]\npub fn page_html<W>("));
+    }
+
+    #[test]
+    fn colliding_names() {
+        use super::TemplateConfig;
+        let dir = test_dir("colliding-names");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(indir.join("template_page_html")).unwrap();
+        create_dir_all(&outdir).unwrap();
+        write(indir.join("page.rs.html"), "@()\n<p>Page</p>\n").unwrap();
+        write(indir.join("foo_bar.rs.html"), "@(x: u8)\n@x\n").unwrap();
+        write(indir.join("fooBar.rs.html"), "@(x: u8)\n@x\n").unwrap();
+        let indirs = [indir.clone()];
+        let err = TemplateConfig::default()
+            .handle_entries(&mut Vec::new(), &indirs, &outdir)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "The directory {} collides with the template {} in the \
+                 generated code\n\
+                 The templates {} and {} get colliding names in the \
+                 generated code",
+                indir.join("template_page_html").display(),
+                indir.join("page.rs.html").display(),
+                indir.join("fooBar.rs.html").display(),
+                indir.join("foo_bar.rs.html").display(),
+            ),
+        );
+    }
+
+    #[test]
+    fn all_templates_compiled_despite_errors() {
+        use super::TemplateConfig;
+        let dir = test_dir("despite-errors");
+        let (indir, outdir) = (dir.join("in"), dir.join("out"));
+        create_dir_all(&indir).unwrap();
+        create_dir_all(&outdir).unwrap();
+        write(indir.join("a.rs.html"), "@(x: u8)\n@if x {\n").unwrap();
+        write(indir.join("b.rs.html"), "@()\n<p>ok</p>\n").unwrap();
+        let indirs = [indir];
+        let config = TemplateConfig::default();
+        assert!(config
+            .handle_entries(&mut Vec::new(), &indirs, &outdir)
+            .is_err());
+        assert!(outdir.join("template_b_html.rs").is_file());
+
+        let config = TemplateConfig {
+            lenient: true,
+            ..TemplateConfig::default()
+        };
+        let stats = config
+            .handle_entries(&mut Vec::new(), &indirs, &outdir)
+            .unwrap();
+        assert_eq!((stats.compiled, stats.failed), (1, 1));
+        let stub = read_to_string(outdir.join("template_a_html.rs")).unwrap();
+        assert!(stub.contains(
+            "#[allow(unreachable_code)]\n\
+             pub fn a_html<W>(mut _ructe_out_: &mut W, x: u8)"
+        ));
+        assert!(stub.contains("let _ = &x;\npanic!(\"{}\", \""));
+        assert!(!outdir.join("template_a_html.hash").exists());
+    }
+
+    #[test]
+    fn changed_layout_regenerates_template() {
+        let dir = test_dir("changed-layout");
+        let (layout, src, out) = (
+            dir.join("layout.rs.html"),
+            dir.join("page.rs.html"),
+            dir.join("template_page_html.rs"),
+        );
+        write(&layout, "@()\n<h1>One</h1>@yield content\n").unwrap();
+        write(&src, "@extends \"layout.html\"\n@()\n@block content {x}\n")
+            .unwrap();
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default(),
+        )
+        .unwrap();
+        assert!(read_to_string(&out).unwrap().contains("One"));
+
+        write(&layout, "@()\n<h1>Two</h1>@yield content\n").unwrap();
+        handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default(),
+        )
+        .unwrap();
+        assert!(read_to_string(&out).unwrap().contains("Two"));
+    }
+
+    #[test]
+    fn up_to_date_without_parsing() {
+        let dir = test_dir("up-to-date");
+        let (layout, src) =
+            (dir.join("layout.rs.html"), dir.join("page.rs.html"));
+        let hashfile = dir.join("template_page_html.hash");
+        write(&layout, "@()\n<h1>One</h1>@yield content\n").unwrap();
+        write(&src, "@extends \"layout.html\"\n@()\n@block content {x}\n")
+            .unwrap();
+        let (target, options) = (Target::default(), CodeOptions::default());
+        let exports =
+            handle_template("page_html", &src, &dir, &dir, target, &options)
+                .unwrap();
+        assert_eq!(exports[..2], ["page_html", "PAGE_HTML_STATIC"]);
+        let hash = read_to_string(&hashfile).unwrap();
+        assert_eq!(
+            hash.lines().skip(2).collect::<Vec<_>>(),
+            [src.to_str().unwrap(), layout.to_str().unwrap()],
+        );
+        let up_to_date =
+            |target| up_to_date_exports(&hashfile, target, &options);
+        assert_eq!(up_to_date(target), Some(exports));
+        assert_eq!(up_to_date(Target::Sync(OutputTrait::Fmt)), None);
+
+        write(&layout, "@()\n<h1>Two</h1>@yield content\n").unwrap();
+        assert_eq!(up_to_date(target), None);
+        remove_file(&layout).unwrap();
+        assert_eq!(up_to_date(target), None);
+    }
+
+    #[test]
+    fn checksum_depends_on_features() {
+        let dir = test_dir("checksum-features");
+        let src = vec![dir.join("page.rs.html")];
+        write(&src[0], "@()\n<p>x</p>\n").unwrap();
+        let (target, options) = (Target::default(), CodeOptions::default());
+        let checksum = |features: &[&str]| {
+            source_checksum(&src, target, &options, features).unwrap()
+        };
+        assert_eq!(checksum(&[]), checksum(&[]));
+        assert_ne!(checksum(&[]), checksum(&["serde"]));
+        assert_ne!(checksum(&["serde"]), checksum(&["bytes"]));
+        assert_eq!(
+            enabled_features().contains(&"serde"),
+            cfg!(feature = "serde"),
+        );
+    }
+
+    #[test]
+    fn include_call_paths() {
+        let call = |from: &str, to: &str| {
+            include_call(Path::new(from), Path::new(to))
+        };
+        assert_eq!(
+            call("t/page.rs.html", "t/nav.rs.html"),
+            "super::nav_html"
+        );
+        assert_eq!(
+            call("t/page.rs.html", "t/./parts/nav.html"),
+            "super::parts::nav_html"
+        );
+        assert_eq!(
+            call("t/a/page.rs.html", "t/a/../b/nav.rs.svg"),
+            "super::super::b::nav_svg"
+        );
+    }
+
+    #[test]
+    fn include_needs_arguments_in_scope() {
+        let dir = test_dir("include-scope");
+        let (partial, src) =
+            (dir.join("partial.rs.html"), dir.join("page.rs.html"));
+        write(&partial, "@(user: &str)\n<p>@user</p>\n").unwrap();
+        write(
+            &src,
+            "@(name: &str)\n<h1>Page</h1>\n@include \"partial.html\"\n",
+        )
+        .unwrap();
+        match handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default(),
+        ) {
+            Err(RucteError::Parse {
+                line,
+                column,
+                message,
+                ..
+            }) => {
+                assert_eq!((line, column), (3, 10));
+                assert_eq!(
+                    message,
+                    "Included template partial.html needs argument \"user\", \
+                     which is not available here",
+                );
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn include_from_template_dir() {
+        let dir = test_dir("include-root");
+        create_dir_all(dir.join("admin")).unwrap();
+        create_dir_all(dir.join("blog")).unwrap();
+        let (partial, src) = (
+            dir.join("admin").join("dashboard.rs.html"),
+            dir.join("blog").join("post.rs.html"),
+        );
+        write(&partial, "@(user: &str, n: u8)\n<p>@user @n</p>\n").unwrap();
+        let handle = || {
+            handle_template(
+                "post_html",
+                &src,
+                &dir,
+                &dir,
+                Target::default(),
+                &CodeOptions::default(),
+            )
+        };
+        write(
+            &src,
+            "@(name: &str)\n@include admin/dashboard { user: name, n: 2 }\n",
+        )
+        .unwrap();
+        handle().unwrap();
+        assert!(read_to_string(dir.join("template_post_html.rs"))
+            .unwrap()
+            .contains("super::super::admin::dashboard_html(&mut _ructe_out_, name, 2)?;"));
+
+        write(&src, "@(user: &str)\n@include admin/dashboard { user }\n")
+            .unwrap();
+        match handle() {
+            Err(RucteError::Parse { message, .. }) => assert_eq!(
+                message,
+                "Included template admin/dashboard needs argument \"n\", \
+                 which is not given",
+            ),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn template_extending_itself_is_an_error() {
+        let dir = test_dir("extends-itself");
+        let src = dir.join("page.rs.html");
+        write(&src, "@extends \"page.rs.html\"\n@()\n").unwrap();
+        assert!(handle_template(
+            "page_html",
+            &src,
+            &dir,
+            &dir,
+            Target::default(),
+            &CodeOptions::default()
+        )
+        .is_err());
+    }
+}
@@ -1,11 +1,36 @@
 use nom::error::{VerboseError, VerboseErrorKind};
-use nom::{Err, IResult};
+use nom::{Err, IResult, Needed};
 use std::io::Write;
 use std::str::from_utf8;
 
 /// Parser result, with verbose error.
 pub type PResult<'a, O> = IResult<&'a [u8], O, VerboseError<&'a [u8]>>;
 
+/// The context of an error where a parser needed more input.
+const INCOMPLETE: &str = "Template parsing stopped";
+
+/// The length of the text shown after an incomplete parse.
+const SNIPPET_LEN: usize = 30;
+
+/// Make `parser` fail where it started, if it needs more input.
+///
+/// The parsers for templates should never need more input, but if
+/// one does, this gives an error with a position, rather than an
+/// `Err::Incomplete` without one.
+pub fn stop_incomplete<'a, O, F>(
+    parser: F,
+) -> impl Fn(&'a [u8]) -> PResult<'a, O>
+where
+    F: Fn(&'a [u8]) -> PResult<'a, O>,
+{
+    move |input| match parser(input) {
+        Err(Err::Incomplete(_)) => Err(Err::Failure(VerboseError {
+            errors: vec![(input, VerboseErrorKind::Context(INCOMPLETE))],
+        })),
+        result => result,
+    }
+}
+
 pub fn show_errors(
     out: &mut impl Write,
     buf: &[u8],
@@ -16,21 +41,37 @@ pub fn show_errors(
         Err::Failure(VerboseError { ref errors })
         | Err::Error(VerboseError { ref errors }) => {
             for (rest, err) in errors.iter().rev() {
-                if let Some(message) = get_message(&err) {
+                if let Some(message) = get_message(buf, rest, err) {
                     let pos = buf.len() - rest.len();
                     show_error(out, buf, pos, &message, prefix);
                 }
             }
         }
         Err::Incomplete(needed) => {
-            let msg = format!("Incomplete: {:?}", needed);
-            show_error(out, buf, 0, &msg, prefix);
+            show_error(out, buf, 0, &incomplete_message(*needed), prefix);
         }
     }
 }
 
-fn get_message(err: &VerboseErrorKind) -> Option<String> {
+fn get_message(
+    buf: &[u8],
+    rest: &[u8],
+    err: &VerboseErrorKind,
+) -> Option<String> {
     match err {
+        VerboseErrorKind::Context(INCOMPLETE) => {
+            let near = rest
+                .split(|c| *c == b'\n')
+                .next()
+                .map(|line| &line[..line.len().min(SNIPPET_LEN)])
+                .unwrap_or(b"");
+            Some(format!(
+                "{} at byte offset {}, near: {:?}",
+                INCOMPLETE,
+                buf.len() - rest.len(),
+                String::from_utf8_lossy(near),
+            ))
+        }
         VerboseErrorKind::Context(msg) => Some((*msg).into()),
         VerboseErrorKind::Char(ch) => Some(format!("Expected {:?}", ch)),
         VerboseErrorKind::Nom(_err) => None,
@@ -44,18 +85,14 @@ fn show_error(
     msg: &str,
     prefix: &str,
 ) {
-    let mut line_start = buf[0..pos].rsplitn(2, |c| *c == b'\n');
-    let _ = line_start.next();
-    let line_start =
-        line_start.next().map(|bytes| bytes.len() + 1).unwrap_or(0);
+    let line_start = line_start(buf, pos);
     let line = buf[line_start..]
         .splitn(2, |c| *c == b'\n')
         .next()
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
         .and_then(|s| from_utf8(s).ok())
         .unwrap_or("(Failed to display line)");
-    let line_no = bytecount::count(&buf[..line_start], b'\n') + 1;
-    let pos_in_line =
-        from_utf8(&buf[line_start..pos]).unwrap().chars().count() + 1;
+    let (line_no, pos_in_line) = line_and_column(buf, pos);
     writeln!(
         out,
         "{prefix}{:>4}:{}\n\
@@ -69,3 +106,148 @@ fn show_error(
     )
     .unwrap();
 }
+
+/// Get the line and column of the innermost error that has a message.
+///
+/// Both the line and column numbers starts at 1.
+/// The column is counted in characters, not bytes.
+pub fn error_location(
+    buf: &[u8],
+    error: &Err<VerboseError<&[u8]>>,
+) -> (usize, usize, String) {
+    match error {
+        Err::Failure(VerboseError { ref errors })
+        | Err::Error(VerboseError { ref errors }) => errors
+            .iter()
+            .filter_map(|(rest, err)| {
+                get_message(buf, rest, err).map(|message| {
+                    let (line, column) =
+                        line_and_column(buf, buf.len() - rest.len());
+                    (line, column, message)
+                })
+            })
+            .next()
+            .unwrap_or_else(|| (1, 1, "Failed to parse template".into())),
+        Err::Incomplete(needed) => (1, 1, incomplete_message(*needed)),
+    }
+}
+
+/// The message for an incomplete parse without a known position.
+fn incomplete_message(needed: Needed) -> String {
+    match needed {
+        Needed::Size(n) => {
+            format!("{} before the end, {} more bytes needed", INCOMPLETE, n)
+        }
+        Needed::Unknown => format!("{} before the end", INCOMPLETE),
+    }
+}
+
+/// Get the line and column of byte position `pos` in `buf`.
+pub fn line_and_column(buf: &[u8], pos: usize) -> (usize, usize) {
+    let line_start = line_start(buf, pos);
+    let line_no = bytecount::count(&buf[..line_start], b'\n') + 1;
+    let column = String::from_utf8_lossy(&buf[line_start..pos])
+        .chars()
+        .count()
+        + 1;
+    (line_no, column)
+}
+
+/// Get the byte position of the start of the line containing `pos`.
+fn line_start(buf: &[u8], pos: usize) -> usize {
+    buf[0..pos]
+        .iter()
+        .rposition(|c| *c == b'\n')
+        .map(|nl| nl + 1)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{error_location, line_and_column, show_errors};
+    use super::{stop_incomplete, PResult};
+    use nom::error::{VerboseError, VerboseErrorKind};
+    use nom::{Err, Needed};
+
+    #[test]
+    fn position_first_line() {
+        assert_eq!(line_and_column(b"abc\ndef", 2), (1, 3));
+    }
+    #[test]
+    fn position_later_line() {
+        assert_eq!(line_and_column(b"abc\ndef\nghi", 9), (3, 2));
+    }
+    #[test]
+    fn position_crlf() {
+        assert_eq!(line_and_column(b"abc\r\ndef\r\nghi", 11), (3, 2));
+    }
+    #[test]
+    fn position_utf8() {
+        assert_eq!(line_and_column("δε\nδεζ".as_bytes(), 9), (2, 3));
+    }
+
+    #[test]
+    fn location_of_innermost_message() {
+        let buf = b"@()\r\n<p>@if { oops }</p>\r\n";
+        let error = Err::Error(VerboseError {
+            errors: vec![
+                (
+                    &buf[14..],
+                    VerboseErrorKind::Context("Expected expression"),
+                ),
+                (&buf[10..], VerboseErrorKind::Context("Error in if")),
+            ],
+        });
+        assert_eq!(
+            error_location(buf, &error),
+            (2, 10, "Expected expression".to_string()),
+        );
+    }
+
+    #[test]
+    fn show_crlf_line() {
+        let buf = b"@()\r\n<p>@if { oops }</p>\r\n";
+        let error = Err::Error(VerboseError {
+            errors: vec![(
+                &buf[14..],
+                VerboseErrorKind::Context("Expected expression"),
+            )],
+        });
+        let mut out = Vec::new();
+        show_errors(&mut out, buf, &error, ":");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ":   2:<p>@if { oops }</p>\n\
+             :              ^ Expected expression\n",
+        );
+    }
+
+    #[test]
+    fn location_of_incomplete() {
+        fn needs_more(_input: &[u8]) -> PResult<'_, ()> {
+            Err(Err::Incomplete(Needed::Size(4)))
+        }
+        let buf = b"@()\n<p>@unfinished(x\n</p>\n";
+        let error = stop_incomplete(needs_more)(&buf[7..]).unwrap_err();
+        assert_eq!(
+            error_location(buf, &error),
+            (
+                2,
+                4,
+                "Template parsing stopped at byte offset 7, \
+                 near: \"@unfinished(x\""
+                    .to_string(),
+            ),
+        );
+        assert_eq!(
+            error_location(buf, &needs_more(buf).unwrap_err()),
+            (
+                1,
+                1,
+                "Template parsing stopped before the end, \
+                 4 more bytes needed"
+                    .to_string(),
+            ),
+        );
+    }
+}
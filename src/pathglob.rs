@@ -0,0 +1,59 @@
+/// True if the relative `path` (with `/` between the parts) matches
+/// the glob `pattern`.
+///
+/// In the pattern, `**` as a whole part matches any number of
+/// directories (including none), `*` matches any characters except
+/// `/`, and `?` matches one such character.
+/// Other characters match themselves.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.split('/').collect::<Vec<_>>();
+    let path = path.split('/').collect::<Vec<_>>();
+    match_parts(&pattern, &path)
+}
+
+fn match_parts(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => {
+            (0..=path.len()).any(|skip| match_parts(rest, &path[skip..]))
+        }
+        Some((part, rest)) => match path.split_first() {
+            Some((name, path)) => {
+                let part = part.chars().collect::<Vec<_>>();
+                let name = name.chars().collect::<Vec<_>>();
+                match_name(&part, &name) && match_parts(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_name(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => {
+            (0..=name.len()).any(|skip| match_name(rest, &name[skip..]))
+        }
+        Some((c, rest)) => match name.split_first() {
+            Some((n, name)) => {
+                (*c == '?' || c == n) && match_name(rest, name)
+            }
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("**/_*.html", "_nav.rs.html"));
+        assert!(matches("**/_*.html", "page/parts/_nav.rs.html"));
+        assert!(!matches("**/_*.html", "page/nav_.rs.html"));
+        assert!(matches("page/*.rs.?ml", "page/nav.rs.xml"));
+        assert!(!matches("page/*.rs.html", "page/parts/nav.rs.html"));
+        assert!(matches("drafts/**", "drafts/a/b.rs.html"));
+    }
+}
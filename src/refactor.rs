@@ -0,0 +1,378 @@
+//! Changes of templates that also need changes of the code using
+//! them.
+//!
+//! The `ructe-refactor` command, built with the `refactor` feature,
+//! runs these.
+use std::fs::{read_dir, read_to_string, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use template::{args_struct_name, template, Template};
+use {template_fn_name, Result, RucteError, TemplateConfig};
+
+/// The changes made by a refactoring.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// The files that were changed.
+    pub changed: Vec<PathBuf>,
+    /// Lines that may need to be changed by hand, as
+    /// `file:line: text`.
+    pub to_check: Vec<String>,
+}
+
+/// Rename the argument `old` of the template at `path` to `new`.
+///
+/// This is what the `ructe-refactor rename-arg` command does.
+/// In the template, each use of `old` as a name in the code is
+/// renamed, but not the same word in the static text.
+/// Since templates are called with positional arguments, calls of
+/// the template need no change, but the named argument setters
+/// called on `{Name}Args::new()` in the rust files in `src` are
+/// renamed.
+/// Other lines of those files that mention the args struct and
+/// `.old` can't be handled safely, so they are returned to be checked
+/// by hand.
+pub fn rename_argument(
+    path: &Path,
+    old: &str,
+    new: &str,
+    src: &Path,
+) -> Result<Changes> {
+    if !is_ident(new) {
+        return Err(fail(format!("{:?} is not a valid name", new)));
+    }
+    let name = fn_name(path).ok_or_else(|| {
+        fail(format!("{} is not a template", path.display()))
+    })?;
+    let source = read_to_string(path)?;
+    let t = parse(&source)
+        .ok_or_else(|| fail(format!("Failed to parse {}", path.display())))?;
+    let names = t.arg_names();
+    if !names.iter().any(|name| name == old) {
+        return Err(fail(format!("{} is not an argument", old)));
+    }
+    if names.iter().any(|name| name == new) {
+        return Err(fail(format!("{} is already an argument", new)));
+    }
+    let mut changes = Changes::default();
+    let static_html = t.static_html();
+    let renamed = rename_at(
+        &source,
+        &ident_positions(&source, old)
+            .into_iter()
+            .filter(|pos| {
+                let renamed = rename_at(&source, &[*pos], old, new);
+                parse(&renamed).map(|t| t.static_html()).as_ref()
+                    == Some(&static_html)
+            })
+            .collect::<Vec<_>>(),
+        old,
+        new,
+    );
+    if renamed != source {
+        write(path, &renamed)?;
+        changes.changed.push(path.into());
+    }
+    let mut sources = Vec::new();
+    find_rust_files(src, &mut sources)?;
+    for file in sources {
+        rename_setters(
+            &file,
+            &args_struct_name(&name),
+            old,
+            new,
+            &mut changes,
+        )?;
+    }
+    Ok(changes)
+}
+
+/// Rename the setter `old` in each chain of calls on `args::new()` in
+/// the rust `file`.
+fn rename_setters(
+    file: &Path,
+    args: &str,
+    old: &str,
+    new: &str,
+    changes: &mut Changes,
+) -> Result<()> {
+    let source = read_to_string(file)?;
+    if !source.contains(args) {
+        return Ok(());
+    }
+    let start = format!("{}::new()", args);
+    let mut setters = Vec::new();
+    for (pos, _) in source.match_indices(&start) {
+        setters.extend(chained_calls(&source, pos + start.len(), old));
+    }
+    let renamed = rename_at(&source, &setters, old, new);
+    for (i, line) in renamed.lines().enumerate() {
+        let mentions_args = line.replace(&start, "").contains(args);
+        let mentions_old = word_positions(line, old)
+            .into_iter()
+            .any(|pos| line[..pos].ends_with('.'));
+        if mentions_args || mentions_old {
+            changes.to_check.push(format!(
+                "{}:{}: {}",
+                file.display(),
+                i + 1,
+                line.trim(),
+            ));
+        }
+    }
+    if renamed != source {
+        write(file, &renamed)?;
+        changes.changed.push(file.into());
+    }
+    Ok(())
+}
+
+/// The positions of the calls of the method `name` in a chain of
+/// method calls starting at `pos` in `source`.
+fn chained_calls(source: &str, mut pos: usize, name: &str) -> Vec<usize> {
+    let mut result = Vec::new();
+    loop {
+        let rest = &source[pos..];
+        let method = rest.trim_start();
+        let method = match method.strip_prefix('.') {
+            Some(method) => method.trim_start(),
+            None => return result,
+        };
+        let start = source.len() - method.len();
+        let len = method
+            .find(|c: char| !is_ident_char(c))
+            .unwrap_or(method.len());
+        let args = method[len..].trim_start();
+        if len == 0 || !args.starts_with('(') {
+            return result;
+        }
+        if &method[..len] == name {
+            result.push(start);
+        }
+        match closing_paren(args) {
+            Some(end) => pos = source.len() - args.len() + end + 1,
+            None => return result,
+        }
+    }
+}
+
+/// The position of the parenthesis that closes the one that `code`
+/// starts with, skipping string literals.
+fn closing_paren(code: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        match (in_string, c) {
+            (true, _) if escaped => escaped = false,
+            (true, '\\') => escaped = true,
+            (true, '"') | (false, '"') => in_string = !in_string,
+            (false, '(') => depth += 1,
+            (false, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// The positions of `name` as a whole word in `text`, except where
+/// it is a field or method after a `.`, a part of a path, or a field
+/// in a struct literal.
+fn ident_positions(text: &str, name: &str) -> Vec<usize> {
+    word_positions(text, name)
+        .into_iter()
+        .filter(|&pos| {
+            let before = text[..pos].trim_end();
+            let after = text[pos + name.len()..].trim_start();
+            !before.ends_with('.')
+                && !before.ends_with("::")
+                && !after.starts_with("::")
+                && !is_struct_field(before, after)
+        })
+        .collect()
+}
+
+/// True if a name between `before` and `after` is a field name in a
+/// struct literal, as in `Foo { name: value }`.
+///
+/// A formal argument, as in `@(name: &str)`, is in parentheses.
+fn is_struct_field(before: &str, after: &str) -> bool {
+    after.starts_with(':')
+        && (before.ends_with('{') || before.ends_with(','))
+        && open_bracket(before) == Some('{')
+}
+
+/// The innermost bracket that is opened but not closed in `code`.
+fn open_bracket(code: &str) -> Option<char> {
+    let mut depth = 0;
+    for c in code.chars().rev() {
+        match c {
+            ')' | ']' | '}' => depth += 1,
+            '(' | '[' | '{' if depth == 0 => return Some(c),
+            '(' | '[' | '{' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// The positions of `name` as a whole word in `text`.
+fn word_positions(text: &str, name: &str) -> Vec<usize> {
+    text.match_indices(name)
+        .map(|(pos, _)| pos)
+        .filter(|&pos| {
+            let before = text[..pos].chars().next_back();
+            let after = text[pos + name.len()..].chars().next();
+            !before.map_or(false, is_ident_char)
+                && !after.map_or(false, is_ident_char)
+        })
+        .collect()
+}
+
+/// Replace `old` at each of the `positions` in `text` with `new`.
+fn rename_at(
+    text: &str,
+    positions: &[usize],
+    old: &str,
+    new: &str,
+) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut done = 0;
+    for &pos in positions {
+        result.push_str(&text[done..pos]);
+        result.push_str(new);
+        done = pos + old.len();
+    }
+    result.push_str(&text[done..]);
+    result
+}
+
+fn parse(source: &str) -> Option<Template> {
+    template(source.as_bytes()).ok().map(|(_, t)| t)
+}
+
+/// The name of the function for the template at `path`.
+fn fn_name(path: &Path) -> Option<String> {
+    let filename = path.file_name()?.to_str()?;
+    TemplateConfig::default()
+        .suffixes
+        .iter()
+        .find_map(|suffix| {
+            let prename = filename.strip_suffix(suffix.as_str())?;
+            Some(template_fn_name(prename, suffix))
+        })
+}
+
+/// Find the rust files in `dir`, recursively.
+fn find_rust_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if entry.file_name() != "target" {
+                find_rust_files(&path, files)?;
+            }
+        } else if path.extension().map_or(false, |e| e == "rs") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_ident(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(is_ident_char)
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn fail(message: String) -> RucteError {
+    io::Error::new(io::ErrorKind::Other, message).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        chained_calls, ident_positions, rename_argument, rename_at, Changes,
+    };
+    use std::fs::{create_dir_all, read_to_string, write};
+    use std::path::PathBuf;
+
+    #[test]
+    fn chained_setters() {
+        let code =
+            "A::new().title(\"a)\").n(f(2))\n    .title(x); y.title(z)";
+        assert_eq!(chained_calls(code, 8, "title"), [9, 34]);
+    }
+
+    #[test]
+    fn paths_and_struct_fields_are_not_renamed() {
+        let code = "@use crate::old;\n@(a: u8, old: u8)\n\
+                    @helpers::old(old) @old::X @Foo { a: 1, old: old }\n";
+        assert_eq!(
+            rename_at(code, &ident_positions(code, "old"), "old", "new"),
+            "@use crate::old;\n@(a: u8, new: u8)\n\
+             @helpers::old(new) @old::X @Foo { a: 1, old: new }\n",
+        );
+    }
+
+    #[test]
+    fn rename_in_template_and_setters() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("target")
+            .join("test-tmp")
+            .join("rename-argument");
+        create_dir_all(dir.join("src")).unwrap();
+        let template = dir.join("page.rs.html");
+        write(
+            &template,
+            "@(title: &str, n: u8)\n\
+             <title>@title</title>\n\
+             @for i in 0..n {<p title=\"title\">@i: @title.len()</p>}\n",
+        )
+        .unwrap();
+        let main = dir.join("src").join("main.rs");
+        write(
+            &main,
+            "fn main() {\n\
+             \x20   let args = PageHtmlArgs::new()\n\
+             \x20       .title(\"Hello\")\n\
+             \x20       .n(2);\n\
+             \x20   let more: PageHtmlArgs = args;\n\
+             \x20   page_html(&mut out, \"title\", 2).unwrap();\n\
+             }\n",
+        )
+        .unwrap();
+
+        let changes =
+            rename_argument(&template, "title", "heading", &dir.join("src"))
+                .unwrap();
+        assert_eq!(
+            read_to_string(&template).unwrap(),
+            "@(heading: &str, n: u8)\n\
+             <title>@heading</title>\n\
+             @for i in 0..n {<p title=\"title\">@i: @heading.len()</p>}\n",
+        );
+        assert!(read_to_string(&main)
+            .unwrap()
+            .contains("PageHtmlArgs::new()\n        .heading(\"Hello\")\n"));
+        assert_eq!(
+            changes,
+            Changes {
+                changed: vec![template.clone(), main.clone()],
+                to_check: vec![format!(
+                    "{}:5: let more: PageHtmlArgs = args;",
+                    main.display(),
+                )],
+            },
+        );
+        assert!(rename_argument(&template, "title", "n", &dir).is_err());
+    }
+}
@@ -0,0 +1,373 @@
+use md5;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{create_dir_all, read, read_dir, write};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use template::template_head;
+use {template_fn_name, Result, TemplateConfig};
+
+/// A template to render as a page of a static site.
+#[derive(Debug, PartialEq)]
+struct Page {
+    /// The path of the template file, from the template directory.
+    file: String,
+    /// The path of the template function, from the templates module.
+    function: String,
+    /// The path of the output file, from the output directory.
+    output: String,
+    /// The arguments of the template, as `name: Type`.
+    args: Vec<String>,
+}
+
+/// Render the templates in `templates` as a static site in `out`.
+///
+/// This is what the `ructe-gen` command does.
+/// A small crate is written to a directory in the system temp
+/// directory, with a build script that compiles the templates and a
+/// main function that writes the output of each template to a file
+/// in `out`, named as the template without the `.rs` part.
+/// The crate is then built and run with cargo.
+///
+/// Templates without arguments are always rendered.
+/// The arguments for other templates are read from the json file
+/// `data`, which has an object for each template, by its path, with
+/// a value for each argument, as in
+/// `{"page.rs.html": {"title": "Hello", "n": 3}}`.
+/// A template without data is not rendered.
+/// The data is read with `serde_json`, so the argument types must be
+/// deserializable, from the standard library, and without lifetimes
+/// (except in references, where `&str` is read as a `String`,
+/// `&[T]` as a `Vec<T>` and `&T` as a `T`).
+///
+/// The crate depends on the published version of ructe that is the
+/// same as this one, or on the ructe source directory `ructe`, if
+/// given.
+pub fn generate_site(
+    templates: &Path,
+    out: &Path,
+    data: Option<&Path>,
+    ructe: Option<&Path>,
+) -> Result<()> {
+    let templates = templates.canonicalize()?;
+    create_dir_all(out)?;
+    let out = out.canonicalize()?;
+    let data = match data {
+        Some(data) => Some(data.canonicalize()?),
+        None => None,
+    };
+    let ructe = match ructe {
+        Some(ructe) => Some(ructe.canonicalize()?),
+        None => None,
+    };
+    let mut pages = Vec::new();
+    find_pages(&templates, "", &TemplateConfig::default(), &mut pages)?;
+    let work = env::temp_dir().join(format!(
+        "ructe-gen-{:x}",
+        md5::compute(templates.to_string_lossy().as_bytes()),
+    ));
+    create_dir_all(work.join("src"))?;
+    write_if_changed(
+        &work.join("Cargo.toml"),
+        &cargo_toml(data.is_some(), ructe.as_deref()),
+    )?;
+    write_if_changed(&work.join("build.rs"), &build_rs(&templates))?;
+    write_if_changed(
+        &work.join("src").join("main.rs"),
+        &main_rs(&pages, &out, data.as_deref()),
+    )?;
+    let status = Command::new(
+        env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")),
+    )
+    .args(["run", "--quiet", "--release", "--manifest-path"])
+    .arg(work.join("Cargo.toml"))
+    .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Rendering the templates failed ({})", status,),
+        )
+        .into())
+    }
+}
+
+/// Find the templates in `dir` that can be rendered, recursively.
+///
+/// The `prefix` is the path of `dir` from the template directory.
+fn find_pages(
+    dir: &Path,
+    prefix: &str,
+    config: &TemplateConfig,
+    pages: &mut Vec<Page>,
+) -> Result<()> {
+    let mut entries = read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let filename = match entry.file_name().into_string() {
+            Ok(filename) => filename,
+            Err(_) => continue,
+        };
+        if entry.file_type()?.is_dir() {
+            let prefix = format!("{}{}/", prefix, filename);
+            find_pages(&entry.path(), &prefix, config, pages)?;
+            continue;
+        }
+        for suffix in &config.suffixes {
+            if let Some(prename) = filename.strip_suffix(suffix.as_str()) {
+                let buf = read(entry.path())?;
+                if let Ok((_, (t, _))) = template_head(&buf) {
+                    if t.has_plain_args() {
+                        pages.push(Page {
+                            file: format!("{}{}", prefix, filename),
+                            function: format!(
+                                "{}{}",
+                                prefix.replace('/', "::"),
+                                template_fn_name(prename, suffix),
+                            ),
+                            output: format!(
+                                "{}{}{}",
+                                prefix,
+                                prename,
+                                &suffix[".rs".len()..],
+                            ),
+                            args: t.args().to_vec(),
+                        });
+                    }
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cargo_toml(with_data: bool, ructe: Option<&Path>) -> String {
+    let ructe = match ructe {
+        Some(path) => format!("{{ path = {:?} }}", path),
+        None => format!("\"={}\"", env!("CARGO_PKG_VERSION")),
+    };
+    format!(
+        "[package]\n\
+         name = \"ructe-gen-site\"\n\
+         version = \"0.0.0\"\n\
+         edition = \"2018\"\n\
+         publish = false\n\
+         build = \"build.rs\"\n\
+         \n\
+         [build-dependencies]\n\
+         ructe = {}\n\
+         \n\
+         [dependencies]\n\
+         {}\
+         \n\
+         [workspace]\n",
+        ructe,
+        if with_data {
+            "serde_json = \"1.0\"\n"
+        } else {
+            ""
+        },
+    )
+}
+
+fn build_rs(templates: &Path) -> String {
+    format!(
+        "use ructe::{{Ructe, RucteError}};\n\
+         \n\
+         fn main() -> Result<(), RucteError> {{\n\
+         Ructe::from_env()?.compile_templates({:?})\n\
+         }}\n",
+        templates,
+    )
+}
+
+/// The main function of the crate that renders the `pages` to `out`.
+fn main_rs(pages: &[Page], out: &Path, data: Option<&Path>) -> String {
+    let mut code = format!(
+        "#![allow(dead_code)]\n\
+         include!(concat!(env!(\"OUT_DIR\"), \"/templates.rs\"));\n\
+         \n\
+         use std::fs::{{create_dir_all, write}};\n\
+         use std::path::Path;\n\
+         \n\
+         fn main() -> Result<(), Box<dyn std::error::Error>> {{\n\
+         let out = Path::new({:?});\n",
+        out,
+    );
+    if let Some(data) = data {
+        code.push_str(&format!(
+            "let data: serde_json::Value = \
+             serde_json::from_slice(&std::fs::read({:?})?)?;\n",
+            data,
+        ));
+    }
+    for page in pages {
+        let call = format!(
+            "write_page(out, {:?}, &templates::{}_to_bytes({})?)?;\n",
+            page.output,
+            page.function,
+            page.args
+                .iter()
+                .map(|arg| arg_value(arg).1)
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        if page.args.is_empty() {
+            code.push_str(&call);
+        } else if data.is_some() {
+            code.push_str(&format!(
+                "if let Some(args) = data.get({:?}) {{\n",
+                page.file,
+            ));
+            for arg in &page.args {
+                let (name, _) = arg_value(arg);
+                code.push_str(&format!(
+                    "let {name}: {ty} = \
+                     serde_json::from_value(args[{name:?}].clone())?;\n",
+                    name = name,
+                    ty = owned_type(arg),
+                ));
+            }
+            code.push_str(&call);
+            code.push_str("}\n");
+        }
+    }
+    code.push_str(
+        "Ok(())\n\
+         }\n\
+         \n\
+         fn write_page(out: &Path, file: &str, content: &[u8]) \
+         -> std::io::Result<()> {\n\
+         let path = out.join(file);\n\
+         if let Some(dir) = path.parent() {\n\
+         create_dir_all(dir)?;\n\
+         }\n\
+         write(path, content)\n\
+         }\n",
+    );
+    code
+}
+
+/// The name of the argument `arg`, and the expression to pass a value
+/// read as its owned type.
+fn arg_value(arg: &str) -> (&str, String) {
+    let (name, ty) = arg.split_at(arg.find(':').unwrap_or(arg.len()));
+    let name = name.trim();
+    if ty[1..].trim_start().starts_with('&') {
+        (name, format!("&{}", name))
+    } else {
+        (name, name.to_string())
+    }
+}
+
+/// The owned type to read for the argument `arg`.
+fn owned_type(arg: &str) -> String {
+    let ty = arg[arg.find(':').map_or(0, |i| i + 1)..].trim();
+    let ty = match ty.strip_prefix('&') {
+        Some(ty) => ty,
+        None => return ty.to_string(),
+    };
+    let ty = ty.trim_start();
+    let ty = match ty.strip_prefix('\'') {
+        Some(lifetime) => lifetime
+            .find(char::is_whitespace)
+            .map_or("", |end| lifetime[end..].trim_start()),
+        None => ty,
+    };
+    if ty == "str" {
+        "String".into()
+    } else if ty.starts_with('[') && ty.ends_with(']') {
+        format!("Vec<{}>", &ty[1..ty.len() - 1])
+    } else {
+        ty.to_string()
+    }
+}
+
+/// Write `content` to `path`, unless it is already there.
+///
+/// Leaving the file untouched keeps cargo from rebuilding the crate.
+fn write_if_changed(path: &Path, content: &str) -> io::Result<()> {
+    if read(path).ok().as_deref() != Some(content.as_bytes()) {
+        write(path, content)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cargo_toml, find_pages, main_rs, owned_type, Page};
+    use std::fs::{create_dir_all, write};
+    use std::path::{Path, PathBuf};
+    use TemplateConfig;
+
+    #[test]
+    fn ructe_dependency() {
+        let published =
+            format!("ructe = \"={}\"\n", env!("CARGO_PKG_VERSION"));
+        assert!(cargo_toml(false, None).contains(&published));
+        assert!(cargo_toml(false, Some(Path::new("/src/ructe")))
+            .contains("ructe = { path = \"/src/ructe\" }\n"));
+    }
+
+    #[test]
+    fn owned_types() {
+        assert_eq!(owned_type("title: &str"), "String");
+        assert_eq!(owned_type("items: &'a [u32]"), "Vec<u32>");
+        assert_eq!(owned_type("n: usize"), "usize");
+        assert_eq!(owned_type("tags: &Vec<String>"), "Vec<String>");
+    }
+
+    #[test]
+    fn pages_and_main() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("target")
+            .join("test-tmp")
+            .join("sitegen");
+        create_dir_all(dir.join("blog")).unwrap();
+        write(dir.join("index.rs.html"), "@()\n<h1>Home</h1>\n").unwrap();
+        write(dir.join("feed.rs.xml"), "@<T: ToHtml>(x: T)\n@x\n").unwrap();
+        write(
+            dir.join("blog").join("post.rs.html"),
+            "@(title: &str, n: u8)\n<h1>@title @n</h1>\n",
+        )
+        .unwrap();
+        let mut pages = Vec::new();
+        find_pages(&dir, "", &TemplateConfig::default(), &mut pages).unwrap();
+        assert_eq!(
+            pages,
+            [
+                Page {
+                    file: "blog/post.rs.html".into(),
+                    function: "blog::post_html".into(),
+                    output: "blog/post.html".into(),
+                    args: vec!["title: &str".into(), "n: u8".into()],
+                },
+                Page {
+                    file: "index.rs.html".into(),
+                    function: "index_html".into(),
+                    output: "index.html".into(),
+                    args: vec![],
+                },
+            ],
+        );
+        let code = main_rs(&pages, Path::new("/dist"), None);
+        assert!(code.contains(
+            "write_page(out, \"index.html\", \
+             &templates::index_html_to_bytes()?)?;\n"
+        ));
+        assert!(!code.contains("post_html"));
+        let code = main_rs(&pages, Path::new("/dist"), Some(Path::new("d")));
+        assert!(code.contains(
+            "if let Some(args) = data.get(\"blog/post.rs.html\") {\n\
+             let title: String = \
+             serde_json::from_value(args[\"title\"].clone())?;\n\
+             let n: u8 = serde_json::from_value(args[\"n\"].clone())?;\n\
+             write_page(out, \"blog/post.html\", \
+             &templates::blog::post_html_to_bytes(&title, n)?)?;\n\
+             }\n"
+        ));
+    }
+}
@@ -1,32 +1,49 @@
 use nom::branch::alt;
 use nom::bytes::complete::{is_not, tag};
-use nom::character::complete::{multispace1, none_of};
-use nom::combinator::{map, value};
+use nom::character::complete::multispace1;
+use nom::combinator::{map, not, opt, value};
 use nom::multi::many0;
-use nom::sequence::preceded;
+use nom::sequence::{preceded, terminated};
 use parseresult::PResult;
 
-pub fn spacelike(input: &[u8]) -> PResult<()> {
-    map(many0(alt((comment, map(multispace1, |_| ())))), |_| ())(input)
+pub fn spacelike(input: &[u8]) -> PResult<'_, ()> {
+    map(
+        many0(alt((comment, line_comment, map(multispace1, |_| ())))),
+        |_| (),
+    )(input)
 }
 
-pub fn comment(input: &[u8]) -> PResult<()> {
+pub fn comment(input: &[u8]) -> PResult<'_, ()> {
     preceded(tag("@*"), comment_tail)(input)
 }
 
-pub fn comment_tail(input: &[u8]) -> PResult<()> {
+pub fn line_comment(input: &[u8]) -> PResult<'_, ()> {
+    preceded(tag("@//"), line_comment_tail)(input)
+}
+
+/// The rest of a `@* ... *@` comment, which may contain nested
+/// comments.
+pub fn comment_tail(input: &[u8]) -> PResult<'_, ()> {
     preceded(
         many0(alt((
-            value((), is_not("*")),
-            value((), preceded(tag("*"), none_of("@"))),
+            value((), is_not("*@")),
+            value((), preceded(tag("@*"), comment_tail)),
+            value((), terminated(tag("*"), not(tag("@")))),
+            value((), tag("@")),
         ))),
         value((), tag("*@")),
     )(input)
 }
 
+/// The rest of a `@//` comment, up to (but not including) the end of
+/// the line.
+pub fn line_comment_tail(input: &[u8]) -> PResult<'_, ()> {
+    value((), opt(is_not("\n")))(input)
+}
+
 #[cfg(test)]
 mod test {
-    use super::{comment, spacelike};
+    use super::{comment, line_comment, spacelike};
     use nom::error::{ErrorKind, VerboseError, VerboseErrorKind};
     use nom::Err;
 
@@ -76,6 +93,29 @@ mod test {
         );
     }
 
+    #[test]
+    fn comment_nested() {
+        assert_eq!(
+            comment(b"@* outer @* inner *@ still outer *@ after"),
+            Ok((&b" after"[..], ()))
+        );
+    }
+    #[test]
+    fn comment_stars_before_end() {
+        assert_eq!(comment(b"@* a **@b"), Ok((&b"b"[..], ())));
+    }
+    #[test]
+    fn line_comment1() {
+        assert_eq!(
+            line_comment(b"@// a @line *@ comment\nnext"),
+            Ok((&b"\nnext"[..], ()))
+        );
+    }
+    #[test]
+    fn line_comment2() {
+        assert_eq!(line_comment(b"@// the end"), Ok((&b""[..], ())));
+    }
+
     #[test]
     fn spacelike_empty() {
         assert_eq!(spacelike(b""), Ok((&b""[..], ())));
@@ -96,4 +136,11 @@ mod test {
             Ok((&b"something else"[..], ()))
         );
     }
+    #[test]
+    fn spacelike_line_comment() {
+        assert_eq!(
+            spacelike(b"@// a line comment\n  @// and another\nsomething"),
+            Ok((&b"something"[..], ()))
+        );
+    }
 }
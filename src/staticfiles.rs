@@ -4,7 +4,7 @@ use itertools::Itertools;
 use md5;
 use std::collections::BTreeMap;
 use std::fmt::{self, Display};
-use std::fs::{read_dir, File};
+use std::fs::{copy, create_dir_all, read_dir, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
@@ -112,6 +112,16 @@ use std::path::{Path, PathBuf};
 /// `content: &'static [u8]` field which contains the actual file
 /// data.
 ///
+/// To find the name of a static file from code that only knows the
+/// original file name, the `statics` module also contains a function
+/// `static_file_path(name: &str) -> Option<&'static str>`, so that
+/// `static_file_path("image.png")` returns `Some("image-SomeHash.png")`.
+///
+/// If the static files should (also) be served by something else than
+/// your rust program, e.g. a separate web server or a CDN, the
+/// [`copy_to`](#method.copy_to) method makes ructe write each file
+/// to a directory under its name including the hash.
+///
 /// # Content-types
 ///
 /// How to get the content type of static files.
@@ -196,6 +206,10 @@ pub struct StaticFiles {
     names: BTreeMap<String, String>,
     /// Maps public names to rust names (foo-abc123.jpg -> foo_jpg)
     names_r: BTreeMap<String, String>,
+    /// Maps original file names to public names (foo.jpg -> foo-abc123.jpg)
+    paths: BTreeMap<String, String>,
+    /// Directory to write each static file to, with its public name.
+    copy_dir: Option<PathBuf>,
 }
 
 impl StaticFiles {
@@ -236,6 +250,16 @@ impl StaticFile {
         } else {None}
     }
 }
+
+/// Get the public name (including the content hash) of a static file
+/// by its original file name, if it exists.
+#[allow(dead_code)]
+pub fn static_file_path(name: &str) -> Option<&'static str> {
+    STATIC_PATHS
+        .binary_search_by_key(&name, |p| p.0)
+        .ok()
+        .map(|pos| STATIC_PATHS[pos].1)
+}
 ",
         )?;
         if cfg!(feature = "mime02") {
@@ -261,6 +285,8 @@ impl StaticFile {
             base_path: base_path.into(),
             names: BTreeMap::new(),
             names_r: BTreeMap::new(),
+            paths: BTreeMap::new(),
+            copy_dir: None,
         })
     }
 
@@ -274,6 +300,38 @@ impl StaticFile {
         }
     }
 
+    /// Write each static file added after this call to `dir`, using
+    /// the public name (with the content hash) as the file name.
+    ///
+    /// This is useful for serving the static files from a separate
+    /// web server or a CDN, rather than from the compiled binary.
+    /// The files are still available in the `statics` module as well.
+    ///
+    /// ````
+    /// # use ructe::{Result, Ructe, StaticFiles};
+    /// # use std::fs::{create_dir_all, read};
+    /// # use std::path::PathBuf;
+    /// # fn main() -> Result<()> {
+    /// # let p = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("test-tmp").join("copy-to");
+    /// # create_dir_all(&p);
+    /// # let mut ructe = Ructe::new(p.clone())?;
+    /// let mut statics = ructe.statics()?;
+    /// statics.copy_to(p.join("public"))?;
+    /// statics.add_file_data("black.css", b"body{color:black}\n")?;
+    /// assert_eq!(
+    ///     read(p.join("public").join("black-r3rltVhW.css"))?,
+    ///     b"body{color:black}\n",
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ````
+    pub fn copy_to(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = self.path_for(dir);
+        create_dir_all(&dir)?;
+        self.copy_dir = Some(dir);
+        Ok(())
+    }
+
     /// Add all files from a specific directory, `indir`, as static files.
     pub fn add_files(&mut self, indir: impl AsRef<Path>) -> Result<()> {
         let indir = self.path_for(indir);
@@ -281,7 +339,7 @@ impl StaticFile {
         for entry in read_dir(indir)? {
             let entry = entry?;
             if entry.file_type()?.is_file() {
-                self.add_file(&entry.path())?;
+                self.add_file(entry.path())?;
             }
         }
         Ok(())
@@ -304,9 +362,9 @@ impl StaticFile {
             let to =
                 format!("{}/{}", to, entry.file_name().to_string_lossy());
             if file_type.is_file() {
-                self.add_file_as(&entry.path(), &to)?;
+                self.add_file_as(entry.path(), &to)?;
             } else if file_type.is_dir() {
-                self.add_files_as(&entry.path(), &to)?;
+                self.add_files_as(entry.path(), &to)?;
             }
         }
         Ok(())
@@ -335,6 +393,8 @@ impl StaticFile {
                 &FileContent(&path),
                 ext,
             )?;
+            self.add_path(&format!("{}.{}", name, ext), &url_name);
+            self.copy_static(&url_name, &buf)?;
         }
         Ok(())
     }
@@ -351,6 +411,14 @@ impl StaticFile {
         let ext = name_and_ext(path).map(|(_, e)| e).unwrap_or("");
         println!("cargo:rerun-if-changed={}", path.display());
         self.add_static(path, url_name, url_name, &FileContent(path), ext)?;
+        self.add_path(url_name, url_name);
+        if let Some(dir) = &self.copy_dir {
+            let target = dir.join(url_name);
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent)?;
+            }
+            copy(path, target)?;
+        }
         Ok(())
     }
 
@@ -410,6 +478,8 @@ impl StaticFile {
                 &ByteString(data),
                 ext,
             )?;
+            self.add_path(&format!("{}.{}", name, ext), &url_name);
+            self.copy_static(&url_name, data)?;
         }
         Ok(())
     }
@@ -506,6 +576,18 @@ impl StaticFile {
         Ok(())
     }
 
+    fn add_path(&mut self, path: &str, url_name: &str) {
+        self.paths.insert(path.into(), url_name.into());
+    }
+
+    /// Write `data` to `url_name` in the copy dir, if any.
+    fn copy_static(&self, url_name: &str, data: &[u8]) -> io::Result<()> {
+        if let Some(dir) = &self.copy_dir {
+            File::create(dir.join(url_name))?.write_all(data)?;
+        }
+        Ok(())
+    }
+
     /// Get a mapping of names, from without hash to with.
     ///
     /// ````
@@ -537,7 +619,7 @@ impl StaticFile {
 
 impl Drop for StaticFiles {
     /// Write the ending of the statics source code, declaring the
-    /// `STATICS` variable.
+    /// `STATICS` and `STATIC_PATHS` variables.
     fn drop(&mut self) {
         // Ignore a possible write failure, rather than a panic in drop.
         let _ = writeln!(
@@ -549,6 +631,14 @@ impl Drop for StaticFiles {
                 .map(|s| format!("&{}", s.1))
                 .format(", "),
         );
+        let _ = writeln!(
+            self.src,
+            "\nstatic STATIC_PATHS: &[(&str, &str)] = &[{}];",
+            self.paths
+                .iter()
+                .map(|(path, name)| format!("({:?}, {:?})", path, name))
+                .format(", "),
+        );
     }
 }
 
@@ -1,108 +1,1825 @@
-use expression::{input_to_str, rust_name};
+use a11ylint;
+use expression::{expr_inside_parens, input_to_str, rust_name};
+use htmllint;
 use itertools::Itertools;
 use nom::branch::alt;
 use nom::bytes::complete::is_not;
 use nom::bytes::complete::tag;
-use nom::character::complete::{char, multispace0};
-use nom::combinator::{map, map_res, opt, recognize};
+use nom::bytes::complete::take_while1;
+use nom::character::complete::{
+    char, digit1, multispace0, multispace1, space0, space1,
+};
+use nom::combinator::{map, map_res, opt, recognize, value};
 use nom::error::context;
-use nom::multi::{many0, many_till, separated_list};
-use nom::sequence::{delimited, preceded, terminated, tuple};
+use nom::multi::{many0, separated_list, separated_nonempty_list};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 use nom_delimited_list::delimited_list;
-use parseresult::PResult;
-use spacelike::spacelike;
+use parseresult::{stop_incomplete, PResult};
+use spacelike::{comment, spacelike};
+use std::collections::BTreeMap;
 use std::io::{self, Write};
-use templateexpression::{template_expression, TemplateExpression};
+use templateexpression::{
+    add_csp_nonce, body_code, byte_string, const_declaration, fill_blocks,
+    for_each_include, pass_call_depth, resolve_filters, resolve_macro_calls,
+    static_text, template_body, trace_expressions, Escaping, Include, Target,
+    TemplateExpression,
+};
+use OutputTrait;
+
+/// The default for the most nested recursive calls of a template.
+pub const DEFAULT_MAX_DEPTH: usize = 32;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Template {
+    extends: Option<String>,
+    email_css: Option<String>,
+    /// The predicate of a `@cfg(...)` directive, if any.
+    cfg: Option<String>,
     preamble: Vec<String>,
+    generics: Option<String>,
     args: Vec<String>,
+    where_clause: Option<String>,
+    content_type: Option<String>,
+    status: Option<u16>,
+    cache_control: Option<String>,
+    /// The argument and rule of each `@validate(arg: rule)` directive.
+    validations: Vec<(String, String)>,
+    /// If set, the template function panics with this message instead
+    /// of writing the body.
+    panic_message: Option<String>,
+    /// Attributes for the template function, such as `#[inline]`.
+    fn_attributes: Vec<String>,
+    /// The most nested recursive calls of the template allowed.
+    max_depth: usize,
+    /// The name of the template file, for the doc comment.
+    file_name: Option<String>,
+    /// The text of a `@* ... *@` comment right after the arguments,
+    /// for the doc comment.
+    doc: Option<String>,
+    /// The name and fields of a struct declared with `@context`, that
+    /// is passed to the template as a `ctx` argument.
+    context: Option<(String, Vec<String>)>,
     body: Vec<TemplateExpression>,
 }
 
 impl Template {
+    /// The path of the layout template this template extends, if any.
+    pub fn extends(&self) -> Option<&str> {
+        self.extends.as_deref()
+    }
+
+    /// The path of the stylesheet to inline for email, if any.
+    pub fn email_css(&self) -> Option<&str> {
+        self.email_css.as_deref()
+    }
+
+    /// The predicate of the `@cfg(...)` directive of the template, if
+    /// any.
+    pub fn cfg(&self) -> Option<&str> {
+        self.cfg.as_deref()
+    }
+
+    /// Set the path of the stylesheet to inline for email.
+    pub fn set_email_css(&mut self, path: String) {
+        self.email_css = Some(path);
+    }
+
+    /// Make the function for this template panic with `message`
+    /// instead of writing the body.
+    ///
+    /// This is for a stub of a template that could not be compiled,
+    /// that has the declared arguments, so calls to it still compile.
+    pub fn set_panic_message(&mut self, message: String) {
+        self.body.clear();
+        self.panic_message = Some(message);
+    }
+
+    /// Add an attribute, such as `#[inline]`, to the function for this
+    /// template.
+    pub fn add_fn_attribute(&mut self, attribute: &str) {
+        self.fn_attributes.push(attribute.to_string());
+    }
+
+    /// Set the name of the template file, as shown in the doc comment
+    /// of the template function.
+    pub fn set_file_name(&mut self, name: &str) {
+        self.file_name = Some(name.into());
+    }
+
+    /// Set the most nested recursive calls of this template allowed.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Write a comment with the source of each expression before and
+    /// after its output, in debug builds.
+    pub fn trace_expressions(&mut self) {
+        let body = std::mem::take(&mut self.body);
+        self.body = trace_expressions(body);
+    }
+
+    /// The number of parts of the body of this template, including
+    /// nested parts.
+    pub fn node_count(&self) -> usize {
+        self.body.iter().map(TemplateExpression::node_count).sum()
+    }
+
+    /// Add a `use` declaration for `path` to the preamble of this
+    /// template, allowing it to be unused.
+    pub fn add_use(&mut self, path: &str) {
+        self.preamble
+            .push(format!("#[allow(unused_imports)]\nuse {}", path));
+    }
+
+    /// The names imported by the `@use` declarations of this template.
+    ///
+    /// A glob import is the name `*`.
+    fn used_names(&self) -> Vec<String> {
+        self.preamble
+            .iter()
+            .filter_map(|line| line.strip_prefix("use "))
+            .flat_map(|tree| tree.split(&[',', '{', '}'][..]))
+            .filter_map(|part| {
+                let name = match part.find(" as ") {
+                    Some(pos) => &part[pos + 4..],
+                    None => part.rsplit("::").next()?,
+                };
+                Some(name.trim().to_string())
+            })
+            .filter(|name| !name.is_empty() && name != "self")
+            .collect()
+    }
+
+    /// The arguments of this template, as `name: Type`.
+    #[cfg(feature = "sitegen")]
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// True if the arguments of this template are only plain values,
+    /// without type parameters, a context struct, `impl` types or
+    /// `Content` closures.
+    #[cfg(feature = "sitegen")]
+    pub fn has_plain_args(&self) -> bool {
+        self.generic_params().is_empty()
+            && self.context.is_none()
+            && !self
+                .args
+                .iter()
+                .any(|arg| arg.contains(" Content") || arg.contains("impl "))
+    }
+
+    /// True if any argument of this template is a `Content` closure.
+    fn has_content_arg(&self) -> bool {
+        self.args.iter().any(|arg| arg.contains(" Content"))
+    }
+
+    /// True if the arguments of this template can be given by name
+    /// in a `{Name}Args` struct.
+    ///
+    /// That takes arguments that don't need type parameters or
+    /// lifetimes other than the one of the struct.
+    fn has_args_builder(&self) -> bool {
+        let simple = |arg: &String| {
+            !arg.contains(" Content")
+                && !arg.contains("impl ")
+                && !may_hide_lifetime(arg)
+        };
+        !self.args.is_empty()
+            && self.generic_params().is_empty()
+            && self.where_clause.is_none()
+            && self.args.iter().all(simple)
+    }
+
+    /// The public items of the code for this template function
+    /// `name`, to export from the module of the template.
+    pub fn exports(&self, name: &str, target: Target) -> Vec<String> {
+        let mut exports = vec![name.to_string()];
+        if let (Some((ref context, _)), Target::Sync(_)) =
+            (&self.context, target)
+        {
+            exports.push(context.clone());
+        }
+        if self.static_text(name).is_some() {
+            exports.push(static_text_name(name));
+        }
+        match target {
+            Target::Sync(output) => {
+                exports.extend(vec![
+                    static_size_name(name),
+                    content_type_name(name),
+                    http_status_name(name),
+                    cache_control_name(name),
+                    format!("{}_to_string", name),
+                    format!("{}_to_bytes", name),
+                ]);
+                if output == OutputTrait::Io && self.has_args_builder() {
+                    exports.push(args_struct_name(name));
+                    exports.push(format!("{}_with_args", name));
+                }
+                if output == OutputTrait::Io && cfg!(feature = "bytes") {
+                    exports.push(format!("{}_to_bytes_buf", name));
+                }
+                if cfg!(feature = "serde") && !self.has_content_arg() {
+                    exports.push(format!("{}_to_json", name));
+                }
+                if output == OutputTrait::Io
+                    && cfg!(feature = "email")
+                    && name.ends_with("_html")
+                {
+                    exports.push(format!("{}_email", name));
+                }
+            }
+            Target::Async(_) => {
+                if cfg!(feature = "stream") && !self.has_content_arg() {
+                    exports.push(format!("{}_stream", name));
+                }
+            }
+        }
+        exports
+    }
+
+    /// The names of the arguments of this template.
+    pub fn arg_names(&self) -> Vec<String> {
+        self.args.iter().map(|a| arg_name(a).to_string()).collect()
+    }
+
+    /// The names of the arguments that are never used in the body of
+    /// this template.
+    ///
+    /// Arguments with names starting with an underscore are not
+    /// reported, just as rustc does for unused variables.
+    pub fn unused_args(&self) -> Vec<String> {
+        let body = self.body_code(Target::default(), Escaping::Html);
+        self.arg_names()
+            .into_iter()
+            .filter(|name| {
+                !name.starts_with('_')
+                    && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+                    && !contains_ident(&body, name)
+            })
+            .collect()
+    }
+
+    /// The static html of each block of this template, with a mark
+    /// for each expression, as checked by the html lint.
+    #[cfg(feature = "refactor")]
+    pub fn static_html(&self) -> Vec<String> {
+        let mut blocks = Vec::new();
+        htmllint::for_each_block(&self.body, &mut |html| {
+            blocks.push(html.to_string())
+        });
+        blocks
+    }
+
+    /// Common mistakes in the static html of this template.
+    pub fn html_problems(&self) -> Vec<String> {
+        htmllint::lint(&self.body)
+    }
+
+    /// Basic accessibility problems in the static html of this
+    /// template.
+    pub fn a11y_problems(&self) -> Vec<String> {
+        a11ylint::lint(&self.body)
+    }
+
+    /// The names of the templates called or included by this
+    /// template, as written in the calls.
+    pub fn called_templates(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for b in &self.body {
+            b.called_templates(&mut names);
+        }
+        names
+    }
+
+    fn body_code(&self, target: Target, escaping: Escaping) -> String {
+        body_code(&self.body, target, escaping)
+    }
+
+    /// The output of the template, if it is the same for every call.
+    fn static_text(&self, name: &str) -> Option<String> {
+        if self.panic_message.is_some() {
+            None
+        } else {
+            static_text(&self.body, Escaping::for_template(name))
+        }
+    }
+
+    /// Call `f` for each `@include` in this template, with the names
+    /// that are in scope where it is included.
+    pub fn for_each_include<E, F>(&mut self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&mut Include, &[String]) -> Result<(), E>,
+    {
+        let mut scope = self.arg_names();
+        for_each_include(&mut self.body, &mut scope, &mut f)
+    }
+
+    /// Combine this template with the layout it extends.
+    ///
+    /// The top-level blocks of this template fill the blocks and yield
+    /// points of the layout.
+    /// The arguments of the layout that are not declared by this
+    /// template are added after the arguments of this template.
+    /// Any other content of this template is written before the
+    /// layout.
+    pub fn extend(self, layout: Template) -> Template {
+        let mut generics = self.generic_params();
+        for param in layout.generic_params() {
+            if !generics.contains(&param) {
+                generics.push(param);
+            }
+        }
+        generics.sort_by_key(|p| !p.starts_with('\''));
+
+        let mut blocks = BTreeMap::new();
+        let mut body = Vec::new();
+        for expr in self.body {
+            match expr {
+                TemplateExpression::Block { name, body } => {
+                    blocks.insert(name, body);
+                }
+                TemplateExpression::Text { ref text }
+                    if text.trim().is_empty() => {}
+                TemplateExpression::Comment => {}
+                expr => body.push(expr),
+            }
+        }
+        body.extend(fill_blocks(layout.body, &blocks));
+
+        let mut preamble = self.preamble;
+        for line in layout.preamble {
+            if !preamble.contains(&line) {
+                preamble.push(line);
+            }
+        }
+        // The context struct of the layout is defined with the layout.
+        if let (None, Some((name, _))) = (&self.context, layout.context) {
+            preamble.push(format!("use super::{}", name));
+        }
+        let mut args = self.args;
+        for arg in layout.args {
+            if !args.iter().any(|a| arg_name(a) == arg_name(&arg)) {
+                args.push(arg);
+            }
+        }
+        let where_clause = self
+            .where_clause
+            .into_iter()
+            .chain(layout.where_clause)
+            .join(", ");
+
+        Template {
+            extends: layout.extends,
+            email_css: self.email_css.or(layout.email_css),
+            cfg: self.cfg,
+            preamble,
+            generics: if generics.is_empty() {
+                None
+            } else {
+                Some(generics.join(", "))
+            },
+            args,
+            where_clause: if where_clause.is_empty() {
+                None
+            } else {
+                Some(where_clause)
+            },
+            content_type: self.content_type.or(layout.content_type),
+            status: self.status.or(layout.status),
+            cache_control: self.cache_control.or(layout.cache_control),
+            validations: self
+                .validations
+                .into_iter()
+                .chain(layout.validations)
+                .collect(),
+            panic_message: self.panic_message.or(layout.panic_message),
+            fn_attributes: self.fn_attributes,
+            max_depth: self.max_depth,
+            file_name: self.file_name,
+            doc: self.doc,
+            context: self.context,
+            body,
+        }
+    }
+
     pub fn write_rust(
         &self,
         out: &mut impl Write,
         name: &str,
+        target: Target,
     ) -> io::Result<()> {
+        let (result, head, out_arg, bounds, content) = match target {
+            Target::Sync(output) => {
+                writeln!(
+                    out,
+                    "use std::{}::{{self, Write}};",
+                    output.module(),
+                )?;
+                (
+                    match output {
+                        OutputTrait::Io => "io::Result<()>",
+                        OutputTrait::Fmt => "fmt::Result",
+                    },
+                    "pub fn",
+                    "mut _ructe_out_",
+                    "?Sized, for<'_ructe> &'_ructe mut W: Write".to_string(),
+                    "FnOnce",
+                )
+            }
+            Target::Async(runtime) => (
+                "std::io::Result<()>",
+                "pub async fn",
+                "_ructe_out_",
+                format!(
+                    "{}::AsyncWrite + Unpin + ?Sized",
+                    runtime.io_module()
+                ),
+                "AsyncFnOnce",
+            ),
+        };
         out.write_all(
-            b"use std::io::{self, Write};\n\
-             #[allow(renamed_and_removed_lints)]\n\
+            b"#[allow(renamed_and_removed_lints)]\n\
              #[cfg_attr(feature=\"cargo-clippy\", \
              allow(useless_attribute))]\n\
              #[allow(unused)]\n\
              use super::{Html,ToHtml};\n",
         )?;
+        if let Target::Async(_) = target {
+            out.write_all(b"#[allow(unused)]\nuse super::ToHtmlAsync;\n")?;
+        }
         for l in &self.preamble {
             writeln!(out, "{};", l)?;
         }
+        if let Some((ref name, ref fields)) = self.context {
+            match target {
+                Target::Sync(_) => write_context_struct(out, name, fields)?,
+                // The struct is in the module of the sync templates.
+                Target::Async(_) => writeln!(out, "use super::{};", name)?,
+            }
+        }
+        let mut attrs = self
+            .fn_attributes
+            .iter()
+            .map(|a| format!("{}\n", a))
+            .collect::<String>();
+        if !target.is_async() {
+            // Panics in the template, such as the one of a stub, are
+            // reported at the call.
+            attrs.insert_str(0, "#[track_caller]\n");
+        }
+        if let Some(ref cfg) = self.cfg {
+            attrs.insert_str(0, &format!("#[cfg({})]\n", cfg));
+        }
+        attrs.insert_str(0, &self.doc_comment(name));
+        let escaping = Escaping::for_template(name);
+        let depth_fn = format!("{}_at_depth", name);
+        let mut depth_body = self.body.clone();
+        let recursive = !target.is_async()
+            && self.generic_params().is_empty()
+            && !self.has_content_arg()
+            && pass_call_depth(
+                &mut depth_body,
+                name,
+                &format!("{}::<W>", depth_fn),
+            );
+        let body = if let Some(ref message) = self.panic_message {
+            attrs.push_str("#[allow(unreachable_code)]\n");
+            format!("panic!(\"{{}}\", {:?});\n", message)
+        } else if let Some(text) = self.static_text(name) {
+            self.write_static_text(out, name, target, &text)?
+        } else if recursive {
+            writeln!(
+                out,
+                "\n\
+                 /// The most nested recursive calls of `{name}`.\n\
+                 const MAX_TEMPLATE_DEPTH: usize = {max_depth};\n\
+                 \n\
+                 {attrs}{head} {name}<W>(_ructe_out_: &mut W{args}) -> {result} where W: {bounds}{where_clause} {{\n\
+                 {depth_fn}(_ructe_out_, 0{arg_names})\n\
+                 }}",
+                name = name,
+                max_depth = self.max_depth,
+                attrs = attrs,
+                head = head,
+                args = self
+                    .args
+                    .iter()
+                    .format_with("", |arg, f| f(&format_args!(", {}", arg))),
+                result = result,
+                bounds = bounds,
+                where_clause = self
+                    .where_clause
+                    .iter()
+                    .format_with("", |w, f| f(&format_args!(", {}", w))),
+                depth_fn = depth_fn,
+                arg_names = self
+                    .arg_names()
+                    .iter()
+                    .format_with("", |a, f| f(&format_args!(", {}", a))),
+            )?;
+            attrs = String::new();
+            format!(
+                "if _depth > MAX_TEMPLATE_DEPTH {{\n\
+                 {}return Ok(());\n\
+                 }}\n\
+                 {}",
+                match escaping {
+                    Escaping::Html | Escaping::Xml => {
+                        TemplateExpression::text(
+                            "<!-- max depth exceeded -->",
+                        )
+                        .code(target, escaping)
+                    }
+                    _ => String::new(),
+                },
+                body_code(&depth_body, target, escaping),
+            )
+        } else {
+            self.body_code(target, escaping)
+        };
+        let (head, fn_name, depth_arg) = if recursive {
+            ("fn", depth_fn.as_str(), ", _depth: usize")
+        } else {
+            (head, name, "")
+        };
         writeln!(
             out,
             "\n\
-             pub fn {name}<W>(mut _ructe_out_: &mut W{args}) -> io::Result<()> where W: ?Sized, for<'a> &'a mut W: Write {{\n\
-             {body}\
+             {attrs}{head} {name}<{generics}W>({out_arg}: &mut W{depth_arg}{args}) -> {result} where W: {bounds}{where_clause} {{\n\
+             {unused}{timer}{body}\
              Ok(())\n\
              }}",
+            attrs = attrs,
+            head = head,
+            name = fn_name,
+            out_arg = out_arg,
+            depth_arg = depth_arg,
+            result = result,
+            bounds = bounds,
+            generics = self
+                .generic_params()
+                .iter()
+                .format_with("", |p, f| f(&format_args!("{}, ", p))),
+            where_clause = self
+                .where_clause
+                .iter()
+                .format_with("", |w, f| f(&format_args!(", {}", w))),
+            args = self.args.iter().format_with("", |arg, f| {
+                f(&format_args!(
+                    ", {}",
+                    arg.replace(
+                        " Content",
+                        &format!(" impl {}(&mut W) -> {}", content, result)
+                    )
+                ))
+            }),
+            unused = self
+                .unused_args()
+                .iter()
+                .format_with("", |a, f| f(&format_args!("let _ = &{};\n", a))),
+            timer = if cfg!(feature = "timing") && self.panic_message.is_none() {
+                format!(
+                    "let _ructe_timer_ = super::timing::Timer::start({:?});\n",
+                    name,
+                )
+            } else {
+                String::new()
+            },
+            body = body,
+        )?;
+        match target {
+            Target::Sync(output) => self.write_render_fns(out, name, output),
+            Target::Async(_) if cfg!(feature = "stream") => {
+                self.write_stream_fn(out, name)
+            }
+            Target::Async(_) => Ok(()),
+        }
+    }
+
+    /// The doc comment for the function of the template `name`.
+    fn doc_comment(&self, name: &str) -> String {
+        let mut doc = format!(
+            "/// Renders the `{}` template.\n",
+            self.file_name.as_deref().unwrap_or(name),
+        );
+        if let Some(ref text) = self.doc {
+            doc.push_str("///\n");
+            for line in text.lines() {
+                doc.push_str(
+                    &format!("/// {}\n", line).replace("/// \n", "///\n"),
+                );
+            }
+        }
+        if !self.args.is_empty() {
+            doc.push_str(&format!(
+                "///\n/// Arguments: {}.\n",
+                self.args
+                    .iter()
+                    .format_with(", ", |a, f| f(&format_args!("`{}`", a))),
+            ));
+        }
+        doc
+    }
+
+    /// Write the `{name}_stream` function, that renders the async
+    /// template as a `TemplateStream` of the parts it writes.
+    ///
+    /// The stream borrows the arguments, so each elided lifetime in
+    /// their types is given the lifetime of the stream.
+    /// A template with `Content` arguments gets no stream function,
+    /// since the type of the async closures can't be named.
+    fn write_stream_fn(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> io::Result<()> {
+        if self.has_content_arg() {
+            return Ok(());
+        }
+        let lifetime = "'_ructe";
+        let args = self
+            .args
+            .iter()
+            .map(|arg| {
+                let (name, ty) = arg.split_at(arg.find(':').unwrap_or(0));
+                let ty = with_lifetime(ty[1..].trim(), lifetime);
+                if ty.starts_with("impl ") {
+                    format!("{}: {} + {}", name.trim(), ty, lifetime)
+                } else {
+                    format!("{}: {}", name.trim(), ty)
+                }
+            })
+            .join(", ");
+        let generics = self.generic_params();
+        let bounds = generics
+            .iter()
+            .map(|p| format!("{}: {}", arg_name(p), lifetime))
+            .chain(self.where_clause.clone())
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "\n\
+             /// Render the template as a stream of the parts it writes.\n\
+             pub fn {name}_stream<{lifetime}{generics}>({args}) -> \
+             super::TemplateStream<impl std::future::Future<Output = std::io::Result<()>> + {lifetime}>{where_clause} {{\n\
+             super::TemplateStream::new(move |mut _ructe_out_| async move {{\n\
+             {name}(&mut _ructe_out_{arg_names}).await\n\
+             }})\n\
+             }}",
             name = name,
-            args =
-                self.args.iter().format_with("", |arg, f| f(&format_args!(
+            lifetime = lifetime,
+            generics = generics
+                .iter()
+                .format_with("", |p, f| f(&format_args!(", {}", p))),
+            args = args,
+            where_clause = if bounds.is_empty() {
+                String::new()
+            } else {
+                format!(" where {}", bounds.join(", "))
+            },
+            arg_names = self
+                .args
+                .iter()
+                .map(|arg| {
+                    format!(", {}", arg_name(arg).trim_start_matches("mut "))
+                })
+                .join(""),
+        )
+    }
+
+    /// Write the `{NAME}_STATIC` constant with the output of a template
+    /// without any expressions, and return the code to write it.
+    ///
+    /// The constant is a `&[u8]`, or a `&str` for templates writing to
+    /// a `fmt::Write`.
+    fn write_static_text(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        target: Target,
+        text: &str,
+    ) -> io::Result<String> {
+        let const_name = static_text_name(name);
+        let (ty, value, write) = match target {
+            Target::Sync(OutputTrait::Io) => (
+                "&[u8]",
+                byte_string(text),
+                format!("_ructe_out_.write_all({})?;\n", const_name),
+            ),
+            Target::Sync(OutputTrait::Fmt) => (
+                "&str",
+                format!("{:?}", text),
+                format!("_ructe_out_.write_str({})?;\n", const_name),
+            ),
+            Target::Async(runtime) => (
+                "&[u8]",
+                byte_string(text),
+                format!(
+                    "{}::AsyncWriteExt::write_all(\
+                     &mut *_ructe_out_, {}).await?;\n",
+                    runtime.io_module(),
+                    const_name,
+                ),
+            ),
+        };
+        writeln!(
+            out,
+            "\n\
+             /// The output of `{name}`, which is the same for every call.\n\
+             pub const {const_name}: {ty} = {value};",
+            name = name,
+            const_name = const_name,
+            ty = ty,
+            value = value,
+        )?;
+        Ok(write)
+    }
+
+    /// Write the `{NAME}_STATIC_SIZE` constant, and the
+    /// `{name}_to_string` and `{name}_to_bytes` functions, that render
+    /// the template to a `String` or a `Vec<u8>`.
+    ///
+    /// The buffer is allocated with room for the static parts of the
+    /// template.
+    fn write_render_fns(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        output: OutputTrait,
+    ) -> io::Result<()> {
+        let (buf, content, call_result, to_string, to_bytes) = match output {
+            OutputTrait::Io => (
+                "Vec",
+                "impl FnOnce(&mut Vec<u8>) -> io::Result<()>",
+                "?",
+                "String::from_utf8(buf).map_err(|e| {\n\
+                 io::Error::new(io::ErrorKind::InvalidData, e)\n\
+                 })",
+                "Ok(buf)",
+            ),
+            OutputTrait::Fmt => (
+                "String",
+                "impl FnOnce(&mut String) -> fmt::Result",
+                ".map_err(|e| {\n\
+                 std::io::Error::new(std::io::ErrorKind::Other, e)\n\
+                 })?",
+                "Ok(buf)",
+                "Ok(buf.into_bytes())",
+            ),
+        };
+        let buf_type = match output {
+            OutputTrait::Io => "Vec<u8>",
+            OutputTrait::Fmt => "String",
+        };
+        let generics = self.generic_params();
+        let generics = if generics.is_empty() {
+            String::new()
+        } else {
+            format!("<{}>", generics.join(", "))
+        };
+        let args = self
+            .args
+            .iter()
+            .map(|arg| arg.replace(" Content", &format!(" {}", content)))
+            .join(", ");
+        let where_clause = self
+            .where_clause
+            .iter()
+            .map(|w| format!(" where {}", w))
+            .join("");
+        let arg_names = self
+            .args
+            .iter()
+            .map(|arg| {
+                format!(", {}", arg_name(arg).trim_start_matches("mut "))
+            })
+            .join("");
+        let size = static_size_name(name);
+        writeln!(
+            out,
+            "\n\
+             /// The number of bytes of static text written by `{name}`.\n\
+             ///\n\
+             /// This is a lower bound of the size of the output.\n\
+             pub const {size}: usize = {value};\n\
+             \n\
+             /// The content type of the output of `{name}`.\n\
+             pub const {content_type}: &str = {mime:?};\n\
+             \n\
+             /// The http status of a response with the output of `{name}`.\n\
+             pub const {status_name}: u16 = {status};\n\
+             \n\
+             /// The `Cache-Control` header of a response with the output of `{name}`, if any.\n\
+             pub const {cache_control_name}: Option<&str> = {cache_control:?};",
+            name = name,
+            size = size,
+            value = self.static_size(),
+            content_type = content_type_name(name),
+            mime = self
+                .content_type
+                .as_deref()
+                .unwrap_or_else(|| default_content_type(name)),
+            status_name = http_status_name(name),
+            status = self.status.unwrap_or(200),
+            cache_control_name = cache_control_name(name),
+            cache_control = self.cache_control,
+        )?;
+        for &(suffix, doc, result, render) in &[
+            ("to_string", "a `String`", "String", to_string),
+            ("to_bytes", "a `Vec<u8>`", "Vec<u8>", to_bytes),
+        ] {
+            writeln!(
+                out,
+                "\n\
+                 /// Render the template to {doc}.\n\
+                 #[inline]\n\
+                 pub fn {name}_{suffix}{generics}({args}) -> std::io::Result<{result}>{where_clause} {{\n\
+                 let mut buf: {buf_type} = {buf}::with_capacity({size});\n\
+                 {name}(&mut buf{arg_names}){call_result};\n\
+                 {render}\n\
+                 }}",
+                doc = doc,
+                name = name,
+                suffix = suffix,
+                generics = generics,
+                args = args,
+                result = result,
+                where_clause = where_clause,
+                buf = buf,
+                buf_type = buf_type,
+                size = size,
+                arg_names = arg_names,
+                call_result = call_result,
+                render = render,
+            )?;
+        }
+        if output == OutputTrait::Io {
+            self.write_args_builder(out, name)?;
+        }
+        if output == OutputTrait::Io && cfg!(feature = "bytes") {
+            self.write_bytes_fn(out, name)?;
+        }
+        if cfg!(feature = "serde") {
+            self.write_json_fn(out, name)?;
+        }
+        match output {
+            OutputTrait::Io
+                if cfg!(feature = "email") && name.ends_with("_html") =>
+            {
+                self.write_email_fn(out, name)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the `{Name}Args` struct, with a setter method for each
+    /// argument, and the `{name}_with_args` function, that calls the
+    /// template with the arguments set in the struct.
+    ///
+    /// The struct also has a `validate` method, that checks the
+    /// `@validate` rules of the template and any custom validators.
+    ///
+    /// Templates without arguments, and templates with generics,
+    /// `impl` types or `Content` arguments, get no args struct.
+    fn write_args_builder(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> io::Result<()> {
+        if !self.has_args_builder() {
+            return Ok(());
+        }
+        let fields = self
+            .args
+            .iter()
+            .map(|arg| {
+                let (name, ty) = arg.split_at(arg.find(':').unwrap_or(0));
+                let name = name.trim();
+                (name, with_lifetime(ty[1..].trim(), "'a"))
+            })
+            .collect::<Vec<_>>();
+        let lifetime = if fields.iter().any(|(_, ty)| ty.contains("'a")) {
+            "<'a>"
+        } else {
+            ""
+        };
+        let validations_for = |arg: &str| {
+            let rules = self
+                .validations
+                .iter()
+                .filter(|(name, _)| name == arg)
+                .map(|(_, rule)| {
+                    format!(
+                        "if let Err(message) = ({})(value) {{\n\
+                         errors.push(super::ValidationError {{ \
+                         argument: {:?}, message }});\n\
+                         }}\n",
+                        validator_path(rule),
+                        arg,
+                    )
+                })
+                .collect::<String>();
+            if rules.is_empty() {
+                "Some(_) => (),\n".to_string()
+            } else {
+                format!("Some(ref value) => {{\n{}}}\n", rules)
+            }
+        };
+        let args_name = args_struct_name(name);
+        writeln!(
+            out,
+            "\n\
+             /// The arguments of `{name}`, for calling it with named arguments.\n\
+             #[derive(Default)]\n\
+             pub struct {args_name}{lifetime} {{\n\
+             {fields}\
+             validators: Vec<(&'static str, Box<dyn Fn(&{args_name}{lifetime}) -> Result<(), String>{bound}>)>,\n\
+             }}\n\
+             \n\
+             impl{lifetime} {args_name}{lifetime} {{\n\
+             /// Create the arguments, with no argument set.\n\
+             pub fn new() -> Self {{\n\
+             Self::default()\n\
+             }}\n\
+             {setters}\
+             /// Add a custom validator for `argument`, that is called by `validate`.\n\
+             pub fn validator<F>(mut self, argument: &'static str, check: F) -> Self \
+             where F: Fn(&Self) -> Result<(), String>{bound} {{\n\
+             self.validators.push((argument, Box::new(check)));\n\
+             self\n\
+             }}\n\
+             /// Check that all arguments are set, and valid by the rules \
+             declared with `@validate` and the custom validators.\n\
+             ///\n\
+             /// All problems found are returned.\n\
+             pub fn validate(&self) -> Result<(), Vec<super::ValidationError>> {{\n\
+             let mut errors = Vec::new();\n\
+             {checks}\
+             for &(argument, ref check) in &self.validators {{\n\
+             if let Err(message) = check(self) {{\n\
+             errors.push(super::ValidationError {{ argument, message }});\n\
+             }}\n\
+             }}\n\
+             if errors.is_empty() {{ Ok(()) }} else {{ Err(errors) }}\n\
+             }}\n\
+             }}\n\
+             \n\
+             /// Call `{name}` with the arguments in `args`.\n\
+             ///\n\
+             /// If an argument is not set, an error of kind `InvalidInput` is returned.\n\
+             pub fn {name}_with_args{generics}(_ructe_out_: &mut W, args: {args_name}{lifetime}) -> io::Result<()> \
+             where W: ?Sized, for<'_ructe> &'_ructe mut W: Write {{\n\
+             {name}(_ructe_out_{values})\n\
+             }}",
+            name = name,
+            args_name = args_name,
+            lifetime = lifetime,
+            bound = if lifetime.is_empty() { " + 'static" } else { " + 'a" },
+            fields = fields.iter().format_with("", |(name, ty), f| {
+                f(&format_args!("pub {}: Option<{}>,\n", name, ty))
+            }),
+            checks = fields.iter().format_with("", |(arg, _), f| {
+                f(&format_args!(
+                    "match self.{arg} {{\n\
+                     {some}\
+                     None => errors.push(super::ValidationError {{ \
+                     argument: {arg:?}, message: \"is not set\".into() }}),\n\
+                     }}\n",
+                    arg = arg,
+                    some = validations_for(arg),
+                ))
+            }),
+            setters = fields.iter().format_with("", |(name, ty), f| {
+                f(&format_args!(
+                    "/// Set the `{name}` argument.\n\
+                     pub fn {name}(mut self, {name}: {ty}) -> Self {{\n\
+                     self.{name} = Some({name});\n\
+                     self\n\
+                     }}\n",
+                    name = name,
+                    ty = ty,
+                ))
+            }),
+            generics = if lifetime.is_empty() { "<W>" } else { "<'a, W>" },
+            values = fields.iter().format_with("", |(arg, _), f| {
+                f(&format_args!(
+                    ", args.{arg}.ok_or_else(|| io::Error::new(\
+                     io::ErrorKind::InvalidInput, \
+                     \"Missing argument {arg} of {name}\"))?",
+                    arg = arg,
+                    name = name,
+                ))
+            }),
+        )
+    }
+
+    /// Write the `{name}_to_bytes_buf` function, that renders the
+    /// template to a `bytes::BytesMut` and freezes it, so the output
+    /// can be used as a response body without copying it.
+    fn write_bytes_fn(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> io::Result<()> {
+        let generics = self.generic_params();
+        writeln!(
+            out,
+            "\n\
+             /// Render the template to a `bytes::Bytes`.\n\
+             #[inline]\n\
+             pub fn {name}_to_bytes_buf{generics}({args}) -> std::io::Result<bytes::Bytes>{where_clause} {{\n\
+             let mut buf: super::BytesMutWriter = super::BytesMutWriter(\
+             bytes::BytesMut::with_capacity({size}));\n\
+             {name}(&mut buf{arg_names})?;\n\
+             Ok(buf.0.freeze())\n\
+             }}",
+            name = name,
+            generics = if generics.is_empty() {
+                String::new()
+            } else {
+                format!("<{}>", generics.join(", "))
+            },
+            args = self
+                .args
+                .iter()
+                .map(|arg| arg.replace(
+                    " Content",
+                    " impl FnOnce(&mut super::BytesMutWriter) \
+                     -> io::Result<()>",
+                ))
+                .join(", "),
+            where_clause = self
+                .where_clause
+                .iter()
+                .map(|w| format!(" where {}", w))
+                .join(""),
+            size = static_size_name(name),
+            arg_names = self
+                .args
+                .iter()
+                .map(|arg| {
+                    format!(", {}", arg_name(arg).trim_start_matches("mut "))
+                })
+                .join(""),
+        )
+    }
+
+    /// Write the `{name}_to_json` function, that serializes the
+    /// arguments of the template as a json object.
+    ///
+    /// Each argument gets a type parameter, so the function compiles
+    /// even if the argument types of the template are not
+    /// `Serialize`.
+    /// Templates with `Content` arguments get no json function.
+    fn write_json_fn(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> io::Result<()> {
+        if self.has_content_arg() {
+            return Ok(());
+        }
+        let names = self
+            .arg_names()
+            .into_iter()
+            .map(|arg| arg.trim_start_matches("mut ").to_string())
+            .collect::<Vec<_>>();
+        let params = (0..names.len())
+            .map(|i| format!("T{}", i))
+            .collect::<Vec<_>>();
+        let generics = if params.is_empty() {
+            String::new()
+        } else {
+            format!("<{}>", params.join(", "))
+        };
+        writeln!(
+            out,
+            "\n\
+             /// Serialize the arguments of `{name}` as a json object.\n\
+             ///\n\
+             /// This takes the same arguments as `{name}`, but each may be of any type that implements `serde::Serialize`.\n\
+             pub fn {name}_to_json{generics}({args}) -> serde_json::Result<serde_json::Value>{where_clause} {{\n\
+             #[derive(serde::Serialize)]\n\
+             struct Args{generics} {{\n\
+             {fields}\
+             }}\n\
+             serde_json::to_value(Args {{ {names} }})\n\
+             }}",
+            name = name,
+            generics = generics,
+            args = names
+                .iter()
+                .zip(&params)
+                .map(|(arg, param)| format!("{}: {}", arg, param))
+                .join(", "),
+            where_clause = if params.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    " where {}",
+                    params
+                        .iter()
+                        .map(|p| format!("{}: serde::Serialize", p))
+                        .join(", ")
+                )
+            },
+            fields = names.iter().zip(&params).format_with(
+                "",
+                |(arg, param), f| f(&format_args!("{}: {},\n", arg, param))
+            ),
+            names = names.join(", "),
+        )
+    }
+
+    /// Write the `{name}_email` function, that renders the template
+    /// and inlines the css from any `<style>` elements, and from the
+    /// `@email_css` stylesheet if there is one, into `style`
+    /// attributes, using the `css-inline` crate.
+    fn write_email_fn(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> io::Result<()> {
+        writeln!(
+            out,
+            "\n\
+             /// Render the template with its css inlined, for html email.\n\
+             pub fn {name}_email<{generics}W>(_ructe_out_: &mut W{args}) -> io::Result<()> where W: Write + ?Sized{where_clause} {{\n\
+             let html = {name}_to_string({arg_names})?;\n\
+             let html = css_inline::CSSInliner::options()\n\
+             .extra_css({css})\n\
+             .build()\n\
+             .inline(&html)\n\
+             .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;\n\
+             _ructe_out_.write_all(html.as_bytes())\n\
+             }}",
+            name = name,
+            css = match &self.email_css {
+                Some(css) => format!("Some(include_str!({:?}).into())", css),
+                None => "None".into(),
+            },
+            generics = self
+                .generic_params()
+                .iter()
+                .format_with("", |p, f| f(&format_args!("{}, ", p))),
+            args = self.args.iter().format_with("", |arg, f| {
+                f(&format_args!(
                     ", {}",
                     arg.replace(
                         " Content",
-                        " impl FnOnce(&mut W) -> io::Result<()>"
+                        " impl FnOnce(&mut Vec<u8>) -> io::Result<()>"
                     )
-                ))),
-            body = self.body.iter().map(|b| b.code()).format(""),
+                ))
+            }),
+            where_clause = self
+                .where_clause
+                .iter()
+                .format_with("", |w, f| f(&format_args!(", {}", w))),
+            arg_names = self
+                .args
+                .iter()
+                .map(|arg| arg_name(arg).trim_start_matches("mut "))
+                .join(", "),
         )
     }
+
+    /// The number of bytes of static text that is always written by
+    /// the template.
+    fn static_size(&self) -> usize {
+        self.body.iter().map(TemplateExpression::static_size).sum()
+    }
+
+    /// The generic parameters of the template, as written.
+    ///
+    /// If no parameters are declared, any plain type parameter or
+    /// lifetime that is bounded in the where clause is declared
+    /// implicitly, with lifetimes first.
+    fn generic_params(&self) -> Vec<String> {
+        if let Some(ref generics) = self.generics {
+            return split_top_level(generics)
+                .into_iter()
+                .map(String::from)
+                .collect();
+        }
+        let mut params = self
+            .where_clause
+            .iter()
+            .flat_map(|w| split_top_level(w))
+            .filter_map(|predicate| {
+                let lhs = predicate_lhs(predicate)?;
+                let name = lhs.trim_start_matches('\'');
+                if !name.is_empty()
+                    && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+                {
+                    Some(lhs.to_string())
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        params.sort_by_key(|p| !p.starts_with('\''));
+        params.dedup();
+        params
+    }
+}
+
+fn arg_name(arg: &str) -> &str {
+    arg.split(':').next().unwrap_or("").trim()
+}
+
+/// True if `name` occurs in `code` as a whole identifier.
+pub fn contains_ident(code: &str, name: &str) -> bool {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    code.match_indices(name).any(|(i, _)| {
+        !code[..i].chars().next_back().map_or(false, is_ident)
+            && !code[i + name.len()..]
+                .chars()
+                .next()
+                .map_or(false, is_ident)
+    })
+}
+
+/// The name of the constant with the static size of the template
+/// function `name`.
+pub fn static_size_name(name: &str) -> String {
+    format!("{}_STATIC_SIZE", name.to_uppercase())
+}
+
+/// Write the `@context` struct `name` with `fields`.
+///
+/// Each reference in the type of a field gets the lifetime of the
+/// struct.
+fn write_context_struct(
+    out: &mut impl Write,
+    name: &str,
+    fields: &[String],
+) -> io::Result<()> {
+    let fields = fields
+        .iter()
+        .map(|field| {
+            let (name, ty) = field.split_at(field.find(':').unwrap_or(0));
+            (name.trim(), with_lifetime(ty[1..].trim(), "'a"))
+        })
+        .collect::<Vec<_>>();
+    let lifetime = if fields.iter().any(|(_, ty)| ty.contains("&'a ")) {
+        "<'a>"
+    } else {
+        ""
+    };
+    writeln!(
+        out,
+        "\n\
+         /// The context argument of the template.\n\
+         pub struct {name}{lifetime} {{\n\
+         {fields}\
+         }}",
+        name = name,
+        lifetime = lifetime,
+        fields = fields.iter().format_with("", |(name, ty), f| {
+            f(&format_args!("pub {}: {},\n", name, ty))
+        }),
+    )
+}
+
+/// Types known not to have lifetime parameters.
+const NO_LIFETIMES: &[&str] = &[
+    "Arc", "BTreeMap", "BTreeSet", "Box", "HashMap", "HashSet", "Option",
+    "Rc", "String", "Vec", "VecDeque",
+];
+
+/// True if the type in `arg` mentions a type that may have an elided
+/// lifetime parameter, which would need a name in a struct field.
+fn may_hide_lifetime(arg: &str) -> bool {
+    let ty = &arg[arg.find(':').map_or(0, |i| i + 1)..];
+    let mut rest = ty;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_uppercase()) {
+        let is_start = !rest[..start].ends_with(|c: char| {
+            c.is_alphanumeric() || c == '_' || c == '\''
+        });
+        let tail = &rest[start..];
+        let len = tail
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(tail.len());
+        let (name, after) = tail.split_at(len);
+        if is_start
+            && !NO_LIFETIMES.contains(&name)
+            && !after.trim_start().starts_with("<'")
+        {
+            return true;
+        }
+        rest = after;
+    }
+    false
+}
+
+/// The type `ty` with `lifetime` for each reference that does not
+/// have an explicit lifetime, and instead of each `'_`.
+fn with_lifetime(ty: &str, lifetime: &str) -> String {
+    let mut parts = ty.split('&');
+    let mut result = parts.next().unwrap_or("").to_string();
+    for part in parts {
+        if part.starts_with('\'') {
+            result.push('&');
+        } else {
+            result.push('&');
+            result.push_str(lifetime);
+            result.push(' ');
+        }
+        result.push_str(part.trim_start());
+    }
+    let mut parts = result.split("'_");
+    let mut result = parts.next().unwrap_or("").to_string();
+    for part in parts {
+        if part.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+            result.push_str("'_");
+        } else {
+            result.push_str(lifetime);
+        }
+        result.push_str(part);
+    }
+    result
 }
 
-pub fn template(input: &[u8]) -> PResult<Template> {
+/// The name of the constant with the output of the template function
+/// `name`, for a template without expressions.
+pub fn static_text_name(name: &str) -> String {
+    format!("{}_STATIC", name.to_uppercase())
+}
+
+/// The name of the constant with the content type of the template
+/// function `name`.
+pub fn content_type_name(name: &str) -> String {
+    format!("{}_CONTENT_TYPE", name.to_uppercase())
+}
+
+/// The name of the constant for the http status of the template
+/// function `name`.
+pub fn http_status_name(name: &str) -> String {
+    format!("{}_HTTP_STATUS", name.to_uppercase())
+}
+
+/// The names of the validators in the `validators` module of the
+/// template utilities.
+const STANDARD_VALIDATORS: &[&str] = &["in_range", "not_empty", "positive"];
+
+/// The code for a `@validate` rule.
+///
+/// The standard validators are found in the `validators` module,
+/// other validators are in the scope of the template.
+fn validator_path(rule: &str) -> String {
+    let name = rule.split('(').next().unwrap_or("").trim();
+    if STANDARD_VALIDATORS.contains(&name) {
+        format!("super::validators::{}", rule)
+    } else {
+        rule.to_string()
+    }
+}
+
+/// The name of the struct with the named arguments of the template
+/// function `name`, e.g. `PageHtmlArgs` for `page_html`.
+pub fn args_struct_name(name: &str) -> String {
+    let mut result = String::new();
+    for part in name.split('_').filter(|part| !part.is_empty()) {
+        let mut chars = part.chars();
+        result.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+        result.push_str(chars.as_str());
+    }
+    result.push_str("Args");
+    result
+}
+
+/// The name of the constant for the `Cache-Control` header of the
+/// template function `name`.
+pub fn cache_control_name(name: &str) -> String {
+    format!("{}_CACHE_CONTROL", name.to_uppercase())
+}
+
+/// The content type of the output of the template function `name`,
+/// based on the last part of the name, which is from the file suffix.
+fn default_content_type(name: &str) -> &'static str {
+    match name.rsplit('_').next() {
+        Some("svg") => "image/svg+xml",
+        Some("xml") => "application/xml",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "text/html; charset=utf-8",
+    }
+}
+
+/// Split a comma-separated list, ignoring commas inside brackets.
+fn split_top_level(list: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut prev = ' ';
+    for (i, c) in list.char_indices() {
+        match c {
+            '<' | '(' | '[' | '{' => depth += 1,
+            '>' if prev != '-' => depth -= 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(list[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+        prev = c;
+    }
+    result.push(list[start..].trim());
+    result.retain(|p| !p.is_empty());
+    result
+}
+
+/// The bounded part of a where clause predicate, e.g. `T` in
+/// `T: std::fmt::Display`.
+fn predicate_lhs(predicate: &str) -> Option<&str> {
+    let bytes = predicate.as_bytes();
+    (0..bytes.len())
+        .find(|&i| {
+            bytes[i] == b':'
+                && bytes.get(i + 1) != Some(&b':')
+                && (i == 0 || bytes[i - 1] != b':')
+        })
+        .map(|i| predicate[..i].trim())
+}
+
+pub fn template(input: &[u8]) -> PResult<'_, Template> {
+    let (input, (mut t, csp_nonce)) = stop_incomplete(template_head)(input)?;
+    let (input, mut body) = template_body(end_of_file)(input)?;
+    resolve_filters(&mut body, &t.used_names());
+    t.body = match csp_nonce {
+        Some(nonce) => add_csp_nonce(body, nonce),
+        None => body,
+    };
+    resolve_macro_calls(&mut t.body, &mut Vec::new());
+    Ok((input, t))
+}
+
+/// The declarations of a template, up to the body.
+///
+/// The body of the returned template is empty.
+/// Any `@csp_nonce` expression is returned beside the template, since
+/// it is only used for the body.
+pub fn template_head(input: &[u8]) -> PResult<'_, (Template, Option<&str>)> {
     map(
         tuple((
             spacelike,
-            many0(map(
-                delimited(
-                    tag("@"),
-                    map_res(is_not(";()"), input_to_str),
-                    terminated(tag(";"), spacelike),
-                ),
-                String::from,
+            opt(terminated(extends, spacelike)),
+            opt(terminated(email_css, spacelike)),
+            opt(terminated(csp_nonce, spacelike)),
+            opt(terminated(cfg_predicate, spacelike)),
+            many0(terminated(
+                alt((
+                    map(
+                        preceded(tag("@const "), const_declaration),
+                        |(name, ty, expr)| {
+                            format!("const {}: {} = {}", name, ty, expr)
+                        },
+                    ),
+                    map(
+                        delimited(
+                            tag("@"),
+                            map_res(is_not(";()"), input_to_str),
+                            tag(";"),
+                        ),
+                        String::from,
+                    ),
+                )),
+                spacelike,
             )),
+            opt(terminated(context_struct, spacelike)),
+            context("expected '@('...')' template declaration.", tag("@")),
+            opt(generic_params),
             delimited_list(
                 context(
                     "expected '@('...')' template declaration.",
-                    terminated(tag("@("), multispace0),
+                    terminated(tag("("), multispace0),
+                ),
+                context(
+                    "expected formal argument",
+                    map(formal_argument, String::from),
+                ),
+                terminated(tag(","), multispace0),
+                context(
+                    "expected ',' or ')'.",
+                    preceded(multispace0, tag(")")),
+                ),
+            ),
+            opt(preceded(space0, where_clause)),
+            preceded(multispace0, opt(doc_comment)),
+            spacelike,
+            many0(terminated(directive, spacelike)),
+        )),
+        |(
+            (),
+            extends,
+            email_css,
+            csp_nonce,
+            cfg,
+            preamble,
+            context_struct,
+            _,
+            generics,
+            mut args,
+            where_clause,
+            doc,
+            (),
+            directives,
+        )| {
+            let mut content_type = None;
+            let mut status = None;
+            let mut cache_control = None;
+            let mut validations = Vec::new();
+            for directive in directives {
+                match directive {
+                    Directive::ContentType(value) => {
+                        content_type = Some(value)
+                    }
+                    Directive::Status(value) => status = Some(value),
+                    Directive::CacheControl(value) => {
+                        cache_control = Some(value)
+                    }
+                    Directive::Validate(arg, rule) => {
+                        validations.push((arg.to_string(), rule.to_string()))
+                    }
+                }
+            }
+            let context_struct = context_struct.map(|(name, fields)| {
+                args.insert(0, format!("ctx: &{}", name));
+                (
+                    name.to_string(),
+                    fields.into_iter().map(String::from).collect(),
+                )
+            });
+            let t = Template {
+                extends: extends.map(String::from),
+                email_css: email_css.map(String::from),
+                cfg: cfg.map(String::from),
+                preamble,
+                generics: generics.map(String::from),
+                args,
+                where_clause: where_clause.map(String::from),
+                content_type: content_type.map(String::from),
+                status,
+                cache_control: cache_control.map(String::from),
+                validations,
+                panic_message: None,
+                fn_attributes: Vec::new(),
+                max_depth: DEFAULT_MAX_DEPTH,
+                file_name: None,
+                doc,
+                context: context_struct,
+                body: Vec::new(),
+            };
+            (t, csp_nonce)
+        },
+    )(input)
+}
+
+/// The `@const` declarations of a `_globals.rs.html` file, as the
+/// name, type and value of each constant.
+pub fn global_constants(
+    input: &[u8],
+) -> PResult<'_, Vec<(&str, &str, &str)>> {
+    preceded(
+        spacelike,
+        many0(terminated(
+            preceded(tag("@const "), const_declaration),
+            spacelike,
+        )),
+    )(input)
+}
+
+/// A `@* ... *@` comment, as the text of a doc comment.
+///
+/// Each line of the comment is trimmed, and blank lines at the start
+/// and end are removed.
+fn doc_comment(input: &[u8]) -> PResult<'_, String> {
+    map(map_res(recognize(comment), input_to_str), |comment| {
+        let lines = comment[2..comment.len() - 2]
+            .lines()
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        let start = lines.iter().position(|l| !l.is_empty());
+        let end = lines.iter().rposition(|l| !l.is_empty());
+        match (start, end) {
+            (Some(start), Some(end)) => lines[start..=end].join("\n"),
+            _ => String::new(),
+        }
+    })(input)
+}
+
+/// An `@extends "layout.rs.html"` declaration.
+fn extends(input: &[u8]) -> PResult<'_, &str> {
+    preceded(
+        terminated(tag("@extends"), space1),
+        context(
+            "Expected quoted path of layout template",
+            delimited(
+                char('"'),
+                map_res(is_not("\"\n"), input_to_str),
+                char('"'),
+            ),
+        ),
+    )(input)
+}
+
+/// An `@email_css("style.css")` declaration.
+fn email_css(input: &[u8]) -> PResult<'_, &str> {
+    preceded(
+        tag("@email_css("),
+        context(
+            "Expected quoted path of stylesheet",
+            terminated(
+                delimited(
+                    char('"'),
+                    map_res(is_not("\"\n"), input_to_str),
+                    char('"'),
                 ),
+                char(')'),
+            ),
+        ),
+    )(input)
+}
+
+/// An `@context Name { field: Type, ... }` declaration.
+fn context_struct(input: &[u8]) -> PResult<'_, (&str, Vec<&str>)> {
+    preceded(
+        terminated(tag("@context"), space1),
+        pair(
+            terminated(
+                context("Expected name of context struct", rust_name),
+                spacelike,
+            ),
+            delimited_list(
                 context(
-                    "expected formal argument",
-                    map(formal_argument, String::from),
+                    "Expected '{' for context fields",
+                    terminated(tag("{"), multispace0),
                 ),
+                context("Expected context field", formal_argument),
                 terminated(tag(","), multispace0),
                 context(
-                    "expected ',' or ')'.",
-                    delimited(multispace0, tag(")"), spacelike),
+                    "Expected ',' or '}'.",
+                    preceded(multispace0, tag("}")),
                 ),
             ),
-            many_till(
-                context(
-                    "Error in expression starting here:",
-                    template_expression,
+        ),
+    )(input)
+}
+
+/// An `@csp_nonce(expr)` declaration.
+fn csp_nonce(input: &[u8]) -> PResult<'_, &str> {
+    map(
+        delimited(
+            tag("@csp_nonce("),
+            context("Expected nonce expression", expr_inside_parens),
+            char(')'),
+        ),
+        str::trim,
+    )(input)
+}
+
+/// A `@cfg(predicate)` directive, that the template is only compiled
+/// when the predicate is true.
+fn cfg_predicate(input: &[u8]) -> PResult<'_, &str> {
+    map(
+        delimited(
+            tag("@cfg("),
+            context("Expected cfg predicate", expr_inside_parens),
+            char(')'),
+        ),
+        str::trim,
+    )(input)
+}
+
+/// A directive after the arguments of a template.
+enum Directive<'a> {
+    ContentType(&'a str),
+    Status(u16),
+    CacheControl(&'a str),
+    Validate(&'a str, &'a str),
+}
+
+fn directive(input: &[u8]) -> PResult<'_, Directive<'_>> {
+    alt((
+        map(content_type, Directive::ContentType),
+        map(status, Directive::Status),
+        map(cache_control, Directive::CacheControl),
+        map(validate, |(arg, rule)| Directive::Validate(arg, rule)),
+    ))(input)
+}
+
+/// A `@validate(arg: rule)` declaration, of a rule for an argument
+/// that the argument builder checks.
+fn validate(input: &[u8]) -> PResult<'_, (&str, &str)> {
+    preceded(
+        tag("@validate("),
+        pair(
+            context(
+                "Expected argument name",
+                terminated(rust_name, tuple((multispace0, char(':')))),
+            ),
+            context(
+                "Expected validation rule",
+                terminated(map(expr_inside_parens, str::trim), char(')')),
+            ),
+        ),
+    )(input)
+}
+
+/// A `@cache_control("max-age=3600")` declaration.
+///
+/// The value must be valid in an http header, so only visible ascii
+/// characters and spaces are allowed.
+fn cache_control(input: &[u8]) -> PResult<'_, &str> {
+    preceded(
+        tag("@cache_control("),
+        context(
+            "Expected quoted Cache-Control header value",
+            terminated(
+                delimited(
+                    char('"'),
+                    map_res(
+                        take_while1(|c: u8| {
+                            c == b' ' || c.is_ascii_graphic() && c != b'"'
+                        }),
+                        input_to_str,
+                    ),
+                    char('"'),
                 ),
-                end_of_file,
+                char(')'),
             ),
-        )),
-        |((), preamble, args, body)| Template {
-            preamble,
-            args,
-            body: body.0,
-        },
+        ),
+    )(input)
+}
+
+/// A `@status(404)` declaration.
+fn status(input: &[u8]) -> PResult<'_, u16> {
+    preceded(
+        tag("@status("),
+        context(
+            "Expected http status code",
+            terminated(
+                map_res(map_res(digit1, input_to_str), str::parse),
+                char(')'),
+            ),
+        ),
+    )(input)
+}
+
+/// A `@mime("type")` or `@content_type("type")` declaration.
+fn content_type(input: &[u8]) -> PResult<'_, &str> {
+    preceded(
+        terminated(alt((tag("@mime"), tag("@content_type"))), char('(')),
+        context(
+            "Expected quoted content type",
+            terminated(
+                delimited(
+                    char('"'),
+                    map_res(is_not("\"\n"), input_to_str),
+                    char('"'),
+                ),
+                char(')'),
+            ),
+        ),
+    )(input)
+}
+
+/// A list of generic parameters, such as `<'a, T: Display>`.
+///
+/// Returns the content of the angle brackets.
+fn generic_params(input: &[u8]) -> PResult<'_, &str> {
+    delimited(
+        tag("<"),
+        map_res(recognize(in_angle_brackets), input_to_str),
+        context("Expected '>'", tag(">")),
+    )(input)
+}
+
+fn in_angle_brackets(input: &[u8]) -> PResult<'_, ()> {
+    map(
+        many0(alt((
+            value((), tag("->")),
+            value((), is_not("<>-")),
+            value((), tag("-")),
+            value((), delimited(tag("<"), in_angle_brackets, tag(">"))),
+        ))),
+        |_| (),
+    )(input)
+}
+
+/// A where clause is everything after the `where` keyword up to the
+/// end of the line.
+fn where_clause(input: &[u8]) -> PResult<'_, &str> {
+    preceded(
+        terminated(tag("where"), multispace1),
+        map(map_res(is_not("\r\n"), input_to_str), str::trim_end),
     )(input)
 }
 
-fn end_of_file(input: &[u8]) -> PResult<()> {
+fn end_of_file(input: &[u8]) -> PResult<'_, ()> {
     if input.is_empty() {
         Ok((input, ()))
     } else {
@@ -113,7 +1830,7 @@ fn end_of_file(input: &[u8]) -> PResult<()> {
     }
 }
 
-fn formal_argument(input: &[u8]) -> PResult<&str> {
+pub fn formal_argument(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(tuple((
             rust_name,
@@ -126,19 +1843,23 @@ fn formal_argument(input: &[u8]) -> PResult<&str> {
     )(input)
 }
 
-fn type_expression(input: &[u8]) -> PResult<()> {
+pub fn type_expression(input: &[u8]) -> PResult<'_, ()> {
     map(
         tuple((
-            alt((tag("&"), tag(""))),
-            delimited(
-                spacelike,
-                alt((tag("impl"), tag("dyn"), tag(""))),
+            opt(tuple((
+                tag("&"),
+                opt(preceded(spacelike, lifetime)),
+                opt(preceded(spacelike, terminated(tag("mut"), multispace1))),
+            ))),
+            preceded(
                 spacelike,
+                opt(terminated(alt((tag("impl"), tag("dyn"))), multispace1)),
             ),
             context(
                 "Expected rust type expression",
                 alt((
-                    map(rust_name, |_| ()),
+                    map(lifetime, |_| ()),
+                    type_path,
                     map(
                         delimited(tag("["), type_expression, tag("]")),
                         |_| (),
@@ -149,13 +1870,82 @@ fn type_expression(input: &[u8]) -> PResult<()> {
                     ),
                 )),
             ),
-            opt(delimited(tag("<"), comma_type_expressions, tag(">"))),
+            many0(preceded(
+                delimited(spacelike, tag("+"), spacelike),
+                alt((
+                    map(lifetime, |_| ()),
+                    preceded(opt(tag("?")), type_path),
+                )),
+            )),
+        )),
+        |_| (),
+    )(input)
+}
+
+/// A possibly qualified path to a type or trait, such as
+/// `std::fmt::Display`, `Iterator<Item = u8>` or `Fn(u8) -> bool`.
+fn type_path(input: &[u8]) -> PResult<'_, ()> {
+    map(
+        tuple((
+            opt(tag("::")),
+            separated_nonempty_list(tag("::"), path_segment),
         )),
         |_| (),
     )(input)
 }
 
-pub fn comma_type_expressions(input: &[u8]) -> PResult<()> {
+fn path_segment(input: &[u8]) -> PResult<'_, ()> {
+    map(
+        pair(
+            rust_name,
+            opt(alt((
+                map(delimited(tag("<"), generic_args, tag(">")), |_| ()),
+                map(
+                    pair(
+                        delimited(tag("("), comma_type_expressions, tag(")")),
+                        opt(preceded(
+                            delimited(spacelike, tag("->"), spacelike),
+                            type_expression,
+                        )),
+                    ),
+                    |_| (),
+                ),
+            ))),
+        ),
+        |_| (),
+    )(input)
+}
+
+/// The arguments of a generic type, which may include associated
+/// type bindings like `Item = &str`.
+fn generic_args(input: &[u8]) -> PResult<'_, ()> {
+    map(
+        terminated(
+            separated_list(
+                preceded(tag(","), multispace0),
+                alt((
+                    map(
+                        tuple((
+                            rust_name,
+                            delimited(spacelike, tag("="), spacelike),
+                            type_expression,
+                        )),
+                        |_| (),
+                    ),
+                    type_expression,
+                )),
+            ),
+            opt(preceded(tag(","), multispace0)),
+        ),
+        |_| (),
+    )(input)
+}
+
+fn lifetime(input: &[u8]) -> PResult<'_, &str> {
+    map_res(recognize(preceded(char('\''), rust_name)), input_to_str)(input)
+}
+
+pub fn comma_type_expressions(input: &[u8]) -> PResult<'_, ()> {
     map(
         terminated(
             separated_list(preceded(tag(","), multispace0), type_expression),
@@ -167,7 +1957,623 @@ pub fn comma_type_expressions(input: &[u8]) -> PResult<()> {
 
 #[cfg(test)]
 mod test {
-    use template::type_expression;
+    use itertools::Itertools;
+    use template::{
+        global_constants, may_hide_lifetime, template, type_expression,
+        with_lifetime, Template,
+    };
+    use templateexpression::{Escaping, Target, TemplateExpression};
+    use OutputTrait;
+
+    #[test]
+    fn generics_and_where() {
+        let t = parse_template(
+            b"@<'a, T: Into<Vec<u8>>, F: Fn(&T) -> bool>(items: &[T], f: F) where T: Clone + 'a\nbody",
+        );
+        assert_eq!(
+            t.generics.as_deref(),
+            Some("'a, T: Into<Vec<u8>>, F: Fn(&T) -> bool"),
+        );
+        assert_eq!(t.where_clause.as_deref(), Some("T: Clone + 'a"),);
+        assert_eq!(
+            t.generic_params(),
+            ["'a", "T: Into<Vec<u8>>", "F: Fn(&T) -> bool"],
+        );
+    }
+
+    #[test]
+    fn implicit_generics_from_where() {
+        let t = parse_template(
+            b"@(items: &[T]) where T: std::fmt::Display, 'a: 'b, T::Item: Copy, HashMap<K, V>: Send\n",
+        );
+        assert_eq!(t.generics, None);
+        assert_eq!(t.generic_params(), ["'a", "T"]);
+    }
+
+    #[test]
+    fn where_must_be_on_same_line() {
+        let t = parse_template(b"@(x: u8)\nwhere is it?\n");
+        assert_eq!(t.where_clause, None);
+        assert!(t.generic_params().is_empty());
+    }
+
+    #[test]
+    fn use_statements() {
+        let t = parse_template(
+            b"@use std::collections::BTreeMap;\n\
+              @use chrono::{DateTime, Utc};\n\
+              \n\
+              @(when: DateTime<Utc>, map: &BTreeMap<u8, u8>)\n",
+        );
+        assert_eq!(
+            t.preamble,
+            [
+                "use std::collections::BTreeMap",
+                "use chrono::{DateTime, Utc}",
+            ]
+        );
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t", Target::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\nuse std::collections::BTreeMap;\n"));
+        assert!(out.contains("\nuse chrono::{DateTime, Utc};\n"));
+    }
+
+    #[test]
+    fn const_declarations() {
+        let t = parse_template(
+            b"@const SIZE: (u32, u32) = (16, 9);\n\
+              @use std::fmt::Display;\n\
+              @(n: usize)\n\
+              @const CLASS: &str = \"item; wide\";\n\
+              <p class=\"@CLASS\">@n of @SIZE.0</p>",
+        );
+        assert_eq!(
+            t.preamble,
+            ["const SIZE: (u32, u32) = (16, 9)", "use std::fmt::Display"],
+        );
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t", Target::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\nconst SIZE: (u32, u32) = (16, 9);\n"));
+        assert!(out.contains("\nconst CLASS: &str = \"item; wide\";\n"));
+    }
+
+    #[test]
+    fn global_constants_file() {
+        assert_eq!(
+            global_constants(
+                b"@* Site settings *@\n\
+                  @const SITE_NAME: &str = \"My Site\";\n\
+                  @const SIZE: (u8, u8) = (16, 32);\n"
+            ),
+            Ok((
+                &b""[..],
+                vec![
+                    ("SITE_NAME", "&str", "\"My Site\""),
+                    ("SIZE", "(u8, u8)", "(16, 32)"),
+                ],
+            )),
+        );
+    }
+
+    #[test]
+    fn extend_layout() {
+        let layout = parse_template(
+            b"@use std::fmt::Display;\n\
+              @<T: Display>(title: T, lang: &str)\n\
+              <title>@block title {@title}</title>@yield content",
+        );
+        let page = parse_template(
+            b"@extends \"layout.html\"\n\
+              @(title: &str, items: &[u8])\n\
+              @block content {<p>Content</p>}\n",
+        );
+        assert_eq!(page.extends(), Some("layout.html"));
+        let page = page.extend(layout);
+        assert_eq!(page.extends(), None);
+        assert_eq!(page.preamble, ["use std::fmt::Display"]);
+        assert_eq!(page.generic_params(), ["T: Display"]);
+        assert_eq!(page.args, ["title: &str", "items: &[u8]", "lang: &str"]);
+        assert_eq!(
+            page.body
+                .iter()
+                .map(|b| b.code(Target::Sync(OutputTrait::Io), Escaping::Html))
+                .join(""),
+            "_ructe_out_.write_all(b\"<title>\")?;\n\
+             title.to_html(&mut _ructe_out_)?;\n\
+             _ructe_out_.write_all(b\"</title>\")?;\n\
+             _ructe_out_.write_all(b\"<p>Content</p>\")?;\n",
+        );
+    }
+
+    #[test]
+    fn unused_args() {
+        let t = parse_template(
+            b"@(title: &str, items: Vec<u8>, count: usize, _x: u8, \
+              body: Content)\n\
+              <h1>@title</h1>@for i in items {@i}@:body()\n\
+              <p>@counter</p>\n",
+        );
+        assert_eq!(t.unused_args(), ["count"]);
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t", Target::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("{\nlet _ = &count;\n"), "{}", out);
+    }
+
+    #[test]
+    fn email_css() {
+        let t = parse_template(
+            b"@email_css(\"email.css\")\n@use super::Foo;\n@(x: u8)\n@x\n",
+        );
+        assert_eq!(t.email_css(), Some("email.css"));
+        assert_eq!(t.preamble, ["use super::Foo"]);
+    }
+
+    #[test]
+    fn content_type_directive() {
+        let t =
+            parse_template(b"@(x: u8)\n@mime(\"application/json\")\n@x\n");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "data_html", Target::default())
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(
+            "pub const DATA_HTML_CONTENT_TYPE: &str = \"application/json\";"
+        ));
+        let t = parse_template(
+            b"@()\n@content_type(\"text/plain; charset=utf-8\")\nx\n",
+        );
+        assert_eq!(
+            t.content_type.as_deref(),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(t.body, [TemplateExpression::text("x\n")]);
+    }
+
+    #[test]
+    fn status_directive() {
+        let t = parse_template(
+            b"@()\n@status(404)\n@mime(\"text/plain\")\n<p>Not found</p>\n",
+        );
+        assert_eq!(t.status, Some(404));
+        assert_eq!(t.content_type.as_deref(), Some("text/plain"));
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "nf_html", Target::default())
+            .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("pub const NF_HTML_HTTP_STATUS: u16 = 404;"));
+    }
+
+    #[test]
+    fn typed_bindings_and_track_caller() {
+        let t = parse_template(b"@(x: u8)\n@let y: u16 = x.into();<p>@y</p>");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t_html", Target::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\n#[track_caller]\npub fn t_html<W>("));
+        assert!(out.contains("let y: u16 = x.into();\n"));
+        assert!(out.contains(
+            "let mut buf: Vec<u8> = Vec::with_capacity(T_HTML_STATIC_SIZE);"
+        ));
+    }
+
+    #[test]
+    fn cfg_directive() {
+        let t = parse_template(
+            b"@cfg(all(feature = \"admin\", not(test)))\n@use super::Admin;\n\
+              @(admin: &Admin)\n<p>@admin</p>\n",
+        );
+        assert_eq!(t.cfg(), Some("all(feature = \"admin\", not(test))"));
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "admin_html", Target::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "#[cfg(all(feature = \"admin\", not(test)))]\n\
+             #[track_caller]\n\
+             pub fn admin_html<W>("
+        ));
+    }
+
+    #[test]
+    fn doc_comment() {
+        let mut t = parse_template(
+            b"@(title: &str, n: u8)\n@* The front page.\n\n   With a title. *@\n<h1>@title</h1>",
+        );
+        t.set_file_name("page/front.rs.html");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "front_html", Target::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "\n/// Renders the `page/front.rs.html` template.\n\
+             ///\n\
+             /// The front page.\n\
+             ///\n\
+             /// With a title.\n\
+             ///\n\
+             /// Arguments: `title: &str`, `n: u8`.\n\
+             #[track_caller]\n\
+             pub fn front_html<W>("
+        ));
+    }
+
+    #[test]
+    fn recursive_template() {
+        let t = parse_template(
+            b"@(n: u8)\n@if n > 0 {<i>@:count_html(n.saturating_sub(1))</i>}",
+        );
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "count_html", Target::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "const MAX_TEMPLATE_DEPTH: usize = 32;\n\n\
+             /// Renders the `count_html` template.\n\
+             ///\n\
+             /// Arguments: `n: u8`.\n\
+             #[track_caller]\n\
+             pub fn count_html<W>(_ructe_out_: &mut W, n: u8) -> \
+             io::Result<()> where W: ?Sized, for<'_ructe> &'_ructe mut W: Write {\n\
+             count_html_at_depth(_ructe_out_, 0, n)\n\
+             }\n"
+        ));
+        assert!(out.contains(
+            "fn count_html_at_depth<W>(mut _ructe_out_: &mut W, \
+             _depth: usize, n: u8) -> io::Result<()> "
+        ));
+        assert!(out.contains(
+            "if _depth > MAX_TEMPLATE_DEPTH {\n\
+             _ructe_out_.write_all(b\"<!-- max depth exceeded -->\")?;\n\
+             return Ok(());\n"
+        ));
+        assert!(out.contains(
+            "count_html_at_depth::<W>(&mut _ructe_out_, _depth + 1, n.saturating_sub(1))?;"
+        ));
+    }
+
+    #[test]
+    fn recursive_json_template_writes_no_comment() {
+        let t = parse_template(
+            b"@(n: u8)\n@if n > 0 {[@:count_json(n.saturating_sub(1))]}",
+        );
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "count_json", Target::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "if _depth > MAX_TEMPLATE_DEPTH {\n\
+             return Ok(());\n"
+        ));
+        assert!(!out.contains("max depth exceeded"));
+    }
+
+    #[test]
+    fn args_builder() {
+        let t = parse_template(b"@(title: &str, n: usize)\n<p>@title</p>");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "page_html", Target::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "pub struct PageHtmlArgs<'a> {\n\
+             pub title: Option<&'a str>,\n\
+             pub n: Option<usize>,\n\
+             validators: Vec<(&'static str, Box<dyn Fn(&PageHtmlArgs<'a>) \
+             -> Result<(), String> + 'a>)>,\n\
+             }\n"
+        ));
+        assert!(out.contains(
+            "pub fn n(mut self, n: usize) -> Self {\n\
+             self.n = Some(n);\n"
+        ));
+        assert!(out.contains(
+            "pub fn page_html_with_args<'a, W>(_ructe_out_: &mut W, \
+             args: PageHtmlArgs<'a>) -> io::Result<()> "
+        ));
+    }
+
+    #[test]
+    fn validation_rules() {
+        let t = parse_template(
+            b"@(url: &str, n: u8)\n@validate(url: not_empty)\n\
+              @validate(n : in_range(1, 9))\n@validate(n: is_odd)\n\
+              <a href=\"@url\">@n</a>",
+        );
+        assert_eq!(
+            t.validations,
+            [
+                ("url".to_string(), "not_empty".to_string()),
+                ("n".to_string(), "in_range(1, 9)".to_string()),
+                ("n".to_string(), "is_odd".to_string()),
+            ],
+        );
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "link_html", Target::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "match self.n {\n\
+             Some(ref value) => {\n\
+             if let Err(message) = \
+             (super::validators::in_range(1, 9))(value) {\n\
+             errors.push(super::ValidationError { argument: \"n\", message });\n\
+             }\n\
+             if let Err(message) = (is_odd)(value) {\n"
+        ));
+    }
+
+    #[test]
+    fn no_args_builder_for_generic_templates() {
+        let t = parse_template(b"@<T: ToHtml>(x: T)\n@x");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "g_html", Target::default()).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("GHtmlArgs"));
+    }
+
+    #[test]
+    fn hidden_lifetimes() {
+        assert!(may_hide_lifetime("user: &User"));
+        assert!(may_hide_lifetime("users: &[models::User]"));
+        assert!(may_hide_lifetime("s: Cow<str>"));
+        assert!(!may_hide_lifetime("s: Cow<'a, str>"));
+        assert!(!may_hide_lifetime("names: &Vec<Option<String>>"));
+        assert!(!may_hide_lifetime("n: u8"));
+    }
+
+    #[test]
+    fn cache_control_directive() {
+        let t = parse_template(
+            b"@()\n@cache_control(\"max-age=3600, public\")\n<p>List</p>\n",
+        );
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "list_html", Target::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "pub const LIST_HTML_CACHE_CONTROL: Option<&str> = \
+             Some(\"max-age=3600, public\");"
+        ));
+        assert!(out.contains("pub const LIST_HTML_HTTP_STATUS: u16 = 200;"));
+    }
+
+    #[test]
+    fn no_cache_control() {
+        let t = parse_template(b"@()\n<p>Page</p>\n");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "p_html", Target::default()).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(
+            "pub const P_HTML_CACHE_CONTROL: Option<&str> = None;"
+        ));
+    }
+
+    #[test]
+    fn context_struct() {
+        let t = parse_template(
+            b"@context Page { title: &str, items: &[&'static str],\n n: usize }\n\
+              @(x: u8)\n<h1>@ctx.title @x</h1>\n",
+        );
+        assert_eq!(t.args, ["ctx: &Page", "x: u8"]);
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "page_html", Target::default())
+            .unwrap();
+        let code = String::from_utf8(out).unwrap();
+        assert!(code.contains(
+            "pub struct Page<'a> {\n\
+             pub title: &'a str,\n\
+             pub items: &'a [&'static str],\n\
+             pub n: usize,\n\
+             }"
+        ));
+        assert!(code.contains("(mut _ructe_out_: &mut W, ctx: &Page, x: u8)"));
+    }
+
+    #[test]
+    fn elided_lifetimes() {
+        assert_eq!(
+            with_lifetime("&[Cow<'_, str>]", "'s"),
+            "&'s [Cow<'s, str>]",
+        );
+        assert_eq!(with_lifetime("&'a Foo<'_x>", "'s"), "&'a Foo<'_x>",);
+    }
+
+    #[test]
+    fn context_struct_without_references() {
+        let t =
+            parse_template(b"@context Count { n: usize, }\n@()\n@ctx.n\n");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "count_html", Target::default())
+            .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("pub struct Count {\npub n: usize,\n}"));
+    }
+
+    #[test]
+    fn static_template_const() {
+        let t = parse_template(b"@()\n@* footer *@<p>&copy; me</p>\n");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "footer_html", Target::default())
+            .unwrap();
+        let code = String::from_utf8(out).unwrap();
+        assert!(code.contains(
+            "pub const FOOTER_HTML_STATIC: &[u8] = b\"<p>&copy; me</p>\\n\";"
+        ));
+        assert!(code.contains("_ructe_out_.write_all(FOOTER_HTML_STATIC)?;"));
+    }
+
+    #[test]
+    fn static_const_with_literals() {
+        let t = parse_template(b"@()\n<p>@(\"Fish & chips\") x @2</p>\n");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "menu_html", Target::default())
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(
+            "pub const MENU_HTML_STATIC: &[u8] = \
+             b\"<p>Fish &amp; chips x 2</p>\\n\";"
+        ));
+    }
+
+    #[test]
+    fn no_static_const_with_expressions() {
+        let t = parse_template(b"@(x: u8)\n<p>@x</p>\n");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "p_html", Target::default()).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("P_HTML_STATIC:"));
+    }
+
+    #[test]
+    fn csp_nonce() {
+        let t = parse_template(
+            b"@csp_nonce(nonce)\n@(nonce: &str)\n\
+              <script src=\"a.js\"></script>@if true {<style>}\n",
+        );
+        assert_eq!(
+            t.body,
+            [
+                TemplateExpression::text("<script"),
+                TemplateExpression::text(" nonce=\""),
+                TemplateExpression::Expression {
+                    expr: "nonce".into()
+                },
+                TemplateExpression::text("\""),
+                TemplateExpression::text(" src=\"a.js\"></script>"),
+                TemplateExpression::IfBlock {
+                    expr: "true".into(),
+                    body: vec![
+                        TemplateExpression::text("<style"),
+                        TemplateExpression::text(" nonce=\""),
+                        TemplateExpression::Expression {
+                            expr: "nonce".into()
+                        },
+                        TemplateExpression::text("\""),
+                        TemplateExpression::text(">"),
+                    ],
+                    else_body: None,
+                },
+                TemplateExpression::text("\n"),
+            ]
+        );
+    }
+
+    #[cfg(feature = "email")]
+    #[test]
+    fn email_fn() {
+        let mut t = parse_template(b"@email_css(\"email.css\")\n@(x: u8)\n");
+        t.set_email_css("/styles/email.css".into());
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t_html", Target::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(
+            "pub fn t_html_email<W>(_ructe_out_: &mut W, x: u8) \
+             -> io::Result<()> where W: Write + ?Sized {\n\
+             let html = t_html_to_string(x)?;\n"
+        ));
+        assert!(out.contains("include_str!(\"/styles/email.css\")"));
+    }
+
+    #[cfg(feature = "email")]
+    #[test]
+    fn email_fn_without_css() {
+        let t = parse_template(b"@()\n<p>x</p>");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t_html", Target::default()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("pub fn t_html_email<W>("));
+        assert!(out.contains(".extra_css(None)"));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_fn() {
+        let t = parse_template(b"@(x: u8, body: Content)\n<p>@x</p>");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t_html", Target::default()).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(
+            "pub fn t_html_to_bytes_buf(x: u8, body: impl FnOnce(\
+             &mut super::BytesMutWriter) -> io::Result<()>) \
+             -> std::io::Result<bytes::Bytes> {\n\
+             let mut buf: super::BytesMutWriter = super::BytesMutWriter(\
+             bytes::BytesMut::with_capacity(T_HTML_STATIC_SIZE));\n\
+             t_html(&mut buf, x, body)?;\n\
+             Ok(buf.0.freeze())\n"
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_fn() {
+        let t = parse_template(b"@(title: &str, user: &User)\n<p>@title</p>");
+        let mut out = Vec::new();
+        t.write_rust(&mut out, "t_html", Target::default()).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(
+            "pub fn t_html_to_json<T0, T1>(title: T0, user: T1) \
+             -> serde_json::Result<serde_json::Value> \
+             where T0: serde::Serialize, T1: serde::Serialize {\n\
+             #[derive(serde::Serialize)]\n\
+             struct Args<T0, T1> {\n\
+             title: T0,\n\
+             user: T1,\n\
+             }\n\
+             serde_json::to_value(Args { title, user })\n"
+        ));
+    }
+
+    #[test]
+    fn unknown_filter_is_text() {
+        let t = parse_template(b"@(x: &str)\n@x | upper | word\n");
+        assert_eq!(
+            t.body,
+            [
+                TemplateExpression::Expression {
+                    expr: "super::filters::upper(&x)".into(),
+                },
+                TemplateExpression::text(" | word"),
+                TemplateExpression::text("\n"),
+            ],
+        );
+    }
+
+    #[test]
+    fn glob_use_is_not_filters() {
+        let t = parse_template(
+            b"@use std::fmt::*;\n@(title: &str)\n<title>@title | my site</title>\n",
+        );
+        assert_eq!(
+            t.body[1..4],
+            [
+                TemplateExpression::Expression {
+                    expr: "title".into(),
+                },
+                TemplateExpression::text(" | my"),
+                TemplateExpression::text(" site</title>\n"),
+            ],
+        );
+    }
+
+    #[test]
+    fn used_names_are_filters() {
+        let t = parse_template(
+            b"@use helpers::{shout as loud, upper};\n\
+              @(x: &str)\n<p>@x | upper | loud</p>\n",
+        );
+        assert_eq!(t.used_names(), ["loud", "upper"]);
+        assert_eq!(
+            t.body[1],
+            TemplateExpression::Expression {
+                expr: "loud(upper(&x))".into(),
+            },
+        );
+    }
+
+    fn parse_template(input: &[u8]) -> Template {
+        match template(input) {
+            Ok((b"", t)) => t,
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
 
     #[test]
     fn tuple() {
@@ -199,6 +2605,70 @@ mod test {
         check_type_expr("Vec<Foo,>");
     }
 
+    #[test]
+    fn reference_with_lifetime() {
+        check_type_expr("&'a str");
+    }
+
+    #[test]
+    fn mut_reference_with_lifetime() {
+        check_type_expr("&'a mut Vec<u8>");
+    }
+
+    #[test]
+    fn mut_reference() {
+        check_type_expr("&mut String");
+    }
+
+    #[test]
+    fn generic_with_lifetime() {
+        check_type_expr("Cow<'static, str>");
+    }
+
+    #[test]
+    fn lifetimes_in_signature() {
+        let t = parse_template(
+            b"@<'a, 'b: 'a, T>(content: &'a str, items: &'b [Foo<'a, T>])\n",
+        );
+        assert_eq!(t.generic_params(), ["'a", "'b: 'a", "T"]);
+        assert_eq!(t.args, ["content: &'a str", "items: &'b [Foo<'a, T>]"],);
+    }
+
+    #[test]
+    fn dyn_iterator() {
+        check_type_expr("&dyn Iterator<Item=&str>");
+    }
+
+    #[test]
+    fn spaced_binding() {
+        check_type_expr("&mut dyn Iterator<Item = (u8, String)>");
+    }
+
+    #[test]
+    fn impl_fn() {
+        check_type_expr("impl Fn() -> String");
+    }
+
+    #[test]
+    fn impl_fn_with_args_and_bounds() {
+        check_type_expr("impl Fn(&str, u8) -> bool + Send + 'static");
+    }
+
+    #[test]
+    fn boxed_dyn_with_lifetime() {
+        check_type_expr("Box<dyn std::fmt::Display + 'a>");
+    }
+
+    #[test]
+    fn qualified_path() {
+        check_type_expr("::std::collections::HashMap<String, Vec<u8>>");
+    }
+
+    #[test]
+    fn name_starting_with_impl() {
+        check_type_expr("implementation::Type");
+    }
+
     fn check_type_expr(expr: &str) {
         assert_eq!(type_expression(expr.as_bytes()), Ok((&b""[..], ())));
     }
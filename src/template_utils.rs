@@ -4,9 +4,74 @@
 /// This trait decides how to format the given object as html.
 /// There exists a default implementation for any `T: Display` that
 /// formats the value using Display and then html-encodes the result.
+///
+/// The trait is a part of the generated `templates` module in your
+/// crate, so you can implement it for your own types (that does not
+/// implement `Display`) to decide how they are rendered, e.g. to
+/// convert markdown to html or to shorten a long text:
+///
+/// ```ignore
+/// use templates::ToHtml;
+///
+/// pub struct Truncated<'a>(pub &'a str, pub usize);
+///
+/// impl<'a> ToHtml for Truncated<'a> {
+///     fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
+///         match self.0.char_indices().nth(self.1) {
+///             Some((i, _)) => format!("{}…", &self.0[..i]).to_html(out),
+///             None => self.0.to_html(out),
+///         }
+///     }
+/// }
+/// ```
 pub trait ToHtml {
     /// Write self to `out`, which is in html representation.
     fn to_html(&self, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Write self to `out`, a `std::fmt::Write`, in html representation.
+    ///
+    /// This is used by templates generated for `fmt::Write` output.
+    /// The default implementation buffers the output of `to_html`.
+    fn fmt_html(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        let mut buf = Vec::new();
+        self.to_html(&mut buf).map_err(|_| std::fmt::Error)?;
+        out.write_str(&String::from_utf8_lossy(&buf))
+    }
+
+    /// Write self to `out`, which is in xml representation.
+    ///
+    /// This is used by xml templates, such as `feed.rs.xml`.
+    /// The default implementation is the same as `to_html`.
+    fn to_xml(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.to_html(out)
+    }
+
+    /// Write self to `out`, a `std::fmt::Write`, in xml representation.
+    ///
+    /// The default implementation buffers the output of `to_xml`.
+    fn fmt_xml(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        let mut buf = Vec::new();
+        self.to_xml(&mut buf).map_err(|_| std::fmt::Error)?;
+        out.write_str(&String::from_utf8_lossy(&buf))
+    }
+
+    /// Write self to `out`, escaped for use in a json string.
+    ///
+    /// This is used by json templates, such as `data.rs.json`.
+    /// The default implementation json-escapes the output of `to_html`.
+    fn to_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.to_html(&mut JsonEscapingWriter(out))
+    }
+
+    /// Write self to `out`, a `std::fmt::Write`, escaped for use in a
+    /// json string.
+    ///
+    /// The default implementation buffers the output of `to_json`.
+    fn fmt_json(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        let mut buf = Vec::new();
+        self.to_json(&mut buf).map_err(|_| std::fmt::Error)?;
+        out.write_str(&String::from_utf8_lossy(&buf))
+    }
 }
 
 /// Wrapper object for data that should be outputted as raw html
@@ -19,16 +84,787 @@ impl<T: Display> ToHtml for Html<T> {
     fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
         write!(out, "{}", self.0)
     }
+
+    #[inline]
+    fn fmt_html(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        out.write_fmt(format_args!("{}", self.0))
+    }
+
+    #[inline]
+    fn to_xml(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.to_html(out)
+    }
+
+    #[inline]
+    fn fmt_xml(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        self.fmt_html(out)
+    }
+
+    #[inline]
+    fn to_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.to_html(out)
+    }
+
+    #[inline]
+    fn fmt_json(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        self.fmt_html(out)
+    }
+}
+
+/// Wrapper object for data that should be percent-encoded, to be
+/// used as a part of an url, such as a query parameter.
+///
+/// The value is formatted with Display and then encoded with
+/// [`url_encode`], so the result needs no further html escaping.
+///
+/// [`url_encode`]: fn.url_encode.html
+#[allow(dead_code)]
+pub struct UrlEncoded<T>(pub T);
+
+impl<T: Display> ToHtml for UrlEncoded<T> {
+    #[inline]
+    fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(url_encode(&self.0.to_string()).as_bytes())
+    }
+}
+
+/// Percent-encode `s` for use as a part of an url.
+///
+/// Every byte of `s` except the unreserved characters (ascii letters
+/// and digits, `-`, `.`, `_`, and `~`) is encoded as `%XX`.
+#[allow(dead_code)]
+pub fn url_encode(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_'
+            | b'~' => result.push(char::from(b)),
+            b => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+/// Write a hidden form field with a CSRF token.
+///
+/// The output is `<input type="hidden" name="csrf" value="...">`,
+/// with the token html escaped.
+/// In a template, it can be written as `@:csrf_field(token)`, after
+/// importing it with `@use super::csrf_field;`.
+#[allow(dead_code)]
+pub fn csrf_field(out: &mut impl Write, token: &str) -> io::Result<()> {
+    out.write_all(b"<input type=\"hidden\" name=\"csrf\" value=\"")?;
+    token.to_html(out)?;
+    out.write_all(b"\">")
+}
+
+/// Write a meta element with a CSRF token, for use by scripts.
+///
+/// The output is `<meta name="csrf-token" content="...">`, with the
+/// token html escaped.
+#[allow(dead_code)]
+pub fn csrf_meta(out: &mut impl Write, token: &str) -> io::Result<()> {
+    out.write_all(b"<meta name=\"csrf-token\" content=\"")?;
+    token.to_html(out)?;
+    out.write_all(b"\">")
+}
+
+/// Write a labeled text input, with an optional error message.
+///
+/// The output is a `<div class="field">` with a `<label>` and an
+/// `<input type="text">`, where the id of the input is `field-` and
+/// `name`, so the label is paired with it.
+/// With an `error`, the div also gets the class `has-error`, and the
+/// message is written in a `<p class="field-error">` that the input
+/// refers to with `aria-describedby`.
+/// All values are html escaped.
+/// In a template, it can be written as
+/// `@:text_field("email", "Email", email, error)`, after importing it
+/// with `@use super::text_field;`.
+#[allow(dead_code)]
+pub fn text_field(
+    out: &mut impl Write,
+    name: &str,
+    label: &str,
+    value: &str,
+    error: Option<&str>,
+) -> io::Result<()> {
+    input_field(out, "text", name, label, value, error)
+}
+
+/// Write a labeled input of the given `kind`, such as `"email"` or
+/// `"password"`, as for [`text_field`].
+///
+/// [`text_field`]: fn.text_field.html
+#[allow(dead_code)]
+pub fn input_field(
+    out: &mut impl Write,
+    kind: &str,
+    name: &str,
+    label: &str,
+    value: &str,
+    error: Option<&str>,
+) -> io::Result<()> {
+    field_start(out, name, label, error)?;
+    out.write_all(b"<input type=\"")?;
+    kind.to_html(out)?;
+    out.write_all(b"\"")?;
+    field_attributes(out, name, error)?;
+    out.write_all(b" value=\"")?;
+    value.to_html(out)?;
+    out.write_all(b"\">")?;
+    field_end(out, name, error)
+}
+
+/// Write a labeled `<textarea>`, as for [`text_field`].
+///
+/// [`text_field`]: fn.text_field.html
+#[allow(dead_code)]
+pub fn textarea_field(
+    out: &mut impl Write,
+    name: &str,
+    label: &str,
+    value: &str,
+    error: Option<&str>,
+) -> io::Result<()> {
+    field_start(out, name, label, error)?;
+    out.write_all(b"<textarea")?;
+    field_attributes(out, name, error)?;
+    out.write_all(b">")?;
+    value.to_html(out)?;
+    out.write_all(b"</textarea>")?;
+    field_end(out, name, error)
+}
+
+/// Write a labeled `<select>`, as for [`text_field`].
+///
+/// Each of the `options` is a value and the text shown for it, and
+/// the option with the value `selected` is selected.
+///
+/// [`text_field`]: fn.text_field.html
+#[allow(dead_code)]
+pub fn select_field(
+    out: &mut impl Write,
+    name: &str,
+    label: &str,
+    options: &[(&str, &str)],
+    selected: &str,
+    error: Option<&str>,
+) -> io::Result<()> {
+    field_start(out, name, label, error)?;
+    out.write_all(b"<select")?;
+    field_attributes(out, name, error)?;
+    out.write_all(b">")?;
+    for (value, text) in options {
+        out.write_all(b"<option value=\"")?;
+        value.to_html(out)?;
+        if *value == selected {
+            out.write_all(b"\" selected>")?;
+        } else {
+            out.write_all(b"\">")?;
+        }
+        text.to_html(out)?;
+        out.write_all(b"</option>")?;
+    }
+    out.write_all(b"</select>")?;
+    field_end(out, name, error)
+}
+
+/// Write the start of a form field, up to the control.
+#[allow(dead_code)]
+fn field_start(
+    out: &mut impl Write,
+    name: &str,
+    label: &str,
+    error: Option<&str>,
+) -> io::Result<()> {
+    if error.is_some() {
+        out.write_all(b"<div class=\"field has-error\"><label for=\"field-")?;
+    } else {
+        out.write_all(b"<div class=\"field\"><label for=\"field-")?;
+    }
+    name.to_html(out)?;
+    out.write_all(b"\">")?;
+    label.to_html(out)?;
+    out.write_all(b"</label>")
+}
+
+/// Write the id and name attributes of a form control, and the aria
+/// attributes for an error.
+#[allow(dead_code)]
+fn field_attributes(
+    out: &mut impl Write,
+    name: &str,
+    error: Option<&str>,
+) -> io::Result<()> {
+    out.write_all(b" id=\"field-")?;
+    name.to_html(out)?;
+    out.write_all(b"\" name=\"")?;
+    name.to_html(out)?;
+    if error.is_some() {
+        out.write_all(b"\" aria-invalid=\"true\" aria-describedby=\"field-")?;
+        name.to_html(out)?;
+        out.write_all(b"-error")?;
+    }
+    out.write_all(b"\"")
+}
+
+/// Write the end of a form field, with the error message if any.
+#[allow(dead_code)]
+fn field_end(
+    out: &mut impl Write,
+    name: &str,
+    error: Option<&str>,
+) -> io::Result<()> {
+    if let Some(error) = error {
+        out.write_all(b"<p class=\"field-error\" id=\"field-")?;
+        name.to_html(out)?;
+        out.write_all(b"-error\">")?;
+        error.to_html(out)?;
+        out.write_all(b"</p>")?;
+    }
+    out.write_all(b"</div>")
+}
+
+/// A writer that fails when more than a limited number of bytes are
+/// written to it.
+///
+/// Wrap the output of a template in it to limit the size of the
+/// rendered output:
+///
+/// ```ignore
+/// let mut buf = Vec::new();
+/// templates::page_html(&mut LimitedWriter::new(&mut buf, 65536), args)?;
+/// ```
+///
+/// A write that crosses the limit writes the bytes up to the limit,
+/// and the next write fails with a "template output too large"
+/// error, so `write_all` of a too large buffer fails.
+/// Since the writer is not buffered, the output written before the
+/// error is already in the wrapped writer.
+#[allow(dead_code)]
+pub struct LimitedWriter<W> {
+    inner: W,
+    remaining: usize,
+}
+
+#[allow(dead_code)]
+impl<W: Write> LimitedWriter<W> {
+    /// Wrap `inner`, allowing at most `limit` bytes to be written.
+    pub fn new(inner: W, limit: usize) -> Self {
+        LimitedWriter {
+            inner,
+            remaining: limit,
+        }
+    }
+
+    /// The number of bytes that can still be written.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Get the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "template output too large",
+            ));
+        }
+        let len = buf.len().min(self.remaining);
+        let written = self.inner.write(&buf[..len])?;
+        self.remaining -= written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A number written with the digit grouping and decimal separator of
+/// a locale, such as `1,234.5` for `"en"` and `1.234,5` for `"de-DE"`.
+///
+/// The number is formatted with Display and then the separators are
+/// changed, so a fixed number of decimals can be had by wrapping a
+/// formatted string, as in
+/// `LocaleNumber::new(format!("{:.2}", price), locale)`.
+/// The locale is given explicitly, usually from an argument of the
+/// template:
+///
+/// ```ignore
+/// @use super::LocaleNumber;
+///
+/// @(total: f64, locale: &str)
+/// <p>Total: @LocaleNumber::new(total, locale)</p>
+/// ```
+///
+/// The separators for some common languages are built in.  With the
+/// `num-format` feature of ructe, they are taken from the [num-format]
+/// crate instead, which the crate using the templates then needs a
+/// dependency on.
+///
+/// [num-format]: https://crates.rs/crates/num-format
+#[allow(dead_code)]
+pub struct LocaleNumber<'a, T> {
+    value: T,
+    locale: &'a str,
+}
+
+#[allow(dead_code)]
+impl<'a, T> LocaleNumber<'a, T> {
+    /// Write `value` as a number in `locale`.
+    pub fn new(value: T, locale: &'a str) -> Self {
+        LocaleNumber { value, locale }
+    }
+}
+
+impl<'a, T: Display> Display for LocaleNumber<'a, T> {
+    fn fmt(&self, out: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (group, decimal) = number_separators(self.locale);
+        let text = self.value.to_string();
+        let (sign, digits) = match text.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", &text[..]),
+        };
+        let int_len = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        out.write_str(sign)?;
+        for i in 0..int_len {
+            if i > 0 && (int_len - i) % 3 == 0 {
+                out.write_str(group)?;
+            }
+            out.write_str(&digits[i..=i])?;
+        }
+        match digits[int_len..].strip_prefix('.') {
+            Some(fraction) => write!(out, "{}{}", decimal, fraction),
+            None => out.write_str(&digits[int_len..]),
+        }
+    }
+}
+
+/// A date written in the style of a locale, such as `Jan 5, 2024` for
+/// `"en-US"` and `5. Januar 2024` for `"de"`.
+///
+/// The date can be any [`CalendarDate`], such as a `(year, month, day)`
+/// tuple.
+/// Dates in languages without a built in style are written as
+/// `2024-01-05`.
+///
+/// [`CalendarDate`]: trait.CalendarDate.html
+#[allow(dead_code)]
+pub struct LocaleDate<'a, T> {
+    date: T,
+    locale: &'a str,
+}
+
+#[allow(dead_code)]
+impl<'a, T> LocaleDate<'a, T> {
+    /// Write `date` as a date in `locale`.
+    pub fn new(date: T, locale: &'a str) -> Self {
+        LocaleDate { date, locale }
+    }
+}
+
+/// A date that can be written by [`LocaleDate`].
+///
+/// Implement this for the date type of your crate, e.g. by calling the
+/// methods of `chrono::Datelike` for a `chrono::NaiveDate`.
+///
+/// [`LocaleDate`]: struct.LocaleDate.html
+pub trait CalendarDate {
+    /// The year, e.g. 2024.
+    fn year(&self) -> i32;
+    /// The month, from 1 for January to 12.
+    fn month(&self) -> u32;
+    /// The day of the month, starting at 1.
+    fn day(&self) -> u32;
+}
+
+impl CalendarDate for (i32, u32, u32) {
+    fn year(&self) -> i32 {
+        self.0
+    }
+    fn month(&self) -> u32 {
+        self.1
+    }
+    fn day(&self) -> u32 {
+        self.2
+    }
+}
+
+impl<'a, T: CalendarDate> Display for LocaleDate<'a, T> {
+    fn fmt(&self, out: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (year, day) = (self.date.year(), self.date.day());
+        let locale = self.locale.to_ascii_lowercase().replace('_', "-");
+        let lang = locale.split('-').next().unwrap_or("");
+        let months = match lang {
+            "en" => MONTHS_EN,
+            "de" => MONTHS_DE,
+            "es" => MONTHS_ES,
+            "fr" => MONTHS_FR,
+            "sv" => MONTHS_SV,
+            _ => &[],
+        };
+        let index = (self.date.month() as usize).wrapping_sub(1);
+        let month = match months.get(index) {
+            Some(month) => month,
+            None => {
+                let month = self.date.month();
+                return write!(out, "{:04}-{:02}-{:02}", year, month, day);
+            }
+        };
+        match lang {
+            "en" if locale == "en" || locale == "en-us" => {
+                write!(out, "{} {}, {}", &month[..3], day, year)
+            }
+            "en" => write!(out, "{} {} {}", day, &month[..3], year),
+            "de" => write!(out, "{}. {} {}", day, month, year),
+            "es" => write!(out, "{} de {} de {}", day, month, year),
+            _ => write!(out, "{} {} {}", day, month, year),
+        }
+    }
+}
+
+const MONTHS_EN: &[&str] = &[
+    "January", "February", "March", "April", "May", "June", "July",
+    "August", "September", "October", "November", "December",
+];
+const MONTHS_DE: &[&str] = &[
+    "Januar", "Februar", "März", "April", "Mai", "Juni", "Juli", "August",
+    "September", "Oktober", "November", "Dezember",
+];
+const MONTHS_ES: &[&str] = &[
+    "enero", "febrero", "marzo", "abril", "mayo", "junio", "julio",
+    "agosto", "septiembre", "octubre", "noviembre", "diciembre",
+];
+const MONTHS_FR: &[&str] = &[
+    "janvier", "février", "mars", "avril", "mai", "juin", "juillet", "août",
+    "septembre", "octobre", "novembre", "décembre",
+];
+const MONTHS_SV: &[&str] = &[
+    "januari", "februari", "mars", "april", "maj", "juni", "juli",
+    "augusti", "september", "oktober", "november", "december",
+];
+
+/// The standard filters for the `@value | filter` syntax.
+///
+/// Each filter takes the value (or the result of the previous filter)
+/// as its first argument, followed by any arguments given in the
+/// template, so `@name | truncate(20)` is `truncate(&name, 20)`.
+/// Other functions in scope of the template can be used as filters
+/// in the same way.
+#[allow(dead_code)]
+pub mod filters {
+    use std::fmt::Display;
+
+    /// The value in uppercase.
+    pub fn upper(value: impl Display) -> String {
+        value.to_string().to_uppercase()
+    }
+
+    /// The value in lowercase.
+    pub fn lower(value: impl Display) -> String {
+        value.to_string().to_lowercase()
+    }
+
+    /// The value without leading and trailing whitespace.
+    pub fn trim(value: impl Display) -> String {
+        value.to_string().trim().to_string()
+    }
+
+    /// The first `len` characters of the value, followed by `…` if
+    /// the value is longer.
+    pub fn truncate(value: impl Display, len: usize) -> String {
+        let value = value.to_string();
+        match value.char_indices().nth(len) {
+            Some((i, _)) => format!("{}…", &value[..i]),
+            None => value,
+        }
+    }
+
+    /// The value with each `from` replaced by `to`.
+    pub fn replace(value: impl Display, from: &str, to: &str) -> String {
+        value.to_string().replace(from, to)
+    }
+
+    /// The value, or `fallback` if the value is empty.
+    pub fn default(value: impl Display, fallback: impl Display) -> String {
+        match value.to_string() {
+            value if value.is_empty() => fallback.to_string(),
+            value => value,
+        }
+    }
+}
+
+/// A problem with an argument of a template, found by the `validate`
+/// method of the argument builder of the template.
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    /// The name of the argument.
+    pub argument: &'static str,
+    /// What is wrong with the argument.
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, out: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(out, "{}: {}", self.argument, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// The standard validators for `@validate(arg: rule)` declarations.
+///
+/// A validator gets a reference to the value of the argument, and
+/// returns an error message if the value is not valid.
+/// Other functions in scope of the template can be used as validators
+/// in the same way.
+#[allow(dead_code)]
+pub mod validators {
+    use std::fmt::Display;
+
+    /// A value that has a length, for [`not_empty`].
+    ///
+    /// [`not_empty`]: fn.not_empty.html
+    pub trait Length {
+        /// The length of the value.
+        fn length(&self) -> usize;
+    }
+
+    impl Length for str {
+        fn length(&self) -> usize {
+            self.len()
+        }
+    }
+
+    impl Length for String {
+        fn length(&self) -> usize {
+            self.len()
+        }
+    }
+
+    impl<T> Length for [T] {
+        fn length(&self) -> usize {
+            self.len()
+        }
+    }
+
+    impl<T> Length for Vec<T> {
+        fn length(&self) -> usize {
+            self.len()
+        }
+    }
+
+    impl<T: Length + ?Sized> Length for &T {
+        fn length(&self) -> usize {
+            (**self).length()
+        }
+    }
+
+    /// The value must not be empty.
+    pub fn not_empty<T: Length + ?Sized>(value: &T) -> Result<(), String> {
+        if value.length() == 0 {
+            Err("must not be empty".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The value must be larger than zero.
+    pub fn positive<T: PartialOrd + Default>(value: &T) -> Result<(), String> {
+        if *value > T::default() {
+            Ok(())
+        } else {
+            Err("must be positive".into())
+        }
+    }
+
+    /// The value must be between `min` and `max`, inclusive.
+    pub fn in_range<T: PartialOrd + Display>(
+        min: T,
+        max: T,
+    ) -> impl Fn(&T) -> Result<(), String> {
+        move |value| {
+            if min <= *value && *value <= max {
+                Ok(())
+            } else {
+                Err(format!("must be between {} and {}", min, max))
+            }
+        }
+    }
+}
+
+/// The most buffers kept by [`with_pooled_buffer`] for each thread.
+///
+/// [`with_pooled_buffer`]: fn.with_pooled_buffer.html
+const BUFFER_POOL_SIZE: usize = 16;
+
+thread_local! {
+    static BUFFER_POOL: std::cell::RefCell<Vec<Vec<u8>>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Call `f` with an empty buffer from a pool for the current thread.
+///
+/// After `f` returns, the buffer is cleared and put back in the pool,
+/// so rendering many small templates reuses the same allocations.
+/// Calls may be nested; an inner call just gets another buffer.
+///
+/// ```ignore
+/// let len = with_pooled_buffer(|buf| {
+///     item_html(buf, &item)?;
+///     socket.write_all(buf)?;
+///     Ok(buf.len())
+/// })?;
+/// ```
+#[allow(dead_code)]
+pub fn with_pooled_buffer<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vec<u8>) -> R,
+{
+    let mut buf = BUFFER_POOL
+        .with(|pool| pool.borrow_mut().pop())
+        .unwrap_or_default();
+    let result = f(&mut buf);
+    buf.clear();
+    BUFFER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < BUFFER_POOL_SIZE {
+            pool.push(buf);
+        }
+    });
+    result
+}
+
+/// Wrapper object for data that should be escaped for use inside a
+/// javascript string literal, e.g. in an inline `<script>` element.
+///
+/// Backslashes, quotes, backticks, forward slashes (so that the value
+/// can't end the script element with `</script>`), control characters
+/// and the U+2028 and U+2029 line terminators are escaped.
+/// No quotes are added around the value, so it should be written
+/// inside quotes in the template, like `"@JsString(value)"`.
+#[allow(dead_code)]
+pub struct JsString<T>(pub T);
+
+impl<T: Display> ToHtml for JsString<T> {
+    #[inline]
+    fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut buf = String::new();
+        for c in self.0.to_string().chars() {
+            match c {
+                '\\' => buf.push_str("\\\\"),
+                '"' => buf.push_str("\\\""),
+                '\'' => buf.push_str("\\'"),
+                '`' => buf.push_str("\\`"),
+                '/' => buf.push_str("\\/"),
+                '\n' => buf.push_str("\\n"),
+                '\r' => buf.push_str("\\r"),
+                '\t' => buf.push_str("\\t"),
+                '\u{2028}' | '\u{2029}' => {
+                    buf.push_str(&format!("\\u{:04X}", c as u32))
+                }
+                c if c.is_control() => {
+                    buf.push_str(&format!("\\u{:04X}", c as u32))
+                }
+                c => buf.push(c),
+            }
+        }
+        out.write_all(buf.as_bytes())
+    }
+}
+
+/// Wrapper object for data that should be escaped for use inside a
+/// json string, e.g. in a json template or a `data-` attribute.
+///
+/// Double quotes, backslashes and control characters are escaped.
+/// In html and xml templates, the json string is then html escaped,
+/// so it can't end the attribute it is written in.
+/// No quotes are added around the value, so it should be written
+/// inside quotes in the template, like `"@JsonString(value)"`.
+#[allow(dead_code)]
+pub struct JsonString<T>(pub T);
+
+impl<T: Display> ToHtml for JsonString<T> {
+    #[inline]
+    fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut json = Vec::new();
+        self.to_json(&mut json)?;
+        ToHtmlEscapingWriter(out, HTML_APOS).write_all(&json)
+    }
+
+    #[inline]
+    fn to_xml(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut json = Vec::new();
+        self.to_json(&mut json)?;
+        ToHtmlEscapingWriter(out, XML_APOS).write_all(&json)
+    }
+
+    #[inline]
+    fn to_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(JsonEscapingWriter(out), "{}", self.0)
+    }
 }
 
 impl<T: Display> ToHtml for T {
     #[inline]
     fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
-        write!(ToHtmlEscapingWriter(out), "{}", self)
+        write!(ToHtmlEscapingWriter(out, HTML_APOS), "{}", self)
+    }
+
+    #[inline]
+    fn fmt_html(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        std::fmt::Write::write_fmt(
+            &mut ToHtmlEscapingFmtWriter(out, HTML_APOS),
+            format_args!("{}", self),
+        )
+    }
+
+    #[inline]
+    fn to_xml(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(ToHtmlEscapingWriter(out, XML_APOS), "{}", self)
+    }
+
+    #[inline]
+    fn fmt_xml(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        std::fmt::Write::write_fmt(
+            &mut ToHtmlEscapingFmtWriter(out, XML_APOS),
+            format_args!("{}", self),
+        )
+    }
+
+    #[inline]
+    fn to_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(JsonEscapingWriter(out), "{}", self)
+    }
+
+    #[inline]
+    fn fmt_json(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        std::fmt::Write::write_fmt(
+            &mut JsonEscapingFmtWriter(out),
+            format_args!("{}", self),
+        )
     }
 }
 
-struct ToHtmlEscapingWriter<'a>(&'a mut dyn Write);
+/// How a `'` is escaped in html.
+const HTML_APOS: &str = "&#39;";
+/// How a `'` is escaped in xml.
+const XML_APOS: &str = "&apos;";
+
+/// A writer that escapes everything written to it, using the given
+/// escape for `'`.
+struct ToHtmlEscapingWriter<'a>(&'a mut dyn Write, &'static str);
 
 impl<'a> Write for ToHtmlEscapingWriter<'a> {
     #[inline]
@@ -37,16 +873,11 @@ impl<'a> Write for ToHtmlEscapingWriter<'a> {
     // (it is a part of `write_all`'s loop or similar.)
     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
         // quickly skip over data that doesn't need escaping
-        let n = data
-            .iter()
-            .take_while(|&&c| {
-                c != b'"' && c != b'&' && c != b'\'' && c != b'<' && c != b'>'
-            })
-            .count();
+        let n = html_safe_len(data);
         if n > 0 {
             self.0.write(&data[0..n])
         } else {
-            Self::write_one_byte_escaped(&mut self.0, data)
+            Self::write_one_byte_escaped(&mut self.0, data, self.1)
         }
     }
 
@@ -61,8 +892,9 @@ impl<'a> ToHtmlEscapingWriter<'a> {
     fn write_one_byte_escaped(
         out: &mut impl Write,
         data: &[u8],
+        apos: &str,
     ) -> io::Result<usize> {
-        let next = data.get(0);
+        let next = data.first();
         out.write_all(match next {
             Some(b'"') => b"&quot;",
             Some(b'&') => b"&amp;",
@@ -71,8 +903,129 @@ impl<'a> ToHtmlEscapingWriter<'a> {
             None => return Ok(0),
             // we know this function is called only for chars that need escaping,
             // so we don't have to handle the "other" case (this one is for `'`)
-            _ => b"&#39;",
+            _ => apos.as_bytes(),
         })?;
         Ok(1)
     }
 }
+
+struct ToHtmlEscapingFmtWriter<'a>(
+    &'a mut dyn std::fmt::Write,
+    &'static str,
+);
+
+impl<'a> std::fmt::Write for ToHtmlEscapingFmtWriter<'a> {
+    fn write_str(&mut self, mut s: &str) -> std::fmt::Result {
+        while let Some(i) = s.find(['"', '&', '\'', '<', '>']) {
+            self.0.write_str(&s[..i])?;
+            self.0.write_str(match s.as_bytes()[i] {
+                b'"' => "&quot;",
+                b'&' => "&amp;",
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                _ => self.1,
+            })?;
+            s = &s[i + 1..];
+        }
+        self.0.write_str(s)
+    }
+}
+
+/// Escape a byte for json, if needed.
+fn json_escape(byte: u8) -> Option<String> {
+    match byte {
+        b'"' => Some("\\\"".into()),
+        b'\\' => Some("\\\\".into()),
+        b if b < 0x20 => Some(format!("\\u{:04x}", b)),
+        _ => None,
+    }
+}
+
+struct JsonEscapingWriter<'a>(&'a mut dyn Write);
+
+impl<'a> Write for JsonEscapingWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for (i, &b) in data.iter().enumerate() {
+            if let Some(escaped) = json_escape(b) {
+                self.0.write_all(&data[start..i])?;
+                self.0.write_all(escaped.as_bytes())?;
+                start = i + 1;
+            }
+        }
+        self.0.write_all(&data[start..])?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+struct JsonEscapingFmtWriter<'a>(&'a mut dyn std::fmt::Write);
+
+impl<'a> std::fmt::Write for JsonEscapingFmtWriter<'a> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let mut start = 0;
+        for (i, &b) in s.as_bytes().iter().enumerate() {
+            if let Some(escaped) = json_escape(b) {
+                self.0.write_str(&s[start..i])?;
+                self.0.write_str(&escaped)?;
+                start = i + 1;
+            }
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+/// Assert that calling a template gives the expected output.
+///
+/// The template is called with a buffer as its first argument,
+/// followed by the given arguments, and the output is compared to
+/// the expected string.
+/// On failure, both the rendered and the expected output is shown.
+///
+/// ```ignore
+/// assert_template_eq!(hello_html("World"), "<h1>Hello World!</h1>\n");
+/// ```
+#[allow(unused_macros)]
+macro_rules! assert_template_eq {
+    ($($t:ident)::+ ($($arg:expr),* $(,)*), $expected:expr $(,)*) => {{
+        let mut buf = Vec::new();
+        $($t)::+(&mut buf, $($arg),*).unwrap();
+        let rendered = String::from_utf8(buf).unwrap();
+        let expected: &str = &$expected;
+        if rendered != expected {
+            panic!(
+                "template output differs\n--- rendered:\n{}\n\
+                 --- expected:\n{}\n---",
+                rendered, expected,
+            );
+        }
+    }};
+}
+
+/// Assert that the output of calling a template contains a substring.
+///
+/// The template is called just as for [`assert_template_eq`], and the
+/// full rendered output is shown on failure.
+///
+/// ```ignore
+/// assert_template_contains!(hello_html("World"), "World");
+/// ```
+#[allow(unused_macros)]
+macro_rules! assert_template_contains {
+    ($($t:ident)::+ ($($arg:expr),* $(,)*), $expected:expr $(,)*) => {{
+        let mut buf = Vec::new();
+        $($t)::+(&mut buf, $($arg),*).unwrap();
+        let rendered = String::from_utf8(buf).unwrap();
+        let expected: &str = &$expected;
+        if !rendered.contains(expected) {
+            panic!(
+                "template output does not contain {:?}\n\
+                 --- rendered:\n{}\n---",
+                expected, rendered,
+            );
+        }
+    }};
+}
@@ -0,0 +1,113 @@
+use actix_web::body::BoxBody;
+use actix_web::http::{header::CACHE_CONTROL, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, Responder};
+
+/// A template call that can be returned from an [actix-web] handler.
+///
+/// The template is rendered to a buffer when the response is created,
+/// and the content type of the response is set to
+/// `text/html; charset=utf-8`.
+/// If rendering the template fails, an internal server error is
+/// returned instead.
+///
+/// # Examples
+///
+/// Given a template `page`, that takes two arguments other than the
+/// `Write` buffer, this handler will render it with `title` and
+/// `body` as the response.
+///
+/// ```ignore
+/// async fn handler() -> impl Responder {
+///     let (title, body) = ("Hello", "World");
+///     RenderedTemplate(move |o: &mut Vec<u8>| page(o, title, body))
+/// }
+/// ```
+///
+/// [actix-web]: https://crates.rs/crates/actix-web
+#[allow(dead_code)]
+pub struct RenderedTemplate<F>(pub F)
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>;
+
+impl<F> Responder for RenderedTemplate<F>
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<BoxBody> {
+        actix_response_as(200, "text/html; charset=utf-8", self.0)
+    }
+}
+
+/// Render a template as an actix-web response with the given status
+/// and content type.
+///
+/// The status and content type would normally be the `HTTP_STATUS`
+/// and `CONTENT_TYPE` constants of the template:
+///
+/// ```ignore
+/// actix_response_as(
+///     NOT_FOUND_HTML_HTTP_STATUS,
+///     NOT_FOUND_HTML_CONTENT_TYPE,
+///     |o| not_found_html(o, path),
+/// )
+/// ```
+///
+/// If rendering the template fails, or the status is not a valid http
+/// status, an internal server error is returned instead.
+#[allow(dead_code)]
+pub fn actix_response_as<F>(
+    status: u16,
+    content_type: &'static str,
+    f: F,
+) -> HttpResponse
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    actix_response_cached(status, content_type, None, f)
+}
+
+/// Render a template as an actix-web response with the given status,
+/// content type and `Cache-Control` header.
+///
+/// This is as [`actix_response_as`], with the cache control header
+/// normally being the `CACHE_CONTROL` constant of the template.
+/// When it is `None`, no `Cache-Control` header is set.
+///
+/// ```ignore
+/// actix_response_cached(
+///     LIST_HTML_HTTP_STATUS,
+///     LIST_HTML_CONTENT_TYPE,
+///     LIST_HTML_CACHE_CONTROL,
+///     |o| list_html(o, products),
+/// )
+/// ```
+///
+/// [`actix_response_as`]: fn.actix_response_as.html
+#[allow(dead_code)]
+pub fn actix_response_cached<F>(
+    status: u16,
+    content_type: &'static str,
+    cache_control: Option<&'static str>,
+    f: F,
+) -> HttpResponse
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    let mut buf = Vec::new();
+    match (StatusCode::from_u16(status), f(&mut buf)) {
+        (Ok(status), Ok(())) => {
+            let mut response = HttpResponse::build(status);
+            response.content_type(content_type);
+            if let Some(cache_control) = cache_control {
+                response.insert_header((CACHE_CONTROL, cache_control));
+            }
+            response.body(buf)
+        }
+        (Err(e), _) => HttpResponse::InternalServerError()
+            .body(format!("Invalid status for template: {}", e)),
+        (_, Err(e)) => HttpResponse::InternalServerError()
+            .body(format!("Failed to render template: {}", e)),
+    }
+}
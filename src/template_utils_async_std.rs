@@ -0,0 +1,79 @@
+
+/// The async counterpart of [`ToHtml`], used by the `_async` template
+/// functions generated for async-std.
+///
+/// There is an implementation for every `ToHtml` value, that renders
+/// the value to a buffer and then writes the buffer to a
+/// `futures::io::AsyncWrite`.
+pub trait ToHtmlAsync {
+    /// Write self to `out`, which is in html representation.
+    fn to_html_async<W>(
+        &self,
+        out: &mut W,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        W: futures::io::AsyncWrite + Unpin + ?Sized;
+
+    /// Write self to `out`, which is in xml representation.
+    fn to_xml_async<W>(
+        &self,
+        out: &mut W,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        W: futures::io::AsyncWrite + Unpin + ?Sized;
+
+    /// Write self to `out`, escaped for use in a json string.
+    fn to_json_async<W>(
+        &self,
+        out: &mut W,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        W: futures::io::AsyncWrite + Unpin + ?Sized;
+}
+
+impl<T: ToHtml + ?Sized> ToHtmlAsync for T {
+    fn to_html_async<W>(
+        &self,
+        out: &mut W,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        W: futures::io::AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = Vec::new();
+        let result = self.to_html(&mut buf);
+        async move {
+            result?;
+            futures::io::AsyncWriteExt::write_all(out, &buf).await
+        }
+    }
+
+    fn to_xml_async<W>(
+        &self,
+        out: &mut W,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        W: futures::io::AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = Vec::new();
+        let result = self.to_xml(&mut buf);
+        async move {
+            result?;
+            futures::io::AsyncWriteExt::write_all(out, &buf).await
+        }
+    }
+
+    fn to_json_async<W>(
+        &self,
+        out: &mut W,
+    ) -> impl std::future::Future<Output = io::Result<()>>
+    where
+        W: futures::io::AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = Vec::new();
+        let result = self.to_json(&mut buf);
+        async move {
+            result?;
+            futures::io::AsyncWriteExt::write_all(out, &buf).await
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+/// A template call that can be returned from an [axum] handler.
+///
+/// The template is rendered to a buffer when the response is created,
+/// and the content type of the response is set to
+/// `text/html; charset=utf-8`.
+/// If rendering the template fails, an internal server error is
+/// returned instead.
+///
+/// # Examples
+///
+/// Given a template `page`, that takes two arguments other than the
+/// `Write` buffer, this handler will render it with `title` and
+/// `body` as the response.
+///
+/// ```ignore
+/// async fn handler() -> impl IntoResponse {
+///     let (title, body) = ("Hello", "World");
+///     TemplateResponse(move |o: &mut Vec<u8>| page(o, title, body))
+/// }
+/// ```
+///
+/// [axum]: https://crates.rs/crates/axum
+#[allow(dead_code)]
+pub struct TemplateResponse<F>(pub F)
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>;
+
+impl<F> IntoResponse for TemplateResponse<F>
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    fn into_response(self) -> Response {
+        render_response_as(200, "text/html; charset=utf-8", self.0)
+    }
+}
+
+/// Render a template as an axum response.
+///
+/// This is a shorthand for creating a [`TemplateResponse`],
+/// `render_response(|o| page(o, title, body))`.
+///
+/// [`TemplateResponse`]: struct.TemplateResponse.html
+#[allow(dead_code)]
+pub fn render_response<F>(f: F) -> TemplateResponse<F>
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    TemplateResponse(f)
+}
+
+/// Render a template as an axum response with the given status and
+/// content type.
+///
+/// The status and content type would normally be the `HTTP_STATUS`
+/// and `CONTENT_TYPE` constants of the template:
+///
+/// ```ignore
+/// render_response_as(
+///     NOT_FOUND_HTML_HTTP_STATUS,
+///     NOT_FOUND_HTML_CONTENT_TYPE,
+///     |o| not_found_html(o, path),
+/// )
+/// ```
+///
+/// If rendering the template fails, or the status is not a valid http
+/// status, an internal server error is returned instead.
+#[allow(dead_code)]
+pub fn render_response_as<F>(
+    status: u16,
+    content_type: &'static str,
+    f: F,
+) -> Response
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    render_response_cached(status, content_type, None, f)
+}
+
+/// Render a template as an axum response with the given status,
+/// content type and `Cache-Control` header.
+///
+/// This is as [`render_response_as`], with the cache control header
+/// normally being the `CACHE_CONTROL` constant of the template.
+/// When it is `None`, no `Cache-Control` header is set.
+///
+/// ```ignore
+/// render_response_cached(
+///     LIST_HTML_HTTP_STATUS,
+///     LIST_HTML_CONTENT_TYPE,
+///     LIST_HTML_CACHE_CONTROL,
+///     |o| list_html(o, products),
+/// )
+/// ```
+///
+/// [`render_response_as`]: fn.render_response_as.html
+#[allow(dead_code)]
+pub fn render_response_cached<F>(
+    status: u16,
+    content_type: &'static str,
+    cache_control: Option<&'static str>,
+    f: F,
+) -> Response
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    let mut buf = Vec::new();
+    match (StatusCode::from_u16(status), f(&mut buf)) {
+        (Ok(status), Ok(())) => {
+            let mut response =
+                (status, [(CONTENT_TYPE, content_type)], buf).into_response();
+            if let Some(cache_control) = cache_control {
+                response.headers_mut().insert(
+                    CACHE_CONTROL,
+                    HeaderValue::from_static(cache_control),
+                );
+            }
+            response
+        }
+        (Err(e), _) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Invalid status for template: {}", e),
+        )
+            .into_response(),
+        (_, Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to render template: {}", e),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,26 @@
+
+/// A `Write` to a `bytes::BytesMut`, used by the `_to_bytes_buf`
+/// functions of the templates.
+///
+/// When the template is rendered, the buffer can be frozen to a
+/// `bytes::Bytes` without copying it.
+pub struct BytesMutWriter(pub bytes::BytesMut);
+
+impl Write for BytesMutWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.extend_from_slice(buf);
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
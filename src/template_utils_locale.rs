@@ -0,0 +1,16 @@
+
+/// The digit group separator and the decimal separator of `locale`.
+///
+/// Languages that are not known get the separators of english.
+fn number_separators(locale: &str) -> (&'static str, &'static str) {
+    let locale = locale.to_ascii_lowercase().replace('_', "-");
+    match locale.split('-').next().unwrap_or("") {
+        "de" if locale == "de-ch" => ("’", "."),
+        "da" | "de" | "el" | "es" | "id" | "it" | "nl" | "pt" | "tr" => {
+            (".", ",")
+        }
+        "cs" | "fi" | "fr" | "hu" | "nb" | "no" | "pl" | "ru" | "sk" | "sv"
+        | "uk" => ("\u{a0}", ","),
+        _ => (",", "."),
+    }
+}
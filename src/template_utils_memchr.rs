@@ -0,0 +1,12 @@
+
+/// The length of the start of `data` that needs no html escaping.
+///
+/// This uses the vectorized search of the [memchr] crate, so long
+/// texts are skipped in large chunks rather than byte by byte.
+///
+/// [memchr]: https://crates.rs/crates/memchr
+#[inline]
+fn html_safe_len(data: &[u8]) -> usize {
+    let n = ::memchr::memchr3(b'"', b'&', b'\'', data).unwrap_or(data.len());
+    ::memchr::memchr2(b'<', b'>', &data[..n]).unwrap_or(n)
+}
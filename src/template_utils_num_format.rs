@@ -0,0 +1,12 @@
+
+/// The digit group separator and the decimal separator of `locale`.
+///
+/// The separators are taken from the [num-format] crate, and
+/// locales it does not know get the separators of english.
+///
+/// [num-format]: https://crates.rs/crates/num-format
+fn number_separators(locale: &str) -> (&'static str, &'static str) {
+    let locale = ::num_format::Locale::from_name(locale.replace('_', "-"))
+        .unwrap_or(::num_format::Locale::en);
+    (locale.separator(), locale.decimal())
+}
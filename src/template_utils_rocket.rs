@@ -0,0 +1,109 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+/// A template call that can be returned from a [rocket] handler.
+///
+/// The template is rendered to a buffer when the response is created,
+/// and the content type of the response is set to
+/// `text/html; charset=utf-8`.
+/// If rendering the template fails, the request fails with an
+/// internal server error.
+///
+/// # Examples
+///
+/// Given a template `page`, that takes two arguments other than the
+/// `Write` buffer, this handler will render it with `title` and
+/// `body` as the response.
+///
+/// ```ignore
+/// #[get("/")]
+/// fn handler() -> impl Responder<'static, 'static> {
+///     let (title, body) = ("Hello", "World");
+///     TemplateResponder(move |o: &mut Vec<u8>| page(o, title, body))
+/// }
+/// ```
+///
+/// [rocket]: https://crates.rs/crates/rocket
+#[allow(dead_code)]
+pub struct TemplateResponder<F>(pub F)
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>;
+
+impl<'r, F> Responder<'r, 'static> for TemplateResponder<F>
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        rocket_response_as(200, "text/html; charset=utf-8", self.0)
+    }
+}
+
+/// Render a template as a rocket response with the given status and
+/// content type.
+///
+/// The status and content type would normally be the `HTTP_STATUS`
+/// and `CONTENT_TYPE` constants of the template:
+///
+/// ```ignore
+/// rocket_response_as(
+///     NOT_FOUND_HTML_HTTP_STATUS,
+///     NOT_FOUND_HTML_CONTENT_TYPE,
+///     |o| not_found_html(o, path),
+/// )
+/// ```
+///
+/// If rendering the template fails, or the status is not a valid http
+/// status, the request fails with an internal server error.
+#[allow(dead_code)]
+pub fn rocket_response_as<F>(
+    status: u16,
+    content_type: &'static str,
+    f: F,
+) -> response::Result<'static>
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    rocket_response_cached(status, content_type, None, f)
+}
+
+/// Render a template as a rocket response with the given status,
+/// content type and `Cache-Control` header.
+///
+/// This is as [`rocket_response_as`], with the cache control header
+/// normally being the `CACHE_CONTROL` constant of the template.
+/// When it is `None`, no `Cache-Control` header is set.
+///
+/// ```ignore
+/// rocket_response_cached(
+///     LIST_HTML_HTTP_STATUS,
+///     LIST_HTML_CONTENT_TYPE,
+///     LIST_HTML_CACHE_CONTROL,
+///     |o| list_html(o, products),
+/// )
+/// ```
+///
+/// [`rocket_response_as`]: fn.rocket_response_as.html
+#[allow(dead_code)]
+pub fn rocket_response_cached<F>(
+    status: u16,
+    content_type: &'static str,
+    cache_control: Option<&'static str>,
+    f: F,
+) -> response::Result<'static>
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    let status =
+        Status::from_code(status).ok_or(Status::InternalServerError)?;
+    let mut buf = Vec::new();
+    f(&mut buf).map_err(|_| Status::InternalServerError)?;
+    let mut response = Response::build();
+    response
+        .status(status)
+        .raw_header("Content-Type", content_type);
+    if let Some(cache_control) = cache_control {
+        response.raw_header("Cache-Control", cache_control);
+    }
+    response.sized_body(buf.len(), io::Cursor::new(buf)).ok()
+}
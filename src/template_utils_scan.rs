@@ -0,0 +1,10 @@
+
+/// The length of the start of `data` that needs no html escaping.
+#[inline]
+fn html_safe_len(data: &[u8]) -> usize {
+    data.iter()
+        .take_while(|&&c| {
+            c != b'"' && c != b'&' && c != b'\'' && c != b'<' && c != b'>'
+        })
+        .count()
+}
@@ -0,0 +1,119 @@
+
+/// The output of a template as a stream of `Bytes`, as returned by
+/// the `_stream` functions of the async templates.
+///
+/// Each part written by the template is an item of the stream, so a
+/// large page can be sent as it is rendered, without keeping all of
+/// it in memory.
+/// The template is rendered as the stream is polled, and the stream
+/// is `Send` if the arguments of the template are.
+pub struct TemplateStream<F> {
+    chunk: std::sync::Arc<std::sync::Mutex<Option<bytes::Bytes>>>,
+    render: Option<std::pin::Pin<Box<F>>>,
+}
+
+impl<F> TemplateStream<F>
+where
+    F: std::future::Future<Output = io::Result<()>>,
+{
+    /// Create a stream of the output written to a [`ChunkWriter`] by
+    /// the future returned by `render`.
+    ///
+    /// [`ChunkWriter`]: struct.ChunkWriter.html
+    pub fn new<R>(render: R) -> Self
+    where
+        R: FnOnce(ChunkWriter) -> F,
+    {
+        let chunk = std::sync::Arc::default();
+        let writer = ChunkWriter {
+            chunk: std::sync::Arc::clone(&chunk),
+        };
+        TemplateStream {
+            chunk,
+            render: Some(Box::pin(render(writer))),
+        }
+    }
+
+    fn take_chunk(&self) -> Option<bytes::Bytes> {
+        self.chunk.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+impl<F> futures_core::Stream for TemplateStream<F>
+where
+    F: std::future::Future<Output = io::Result<()>>,
+{
+    type Item = io::Result<bytes::Bytes>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        use std::task::Poll;
+        if let Some(chunk) = self.take_chunk() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        let result = match self.render {
+            Some(ref mut render) => {
+                std::future::Future::poll(render.as_mut(), cx)
+            }
+            None => return Poll::Ready(None),
+        };
+        match result {
+            Poll::Pending => match self.take_chunk() {
+                Some(chunk) => Poll::Ready(Some(Ok(chunk))),
+                None => Poll::Pending,
+            },
+            Poll::Ready(result) => {
+                self.render = None;
+                match result {
+                    Ok(()) => Poll::Ready(self.take_chunk().map(Ok)),
+                    Err(e) => Poll::Ready(Some(Err(e))),
+                }
+            }
+        }
+    }
+}
+
+/// The `AsyncWrite` of a [`TemplateStream`], that makes each write a
+/// chunk of the stream.
+///
+/// A write waits until the stream has taken the previous chunk.
+///
+/// [`TemplateStream`]: struct.TemplateStream.html
+pub struct ChunkWriter {
+    chunk: std::sync::Arc<std::sync::Mutex<Option<bytes::Bytes>>>,
+}
+
+impl tokio::io::AsyncWrite for ChunkWriter {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let mut chunk = self.chunk.lock().unwrap_or_else(|e| e.into_inner());
+        if buf.is_empty() {
+            std::task::Poll::Ready(Ok(0))
+        } else if chunk.is_some() {
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        } else {
+            *chunk = Some(bytes::Bytes::copy_from_slice(buf));
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,46 @@
+/// Helpers for testing templates.
+///
+/// The macros in this module render a template to a value, which is
+/// useful as input for snapshot testing with [insta]:
+///
+/// ```ignore
+/// #[test]
+/// fn test_page() {
+///     insta::assert_snapshot!(render_template!(templates::page, "Title"));
+/// }
+/// ```
+///
+/// Since the insta macro is expanded in your test code, the snapshot
+/// files are stored in a `snapshots` directory next to the test file,
+/// where they can be committed to version control.
+///
+/// [insta]: https://crates.rs/crates/insta
+pub mod testing {
+    #![macro_use]
+
+    /// Render a template to a `String`.
+    ///
+    /// The template is called with a buffer as its first argument,
+    /// followed by the given arguments.
+    /// Panics if the template fails or the output is not valid utf-8.
+    #[allow(unused_macros)]
+    macro_rules! render_template {
+        ($t:path $(, $arg:expr)* $(,)*) => {
+            String::from_utf8(render_template_to_bytes!($t $(, $arg)*))
+                .unwrap()
+        };
+    }
+
+    /// Render a template to a `Vec<u8>`.
+    ///
+    /// This is like `render_template!`, but for templates where the
+    /// output is not necessarily utf-8.
+    #[allow(unused_macros)]
+    macro_rules! render_template_to_bytes {
+        ($t:path $(, $arg:expr)* $(,)*) => {{
+            let mut buf = Vec::new();
+            $t(&mut buf $(, $arg)*).unwrap();
+            buf
+        }};
+    }
+}
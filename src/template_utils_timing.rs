@@ -0,0 +1,61 @@
+/// Render times of the templates.
+///
+/// Each call of a template function records how long it took, in a
+/// buffer for the current thread, so recording takes no locks.
+/// Take the times recorded by the current thread with
+/// [`drain_timings`], e.g. after rendering a page:
+///
+/// ```ignore
+/// templates::page_html(&mut buf, &data)?;
+/// for (template, time) in templates::timing::drain_timings() {
+///     println!("{} took {:?}", template, time);
+/// }
+/// ```
+///
+/// The time of a template includes the time of the templates it
+/// calls, which are also recorded by themselves.
+///
+/// [`drain_timings`]: fn.drain_timings.html
+pub mod timing {
+    use std::cell::RefCell;
+    use std::time::{Duration, Instant};
+
+    thread_local! {
+        static TIMINGS: RefCell<Vec<(&'static str, Duration)>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    /// Take the render times recorded by the current thread.
+    ///
+    /// Each time is given with the name of the template function, in
+    /// the order the calls ended.
+    pub fn drain_timings() -> Vec<(&'static str, Duration)> {
+        TIMINGS.with(|t| std::mem::take(&mut *t.borrow_mut()))
+    }
+
+    /// Records the time from its creation until it is dropped.
+    ///
+    /// Each generated template function creates one, so the time is
+    /// recorded also when the template returns an error.
+    pub struct Timer {
+        name: &'static str,
+        start: Instant,
+    }
+
+    impl Timer {
+        /// Start timing the template `name`.
+        pub fn start(name: &'static str) -> Self {
+            Timer {
+                name,
+                start: Instant::now(),
+            }
+        }
+    }
+
+    impl Drop for Timer {
+        fn drop(&mut self) {
+            let time = (self.name, self.start.elapsed());
+            let _ = TIMINGS.try_with(|t| t.borrow_mut().push(time));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+/// Support for rendering templates as replies in the [warp] framework.
+///
+/// [warp]: https://crates.rs/crates/warp
+pub mod warp_support {
+    use std::io;
+    use warp::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+    use warp::http::{HeaderValue, StatusCode};
+    use warp::reply::{self, Reply};
+
+    /// Render a template as a warp reply.
+    ///
+    /// The template is rendered to a buffer and the content type of
+    /// the reply is set to `text/html; charset=utf-8`.
+    /// If rendering the template fails, the reply is an internal
+    /// server error instead.
+    ///
+    /// # Examples
+    ///
+    /// Given a template `page`, that takes two arguments other than
+    /// the `Write` buffer, this handler will render it with `title`
+    /// and `body` as the reply.
+    ///
+    /// ```ignore
+    /// async fn handler() -> Result<impl Reply, Rejection> {
+    ///     let (title, body) = ("Hello", "World");
+    ///     Ok(warp_reply(|o| page(o, title, body)))
+    /// }
+    /// ```
+    #[allow(dead_code)]
+    pub fn warp_reply<F>(f: F) -> impl Reply
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut buf = Vec::new();
+        match f(&mut buf) {
+            Ok(()) => reply::html(buf).into_response(),
+            Err(e) => reply::with_status(
+                format!("Failed to render template: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response(),
+        }
+    }
+
+    /// Render a template as a warp reply with the given status and
+    /// content type.
+    ///
+    /// The status and content type would normally be the
+    /// `HTTP_STATUS` and `CONTENT_TYPE` constants of the template:
+    ///
+    /// ```ignore
+    /// warp_reply_as(
+    ///     NOT_FOUND_HTML_HTTP_STATUS,
+    ///     NOT_FOUND_HTML_CONTENT_TYPE,
+    ///     |o| not_found_html(o, path),
+    /// )
+    /// ```
+    ///
+    /// If rendering the template fails, or the status is not a valid
+    /// http status, the reply is an internal server error instead.
+    #[allow(dead_code)]
+    pub fn warp_reply_as<F>(
+        status: u16,
+        content_type: &'static str,
+        f: F,
+    ) -> impl Reply
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        warp_reply_cached(status, content_type, None, f)
+    }
+
+    /// Render a template as a warp reply with the given status,
+    /// content type and `Cache-Control` header.
+    ///
+    /// This is as [`warp_reply_as`], with the cache control header
+    /// normally being the `CACHE_CONTROL` constant of the template.
+    /// When it is `None`, no `Cache-Control` header is set.
+    ///
+    /// ```ignore
+    /// warp_reply_cached(
+    ///     LIST_HTML_HTTP_STATUS,
+    ///     LIST_HTML_CONTENT_TYPE,
+    ///     LIST_HTML_CACHE_CONTROL,
+    ///     |o| list_html(o, products),
+    /// )
+    /// ```
+    ///
+    /// [`warp_reply_as`]: fn.warp_reply_as.html
+    #[allow(dead_code)]
+    pub fn warp_reply_cached<F>(
+        status: u16,
+        content_type: &'static str,
+        cache_control: Option<&'static str>,
+        f: F,
+    ) -> impl Reply
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut buf = Vec::new();
+        match (StatusCode::from_u16(status), f(&mut buf)) {
+            (Ok(status), Ok(())) => {
+                let mut response = reply::with_status(
+                    reply::with_header(buf, CONTENT_TYPE, content_type),
+                    status,
+                )
+                .into_response();
+                if let Some(cache_control) = cache_control {
+                    response.headers_mut().insert(
+                        CACHE_CONTROL,
+                        HeaderValue::from_static(cache_control),
+                    );
+                }
+                response
+            }
+            (Err(e), _) => reply::with_status(
+                format!("Invalid status for template: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response(),
+            (_, Err(e)) => reply::with_status(
+                format!("Failed to render template: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response(),
+        }
+    }
+}
@@ -1,21 +1,31 @@
 use expression::{
-    comma_expressions, expr_in_braces, expr_inside_parens, expression,
-    input_to_str, rust_name,
+    apply_filters, comma_expressions, expr_in_braces, expr_in_brackets,
+    expr_in_parens, expr_inside_parens, expression, filters, input_to_str,
+    quoted_string, rust_name, Filter,
 };
 use itertools::Itertools;
 use nom::branch::alt;
 use nom::bytes::complete::is_not;
 use nom::bytes::complete::tag;
-use nom::character::complete::char;
-use nom::combinator::{map, map_res, opt, recognize, value};
+use nom::bytes::complete::take_while1;
+use nom::character::complete::{
+    alphanumeric1, char, multispace1, one_of, space0, space1,
+};
+use nom::combinator::{cut, map, map_res, not, opt, peek, recognize, value};
 use nom::error::context;
-use nom::multi::{many0, many_till, separated_list};
+use nom::multi::{
+    many0, many1, many_till, separated_list, separated_nonempty_list,
+};
 use nom::sequence::{delimited, pair, preceded, terminated, tuple};
-use parseresult::PResult;
-use spacelike::{comment_tail, spacelike};
-use std::fmt::{self, Display};
+use parseresult::{stop_incomplete, PResult};
+use spacelike::{comment_tail, line_comment_tail, spacelike};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use template::{formal_argument, type_expression};
+use {AsyncRuntime, OutputTrait};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TemplateExpression {
     Comment,
     Text {
@@ -24,6 +34,13 @@ pub enum TemplateExpression {
     Expression {
         expr: String,
     },
+    /// An expression followed by possible filters, that are replaced
+    /// by `resolve_filters` when the names used by the template are
+    /// known.
+    Filtered {
+        expr: String,
+        filters: Vec<Filter>,
+    },
     ForLoop {
         name: String,
         expr: String,
@@ -34,6 +51,20 @@ pub enum TemplateExpression {
         body: Vec<TemplateExpression>,
         else_body: Option<Vec<TemplateExpression>>,
     },
+    WhileLoop {
+        expr: String,
+        body: Vec<TemplateExpression>,
+    },
+    Let {
+        pattern: String,
+        expr: String,
+    },
+    /// A `@const NAME: Type = value;` declaration.
+    Const {
+        name: String,
+        ty: String,
+        expr: String,
+    },
     MatchBlock {
         expr: String,
         arms: Vec<(String, Vec<TemplateExpression>)>,
@@ -42,58 +73,394 @@ pub enum TemplateExpression {
         name: String,
         args: Vec<TemplateArgument>,
     },
+    RawBlock {
+        body: Vec<TemplateExpression>,
+    },
+    Block {
+        name: String,
+        body: Vec<TemplateExpression>,
+    },
+    Yield {
+        name: String,
+    },
+    Include(Include),
+    /// A `@macro name(args) { ... }` definition of a fragment that can
+    /// be used later in the same template.
+    Macro {
+        name: String,
+        args: Vec<String>,
+        body: Vec<TemplateExpression>,
+    },
+    /// A use of a macro, parsed as an expression and then recognized
+    /// by the name of a macro defined before it.
+    MacroCall {
+        name: String,
+        args: String,
+    },
+}
+
+/// An `@include "path"` of another template.
+///
+/// The included file, the function to call and the arguments to pass
+/// are not known when parsing, they are filled in when the included
+/// template is found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Include {
+    /// The path as written in the template.
+    pub path: String,
+    /// The length of the template source from the path on, to find
+    /// the position of the include for error messages.
+    pub rest_len: usize,
+    /// True if the path is relative to the template directory rather
+    /// than to the including template.
+    pub from_root: bool,
+    /// The arguments given by name in braces, as the name and the
+    /// expression for the value, if any.
+    pub given_args: Option<Vec<(String, String)>>,
+    /// The included template file.
+    pub file: PathBuf,
+    /// The path of the function generated for the included template.
+    pub call: String,
+    /// The expressions of the arguments to pass.
+    pub args: Vec<String>,
+}
+
+/// How the values of expressions are written to the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Escaping {
+    /// Write values with `to_html`, escaping them for html.
+    Html,
+    /// Write values with `to_xml`, escaping them for xml.
+    Xml,
+    /// Write values with `to_json`, escaping them for json strings.
+    Json,
+    /// Write values as they are, inside a `@raw` block.
+    Raw,
+    /// Write values as they are, in a plain text template.
+    Text,
+}
+
+impl Escaping {
+    /// Escape `text` as a value written with this escaping.
+    ///
+    /// This is what the escaping writers in the generated templates
+    /// module do at runtime, for text known when compiling.
+    pub fn escape(self, text: &str) -> String {
+        let apos = match self {
+            Escaping::Html => "&#39;",
+            Escaping::Xml => "&apos;",
+            Escaping::Json => {
+                return text
+                    .chars()
+                    .map(|c| match c {
+                        '"' => "\\\"".to_string(),
+                        '\\' => "\\\\".to_string(),
+                        c if (c as u32) < 0x20 => {
+                            format!("\\u{:04x}", c as u32)
+                        }
+                        c => c.to_string(),
+                    })
+                    .collect();
+            }
+            Escaping::Raw | Escaping::Text => return text.to_string(),
+        };
+        text.chars()
+            .map(|c| match c {
+                '"' => "&quot;".to_string(),
+                '&' => "&amp;".to_string(),
+                '<' => "&lt;".to_string(),
+                '>' => "&gt;".to_string(),
+                '\'' => apos.to_string(),
+                c => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// The escaping for the template function `name`.
+    ///
+    /// The functions for xml templates, such as `feed.rs.xml`, have
+    /// names ending with `_xml` (or `_svg`, for svg images, which are
+    /// also xml), and the functions for plain text
+    /// templates, such as `mail.rs.txt`, have names ending with `_txt`.
+    /// Json templates, such as `data.rs.json`, end with `_json`.
+    pub fn for_template(name: &str) -> Self {
+        if name.ends_with("_xml") || name.ends_with("_svg") {
+            Escaping::Xml
+        } else if name.ends_with("_json") {
+            Escaping::Json
+        } else if name.ends_with("_txt") {
+            Escaping::Text
+        } else {
+            Escaping::Html
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TemplateArgument {
     Rust(String),
     Body(Vec<TemplateExpression>),
 }
 
-impl Display for TemplateArgument {
-    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+impl TemplateArgument {
+    fn code(&self, target: Target, escaping: Escaping) -> String {
+        let (closure, out) = match target {
+            Target::Sync(_) => ("", "mut _ructe_out_"),
+            Target::Async(_) => ("async ", "_ructe_out_"),
+        };
         match *self {
-            TemplateArgument::Rust(ref s) => out.write_str(&s),
+            TemplateArgument::Rust(ref s) => s.clone(),
             TemplateArgument::Body(ref v) if v.is_empty() => {
-                out.write_str("|_| Ok(())")
+                format!("{}|_| Ok(())", closure)
             }
-            TemplateArgument::Body(ref v) => writeln!(
+            TemplateArgument::Body(ref v) => format!(
+                "{}|{}| {{\n{}\nOk(())\n}}\n",
+                closure,
                 out,
-                "|mut _ructe_out_| {{\n{}\nOk(())\n}}",
-                v.iter().map(|b| b.code()).format(""),
+                body_code(v, target, escaping),
             ),
         }
     }
 }
 
+/// The kind of function the code for a template is generated for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// A plain function writing to the given kind of output.
+    Sync(OutputTrait),
+    /// An `async fn` writing to the `AsyncWrite` of the given runtime.
+    Async(AsyncRuntime),
+}
+
+impl Target {
+    pub fn is_async(self) -> bool {
+        match self {
+            Target::Sync(_) => false,
+            Target::Async(_) => true,
+        }
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::Sync(OutputTrait::Io)
+    }
+}
+
 impl TemplateExpression {
     pub fn text(text: &str) -> Self {
         TemplateExpression::Text {
             text: text.to_string(),
         }
     }
-    pub fn code(&self) -> String {
+    /// The expression `expr`, followed by the possible `filters`.
+    fn filtered(expr: String, filters: Vec<Filter>) -> Self {
+        if filters.is_empty() {
+            TemplateExpression::Expression { expr }
+        } else {
+            TemplateExpression::Filtered { expr, filters }
+        }
+    }
+    /// The number of bytes of static text that is always written by
+    /// this expression.
+    ///
+    /// Text in loops and in a conditional without an else branch may
+    /// not be written at all, so it is not counted.
+    pub fn static_size(&self) -> usize {
+        let block = |body: &[TemplateExpression]| -> usize {
+            body.iter().map(TemplateExpression::static_size).sum()
+        };
         match *self {
-            TemplateExpression::Comment => String::new(),
-            TemplateExpression::Text { ref text } if text.is_ascii() => {
-                format!("_ructe_out_.write_all(b{:?})?;\n", text)
+            TemplateExpression::Text { ref text } => text.len(),
+            TemplateExpression::IfBlock {
+                ref body,
+                ref else_body,
+                ..
+            } => else_body
+                .as_ref()
+                .map(|else_body| block(body).min(block(else_body)))
+                .unwrap_or(0),
+            TemplateExpression::MatchBlock { ref arms, .. } => {
+                arms.iter().map(|(_, body)| block(body)).min().unwrap_or(0)
+            }
+            TemplateExpression::RawBlock { ref body }
+            | TemplateExpression::Block { ref body, .. } => block(body),
+            _ => 0,
+        }
+    }
+
+    /// The number of parts of this expression, including itself and
+    /// any nested parts.
+    pub fn node_count(&self) -> usize {
+        let block = |body: &[TemplateExpression]| -> usize {
+            body.iter().map(TemplateExpression::node_count).sum()
+        };
+        1 + match *self {
+            TemplateExpression::ForLoop { ref body, .. }
+            | TemplateExpression::WhileLoop { ref body, .. }
+            | TemplateExpression::RawBlock { ref body }
+            | TemplateExpression::Block { ref body, .. }
+            | TemplateExpression::Macro { ref body, .. } => block(body),
+            TemplateExpression::IfBlock {
+                ref body,
+                ref else_body,
+                ..
+            } => block(body) + else_body.as_ref().map_or(0, |b| block(b)),
+            TemplateExpression::MatchBlock { ref arms, .. } => {
+                arms.iter().map(|(_, body)| block(body)).sum()
             }
-            TemplateExpression::Text { ref text } => {
-                format!("_ructe_out_.write_all({:?}.as_bytes())?;\n", text)
+            TemplateExpression::CallTemplate { ref args, .. } => args
+                .iter()
+                .map(|arg| match *arg {
+                    TemplateArgument::Body(ref body) => block(body),
+                    TemplateArgument::Rust(_) => 0,
+                })
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    /// Add the names of the templates called from this expression,
+    /// including any nested calls and includes, to `names`.
+    pub fn called_templates(&self, names: &mut Vec<String>) {
+        let mut block = |body: &[TemplateExpression]| {
+            for b in body {
+                b.called_templates(names);
             }
-            TemplateExpression::Expression { ref expr } => {
-                format!("{}.to_html(&mut _ructe_out_)?;\n", expr)
+        };
+        match *self {
+            TemplateExpression::CallTemplate { ref name, ref args } => {
+                names.push(name.clone());
+                for arg in args {
+                    if let TemplateArgument::Body(ref body) = *arg {
+                        for b in body {
+                            b.called_templates(names);
+                        }
+                    }
+                }
+            }
+            TemplateExpression::Include(ref include) => {
+                names.push(include.call.clone());
+            }
+            TemplateExpression::IfBlock {
+                ref body,
+                ref else_body,
+                ..
+            } => {
+                block(body);
+                if let Some(ref else_body) = *else_body {
+                    block(else_body);
+                }
+            }
+            TemplateExpression::MatchBlock { ref arms, .. } => {
+                for (_, body) in arms {
+                    block(body);
+                }
+            }
+            TemplateExpression::ForLoop { ref body, .. }
+            | TemplateExpression::WhileLoop { ref body, .. }
+            | TemplateExpression::RawBlock { ref body }
+            | TemplateExpression::Block { ref body, .. }
+            | TemplateExpression::Macro { ref body, .. } => block(body),
+            _ => (),
+        }
+    }
+
+    pub fn code(&self, target: Target, escaping: Escaping) -> String {
+        let block =
+            |body: &[TemplateExpression]| body_code(body, target, escaping);
+        let write_value = |value: &str, markup: &str| match target {
+            Target::Sync(OutputTrait::Io) => {
+                format!("{}.to_{}(&mut _ructe_out_)?;\n", value, markup)
+            }
+            Target::Sync(OutputTrait::Fmt) => {
+                format!("{}.fmt_{}(&mut _ructe_out_)?;\n", value, markup)
+            }
+            Target::Async(_) => format!(
+                "{}.to_{}_async(&mut *_ructe_out_).await?;\n",
+                value, markup
+            ),
+        };
+        // Bodies passed to other templates are not raw (but bodies in
+        // a text template are text).
+        let body_escaping = match escaping {
+            Escaping::Raw => Escaping::Html,
+            escaping => escaping,
+        };
+        let call = |name: &str, args: &dyn Display| match target {
+            Target::Sync(_) => {
+                format!("{}(&mut _ructe_out_{})?;\n", name, args)
+            }
+            Target::Async(_) => {
+                format!("{}(&mut *_ructe_out_{}).await?;\n", name, args)
+            }
+        };
+        match *self {
+            TemplateExpression::Comment => String::new(),
+            TemplateExpression::Text { ref text } => match target {
+                Target::Sync(OutputTrait::Io) => {
+                    format!(
+                        "_ructe_out_.write_all({})?;\n",
+                        byte_string(text)
+                    )
+                }
+                Target::Sync(OutputTrait::Fmt) => {
+                    format!("_ructe_out_.write_str({:?})?;\n", text)
+                }
+                Target::Async(runtime) => format!(
+                    "{}::AsyncWriteExt::write_all(\
+                     &mut *_ructe_out_, {}).await?;\n",
+                    runtime.io_module(),
+                    byte_string(text),
+                ),
+            },
+            TemplateExpression::Expression { ref expr } => match escaping {
+                Escaping::Html => write_value(expr, "html"),
+                Escaping::Xml => write_value(expr, "xml"),
+                Escaping::Json => write_value(expr, "json"),
+                Escaping::Raw | Escaping::Text => {
+                    write_value(&format!("Html(&({}))", expr), "html")
+                }
+            },
+            TemplateExpression::Filtered { .. } => {
+                // Not resolved by the template, so only the standard
+                // filters are known.
+                let mut resolved = vec![self.clone()];
+                resolve_filters(&mut resolved, &[]);
+                block(&resolved)
             }
             TemplateExpression::ForLoop {
                 ref name,
                 ref expr,
                 ref body,
-            } => format!(
-                "for {} in {} {{\n{}}}\n",
-                name,
-                expr,
-                body.iter().map(|b| b.code()).format(""),
-            ),
+            } => match loop_text(body) {
+                Some((head, body, tail)) => {
+                    let text = |text: &str| {
+                        TemplateExpression::text(text).code(target, escaping)
+                    };
+                    format!(
+                        "{{\nlet mut _ructe_first_ = true;\n\
+                         for {} in {} {{\n\
+                         if _ructe_first_ {{\n{}}} else {{\n{}}}\n\
+                         _ructe_first_ = false;\n{}}}\n\
+                         if !_ructe_first_ {{\n{}}}\n}}\n",
+                        name,
+                        expr,
+                        text(&head),
+                        text(&(tail.clone() + &head)),
+                        block(body),
+                        text(&tail),
+                    )
+                }
+                None => {
+                    format!(
+                        "for {} in {} {{\n{}}}\n",
+                        name,
+                        expr,
+                        block(body)
+                    )
+                }
+            },
             TemplateExpression::IfBlock {
                 ref expr,
                 ref body,
@@ -101,524 +468,2670 @@ impl TemplateExpression {
             } => format!(
                 "if {} {{\n{}}}{}\n",
                 expr,
-                body.iter().map(|b| b.code()).format(""),
+                block(body),
                 else_body.iter().format_with("", |body, f| f(&format_args!(
                     " else {{\n{}}}",
-                    body.iter().map(|b| b.code()).format(""),
+                    block(body),
                 ))),
             ),
+            TemplateExpression::WhileLoop { ref expr, ref body } => {
+                format!("while {} {{\n{}}}\n", expr, block(body),)
+            }
+            TemplateExpression::Let {
+                ref pattern,
+                ref expr,
+            } => format!("let {} = {};\n", pattern, expr),
+            TemplateExpression::Const {
+                ref name,
+                ref ty,
+                ref expr,
+            } => format!("const {}: {} = {};\n", name, ty, expr),
             TemplateExpression::MatchBlock { ref expr, ref arms } => format!(
                 "match {} {{{}}}\n",
                 expr,
                 arms.iter().format_with("", |(expr, body), f| {
-                    f(&format_args!(
-                        "\n  {} => {{\n{}}}",
-                        expr,
-                        body.iter().map(|b| b.code()).format(""),
-                    ))
+                    f(&format_args!("\n  {} => {{\n{}}}", expr, block(body)))
                 })
             ),
-            TemplateExpression::CallTemplate { ref name, ref args } => {
+            TemplateExpression::RawBlock { ref body } => {
+                body_code(body, target, Escaping::Raw)
+            }
+            TemplateExpression::Block { ref body, .. } => block(body),
+            TemplateExpression::Yield { .. } => String::new(),
+            TemplateExpression::Include(ref include) => call(
+                &include.call,
+                &include
+                    .args
+                    .iter()
+                    .format_with("", |arg, f| f(&format_args!(", {}", arg))),
+            ),
+            TemplateExpression::CallTemplate { ref name, ref args } => call(
+                name,
+                &args.iter().format_with("", |arg, f| {
+                    f(&format_args!(", {}", arg.code(target, body_escaping)))
+                }),
+            ),
+            TemplateExpression::Macro {
+                ref name,
+                ref args,
+                ref body,
+            } => {
+                let (closure, out, result) = match target {
+                    Target::Sync(OutputTrait::Io) => {
+                        ("", "mut _ructe_out_", "io::Result<()>")
+                    }
+                    Target::Sync(OutputTrait::Fmt) => {
+                        ("", "mut _ructe_out_", "fmt::Result")
+                    }
+                    Target::Async(_) => {
+                        ("async ", "_ructe_out_", "std::io::Result<()>")
+                    }
+                };
                 format!(
-                    "{}(&mut _ructe_out_{})?;\n",
-                    name,
-                    args.iter().format_with("", |arg, f| f(&format_args!(
-                        ", {}",
-                        arg
-                    ))),
+                    "#[allow(unused_variables)]\n\
+                     let {name} = {closure}|{out}: &mut W{args}| -> {result} {{\n\
+                     {body}Ok(())\n\
+                     }};\n",
+                    name = name,
+                    closure = closure,
+                    out = out,
+                    args = args
+                        .iter()
+                        .format_with("", |arg, f| f(&format_args!(", {}", arg))),
+                    result = result,
+                    body = block(body),
                 )
             }
+            TemplateExpression::MacroCall { ref name, ref args } => {
+                let args = if args.is_empty() {
+                    String::new()
+                } else {
+                    format!(", {}", args)
+                };
+                match target {
+                    Target::Sync(_) => {
+                        format!("{}(&mut *_ructe_out_{})?;\n", name, args)
+                    }
+                    Target::Async(_) => format!(
+                        "{}(&mut *_ructe_out_{}).await?;\n",
+                        name, args
+                    ),
+                }
+            }
         }
     }
 }
 
-pub fn template_expression(input: &[u8]) -> PResult<TemplateExpression> {
-    match opt(preceded(
-        char('@'),
-        alt((
-            tag("*"),
-            tag(":"),
-            tag("@"),
-            tag("{"),
-            tag("}"),
-            tag("("),
-            terminated(alt((tag("if"), tag("for"), tag("match"))), tag(" ")),
-            value(&b""[..], tag("")),
-        )),
-    ))(input)?
-    {
-        (i, Some(b":")) => map(
-            pair(
-                rust_name,
-                delimited(
-                    char('('),
-                    separated_list(
-                        terminated(tag(","), spacelike),
-                        template_argument,
-                    ),
-                    char(')'),
-                ),
-            ),
-            |(name, args)| TemplateExpression::CallTemplate {
-                name: name.to_string(),
-                args,
-            },
-        )(i),
-        (i, Some(b"@")) => Ok((i, TemplateExpression::text("@"))),
-        (i, Some(b"{")) => Ok((i, TemplateExpression::text("{"))),
-        (i, Some(b"}")) => Ok((i, TemplateExpression::text("}"))),
-        (i, Some(b"*")) => {
-            map(comment_tail, |()| TemplateExpression::Comment)(i)
-        }
-        (i, Some(b"if")) => context(
-            "Error in conditional expression:",
-            map(
-                tuple((
-                    delimited(spacelike, cond_expression, spacelike),
-                    template_block,
-                    opt(preceded(
-                        delimited(spacelike, tag("else"), spacelike),
-                        template_block,
-                    )),
-                )),
-                |(expr, body, else_body)| TemplateExpression::IfBlock {
-                    expr,
-                    body,
-                    else_body,
-                },
-            ),
-        )(i),
-        (i, Some(b"for")) => map(
-            tuple((
-                for_variable,
-                delimited(
-                    terminated(
-                        context("Expected \"in\"", tag("in")),
-                        spacelike,
-                    ),
-                    context("Expected iterable expression", loop_expression),
-                    spacelike,
-                ),
-                terminated(
-                    context("Error in loop block:", template_block),
-                    spacelike,
-                ),
-            )),
-            |(name, expr, body)| TemplateExpression::ForLoop {
-                name,
-                expr,
-                body,
-            },
-        )(i),
-        (i, Some(b"match")) => context(
-            "Error in match expression:",
-            map(
-                tuple((
-                    delimited(spacelike, expression, spacelike),
-                    preceded(
-                        char('{'),
-                        map(
-                            many_till(
-                                context(
-                                    "Error in match arm starting here:",
-                                    pair(
-                                        delimited(
-                                            spacelike,
-                                            map(expression, String::from),
-                                            spacelike,
-                                        ),
-                                        preceded(
-                                            terminated(tag("=>"), spacelike),
-                                            template_block,
-                                        ),
-                                    ),
-                                ),
-                                preceded(spacelike, char('}')),
-                            ),
-                            |(arms, _end)| arms,
-                        ),
-                    ),
-                )),
-                |(expr, arms)| TemplateExpression::MatchBlock {
-                    expr: expr.to_string(),
-                    arms,
-                },
+/// Parse template expressions up to `end`.
+///
+/// Whitespace in the text before an expression marked with `@-` and
+/// after an expression marked with `-@` is removed.
+pub fn template_body<'a, E, T>(
+    end: E,
+) -> impl Fn(&'a [u8]) -> PResult<'a, Vec<TemplateExpression>>
+where
+    E: Fn(&'a [u8]) -> PResult<'a, T>,
+{
+    map(
+        many_till(
+            context(
+                "Error in expression starting here:",
+                stop_incomplete(template_item),
             ),
-        )(i),
-        (i, Some(b"(")) => {
-            map(terminated(expr_inside_parens, tag(")")), |expr| {
-                TemplateExpression::Expression {
-                    expr: format!("({})", expr),
-                }
-            })(i)
+            end,
+        ),
+        |(items, _end)| trim_whitespace(items),
+    )
+}
+
+/// A template expression with its trim-left and trim-right flags.
+fn template_item(
+    input: &[u8],
+) -> PResult<'_, (bool, TemplateExpression, bool)> {
+    tuple((
+        map(opt(peek(tag("@-"))), |t| t.is_some()),
+        template_expression,
+        map(opt(trim_right_marker), |t| t.is_some()),
+    ))(input)
+}
+
+/// A `-@` that ends an expression, rather than a dash followed by
+/// another expression.
+fn trim_right_marker(input: &[u8]) -> PResult<'_, ()> {
+    value(
+        (),
+        delimited(
+            space0,
+            tag("-@"),
+            not(peek(alt((
+                value((), one_of("@{}*:(-[&\"_")),
+                value((), alphanumeric1),
+            )))),
+        ),
+    )(input)
+}
+
+fn trim_whitespace(
+    items: Vec<(bool, TemplateExpression, bool)>,
+) -> Vec<TemplateExpression> {
+    let mut result: Vec<TemplateExpression> = Vec::with_capacity(items.len());
+    let mut trim_next = false;
+    for (trim_left, mut expr, trim_right) in items {
+        if trim_left {
+            if let Some(TemplateExpression::Text { ref mut text }) =
+                result.last_mut()
+            {
+                let len = text.trim_end().len();
+                text.truncate(len);
+            }
         }
-        (i, Some(b"")) => {
-            map(expression, |expr| TemplateExpression::Expression {
-                expr: expr.to_string(),
-            })(i)
+        if trim_next {
+            if let TemplateExpression::Text { ref mut text } = expr {
+                *text = text.trim_start().to_string();
+            }
         }
-        (_i, Some(_)) => unreachable!(),
-        (i, None) => map(map_res(is_not("@{}"), input_to_str), |text| {
-            TemplateExpression::Text {
-                text: text.to_string(),
+        result.push(expr);
+        trim_next = trim_right;
+    }
+    result.retain(|e| *e != TemplateExpression::text(""));
+    result
+}
+
+/// The code for `body`.
+///
+/// Adjacent text (also with comments in between) is written in one
+/// call, rather than one call for each part.
+pub fn body_code(
+    body: &[TemplateExpression],
+    target: Target,
+    escaping: Escaping,
+) -> String {
+    let mut code = String::new();
+    let mut text = String::new();
+    for expr in body {
+        match *expr {
+            TemplateExpression::Text { text: ref part } => {
+                text.push_str(part)
             }
-        })(i),
+            TemplateExpression::Comment => (),
+            ref expr => {
+                if !text.is_empty() {
+                    code.push_str(
+                        &TemplateExpression::text(&text)
+                            .code(target, escaping),
+                    );
+                    text.clear();
+                }
+                code.push_str(&expr.code(target, escaping));
+            }
+        }
     }
+    if !text.is_empty() {
+        code.push_str(
+            &TemplateExpression::text(&text).code(target, escaping),
+        );
+    }
+    code
 }
 
-fn for_variable(input: &[u8]) -> PResult<String> {
-    delimited(
-        spacelike,
-        context(
-            "Expected loop variable name or destructuring tuple",
-            alt((
-                map(
-                    map_res(
-                        recognize(preceded(rust_name, opt(expr_in_braces))),
-                        input_to_str,
-                    ),
-                    String::from,
-                ),
-                map(
-                    pair(
-                        opt(char('&')),
-                        delimited(char('('), comma_expressions, char(')')),
-                    ),
-                    |(pre, args)| match pre {
-                        Some(_) => format!("&({})", args),
-                        None => format!("({})", args),
-                    },
-                ),
-            )),
-        ),
-        spacelike,
-    )(input)
+/// The text at the start and at the end of a loop `body`, and the
+/// rest of the body, if the body starts and ends with text and has
+/// something else in between.
+///
+/// The text at the end of one iteration and at the start of the next
+/// can then be written together, so each iteration writes text once.
+fn loop_text(
+    body: &[TemplateExpression],
+) -> Option<(String, &[TemplateExpression], String)> {
+    let is_text = |expr: &TemplateExpression| {
+        matches!(
+            *expr,
+            TemplateExpression::Text { .. } | TemplateExpression::Comment
+        )
+    };
+    let text = |body: &[TemplateExpression]| {
+        body.iter()
+            .filter_map(|expr| match *expr {
+                TemplateExpression::Text { ref text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<String>()
+    };
+    let start = body.iter().position(|e| !is_text(e))?;
+    let end = body.iter().rposition(|e| !is_text(e))? + 1;
+    let (head, tail) = (text(&body[..start]), text(&body[end..]));
+    if head.is_empty() || tail.is_empty() {
+        return None;
+    }
+    Some((head, &body[start..end], tail))
 }
 
-fn template_block(input: &[u8]) -> PResult<Vec<TemplateExpression>> {
-    preceded(
-        char('{'),
-        map(
-            many_till(
-                context(
-                    "Error in expression starting here:",
-                    template_expression,
-                ),
-                char('}'),
-            ),
-            |(block, _end)| block,
-        ),
-    )(input)
+/// A byte string literal for `text`.
+pub fn byte_string(text: &str) -> String {
+    if text.is_ascii() {
+        format!("b{:?}", text)
+    } else {
+        format!("{:?}.as_bytes()", text)
+    }
 }
 
-fn template_argument(input: &[u8]) -> PResult<TemplateArgument> {
-    alt((
-        map(
-            delimited(
-                char('{'),
-                many0(template_expression),
-                terminated(char('}'), spacelike),
-            ),
-            TemplateArgument::Body,
-        ),
-        map(map(expression, String::from), TemplateArgument::Rust),
-    ))(input)
+/// The text written by `body`, if it is only static text.
+///
+/// Comments are skipped, and the content of raw blocks and named
+/// blocks is included.
+/// Expressions that are string or integer literals are written as
+/// they are escaped with `escaping`.
+/// Any other expression or control structure makes the output depend
+/// on the arguments, so `None` is returned.
+pub fn static_text(
+    body: &[TemplateExpression],
+    escaping: Escaping,
+) -> Option<String> {
+    let mut result = String::new();
+    for expr in body {
+        match *expr {
+            TemplateExpression::Text { ref text } => result.push_str(text),
+            TemplateExpression::Comment => (),
+            TemplateExpression::Expression { ref expr } => {
+                result.push_str(&escaping.escape(&literal_value(expr)?))
+            }
+            TemplateExpression::RawBlock { ref body } => {
+                result.push_str(&static_text(body, Escaping::Raw)?)
+            }
+            TemplateExpression::Block { ref body, .. } => {
+                result.push_str(&static_text(body, escaping)?)
+            }
+            _ => return None,
+        }
+    }
+    Some(result)
 }
 
-fn cond_expression(input: &[u8]) -> PResult<String> {
-    match opt(tag("let"))(input)? {
-        (i, Some(b"let")) => map(
-            pair(
-                preceded(
-                    spacelike,
-                    context(
-                        "Expected LHS expression in let binding",
-                        expression,
-                    ),
-                ),
-                preceded(
-                    delimited(spacelike, char('='), spacelike),
-                    context(
-                        "Expected RHS expression in let binding",
-                        expression,
-                    ),
-                ),
-            ),
-            |(lhs, rhs)| format!("let {} = {}", lhs, rhs),
-        )(i),
-        (_i, Some(_)) => unreachable!(),
-        (i, None) => map(
-            context("Expected expression", logic_expression),
-            String::from,
-        )(i),
+/// The value of `expr`, if it is a string or integer literal.
+///
+/// Strings with unicode or byte escapes are not handled.
+fn literal_value(expr: &str) -> Option<String> {
+    let expr = expr
+        .strip_prefix('(')
+        .and_then(|e| e.strip_suffix(')'))
+        .unwrap_or(expr)
+        .trim();
+    if !expr.is_empty() && expr.bytes().all(|b| b.is_ascii_digit()) {
+        return Some(expr.to_string());
+    }
+    let mut chars = expr.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        value.push(match c {
+            '"' => return None,
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                c @ ('\\' | '"' | '\'') => c,
+                _ => return None,
+            },
+            c => c,
+        });
     }
+    Some(value)
 }
 
-fn loop_expression(input: &[u8]) -> PResult<String> {
-    map(
-        map_res(
-            recognize(terminated(
-                expression,
-                opt(preceded(
-                    terminated(tag(".."), opt(char('='))),
-                    expression,
-                )),
-            )),
-            input_to_str,
-        ),
-        String::from,
-    )(input)
+/// Fill the named blocks and yield points in `body` with `blocks`.
+///
+/// A block keeps its own content unless it is given in `blocks`, a
+/// yield point without a given block is left empty.
+/// The result is still a block, so a block can be overridden again
+/// through another level of `@extends`.
+pub fn fill_blocks(
+    body: Vec<TemplateExpression>,
+    blocks: &BTreeMap<String, Vec<TemplateExpression>>,
+) -> Vec<TemplateExpression> {
+    let fill = |body| fill_blocks(body, blocks);
+    body.into_iter()
+        .map(|expr| match expr {
+            TemplateExpression::Block { name, body } => {
+                let body = match blocks.get(&name) {
+                    Some(given) => given.clone(),
+                    None => fill(body),
+                };
+                TemplateExpression::Block { name, body }
+            }
+            TemplateExpression::Yield { name } => TemplateExpression::Block {
+                body: blocks.get(&name).cloned().unwrap_or_default(),
+                name,
+            },
+            TemplateExpression::ForLoop { name, expr, body } => {
+                TemplateExpression::ForLoop {
+                    name,
+                    expr,
+                    body: fill(body),
+                }
+            }
+            TemplateExpression::IfBlock {
+                expr,
+                body,
+                else_body,
+            } => TemplateExpression::IfBlock {
+                expr,
+                body: fill(body),
+                else_body: else_body.map(fill),
+            },
+            TemplateExpression::WhileLoop { expr, body } => {
+                TemplateExpression::WhileLoop {
+                    expr,
+                    body: fill(body),
+                }
+            }
+            TemplateExpression::MatchBlock { expr, arms } => {
+                TemplateExpression::MatchBlock {
+                    expr,
+                    arms: arms
+                        .into_iter()
+                        .map(|(pattern, body)| (pattern, fill(body)))
+                        .collect(),
+                }
+            }
+            TemplateExpression::RawBlock { body } => {
+                TemplateExpression::RawBlock { body: fill(body) }
+            }
+            TemplateExpression::Macro { name, args, body } => {
+                TemplateExpression::Macro {
+                    name,
+                    args,
+                    body: fill(body),
+                }
+            }
+            TemplateExpression::CallTemplate { name, args } => {
+                TemplateExpression::CallTemplate {
+                    name,
+                    args: args
+                        .into_iter()
+                        .map(|arg| match arg {
+                            TemplateArgument::Body(body) => {
+                                TemplateArgument::Body(fill(body))
+                            }
+                            arg => arg,
+                        })
+                        .collect(),
+                }
+            }
+            expr => expr,
+        })
+        .collect()
 }
 
-fn logic_expression(input: &[u8]) -> PResult<&str> {
-    map_res(
-        recognize(tuple((
-            opt(terminated(char('!'), spacelike)),
-            expression,
-            opt(pair(
-                rel_operator,
-                context("Expected expression", logic_expression),
-            )),
-        ))),
-        input_to_str,
-    )(input)
+/// Write an html comment with the source of each expression in
+/// `body` before and after its output, in debug builds.
+pub fn trace_expressions(
+    body: Vec<TemplateExpression>,
+) -> Vec<TemplateExpression> {
+    body.into_iter()
+        .flat_map(|expr| match expr {
+            TemplateExpression::Expression { expr } => vec![
+                trace_comment("begin", &expr),
+                TemplateExpression::Expression { expr: expr.clone() },
+                trace_comment("end", &expr),
+            ],
+            TemplateExpression::ForLoop { name, expr, body } => {
+                vec![TemplateExpression::ForLoop {
+                    name,
+                    expr,
+                    body: trace_expressions(body),
+                }]
+            }
+            TemplateExpression::IfBlock {
+                expr,
+                body,
+                else_body,
+            } => vec![TemplateExpression::IfBlock {
+                expr,
+                body: trace_expressions(body),
+                else_body: else_body.map(trace_expressions),
+            }],
+            TemplateExpression::WhileLoop { expr, body } => {
+                vec![TemplateExpression::WhileLoop {
+                    expr,
+                    body: trace_expressions(body),
+                }]
+            }
+            TemplateExpression::MatchBlock { expr, arms } => {
+                vec![TemplateExpression::MatchBlock {
+                    expr,
+                    arms: arms
+                        .into_iter()
+                        .map(|(pattern, body)| {
+                            (pattern, trace_expressions(body))
+                        })
+                        .collect(),
+                }]
+            }
+            TemplateExpression::RawBlock { body } => {
+                vec![TemplateExpression::RawBlock {
+                    body: trace_expressions(body),
+                }]
+            }
+            TemplateExpression::Block { name, body } => {
+                vec![TemplateExpression::Block {
+                    name,
+                    body: trace_expressions(body),
+                }]
+            }
+            TemplateExpression::Macro { name, args, body } => {
+                vec![TemplateExpression::Macro {
+                    name,
+                    args,
+                    body: trace_expressions(body),
+                }]
+            }
+            TemplateExpression::CallTemplate { name, args } => {
+                vec![TemplateExpression::CallTemplate {
+                    name,
+                    args: args
+                        .into_iter()
+                        .map(|arg| match arg {
+                            TemplateArgument::Body(body) => {
+                                TemplateArgument::Body(trace_expressions(
+                                    body,
+                                ))
+                            }
+                            arg => arg,
+                        })
+                        .collect(),
+                }]
+            }
+            expr => vec![expr],
+        })
+        .collect()
 }
 
-fn rel_operator(input: &[u8]) -> PResult<&str> {
-    map_res(
-        delimited(
-            spacelike,
-            context(
-                "Expected relational operator",
-                alt((
-                    tag("!="),
-                    tag("&&"),
-                    tag("<="),
-                    tag("<"),
-                    tag("=="),
-                    tag(">="),
-                    tag(">"),
-                    tag("||"),
-                )),
-            ),
-            spacelike,
-        ),
-        input_to_str,
-    )(input)
+/// A `<!-- what: expr -->` comment, written only in debug builds.
+///
+/// Any `--` in `expr` is split, so it does not end the comment.
+fn trace_comment(what: &str, expr: &str) -> TemplateExpression {
+    TemplateExpression::IfBlock {
+        expr: "cfg!(debug_assertions)".into(),
+        body: vec![TemplateExpression::text(&format!(
+            "<!-- {}: {} -->",
+            what,
+            expr.replace("--", "- -"),
+        ))],
+        else_body: None,
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::super::parseresult::show_errors;
-    use super::*;
+/// Add a `nonce` attribute with the value of the `nonce` expression
+/// to each `<script` and `<style` opening tag in the text of `body`.
+pub fn add_csp_nonce(
+    body: Vec<TemplateExpression>,
+    nonce: &str,
+) -> Vec<TemplateExpression> {
+    let add = |body| add_csp_nonce(body, nonce);
+    body.into_iter()
+        .flat_map(|expr| match expr {
+            TemplateExpression::Text { text } => nonce_text(&text, nonce),
+            TemplateExpression::ForLoop { name, expr, body } => {
+                vec![TemplateExpression::ForLoop {
+                    name,
+                    expr,
+                    body: add(body),
+                }]
+            }
+            TemplateExpression::IfBlock {
+                expr,
+                body,
+                else_body,
+            } => vec![TemplateExpression::IfBlock {
+                expr,
+                body: add(body),
+                else_body: else_body.map(add),
+            }],
+            TemplateExpression::WhileLoop { expr, body } => {
+                vec![TemplateExpression::WhileLoop {
+                    expr,
+                    body: add(body),
+                }]
+            }
+            TemplateExpression::MatchBlock { expr, arms } => {
+                vec![TemplateExpression::MatchBlock {
+                    expr,
+                    arms: arms
+                        .into_iter()
+                        .map(|(pattern, body)| (pattern, add(body)))
+                        .collect(),
+                }]
+            }
+            TemplateExpression::RawBlock { body } => {
+                vec![TemplateExpression::RawBlock { body: add(body) }]
+            }
+            TemplateExpression::Block { name, body } => {
+                vec![TemplateExpression::Block {
+                    name,
+                    body: add(body),
+                }]
+            }
+            TemplateExpression::Macro { name, args, body } => {
+                vec![TemplateExpression::Macro {
+                    name,
+                    args,
+                    body: add(body),
+                }]
+            }
+            TemplateExpression::CallTemplate { name, args } => {
+                vec![TemplateExpression::CallTemplate {
+                    name,
+                    args: args
+                        .into_iter()
+                        .map(|arg| match arg {
+                            TemplateArgument::Body(body) => {
+                                TemplateArgument::Body(add(body))
+                            }
+                            arg => arg,
+                        })
+                        .collect(),
+                }]
+            }
+            expr => vec![expr],
+        })
+        .collect()
+}
+
+/// Split `text` after each `<script` and `<style` tag name, with a
+/// nonce attribute in between.
+///
+/// The tag name may be followed by any whitespace, including a
+/// newline, or by the end of the tag or of the text.
+fn nonce_text(text: &str, nonce: &str) -> Vec<TemplateExpression> {
+    let lower = text.to_ascii_lowercase();
+    let mut ends = ["<script", "<style"]
+        .iter()
+        .flat_map(|tag| {
+            lower.match_indices(tag).map(move |(i, _)| i + tag.len())
+        })
+        .filter(|&end| match lower.as_bytes().get(end) {
+            None | Some(b'>') | Some(b'/') => true,
+            Some(c) => c.is_ascii_whitespace(),
+        })
+        .collect::<Vec<_>>();
+    ends.sort_unstable();
+    let mut result = Vec::new();
+    let mut start = 0;
+    for end in ends {
+        result.push(TemplateExpression::text(&text[start..end]));
+        result.push(TemplateExpression::text(" nonce=\""));
+        result.push(TemplateExpression::Expression {
+            expr: nonce.to_string(),
+        });
+        result.push(TemplateExpression::text("\""));
+        start = end;
+    }
+    if start < text.len() || result.is_empty() {
+        result.push(TemplateExpression::text(&text[start..]));
+    }
+    result
+}
+
+/// Call `f` for each `@include` in `body`.
+///
+/// The names bound where the include is (the names in `scope`, and
+/// names bound by enclosing loops, let-bindings etc. in `body`) are
+/// given to `f`.
+pub fn for_each_include<E, F>(
+    body: &mut [TemplateExpression],
+    scope: &mut Vec<String>,
+    f: &mut F,
+) -> Result<(), E>
+where
+    F: FnMut(&mut Include, &[String]) -> Result<(), E>,
+{
+    let outer_len = scope.len();
+    for expr in body {
+        let inner_len = scope.len();
+        match *expr {
+            TemplateExpression::Include(ref mut include) => {
+                f(include, scope)?;
+            }
+            TemplateExpression::Let { ref pattern, .. } => {
+                // A let binding is in scope for the rest of the block.
+                scope.extend(pattern_names(pattern));
+                continue;
+            }
+            TemplateExpression::Const { ref name, .. } => {
+                scope.push(name.clone());
+                continue;
+            }
+            TemplateExpression::ForLoop {
+                ref name,
+                ref mut body,
+                ..
+            } => {
+                scope.extend(pattern_names(name));
+                for_each_include(body, scope, f)?;
+            }
+            TemplateExpression::IfBlock {
+                ref expr,
+                ref mut body,
+                ref mut else_body,
+            } => {
+                scope.extend(let_names(expr));
+                for_each_include(body, scope, f)?;
+                scope.truncate(inner_len);
+                if let Some(ref mut else_body) = *else_body {
+                    for_each_include(else_body, scope, f)?;
+                }
+            }
+            TemplateExpression::WhileLoop {
+                ref expr,
+                ref mut body,
+            } => {
+                scope.extend(let_names(expr));
+                for_each_include(body, scope, f)?;
+            }
+            TemplateExpression::MatchBlock { ref mut arms, .. } => {
+                for (pattern, ref mut body) in arms {
+                    let pattern = pattern.split(" if ").next().unwrap_or("");
+                    scope.extend(pattern_names(pattern));
+                    for_each_include(body, scope, f)?;
+                    scope.truncate(inner_len);
+                }
+            }
+            TemplateExpression::RawBlock { ref mut body }
+            | TemplateExpression::Block { ref mut body, .. } => {
+                for_each_include(body, scope, f)?;
+            }
+            TemplateExpression::Macro {
+                ref args,
+                ref mut body,
+                ..
+            } => {
+                scope.extend(args.iter().map(|arg| formal_name(arg)));
+                for_each_include(body, scope, f)?;
+            }
+            TemplateExpression::CallTemplate { ref mut args, .. } => {
+                for arg in args {
+                    if let TemplateArgument::Body(ref mut body) = *arg {
+                        for_each_include(body, scope, f)?;
+                    }
+                }
+            }
+            _ => (),
+        }
+        scope.truncate(inner_len);
+    }
+    scope.truncate(outer_len);
+    Ok(())
+}
+
+/// Replace each expression in `body` that is a call of one of the
+/// `macros` (or of a macro defined earlier in `body`) with a
+/// `MacroCall`.
+///
+/// A macro can be used after its definition, in the same block or in
+/// any block nested in it, including later macros.
+pub fn resolve_macro_calls(
+    body: &mut [TemplateExpression],
+    macros: &mut Vec<String>,
+) {
+    let outer_len = macros.len();
+    for expr in body {
+        match *expr {
+            TemplateExpression::Expression { expr: ref call } => {
+                let resolved = macros.iter().rev().find_map(|name| {
+                    let args = macro_call_args(call, name)?;
+                    Some(TemplateExpression::MacroCall {
+                        name: name.clone(),
+                        args: args.trim().to_string(),
+                    })
+                });
+                if let Some(resolved) = resolved {
+                    *expr = resolved;
+                }
+            }
+            TemplateExpression::Macro {
+                ref name,
+                ref mut body,
+                ..
+            } => {
+                resolve_macro_calls(body, macros);
+                macros.push(name.clone());
+            }
+            TemplateExpression::ForLoop { ref mut body, .. }
+            | TemplateExpression::WhileLoop { ref mut body, .. }
+            | TemplateExpression::RawBlock { ref mut body }
+            | TemplateExpression::Block { ref mut body, .. } => {
+                resolve_macro_calls(body, macros)
+            }
+            TemplateExpression::IfBlock {
+                ref mut body,
+                ref mut else_body,
+                ..
+            } => {
+                resolve_macro_calls(body, macros);
+                if let Some(ref mut else_body) = *else_body {
+                    resolve_macro_calls(else_body, macros);
+                }
+            }
+            TemplateExpression::MatchBlock { ref mut arms, .. } => {
+                for (_, ref mut body) in arms {
+                    resolve_macro_calls(body, macros);
+                }
+            }
+            TemplateExpression::CallTemplate { ref mut args, .. } => {
+                for arg in args {
+                    if let TemplateArgument::Body(ref mut body) = *arg {
+                        resolve_macro_calls(body, macros);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    macros.truncate(outer_len);
+}
+
+/// Replace each `Filtered` expression in `body` with the expression
+/// with its filters applied, up to the first filter that is neither a
+/// standard filter nor one of the names `used` by the template.
+///
+/// The source from that filter on is template text, so a text like
+/// "@title | home" means the same as it did before there were filters.
+pub fn resolve_filters(body: &mut Vec<TemplateExpression>, used: &[String]) {
+    for expr in std::mem::take(body) {
+        let mut expr = match expr {
+            TemplateExpression::Filtered { expr, filters } => {
+                let known =
+                    filters.iter().take_while(|f| f.is_known(used)).count();
+                body.push(TemplateExpression::Expression {
+                    expr: apply_filters(&expr, &filters[..known], used),
+                });
+                if known < filters.len() {
+                    body.push(TemplateExpression::Text {
+                        text: filters[known..]
+                            .iter()
+                            .map(|f| f.source.as_str())
+                            .collect(),
+                    });
+                }
+                continue;
+            }
+            expr => expr,
+        };
+        match expr {
+            TemplateExpression::ForLoop { ref mut body, .. }
+            | TemplateExpression::WhileLoop { ref mut body, .. }
+            | TemplateExpression::RawBlock { ref mut body }
+            | TemplateExpression::Block { ref mut body, .. }
+            | TemplateExpression::Macro { ref mut body, .. } => {
+                resolve_filters(body, used)
+            }
+            TemplateExpression::IfBlock {
+                ref mut body,
+                ref mut else_body,
+                ..
+            } => {
+                resolve_filters(body, used);
+                if let Some(ref mut else_body) = *else_body {
+                    resolve_filters(else_body, used);
+                }
+            }
+            TemplateExpression::MatchBlock { ref mut arms, .. } => {
+                for (_, ref mut body) in arms {
+                    resolve_filters(body, used);
+                }
+            }
+            TemplateExpression::CallTemplate { ref mut args, .. } => {
+                for arg in args {
+                    if let TemplateArgument::Body(ref mut body) = *arg {
+                        resolve_filters(body, used);
+                    }
+                }
+            }
+            _ => (),
+        }
+        body.push(expr);
+    }
+}
+
+/// Replace each call of the template `name` in `body` with a call
+/// of `depth_fn`, passing the depth of the call as the first argument,
+/// and tell if there were any.
+pub fn pass_call_depth(
+    body: &mut [TemplateExpression],
+    name: &str,
+    depth_fn: &str,
+) -> bool {
+    let mut found = false;
+    for expr in body {
+        match *expr {
+            TemplateExpression::CallTemplate {
+                name: ref mut call,
+                ref mut args,
+            } => {
+                for arg in args.iter_mut() {
+                    if let TemplateArgument::Body(ref mut body) = *arg {
+                        found |= pass_call_depth(body, name, depth_fn);
+                    }
+                }
+                if call.trim_start_matches("self::") == name {
+                    *call = depth_fn.to_string();
+                    args.insert(
+                        0,
+                        TemplateArgument::Rust("_depth + 1".into()),
+                    );
+                    found = true;
+                }
+            }
+            TemplateExpression::ForLoop { ref mut body, .. }
+            | TemplateExpression::WhileLoop { ref mut body, .. }
+            | TemplateExpression::RawBlock { ref mut body }
+            | TemplateExpression::Block { ref mut body, .. }
+            | TemplateExpression::Macro { ref mut body, .. } => {
+                found |= pass_call_depth(body, name, depth_fn);
+            }
+            TemplateExpression::IfBlock {
+                ref mut body,
+                ref mut else_body,
+                ..
+            } => {
+                found |= pass_call_depth(body, name, depth_fn);
+                if let Some(ref mut else_body) = *else_body {
+                    found |= pass_call_depth(else_body, name, depth_fn);
+                }
+            }
+            TemplateExpression::MatchBlock { ref mut arms, .. } => {
+                for (_, ref mut body) in arms {
+                    found |= pass_call_depth(body, name, depth_fn);
+                }
+            }
+            _ => (),
+        }
+    }
+    found
+}
+
+/// The arguments of `call`, if it is just a call of `name`.
+fn macro_call_args<'a>(call: &'a str, name: &str) -> Option<&'a str> {
+    let rest = call.strip_prefix(name)?;
+    match expr_in_parens(rest.as_bytes()) {
+        Ok((b"", args)) => Some(&args[1..args.len() - 1]),
+        _ => None,
+    }
+}
+
+/// The name of a formal argument, as in `name: Type`.
+fn formal_name(arg: &str) -> String {
+    arg.split(':').next().unwrap_or("").trim().to_string()
+}
+
+/// The names bound by the pattern of an `if let` or `while let`.
+fn let_names(cond: &str) -> Vec<String> {
+    match cond.strip_prefix("let ") {
+        Some(rest) => pattern_names(rest.split('=').next().unwrap_or("")),
+        None => vec![],
+    }
+}
+
+/// The names bound by a pattern, such as `x` and `y` in
+/// `(Some(x), Point { y, .. })`.
+fn pattern_names(pattern: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = pattern;
+    while let Some(start) =
+        rest.find(|c: char| c.is_alphanumeric() || c == '_')
+    {
+        let preceded_by_path = rest[..start].ends_with("::");
+        rest = &rest[start..];
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let (name, after) = rest.split_at(end);
+        let after = after.trim_start();
+        let starts_lowercase =
+            name.starts_with(|c: char| c.is_lowercase() || c == '_');
+        if starts_lowercase
+            && !preceded_by_path
+            && name != "ref"
+            && name != "mut"
+            && name != "_"
+            && !after.starts_with(['(', '{', '!'])
+            && !after.starts_with("::")
+        {
+            names.push(name.to_string());
+        }
+        rest = after;
+    }
+    names
+}
+
+pub fn template_expression(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    match opt(preceded(
+        terminated(char('@'), opt(terminated(char('-'), space0))),
+        alt((
+            tag("*"),
+            tag("//"),
+            tag(":"),
+            tag("@"),
+            tag("{"),
+            tag("}"),
+            tag("("),
+            terminated(
+                alt((
+                    tag("if"),
+                    tag("for"),
+                    tag("match"),
+                    tag("while"),
+                    tag("let"),
+                    tag("const"),
+                )),
+                tag(" "),
+            ),
+            terminated(tag("raw"), peek(preceded(space0, char('{')))),
+            terminated(
+                alt((tag("tn"), tag("t"), tag("ngettext"), tag("icu_msg"))),
+                peek(char('(')),
+            ),
+            terminated(tag("yield"), tag(" ")),
+            terminated(tag("macro"), tag(" ")),
+            terminated(
+                tag("include"),
+                peek(alt((
+                    value((), preceded(space0, char('"'))),
+                    value((), tuple((space1, root_path, space0, char('{')))),
+                ))),
+            ),
+            terminated(
+                tag("block"),
+                peek(tuple((tag(" "), rust_name, space0, char('{')))),
+            ),
+            value(&b""[..], tag("")),
+        )),
+    ))(input)?
+    {
+        (i, Some(b":")) => map(
+            pair(
+                rust_name,
+                delimited(
+                    char('('),
+                    separated_list(
+                        terminated(tag(","), spacelike),
+                        template_argument,
+                    ),
+                    char(')'),
+                ),
+            ),
+            |(name, args)| TemplateExpression::CallTemplate {
+                name: name.to_string(),
+                args,
+            },
+        )(i),
+        (i, Some(b"@")) => Ok((i, TemplateExpression::text("@"))),
+        (i, Some(b"{")) => Ok((i, TemplateExpression::text("{"))),
+        (i, Some(b"}")) => Ok((i, TemplateExpression::text("}"))),
+        (i, Some(b"*")) => {
+            map(comment_tail, |()| TemplateExpression::Comment)(i)
+        }
+        (i, Some(b"//")) => {
+            map(line_comment_tail, |()| TemplateExpression::Comment)(i)
+        }
+        (i, Some(b"if")) => if_block(i),
+        (i, Some(b"while")) => context(
+            "Error in while loop:",
+            map(
+                pair(
+                    delimited(spacelike, cond_expression, spacelike),
+                    terminated(
+                        context("Error in loop block:", template_block),
+                        spacelike,
+                    ),
+                ),
+                |(expr, body)| TemplateExpression::WhileLoop { expr, body },
+            ),
+        )(i),
+        (i, Some(b"raw")) => map(
+            preceded(space0, context("Error in raw block:", template_block)),
+            |body| TemplateExpression::RawBlock { body },
+        )(i),
+        (i, Some(b"block")) => context(
+            "Error in block:",
+            map(
+                pair(
+                    delimited(spacelike, rust_name, spacelike),
+                    template_block,
+                ),
+                |(name, body)| TemplateExpression::Block {
+                    name: name.to_string(),
+                    body,
+                },
+            ),
+        )(i),
+        (i, Some(b"include")) => {
+            let (i, _) = space0(i)?;
+            let rest_len = i.len();
+            let (i, (path, from_root)) = context(
+                "Expected quoted path of template to include",
+                alt((
+                    map(
+                        delimited(
+                            char('"'),
+                            map_res(is_not("\"\n"), input_to_str),
+                            char('"'),
+                        ),
+                        |path| (path, false),
+                    ),
+                    map(root_path, |path| (path, true)),
+                )),
+            )(i)?;
+            let (i, given_args) = opt(preceded(space0, include_args))(i)?;
+            let include = Include {
+                path: path.to_string(),
+                rest_len,
+                from_root,
+                given_args,
+                ..Include::default()
+            };
+            Ok((i, TemplateExpression::Include(include)))
+        }
+        (i, Some(b"t")) => context(
+            "Error in translated string:",
+            map(
+                delimited(
+                    terminated(char('('), spacelike),
+                    context("Expected quoted string", quoted_string),
+                    preceded(spacelike, char(')')),
+                ),
+                |msgid| TemplateExpression::Expression {
+                    expr: format!("gettext({})", msgid),
+                },
+            ),
+        )(i),
+        (i, Some(b"tn")) | (i, Some(b"ngettext")) => context(
+            "Error in translated plural string:",
+            map(
+                delimited(
+                    terminated(char('('), spacelike),
+                    tuple((
+                        context("Expected quoted string", quoted_string),
+                        preceded(
+                            delimited(spacelike, char(','), spacelike),
+                            context("Expected quoted string", quoted_string),
+                        ),
+                        preceded(
+                            delimited(spacelike, char(','), spacelike),
+                            context("Expected count expression", expression),
+                        ),
+                    )),
+                    preceded(spacelike, char(')')),
+                ),
+                |(msgid, plural, n)| TemplateExpression::Expression {
+                    expr: format!("ngettext({}, {}, {})", msgid, plural, n),
+                },
+            ),
+        )(i),
+        (i, Some(b"icu_msg")) => context(
+            "Error in ICU message:",
+            map(
+                delimited(
+                    terminated(char('('), spacelike),
+                    pair(
+                        context("Expected quoted pattern", quoted_string),
+                        many0(preceded(
+                            delimited(spacelike, char(','), spacelike),
+                            pair(
+                                context("Expected argument name", rust_name),
+                                preceded(
+                                    delimited(
+                                        spacelike,
+                                        char(':'),
+                                        spacelike,
+                                    ),
+                                    context(
+                                        "Expected expression",
+                                        expression,
+                                    ),
+                                ),
+                            ),
+                        )),
+                    ),
+                    preceded(spacelike, char(')')),
+                ),
+                |(pattern, args)| TemplateExpression::Expression {
+                    expr: format!(
+                        "icu_msg({}, &[{}])",
+                        pattern,
+                        args.iter().format_with(", ", |(name, value), f| {
+                            f(&format_args!(
+                                "({:?}, ({}).into())",
+                                name, value
+                            ))
+                        }),
+                    ),
+                },
+            ),
+        )(i),
+        (i, Some(b"macro")) => context(
+            "Error in macro definition:",
+            map(
+                tuple((
+                    delimited(
+                        spacelike,
+                        context("Expected macro name", rust_name),
+                        spacelike,
+                    ),
+                    delimited(
+                        terminated(char('('), spacelike),
+                        separated_list(
+                            delimited(spacelike, char(','), spacelike),
+                            context(
+                                "Expected formal argument",
+                                formal_argument,
+                            ),
+                        ),
+                        preceded(spacelike, char(')')),
+                    ),
+                    delimited(
+                        spacelike,
+                        context("Error in macro body:", template_block),
+                        spacelike,
+                    ),
+                )),
+                |(name, args, body)| TemplateExpression::Macro {
+                    name: name.to_string(),
+                    args: args.into_iter().map(String::from).collect(),
+                    body,
+                },
+            ),
+        )(i),
+        (i, Some(b"yield")) => map(
+            preceded(
+                spacelike,
+                context("Expected name of block to yield", rust_name),
+            ),
+            |name| TemplateExpression::Yield {
+                name: name.to_string(),
+            },
+        )(i),
+        (i, Some(b"let")) => context(
+            "Error in let binding:",
+            map(
+                pair(
+                    delimited(
+                        spacelike,
+                        context("Expected pattern", let_pattern),
+                        delimited(spacelike, char('='), spacelike),
+                    ),
+                    terminated(
+                        context("Expected expression", let_value),
+                        context("Expected \";\"", tag(";")),
+                    ),
+                ),
+                |(pattern, expr)| TemplateExpression::Let {
+                    pattern: pattern.to_string(),
+                    expr: expr.trim_end().to_string(),
+                },
+            ),
+        )(i),
+        (i, Some(b"const")) => map(
+            context("Error in const declaration:", const_declaration),
+            |(name, ty, expr)| TemplateExpression::Const {
+                name: name.to_string(),
+                ty: ty.to_string(),
+                expr: expr.to_string(),
+            },
+        )(i),
+        (i, Some(b"for")) => map(
+            tuple((
+                for_variable,
+                delimited(
+                    terminated(
+                        context("Expected \"in\"", tag("in")),
+                        spacelike,
+                    ),
+                    context("Expected iterable expression", loop_expression),
+                    spacelike,
+                ),
+                terminated(
+                    context("Error in loop block:", template_block),
+                    spacelike,
+                ),
+            )),
+            |(name, expr, body)| TemplateExpression::ForLoop {
+                name,
+                expr,
+                body,
+            },
+        )(i),
+        (i, Some(b"match")) => context(
+            "Error in match expression:",
+            map(
+                tuple((
+                    delimited(spacelike, expression, spacelike),
+                    preceded(
+                        char('{'),
+                        map(
+                            many_till(
+                                context(
+                                    "Error in match arm starting here:",
+                                    pair(
+                                        delimited(
+                                            spacelike,
+                                            map(match_pattern, String::from),
+                                            spacelike,
+                                        ),
+                                        preceded(
+                                            terminated(tag("=>"), spacelike),
+                                            template_block,
+                                        ),
+                                    ),
+                                ),
+                                preceded(spacelike, char('}')),
+                            ),
+                            |(arms, _end)| arms,
+                        ),
+                    ),
+                )),
+                |(expr, arms)| TemplateExpression::MatchBlock {
+                    expr: expr.to_string(),
+                    arms,
+                },
+            ),
+        )(i),
+        (i, Some(b"(")) => map(
+            pair(terminated(expr_inside_parens, tag(")")), filters),
+            |(expr, filters)| {
+                TemplateExpression::filtered(format!("({})", expr), filters)
+            },
+        )(i),
+        (i, Some(b"")) => {
+            map(pair(expression, filters), |(expr, filters)| {
+                TemplateExpression::filtered(expr.to_string(), filters)
+            })(i)
+        }
+        (_i, Some(_)) => unreachable!(),
+        (i, None) => map(map_res(is_not("@{}"), input_to_str), |text| {
+            TemplateExpression::Text {
+                text: text.to_string(),
+            }
+        })(i),
+    }
+}
+
+fn for_variable(input: &[u8]) -> PResult<'_, String> {
+    delimited(
+        spacelike,
+        context(
+            "Expected loop variable name or destructuring tuple",
+            alt((
+                map(
+                    map_res(
+                        recognize(preceded(rust_name, opt(expr_in_braces))),
+                        input_to_str,
+                    ),
+                    String::from,
+                ),
+                map(
+                    pair(
+                        opt(char('&')),
+                        alt((
+                            map(
+                                delimited(
+                                    char('('),
+                                    comma_expressions,
+                                    char(')'),
+                                ),
+                                |args| format!("({})", args),
+                            ),
+                            map(
+                                delimited(
+                                    char('['),
+                                    comma_expressions,
+                                    char(']'),
+                                ),
+                                |args| format!("[{}]", args),
+                            ),
+                        )),
+                    ),
+                    |(pre, pattern)| match pre {
+                        Some(_) => format!("&{}", pattern),
+                        None => pattern,
+                    },
+                ),
+            )),
+        ),
+        spacelike,
+    )(input)
+}
+
+/// A pattern of a match arm, with alternatives and an optional guard.
+fn match_pattern(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(tuple((
+            opt(terminated(tag("|"), spacelike)),
+            separated_nonempty_list(
+                delimited(spacelike, tag("|"), spacelike),
+                context("Expected pattern", loop_expression),
+            ),
+            opt(preceded(
+                delimited(spacelike, tag("if"), multispace1),
+                cut(context("Expected guard expression", logic_expression)),
+            )),
+        ))),
+        input_to_str,
+    )(input)
+}
+
+/// The part of an `@if` expression following the `if` keyword.
+///
+/// The else part may be written as `else` or `@else`, and may be
+/// followed by another `if` to chain conditions.
+fn if_block(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in conditional expression:",
+        map(
+            tuple((
+                delimited(spacelike, cond_expression, spacelike),
+                template_block,
+                opt(preceded(
+                    delimited(
+                        spacelike,
+                        preceded(opt(char('@')), tag("else")),
+                        spacelike,
+                    ),
+                    alt((
+                        template_block,
+                        map(preceded(tag("if "), if_block), |e| vec![e]),
+                    )),
+                )),
+            )),
+            |(expr, body, else_body)| TemplateExpression::IfBlock {
+                expr,
+                body,
+                else_body,
+            },
+        ),
+    )(input)
+}
+
+fn let_pattern(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(tuple((
+            opt(terminated(tag("mut"), multispace1)),
+            expression,
+            opt(tuple((spacelike, char(':'), spacelike, type_expression))),
+        ))),
+        input_to_str,
+    )(input)
+}
+
+/// The `NAME: Type = value;` of a `@const` declaration, as the name,
+/// the type and the value.
+pub fn const_declaration(input: &[u8]) -> PResult<'_, (&str, &str, &str)> {
+    tuple((
+        delimited(
+            spacelike,
+            context(
+                "Expected constant name",
+                map_res(
+                    take_while1(|c: u8| {
+                        c.is_ascii_alphanumeric() || c == b'_'
+                    }),
+                    input_to_str,
+                ),
+            ),
+            delimited(
+                spacelike,
+                context("Expected \":\"", char(':')),
+                spacelike,
+            ),
+        ),
+        terminated(
+            context(
+                "Expected type",
+                map_res(recognize(type_expression), input_to_str),
+            ),
+            delimited(
+                spacelike,
+                context("Expected \"=\"", char('=')),
+                spacelike,
+            ),
+        ),
+        map(
+            terminated(
+                context("Expected expression", let_value),
+                context("Expected \";\"", tag(";")),
+            ),
+            str::trim_end,
+        ),
+    ))(input)
+}
+
+/// The value of a let binding is any rust expression up to the
+/// terminating semicolon.
+fn let_value(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(many1(alt((
+            value((), is_not("{}[]()\";")),
+            value((), expr_in_braces),
+            value((), expr_in_brackets),
+            value((), expr_in_parens),
+            value((), quoted_string),
+        )))),
+        input_to_str,
+    )(input)
+}
+
+/// An unquoted template path, relative to the template directory.
+fn root_path(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        take_while1(|c: u8| {
+            c.is_ascii_alphanumeric() || b"_-./".contains(&c)
+        }),
+        input_to_str,
+    )(input)
+}
+
+/// The arguments of an include, as `{ name, name: value }`.
+fn include_args(input: &[u8]) -> PResult<'_, Vec<(String, String)>> {
+    delimited(
+        terminated(char('{'), spacelike),
+        separated_list(
+            delimited(spacelike, char(','), spacelike),
+            map(
+                pair(
+                    rust_name,
+                    opt(preceded(
+                        delimited(spacelike, char(':'), spacelike),
+                        expression,
+                    )),
+                ),
+                |(name, value)| {
+                    (name.to_string(), value.unwrap_or(name).to_string())
+                },
+            ),
+        ),
+        context(
+            "Expected \"}\" after include arguments",
+            preceded(
+                pair(spacelike, opt(char(','))),
+                preceded(spacelike, char('}')),
+            ),
+        ),
+    )(input)
+}
+
+fn template_block(input: &[u8]) -> PResult<'_, Vec<TemplateExpression>> {
+    preceded(char('{'), template_body(char('}')))(input)
+}
+
+fn template_argument(input: &[u8]) -> PResult<'_, TemplateArgument> {
+    alt((
+        map(
+            delimited(
+                char('{'),
+                many0(template_expression),
+                terminated(char('}'), spacelike),
+            ),
+            TemplateArgument::Body,
+        ),
+        map(map(expression, String::from), TemplateArgument::Rust),
+    ))(input)
+}
+
+fn cond_expression(input: &[u8]) -> PResult<'_, String> {
+    match opt(tag("let"))(input)? {
+        (i, Some(b"let")) => map(
+            pair(
+                preceded(
+                    spacelike,
+                    context(
+                        "Expected LHS expression in let binding",
+                        expression,
+                    ),
+                ),
+                preceded(
+                    delimited(spacelike, char('='), spacelike),
+                    context(
+                        "Expected RHS expression in let binding",
+                        expression,
+                    ),
+                ),
+            ),
+            |(lhs, rhs)| format!("let {} = {}", lhs, rhs),
+        )(i),
+        (_i, Some(_)) => unreachable!(),
+        (i, None) => map(
+            context("Expected expression", logic_expression),
+            String::from,
+        )(i),
+    }
+}
+
+fn loop_expression(input: &[u8]) -> PResult<'_, String> {
+    map(
+        map_res(
+            recognize(terminated(
+                expression,
+                opt(preceded(
+                    terminated(tag(".."), opt(char('='))),
+                    expression,
+                )),
+            )),
+            input_to_str,
+        ),
+        String::from,
+    )(input)
+}
+
+fn logic_expression(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(tuple((
+            opt(terminated(char('!'), spacelike)),
+            expression,
+            opt(pair(
+                rel_operator,
+                context("Expected expression", logic_expression),
+            )),
+        ))),
+        input_to_str,
+    )(input)
+}
+
+fn rel_operator(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        delimited(
+            spacelike,
+            context(
+                "Expected relational operator",
+                alt((
+                    tag("!="),
+                    tag("&&"),
+                    tag("<="),
+                    tag("<"),
+                    tag("=="),
+                    tag(">="),
+                    tag(">"),
+                    tag("||"),
+                )),
+            ),
+            spacelike,
+        ),
+        input_to_str,
+    )(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::parseresult::show_errors;
+    use super::*;
+
+    #[test]
+    fn for_variable_simple() {
+        assert_eq!(
+            for_variable(b"foo").unwrap(),
+            (&b""[..], "foo".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_tuple() {
+        assert_eq!(
+            for_variable(b"(foo, bar)").unwrap(),
+            (&b""[..], "(foo, bar)".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_tuple_ref() {
+        assert_eq!(
+            for_variable(b"&(foo, bar)").unwrap(),
+            (&b""[..], "&(foo, bar)".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_nested_tuple() {
+        assert_eq!(
+            for_variable(b"(i, (key, &value)) ").unwrap(),
+            (&b""[..], "(i, (key, &value))".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_array() {
+        assert_eq!(
+            for_variable(b"&[x, y]").unwrap(),
+            (&b""[..], "&[x, y]".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_struct() {
+        assert_eq!(
+            for_variable(b"MyStruct{foo, bar}").unwrap(),
+            (&b""[..], "MyStruct{foo, bar}".to_string())
+        )
+    }
+
+    #[test]
+    fn call_simple() {
+        assert_eq!(
+            template_expression(b"@foo()"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "foo()".to_string(),
+                },
+            ))
+        )
+    }
+
+    /// Check that issue #53 stays fixed.
+    #[test]
+    fn call_empty_str() {
+        assert_eq!(
+            template_expression(b"@foo(\"\")"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "foo(\"\")".to_string(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn macro_definition() {
+        assert_eq!(
+            template_expression(
+                b"@macro btn(label: &str, n: u8) {<b>@label</b>}"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::Macro {
+                    name: "btn".into(),
+                    args: vec!["label: &str".into(), "n: u8".into()],
+                    body: vec![
+                        TemplateExpression::text("<b>"),
+                        TemplateExpression::Expression {
+                            expr: "label".into()
+                        },
+                        TemplateExpression::text("</b>"),
+                    ],
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn macro_calls_resolved_after_definition() {
+        let (_, mut body) = template_body(tag("!"))(
+            b"@btn(1)@macro btn(n: u8) {@n}@btn(2)@btn(3).x@if a {@btn()}!",
+        )
+        .unwrap();
+        resolve_macro_calls(&mut body, &mut Vec::new());
+        let call = |args: &str| TemplateExpression::MacroCall {
+            name: "btn".into(),
+            args: args.into(),
+        };
+        assert_eq!(
+            body[0],
+            TemplateExpression::Expression {
+                expr: "btn(1)".into()
+            }
+        );
+        assert_eq!(body[2], call("2"));
+        assert_eq!(
+            body[3],
+            TemplateExpression::Expression {
+                expr: "btn(3).x".into()
+            }
+        );
+        match body[4] {
+            TemplateExpression::IfBlock { ref body, .. } => {
+                assert_eq!(body, &[call("")])
+            }
+            ref other => panic!("Expected if block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn translated_string() {
+        assert_eq!(
+            template_expression(b"@t(\"Hello, \\\"world\\\"\")"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "gettext(\"Hello, \\\"world\\\"\")".to_string(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn translated_plural_string() {
+        assert_eq!(
+            template_expression(b"@tn(\"one item\", \"{} items\", n.len())"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "ngettext(\"one item\", \"{} items\", n.len())"
+                        .to_string(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn ngettext_plural_string() {
+        assert_eq!(
+            template_expression(b"@ngettext(\"one item\", \"{n} items\", n)"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "ngettext(\"one item\", \"{n} items\", n)".into(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn icu_message() {
+        assert_eq!(
+            template_expression(
+                b"@icu_msg(\"{count, plural, one{# item} other{# items}}\", \
+                  count: items.len(), user: &user.name)"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "icu_msg(\"{count, plural, one{# item} \
+                           other{# items}}\", &[\
+                           (\"count\", (items.len()).into()), \
+                           (\"user\", (&user.name).into())])"
+                        .into(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn icu_message_without_arguments() {
+        assert_eq!(
+            template_expression(b"@icu_msg(\"Welcome!\")"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "icu_msg(\"Welcome!\", &[])".into(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn ngettext_needs_three_arguments() {
+        assert!(template_expression(b"@ngettext(\"one item\", n)").is_err())
+    }
+
+    #[test]
+    fn call_starting_with_t() {
+        assert_eq!(
+            template_expression(b"@tr(1)"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "tr(1)".to_string(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn if_boolean_var() {
+        assert_eq!(
+            template_expression(b"@if cond { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "cond".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let() {
+        assert_eq!(
+            template_expression(b"@if let Some(x) = x { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Some(x) = x".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let_2() {
+        assert_eq!(
+            template_expression(b"@if let Some((x, y)) = x { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Some((x, y)) = x".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let_3() {
+        assert_eq!(
+            template_expression(
+                b"@if let Some(p) = Uri::borrow_from(&state) { something }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Some(p) = Uri::borrow_from(&state)"
+                        .to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let_at_else() {
+        assert_eq!(
+            template_expression(b"@if let Some(x) = x { @x } @else { none }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Some(x) = x".to_string(),
+                    body: vec![
+                        TemplateExpression::text(" "),
+                        TemplateExpression::Expression {
+                            expr: "x".to_string()
+                        },
+                        TemplateExpression::text(" "),
+                    ],
+                    else_body: Some(vec![TemplateExpression::text(" none ")]),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_else_if_let() {
+        assert_eq!(
+            template_expression(
+                b"@if let Ok(x) = a { ok } else if let Err(e) = b { err }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Ok(x) = a".to_string(),
+                    body: vec![TemplateExpression::text(" ok ")],
+                    else_body: Some(vec![TemplateExpression::IfBlock {
+                        expr: "let Err(e) = b".to_string(),
+                        body: vec![TemplateExpression::text(" err ")],
+                        else_body: None,
+                    }]),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let_struct() {
+        assert_eq!(
+            template_expression(
+                b"@if let Struct{x, y} = variable { something }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Struct{x, y} = variable".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_compare() {
+        assert_eq!(
+            template_expression(b"@if x == 17 { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "x == 17".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+
+    /// Check that issue #53 stays fixed.
+    #[test]
+    fn if_compare_empty_string() {
+        // Note that x.is_empty() would be better in real code, but this and
+        // other uses of empty strings in conditionals should be ok.
+        assert_eq!(
+            template_expression(b"@if x == \"\" { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "x == \"\"".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_complex_logig() {
+        assert_eq!(
+            template_expression(b"@if x == 17 || y && z() { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "x == 17 || y && z()".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                }
+            ))
+        )
+    }
+    #[test]
+    fn if_missing_conditional() {
+        assert_eq!(
+            expression_error(b"@if { oops }"),
+            ":   1:@if { oops }\n\
+             :         ^ Error in conditional expression:\n\
+             :   1:@if { oops }\n\
+             :         ^ Expected expression\n\
+             :   1:@if { oops }\n\
+             :         ^ Expected rust expression\n"
+        )
+    }
+
+    #[test]
+    fn if_bad_let() {
+        assert_eq!(
+            expression_error(b"@if let foo { oops }"),
+            ":   1:@if let foo { oops }\n\
+             :         ^ Error in conditional expression:\n\
+             :   1:@if let foo { oops }\n\
+             :                 ^ Expected \'=\'\n"
+        )
+    }
+
+    #[test]
+    fn while_loop() {
+        assert_eq!(
+            template_expression(b"@while n.get() < 3 { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::WhileLoop {
+                    expr: "n.get() < 3".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                }
+            ))
+        )
+    }
 
     #[test]
-    fn for_variable_simple() {
+    fn const_declaration() {
         assert_eq!(
-            for_variable(b"foo").unwrap(),
-            (&b""[..], "foo".to_string())
+            template_expression(b"@const MAX_ITEMS: usize = 2 * 5;"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Const {
+                    name: "MAX_ITEMS".to_string(),
+                    ty: "usize".to_string(),
+                    expr: "2 * 5".to_string(),
+                }
+            ))
         )
     }
 
     #[test]
-    fn for_variable_tuple() {
+    fn const_without_type() {
         assert_eq!(
-            for_variable(b"(foo, bar)").unwrap(),
-            (&b""[..], "(foo, bar)".to_string())
+            expression_error(b"@const MAX = 10;"),
+            ":   1:@const MAX = 10;\n\
+             :            ^ Error in const declaration:\n\
+             :   1:@const MAX = 10;\n\
+             :                ^ Expected \":\"\n\
+             :   1:@const MAX = 10;\n\
+             :                ^ Expected ':'\n"
         )
     }
 
     #[test]
-    fn for_variable_tuple_ref() {
+    fn while_let() {
         assert_eq!(
-            for_variable(b"&(foo, bar)").unwrap(),
-            (&b""[..], "&(foo, bar)".to_string())
+            template_expression(b"@while let Some(x) = iter.next() { @x }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::WhileLoop {
+                    expr: "let Some(x) = iter.next()".to_string(),
+                    body: vec![
+                        TemplateExpression::text(" "),
+                        TemplateExpression::Expression {
+                            expr: "x".to_string()
+                        },
+                        TemplateExpression::text(" "),
+                    ],
+                }
+            ))
         )
     }
 
     #[test]
-    fn for_variable_struct() {
+    fn while_missing_condition() {
         assert_eq!(
-            for_variable(b"MyStruct{foo, bar}").unwrap(),
-            (&b""[..], "MyStruct{foo, bar}".to_string())
+            expression_error(b"@while { oops }"),
+            ":   1:@while { oops }\n\
+             :            ^ Error in while loop:\n\
+             :   1:@while { oops }\n\
+             :            ^ Expected expression\n\
+             :   1:@while { oops }\n\
+             :            ^ Expected rust expression\n"
         )
     }
 
     #[test]
-    fn call_simple() {
+    fn let_simple() {
         assert_eq!(
-            template_expression(b"@foo()"),
+            template_expression(b"@let n = items.len() * 2;"),
             Ok((
                 &b""[..],
-                TemplateExpression::Expression {
-                    expr: "foo()".to_string(),
-                },
+                TemplateExpression::Let {
+                    pattern: "n".to_string(),
+                    expr: "items.len() * 2".to_string(),
+                }
             ))
         )
     }
 
-    /// Check that issue #53 stays fixed.
     #[test]
-    fn call_empty_str() {
+    fn let_tuple() {
         assert_eq!(
-            template_expression(b"@foo(\"\")"),
+            template_expression(b"@let (a, b) = pair;<p>"),
             Ok((
-                &b""[..],
-                TemplateExpression::Expression {
-                    expr: "foo(\"\")".to_string(),
-                },
+                &b"<p>"[..],
+                TemplateExpression::Let {
+                    pattern: "(a, b)".to_string(),
+                    expr: "pair".to_string(),
+                }
             ))
         )
     }
 
     #[test]
-    fn if_boolean_var() {
+    fn let_mut_with_string() {
         assert_eq!(
-            template_expression(b"@if cond { something }"),
+            template_expression(b"@let mut s = format!(\"{};\", x) ;"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "cond".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Let {
+                    pattern: "mut s".to_string(),
+                    expr: "format!(\"{};\", x)".to_string(),
                 }
             ))
         )
     }
 
     #[test]
-    fn if_let() {
+    fn let_with_type() {
         assert_eq!(
-            template_expression(b"@if let Some(x) = x { something }"),
+            template_expression(b"@let n: Option<&str> = x.get(1);"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Some(x) = x".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Let {
+                    pattern: "n: Option<&str>".to_string(),
+                    expr: "x.get(1)".to_string(),
                 }
             ))
         )
     }
 
     #[test]
-    fn if_let_2() {
+    fn let_missing_semicolon() {
         assert_eq!(
-            template_expression(b"@if let Some((x, y)) = x { something }"),
+            expression_error(b"@let n = 17 }"),
+            ":   1:@let n = 17 }\n\
+             :          ^ Error in let binding:\n\
+             :   1:@let n = 17 }\n\
+             :                 ^ Expected \";\"\n"
+        )
+    }
+
+    #[test]
+    fn raw_block() {
+        assert_eq!(
+            template_expression(b"@raw { <b>@html</b> }"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Some((x, y)) = x".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::RawBlock {
+                    body: vec![
+                        TemplateExpression::text(" <b>"),
+                        TemplateExpression::Expression {
+                            expr: "html".to_string()
+                        },
+                        TemplateExpression::text("</b> "),
+                    ],
                 }
             ))
         )
     }
 
     #[test]
-    fn if_let_3() {
+    fn fmt_output_code() {
+        let (_, body) =
+            template_body(tag("!"))(b"<p>@x</p>@raw {@y}!").unwrap();
         assert_eq!(
-            template_expression(
-                b"@if let Some(p) = Uri::borrow_from(&state) { something }"
-            ),
+            body.iter()
+                .map(|b| b
+                    .code(Target::Sync(OutputTrait::Fmt), Escaping::Html))
+                .join(""),
+            "_ructe_out_.write_str(\"<p>\")?;\n\
+             x.fmt_html(&mut _ructe_out_)?;\n\
+             _ructe_out_.write_str(\"</p>\")?;\n\
+             Html(&(y)).fmt_html(&mut _ructe_out_)?;\n"
+        )
+    }
+
+    #[test]
+    fn loop_text_is_written_once_per_iteration() {
+        let (_, body) = template_body(tag("!"))(
+            b"@for x in xs {<li>@x</li>}@for y in ys {@y}!",
+        )
+        .unwrap();
+        let code = body
+            .iter()
+            .map(|b| b.code(Target::Sync(OutputTrait::Fmt), Escaping::Html))
+            .join("");
+        assert_eq!(
+            code,
+            "{\nlet mut _ructe_first_ = true;\n\
+             for x in xs {\n\
+             if _ructe_first_ {\n_ructe_out_.write_str(\"<li>\")?;\n\
+             } else {\n_ructe_out_.write_str(\"</li><li>\")?;\n}\n\
+             _ructe_first_ = false;\n\
+             x.fmt_html(&mut _ructe_out_)?;\n}\n\
+             if !_ructe_first_ {\n_ructe_out_.write_str(\"</li>\")?;\n}\n\
+             }\n\
+             for y in ys {\ny.fmt_html(&mut _ructe_out_)?;\n}\n"
+        );
+    }
+
+    #[cfg(feature = "async-tokio")]
+    #[test]
+    fn async_output_code() {
+        let (_, body) =
+            template_body(tag("!"))(b"<p>@x</p>@:part(x, {y})!").unwrap();
+        assert_eq!(
+            body.iter()
+                .map(|b| b
+                    .code(Target::Async(AsyncRuntime::Tokio), Escaping::Html))
+                .join(""),
+            "tokio::io::AsyncWriteExt::write_all(\
+             &mut *_ructe_out_, b\"<p>\").await?;\n\
+             x.to_html_async(&mut *_ructe_out_).await?;\n\
+             tokio::io::AsyncWriteExt::write_all(\
+             &mut *_ructe_out_, b\"</p>\").await?;\n\
+             part(&mut *_ructe_out_, x, async |_ructe_out_| {\n\
+             tokio::io::AsyncWriteExt::write_all(\
+             &mut *_ructe_out_, b\"y\").await?;\n\
+             \nOk(())\n}\n).await?;\n"
+        )
+    }
+
+    #[test]
+    fn called_templates() {
+        let (_, body) = template_body(tag("!"))(
+            b"@:a()@if x {@:b(1, {@:c()})} else {@for y in x {@:d(y)}}!",
+        )
+        .unwrap();
+        let mut names = Vec::new();
+        for b in &body {
+            b.called_templates(&mut names);
+        }
+        assert_eq!(names, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn nonce_text_finds_opening_tags() {
+        let texts = |text| {
+            nonce_text(text, "n")
+                .into_iter()
+                .map(|e| match e {
+                    TemplateExpression::Text { text } => text,
+                    TemplateExpression::Expression { expr } => expr,
+                    e => panic!("Unexpected {:?}", e),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            texts("<SCRIPT\n  src=x></SCRIPT><scripts><style/>"),
+            [
+                "<SCRIPT",
+                " nonce=\"",
+                "n",
+                "\"",
+                "\n  src=x></SCRIPT><scripts><style",
+                " nonce=\"",
+                "n",
+                "\"",
+                "/>",
+            ]
+        );
+        assert_eq!(texts("<p>no tags</p>"), ["<p>no tags</p>"]);
+    }
+
+    #[test]
+    fn node_count_includes_nested() {
+        let (_, body) =
+            template_body(tag("!"))(b"<p>@x</p>@if y {a@:c({b})} else {d}!")
+                .unwrap();
+        assert_eq!(
+            body.iter()
+                .map(TemplateExpression::node_count)
+                .sum::<usize>(),
+            8,
+        );
+    }
+
+    #[test]
+    fn static_text_with_literals() {
+        let (_, body) = template_body(tag("!"))(
+            b"<p title=\"@(\"a \\\"b\\\"\")\">@1 &lt; 2@@</p>@raw{@\"<br>\"}!",
+        )
+        .unwrap();
+        assert_eq!(
+            static_text(&body, Escaping::Html).as_deref(),
+            Some("<p title=\"a &quot;b&quot;\">1 &lt; 2@</p><br>"),
+        );
+        assert_eq!(
+            static_text(&body, Escaping::Json).as_deref(),
+            Some("<p title=\"a \\\"b\\\"\">1 &lt; 2@</p><br>"),
+        );
+    }
+
+    #[test]
+    fn no_static_text_with_variables() {
+        let (_, body) =
+            template_body(tag("!"))(b"<p>@(\"a\")@x</p>@y!").unwrap();
+        assert_eq!(static_text(&body, Escaping::Html), None);
+    }
+
+    #[test]
+    fn adjacent_text_written_at_once() {
+        let (_, body) =
+            template_body(tag("!"))(b"a@@b@*c*@d@x@{e@}@if y {f@@g}!")
+                .unwrap();
+        assert_eq!(
+            body_code(&body, Target::default(), Escaping::Html),
+            "_ructe_out_.write_all(b\"a@bd\")?;\n\
+             x.to_html(&mut _ructe_out_)?;\n\
+             _ructe_out_.write_all(b\"{e}\")?;\n\
+             if y {\n\
+             _ructe_out_.write_all(b\"f@g\")?;\n\
+             }\n",
+        );
+    }
+
+    #[test]
+    fn text_template_code() {
+        let (_, body) =
+            template_body(tag("!"))(b"@x @:wrap({a @y})!").unwrap();
+        assert_eq!(
+            body.iter()
+                .map(|b| b.code(Target::Sync(OutputTrait::Io), Escaping::Text))
+                .join(""),
+            "Html(&(x)).to_html(&mut _ructe_out_)?;\n\
+             _ructe_out_.write_all(b\" \")?;\n\
+             wrap(&mut _ructe_out_, |mut _ructe_out_| {\n\
+             _ructe_out_.write_all(b\"a \")?;\n\
+             Html(&(y)).to_html(&mut _ructe_out_)?;\n\
+             \n\
+             Ok(())\n\
+             }\n\
+             )?;\n",
+        );
+    }
+
+    #[test]
+    fn static_size_counts_text_always_written() {
+        let (_, body) = template_body(tag("!"))(
+            b"<p>@x</p>@for y in x {<br>}@if x {ab} else {abc}@if x {ab}!",
+        )
+        .unwrap();
+        assert_eq!(
+            body.iter()
+                .map(TemplateExpression::static_size)
+                .sum::<usize>(),
+            "<p></p>ab".len()
+        );
+    }
+
+    #[test]
+    fn raw_block_code() {
+        let (_, raw) =
+            template_expression(b"@raw {@if x {@a.b()}@y}").unwrap();
+        assert_eq!(
+            raw.code(Target::Sync(OutputTrait::Io), Escaping::Html),
+            "if x {\n\
+             Html(&(a.b())).to_html(&mut _ructe_out_)?;\n\
+             }\n\
+             Html(&(y)).to_html(&mut _ructe_out_)?;\n"
+        )
+    }
+
+    #[test]
+    fn raw_as_a_name() {
+        assert_eq!(
+            template_expression(b"@raw is raw"),
             Ok((
-                &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Some(p) = Uri::borrow_from(&state)"
-                        .to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                &b" is raw"[..],
+                TemplateExpression::Expression {
+                    expr: "raw".to_string()
                 }
             ))
         )
     }
 
     #[test]
-    fn if_let_struct() {
+    fn trim_around_block() {
         assert_eq!(
-            template_expression(
-                b"@if let Struct{x, y} = variable { something }"
-            ),
+            template_block(b"{<ul>\n  @-if x {<li>}-@\n</ul>}"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Struct{x, y} = variable".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
-                }
+                vec![
+                    TemplateExpression::text("<ul>"),
+                    TemplateExpression::IfBlock {
+                        expr: "x".to_string(),
+                        body: vec![TemplateExpression::text("<li>")],
+                        else_body: None,
+                    },
+                    TemplateExpression::text("</ul>"),
+                ]
             ))
         )
     }
 
     #[test]
-    fn if_compare() {
+    fn trim_spaced_expression() {
         assert_eq!(
-            template_expression(b"@if x == 17 { something }"),
+            template_block(b"{ a @- x -@ b }"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "x == 17".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
-                }
+                vec![
+                    TemplateExpression::text(" a"),
+                    TemplateExpression::Expression {
+                        expr: "x".to_string()
+                    },
+                    TemplateExpression::text("b "),
+                ]
             ))
         )
     }
 
-    /// Check that issue #53 stays fixed.
     #[test]
-    fn if_compare_empty_string() {
-        // Note that x.is_empty() would be better in real code, but this and
-        // other uses of empty strings in conditionals should be ok.
+    fn dash_between_expressions() {
         assert_eq!(
-            template_expression(b"@if x == \"\" { something }"),
+            template_block(b"{@a-@b}"),
+            Ok((
+                &b""[..],
+                vec![
+                    TemplateExpression::Expression {
+                        expr: "a".to_string()
+                    },
+                    TemplateExpression::text("-"),
+                    TemplateExpression::Expression {
+                        expr: "b".to_string()
+                    },
+                ]
+            ))
+        )
+    }
+
+    #[test]
+    fn block_and_yield() {
+        assert_eq!(
+            template_block(b"{@block title {Hello} @yield content}"),
             Ok((
                 &b""[..],
+                vec![
+                    TemplateExpression::Block {
+                        name: "title".to_string(),
+                        body: vec![TemplateExpression::text("Hello")],
+                    },
+                    TemplateExpression::text(" "),
+                    TemplateExpression::Yield {
+                        name: "content".to_string(),
+                    },
+                ]
+            ))
+        )
+    }
+
+    #[test]
+    fn block_as_a_name() {
+        assert_eq!(
+            template_expression(b"@block is a name"),
+            Ok((
+                &b" is a name"[..],
+                TemplateExpression::Expression {
+                    expr: "block".to_string()
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn fill_nested_blocks() {
+        let (_, layout) = template_block(
+            b"{@block title {Default}@if x {@yield content}@yield footer}",
+        )
+        .unwrap();
+        let mut blocks = BTreeMap::new();
+        blocks.insert(
+            "content".to_string(),
+            vec![TemplateExpression::text("Content")],
+        );
+        assert_eq!(
+            fill_blocks(layout, &blocks),
+            vec![
+                TemplateExpression::Block {
+                    name: "title".to_string(),
+                    body: vec![TemplateExpression::text("Default")],
+                },
                 TemplateExpression::IfBlock {
-                    expr: "x == \"\"".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
+                    expr: "x".to_string(),
+                    body: vec![TemplateExpression::Block {
+                        name: "content".to_string(),
+                        body: vec![TemplateExpression::text("Content")],
+                    }],
                     else_body: None,
+                },
+                TemplateExpression::Block {
+                    name: "footer".to_string(),
+                    body: vec![],
+                },
+            ]
+        )
+    }
+
+    #[test]
+    fn include() {
+        assert_eq!(
+            template_expression(b"@include \"partials/nav.html\"<main>"),
+            Ok((
+                &b"<main>"[..],
+                TemplateExpression::Include(Include {
+                    path: "partials/nav.html".to_string(),
+                    rest_len: 25,
+                    ..Include::default()
+                })
+            ))
+        )
+    }
+
+    #[test]
+    fn include_from_root() {
+        assert_eq!(
+            template_expression(
+                b"@include admin/dashboard { user, title: page.title() }!"
+            ),
+            Ok((
+                &b"!"[..],
+                TemplateExpression::Include(Include {
+                    path: "admin/dashboard".to_string(),
+                    rest_len: 46,
+                    from_root: true,
+                    given_args: Some(vec![
+                        ("user".into(), "user".into()),
+                        ("title".into(), "page.title()".into()),
+                    ]),
+                    ..Include::default()
+                })
+            ))
+        )
+    }
+
+    #[test]
+    fn include_scope() {
+        let (_, mut body) = template_block(
+            b"{@let n = 1;@for (i, x) in xs {@include \"a\"}\
+              @if let Some(Foo { y, .. }) = z {@include \"b\"}\
+              @match m {Some(ref k) if k > 0 => {@include \"c\"} _ => {}}\
+              @include \"d\"}",
+        )
+        .unwrap();
+        let mut scopes = Vec::new();
+        for_each_include(
+            &mut body,
+            &mut vec!["arg".to_string()],
+            &mut |include: &mut Include, scope: &[String]| {
+                scopes.push(format!("{}: {}", include.path, scope.join(" ")));
+                Ok::<(), ()>(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            scopes,
+            ["a: arg n i x", "b: arg n y", "c: arg n k", "d: arg n"]
+        );
+    }
+
+    #[test]
+    fn match_simple() {
+        assert_eq!(
+            template_expression(
+                b"@match x { Some(x) => { @x } None => { none } }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::MatchBlock {
+                    expr: "x".to_string(),
+                    arms: vec![
+                        (
+                            "Some(x)".to_string(),
+                            vec![
+                                TemplateExpression::text(" "),
+                                TemplateExpression::Expression {
+                                    expr: "x".to_string()
+                                },
+                                TemplateExpression::text(" "),
+                            ]
+                        ),
+                        (
+                            "None".to_string(),
+                            vec![TemplateExpression::text(" none ")]
+                        ),
+                    ],
                 }
             ))
         )
     }
 
     #[test]
-    fn if_complex_logig() {
+    fn match_guard_and_alternatives() {
         assert_eq!(
-            template_expression(b"@if x == 17 || y && z() { something }"),
+            template_expression(
+                b"@match n { 0 | 1 => { few } n if n < 10 => { some } \
+                  _ => { many } }"
+            ),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "x == 17 || y && z()".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::MatchBlock {
+                    expr: "n".to_string(),
+                    arms: vec![
+                        (
+                            "0 | 1".to_string(),
+                            vec![TemplateExpression::text(" few ")]
+                        ),
+                        (
+                            "n if n < 10".to_string(),
+                            vec![TemplateExpression::text(" some ")]
+                        ),
+                        (
+                            "_".to_string(),
+                            vec![TemplateExpression::text(" many ")]
+                        ),
+                    ],
                 }
             ))
         )
     }
+
     #[test]
-    fn if_missing_conditional() {
+    fn match_range() {
         assert_eq!(
-            expression_error(b"@if { oops }"),
-            ":   1:@if { oops }\n\
-             :         ^ Error in conditional expression:\n\
-             :   1:@if { oops }\n\
-             :         ^ Expected expression\n\
-             :   1:@if { oops }\n\
-             :         ^ Expected rust expression\n"
+            template_expression(b"@match n { 1..=9 => { digit } _ => {} }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::MatchBlock {
+                    expr: "n".to_string(),
+                    arms: vec![
+                        (
+                            "1..=9".to_string(),
+                            vec![TemplateExpression::text(" digit ")]
+                        ),
+                        ("_".to_string(), vec![]),
+                    ],
+                }
+            ))
         )
     }
 
     #[test]
-    fn if_bad_let() {
+    fn match_missing_guard() {
         assert_eq!(
-            expression_error(b"@if let foo { oops }"),
-            ":   1:@if let foo { oops }\n\
-             :         ^ Error in conditional expression:\n\
-             :   1:@if let foo { oops }\n\
-             :                 ^ Expected \'=\'\n"
+            expression_error(b"@match n { n if => { oops } }"),
+            ":   1:@match n { n if => { oops } }\n\
+             :            ^ Error in match expression:\n\
+             :   1:@match n { n if => { oops } }\n\
+             :               ^ Error in match arm starting here:\n\
+             :   1:@match n { n if => { oops } }\n\
+             :                     ^ Expected guard expression\n\
+             :   1:@match n { n if => { oops } }\n\
+             :                     ^ Expected rust expression\n"
         )
     }
 
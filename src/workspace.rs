@@ -0,0 +1,160 @@
+use super::Result;
+use std::env;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+/// The root directory of the workspace of the crate being built.
+///
+/// This is `CARGO_WORKSPACE_DIR` if cargo sets it, otherwise the
+/// closest directory containing `CARGO_MANIFEST_DIR` with a
+/// `Cargo.toml` that has a `[workspace]` section.
+/// A crate that is not in a workspace is its own root.
+pub fn workspace_root() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os("CARGO_WORKSPACE_DIR") {
+        return Ok(dir.into());
+    }
+    let crate_dir = PathBuf::from(super::get_env("CARGO_MANIFEST_DIR")?);
+    for dir in crate_dir.ancestors() {
+        let manifest = dir.join("Cargo.toml");
+        if let Ok(manifest) = read_to_string(manifest) {
+            if section(&manifest, "workspace").is_some() {
+                return Ok(dir.into());
+            }
+        }
+    }
+    Ok(crate_dir)
+}
+
+/// The `templates` directories of the crates in the workspace at
+/// `root`.
+///
+/// The directory of the root package, if any, comes first, then the
+/// directories of the members, in the order they are listed.
+pub fn template_dirs(root: &Path) -> Result<Vec<PathBuf>> {
+    let manifest_path = root.join("Cargo.toml");
+    println!("cargo:rerun-if-changed={}", manifest_path.display());
+    let manifest = read_to_string(&manifest_path)?;
+    let mut crates = Vec::new();
+    if section(&manifest, "package").is_some() {
+        crates.push(root.to_path_buf());
+    }
+    if let Some(workspace) = section(&manifest, "workspace") {
+        let exclude = array(workspace, "exclude")
+            .into_iter()
+            .map(|dir| root.join(dir))
+            .collect::<Vec<_>>();
+        for member in array(workspace, "members") {
+            for dir in expand(root, member)? {
+                if !exclude.contains(&dir) && !crates.contains(&dir) {
+                    crates.push(dir);
+                }
+            }
+        }
+    }
+    Ok(crates
+        .into_iter()
+        .map(|dir| dir.join("templates"))
+        .filter(|dir| dir.is_dir())
+        .collect())
+}
+
+/// The directories of a member, which may end with a `*` wildcard.
+fn expand(root: &Path, member: &str) -> Result<Vec<PathBuf>> {
+    if let Some(parent) = member.strip_suffix('*') {
+        let mut dirs = Vec::new();
+        for entry in read_dir(root.join(parent))? {
+            let dir = entry?.path();
+            if dir.join("Cargo.toml").is_file() {
+                dirs.push(dir);
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    } else {
+        Ok(vec![root.join(member)])
+    }
+}
+
+/// The content of the section `[name]` in a toml manifest.
+fn section<'a>(manifest: &'a str, name: &str) -> Option<&'a str> {
+    let head = format!("[{}]", name);
+    let mut pos = 0;
+    let mut start = None;
+    for line in manifest.split_inclusive('\n') {
+        let line_start = pos;
+        pos += line.len();
+        match start {
+            None if line.trim() == head => start = Some(pos),
+            Some(start) if line.starts_with('[') => {
+                return Some(&manifest[start..line_start]);
+            }
+            _ => (),
+        }
+    }
+    start.map(|start| &manifest[start..])
+}
+
+/// The strings in the array `key = [...]` in a toml section.
+///
+/// This is not a full toml parser, but handles the simple arrays of
+/// paths that are used for workspace members.
+fn array<'a>(section: &'a str, key: &str) -> Vec<&'a str> {
+    let mut pos = 0;
+    for line in section.split_inclusive('\n') {
+        let line_start = pos;
+        pos += line.len();
+        let is_key = line
+            .trim_start()
+            .strip_prefix(key)
+            .map_or(false, |rest| rest.trim_start().starts_with('='));
+        if is_key {
+            let rest = &section[line_start..];
+            let rest = &rest[rest.find('[').unwrap_or(0)..];
+            return rest[..rest.find(']').unwrap_or(rest.len())]
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or(""))
+                .flat_map(|line| line.split(','))
+                .map(|item| item.trim().trim_start_matches('[').trim())
+                .filter_map(|item| {
+                    item.strip_prefix('"')
+                        .and_then(|item| item.strip_suffix('"'))
+                })
+                .collect();
+        }
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{array, section};
+
+    const MANIFEST: &str = "[package]\n\
+                            name = \"site\"\n\
+                            \n\
+                            [workspace]\n\
+                            members = [\n    \
+                            \"web\", # the server\n    \
+                            \"crates/*\",\n\
+                            ]\n\
+                            exclude = [\"crates/old\"]\n\
+                            \n\
+                            [dependencies]\n\
+                            members = \"2\"\n";
+
+    #[test]
+    fn workspace_section() {
+        let workspace = section(MANIFEST, "workspace").unwrap();
+        assert!(workspace.contains("exclude"));
+        assert!(!workspace.contains("dependencies"));
+        assert!(section(MANIFEST, "lib").is_none());
+    }
+
+    #[test]
+    fn workspace_members() {
+        let workspace = section(MANIFEST, "workspace").unwrap();
+        assert_eq!(array(workspace, "members"), ["web", "crates/*"]);
+        assert_eq!(array(workspace, "exclude"), ["crates/old"]);
+        assert!(array(workspace, "default-members").is_empty());
+    }
+}